@@ -0,0 +1,4 @@
+//! Off-chain helpers for building dexaggregator routes, quoting the venues whose on-chain
+//! `quote` is unavailable.
+
+pub mod whirlpool;
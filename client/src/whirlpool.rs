@@ -0,0 +1,514 @@
+//! Quoting for Whirlpool concentrated liquidity pools, walking the initialized ticks of the
+//! tick arrays a swap would pass to the `Whirlpool` route leg.
+//!
+//! The swap math is a port of the program's Q64.64 integer math, so a quote is the exact
+//! output of a swap through the same accounts, until the pool changes.
+
+use solana_program::pubkey::Pubkey;
+use uint::construct_uint;
+
+solana_program::declare_id!("whirLbMiicVdio4qvUfM5KAg6Ct8VwpYzGff3uctyCc");
+
+/// Ticks in a tick array.
+pub const TICK_ARRAY_SIZE: i32 = 88;
+/// Tick arrays a swap can pass through.
+pub const MAX_TICK_ARRAYS: usize = 3;
+
+// Offsets in the `Whirlpool` account, discriminator included.
+const TICK_SPACING_OFFSET: usize = 41;
+const FEE_RATE_OFFSET: usize = 45;
+const LIQUIDITY_OFFSET: usize = 49;
+const SQRT_PRICE_OFFSET: usize = 65;
+const TICK_CURRENT_INDEX_OFFSET: usize = 81;
+const TOKEN_MINT_A_OFFSET: usize = 101;
+const TOKEN_VAULT_A_OFFSET: usize = 133;
+const TOKEN_MINT_B_OFFSET: usize = 181;
+const TOKEN_VAULT_B_OFFSET: usize = 213;
+
+// Offsets in the `TickArray` account, discriminator included, and the size of a tick.
+const START_TICK_INDEX_OFFSET: usize = 8;
+const TICKS_OFFSET: usize = 12;
+const TICK_LEN: usize = 113;
+
+// Fee rates are in hundredths of a basis point.
+const FEE_RATE_DENOMINATOR: u128 = 1_000_000;
+
+/// Bounds of a tick index.
+pub const MIN_TICK_INDEX: i32 = -443_636;
+pub const MAX_TICK_INDEX: i32 = 443_636;
+/// Bounds of the Q64.64 square root price, those of the tick index bounds.
+pub const MIN_SQRT_PRICE_X64: u128 = 4_295_048_016;
+pub const MAX_SQRT_PRICE_X64: u128 = 79_226_673_515_401_279_992_447_579_055;
+
+// sqrt(1.0001)^(2^i) in Q96.96 for each bit i of a positive tick index, and its inverse in
+// Q64.64 for each bit of a negative one.
+const TICK_RATIOS_X96: [u128; 19] = [
+    79_232_123_823_359_799_118_286_999_567,
+    79_236_085_330_515_764_027_303_304_731,
+    79_244_008_939_048_815_603_706_035_061,
+    79_259_858_533_276_714_757_314_932_305,
+    79_291_567_232_598_584_799_939_703_904,
+    79_355_022_692_464_371_645_785_046_466,
+    79_482_085_999_252_804_386_437_311_141,
+    79_736_823_300_114_093_921_829_183_326,
+    80_248_749_790_819_932_309_965_073_892,
+    81_282_483_887_344_747_381_513_967_011,
+    83_390_072_131_320_151_908_154_831_281,
+    87_770_609_709_833_776_024_991_924_138,
+    97_234_110_755_111_693_312_479_820_773,
+    119_332_217_159_966_728_226_237_229_890,
+    179_736_315_981_702_064_433_883_588_727,
+    407_748_233_172_238_350_107_850_275_304,
+    2_098_478_828_474_011_932_436_660_412_517,
+    55_581_415_166_113_811_149_459_800_483_533,
+    38_992_368_544_603_139_932_233_054_999_993_551,
+];
+const INVERSE_TICK_RATIOS_X64: [u128; 19] = [
+    18_445_821_805_675_392_311,
+    18_444_899_583_751_176_498,
+    18_443_055_278_223_354_162,
+    18_439_367_220_385_604_838,
+    18_431_993_317_065_449_817,
+    18_417_254_355_718_160_513,
+    18_387_811_781_193_591_352,
+    18_329_067_761_203_520_168,
+    18_212_142_134_806_087_854,
+    17_980_523_815_641_551_639,
+    17_526_086_738_831_147_013,
+    16_651_378_430_235_024_244,
+    15_030_750_278_693_429_944,
+    12_247_334_978_882_834_399,
+    8_131_365_268_884_726_200,
+    3_584_323_654_723_342_297,
+    696_457_651_847_595_233,
+    26_294_789_957_452_057,
+    37_481_735_321_082,
+];
+
+construct_uint! {
+    /// Unsigned 256 bit integer, for the products and quotients of Q64.64 values.
+    pub struct U256(4);
+}
+
+/// The fields of a `Whirlpool` account needed to quote and route through it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Whirlpool {
+    pub tick_spacing: u16,
+    pub fee_rate: u16,
+    pub liquidity: u128,
+    pub sqrt_price: u128,
+    pub tick_current_index: i32,
+    pub token_mint_a: Pubkey,
+    pub token_vault_a: Pubkey,
+    pub token_mint_b: Pubkey,
+    pub token_vault_b: Pubkey,
+}
+
+impl Whirlpool {
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        if data.len() < TOKEN_VAULT_B_OFFSET + 32 {
+            return None;
+        }
+        Some(Whirlpool {
+            tick_spacing: u16::from_le_bytes(read(data, TICK_SPACING_OFFSET)),
+            fee_rate: u16::from_le_bytes(read(data, FEE_RATE_OFFSET)),
+            liquidity: u128::from_le_bytes(read(data, LIQUIDITY_OFFSET)),
+            sqrt_price: u128::from_le_bytes(read(data, SQRT_PRICE_OFFSET)),
+            tick_current_index: i32::from_le_bytes(read(data, TICK_CURRENT_INDEX_OFFSET)),
+            token_mint_a: Pubkey::new_from_array(read(data, TOKEN_MINT_A_OFFSET)),
+            token_vault_a: Pubkey::new_from_array(read(data, TOKEN_VAULT_A_OFFSET)),
+            token_mint_b: Pubkey::new_from_array(read(data, TOKEN_MINT_B_OFFSET)),
+            token_vault_b: Pubkey::new_from_array(read(data, TOKEN_VAULT_B_OFFSET)),
+        })
+    }
+
+    /// Start indexes of the tick arrays a swap in the direction `a_to_b` passes through, in
+    /// the order the `Whirlpool` route leg takes them.
+    pub fn tick_array_start_indexes(&self, a_to_b: bool) -> [i32; MAX_TICK_ARRAYS] {
+        let ticks_in_array = TICK_ARRAY_SIZE * self.tick_spacing as i32;
+        let start = tick_array_start_index(self.tick_current_index, self.tick_spacing);
+        let step = if a_to_b {
+            -ticks_in_array
+        } else {
+            ticks_in_array
+        };
+        [start, start + step, start + 2 * step]
+    }
+}
+
+/// An initialized or empty tick.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Tick {
+    pub initialized: bool,
+    pub liquidity_net: i128,
+}
+
+/// The ticks of a `TickArray` account.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TickArray {
+    pub start_tick_index: i32,
+    pub ticks: Vec<Tick>,
+}
+
+impl TickArray {
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        if data.len() < TICKS_OFFSET + TICK_ARRAY_SIZE as usize * TICK_LEN {
+            return None;
+        }
+        let ticks = (0..TICK_ARRAY_SIZE as usize)
+            .map(|i| {
+                let offset = TICKS_OFFSET + i * TICK_LEN;
+                Tick {
+                    initialized: data[offset] != 0,
+                    liquidity_net: i128::from_le_bytes(read(data, offset + 1)),
+                }
+            })
+            .collect();
+        Some(TickArray {
+            start_tick_index: i32::from_le_bytes(read(data, START_TICK_INDEX_OFFSET)),
+            ticks,
+        })
+    }
+}
+
+/// Start index of the tick array holding `tick_index`.
+pub fn tick_array_start_index(tick_index: i32, tick_spacing: u16) -> i32 {
+    let ticks_in_array = TICK_ARRAY_SIZE * tick_spacing as i32;
+    tick_index.div_euclid(ticks_in_array) * ticks_in_array
+}
+
+pub fn tick_array_address(whirlpool: &Pubkey, start_tick_index: i32) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            b"tick_array",
+            whirlpool.as_ref(),
+            start_tick_index.to_string().as_bytes(),
+        ],
+        &ID,
+    )
+    .0
+}
+
+pub fn oracle_address(whirlpool: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"oracle", whirlpool.as_ref()], &ID).0
+}
+
+/// Result of a swap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quote {
+    /// Input consumed, less than requested if the tick arrays ran out of liquidity.
+    pub amount_in: u64,
+    pub amount_out: u64,
+    /// Q64.64 square root price after the swap.
+    pub sqrt_price: u128,
+    pub ticks_crossed: u32,
+}
+
+/// Quotes swapping `amount_in` of token a for token b if `a_to_b`, else of token b for
+/// token a, through the pool's liquidity in `tick_arrays`. `None` if the swap math
+/// overflows, failing the program's swap too.
+pub fn quote_exact_in(
+    whirlpool: &Whirlpool,
+    tick_arrays: &[TickArray],
+    amount_in: u64,
+    a_to_b: bool,
+) -> Option<Quote> {
+    let mut remaining = amount_in;
+    let mut amount_out: u64 = 0;
+    let mut sqrt_price = whirlpool.sqrt_price;
+    let mut liquidity = whirlpool.liquidity;
+    let mut ticks_crossed = 0;
+
+    for (tick_index, tick) in ticks_in_direction(whirlpool, tick_arrays, a_to_b) {
+        if remaining == 0 {
+            break;
+        }
+        let target = sqrt_price_from_tick_index(tick_index);
+        let step = compute_swap_step(
+            remaining,
+            whirlpool.fee_rate,
+            liquidity,
+            sqrt_price,
+            target,
+            a_to_b,
+        )?;
+        remaining = remaining.checked_sub(step.amount_in.checked_add(step.fee_amount)?)?;
+        amount_out = amount_out.checked_add(step.amount_out)?;
+        sqrt_price = step.next_sqrt_price;
+        if sqrt_price != target {
+            // The swap ends within this range.
+            break;
+        }
+        if let Some(tick) = tick {
+            // Liquidity net is added crossing a tick upwards, and removed crossing it down.
+            let liquidity_net = if a_to_b {
+                tick.liquidity_net.checked_neg()?
+            } else {
+                tick.liquidity_net
+            };
+            liquidity = liquidity.checked_add_signed(liquidity_net)?;
+            ticks_crossed += 1;
+        }
+    }
+
+    Some(Quote {
+        amount_in: amount_in - remaining,
+        amount_out,
+        sqrt_price,
+        ticks_crossed,
+    })
+}
+
+/// Q64.64 square root price of `tick_index`, i.e. sqrt(1.0001^tick_index), for a tick
+/// index between `MIN_TICK_INDEX` and `MAX_TICK_INDEX`.
+pub fn sqrt_price_from_tick_index(tick_index: i32) -> u128 {
+    let bits = tick_index.unsigned_abs();
+    if tick_index >= 0 {
+        let mut ratio = if bits & 1 != 0 {
+            TICK_RATIOS_X96[0]
+        } else {
+            1 << 96
+        };
+        for (bit, factor) in TICK_RATIOS_X96.iter().enumerate().skip(1) {
+            if bits & (1 << bit) != 0 {
+                ratio = ((U256::from(ratio) * U256::from(*factor)) >> 96).as_u128();
+            }
+        }
+        ratio >> 32
+    } else {
+        let mut ratio = if bits & 1 != 0 {
+            INVERSE_TICK_RATIOS_X64[0]
+        } else {
+            1 << 64
+        };
+        for (bit, factor) in INVERSE_TICK_RATIOS_X64.iter().enumerate().skip(1) {
+            if bits & (1 << bit) != 0 {
+                ratio = (ratio * factor) >> 64;
+            }
+        }
+        ratio
+    }
+}
+
+/// Amount of token a between two Q64.64 square root prices at `liquidity`, i.e.
+/// liquidity * (1 / lower - 1 / upper). `None` if it doesn't fit a u64.
+pub fn get_amount_delta_a(
+    sqrt_price_0: u128,
+    sqrt_price_1: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Option<u64> {
+    let (lower, upper) = (
+        sqrt_price_0.min(sqrt_price_1),
+        sqrt_price_0.max(sqrt_price_1),
+    );
+    let numerator = shift_word_left(U256::from(liquidity) * U256::from(upper - lower))?;
+    let denominator = U256::from(upper) * U256::from(lower);
+    to_u64(div_round_up_if(numerator, denominator, round_up)?)
+}
+
+/// Amount of token b between two Q64.64 square root prices at `liquidity`, i.e.
+/// liquidity * (upper - lower). `None` if it doesn't fit a u64.
+pub fn get_amount_delta_b(
+    sqrt_price_0: u128,
+    sqrt_price_1: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Option<u64> {
+    let product = liquidity.checked_mul(sqrt_price_0.abs_diff(sqrt_price_1))?;
+    let round = round_up && product & u64::MAX as u128 > 0;
+    u64::try_from(product >> 64).ok()?.checked_add(round as u64)
+}
+
+/// Q64.64 square root price after swapping `amount` from `sqrt_price` at `liquidity`, the
+/// input if `amount_specified_is_input`, else the output. Rounded away from the input's
+/// side, so the pool never gives out more than the amount pays for.
+pub fn get_next_sqrt_price(
+    sqrt_price: u128,
+    liquidity: u128,
+    amount: u64,
+    amount_specified_is_input: bool,
+    a_to_b: bool,
+) -> Option<u128> {
+    if amount_specified_is_input == a_to_b {
+        next_sqrt_price_from_a_round_up(sqrt_price, liquidity, amount, amount_specified_is_input)
+    } else {
+        next_sqrt_price_from_b_round_down(sqrt_price, liquidity, amount, amount_specified_is_input)
+    }
+}
+
+// liquidity * sqrt_price / (liquidity ± amount * sqrt_price), rounded up.
+fn next_sqrt_price_from_a_round_up(
+    sqrt_price: u128,
+    liquidity: u128,
+    amount: u64,
+    amount_specified_is_input: bool,
+) -> Option<u128> {
+    if amount == 0 {
+        return Some(sqrt_price);
+    }
+    let product = U256::from(sqrt_price) * U256::from(amount);
+    let numerator = shift_word_left(U256::from(liquidity) * U256::from(sqrt_price))?;
+    let liquidity_x64 = U256::from(liquidity) << 64;
+    let denominator = if amount_specified_is_input {
+        liquidity_x64.checked_add(product)?
+    } else {
+        liquidity_x64.checked_sub(product)?
+    };
+    let price = div_round_up_if(numerator, denominator, true)?;
+    if price < U256::from(MIN_SQRT_PRICE_X64) || price > U256::from(MAX_SQRT_PRICE_X64) {
+        return None;
+    }
+    Some(price.as_u128())
+}
+
+// sqrt_price ± amount / liquidity, rounded down.
+fn next_sqrt_price_from_b_round_down(
+    sqrt_price: u128,
+    liquidity: u128,
+    amount: u64,
+    amount_specified_is_input: bool,
+) -> Option<u128> {
+    let amount_x64 = (amount as u128) << 64;
+    let round = !amount_specified_is_input && amount_x64.checked_rem(liquidity)? > 0;
+    let delta = amount_x64.checked_div(liquidity)? + round as u128;
+    if amount_specified_is_input {
+        sqrt_price.checked_add(delta)
+    } else {
+        sqrt_price.checked_sub(delta)
+    }
+}
+
+// One step of a swap of an exact input, from `sqrt_price` towards `target` at constant
+// liquidity, as the program computes it.
+struct SwapStep {
+    amount_in: u64,
+    amount_out: u64,
+    fee_amount: u64,
+    next_sqrt_price: u128,
+}
+
+fn compute_swap_step(
+    amount_remaining: u64,
+    fee_rate: u16,
+    liquidity: u128,
+    sqrt_price: u128,
+    target: u128,
+    a_to_b: bool,
+) -> Option<SwapStep> {
+    let fee_rate = fee_rate as u128;
+    let amount_in_delta = |next| match a_to_b {
+        true => get_amount_delta_a(sqrt_price, next, liquidity, true),
+        false => get_amount_delta_b(sqrt_price, next, liquidity, true),
+    };
+    let amount_out_delta = |next| match a_to_b {
+        true => get_amount_delta_b(sqrt_price, next, liquidity, false),
+        false => get_amount_delta_a(sqrt_price, next, liquidity, false),
+    };
+
+    // The input net of the fee, and where it takes the price, the target if it reaches it.
+    let amount_net = (amount_remaining as u128 * (FEE_RATE_DENOMINATOR - fee_rate)
+        / FEE_RATE_DENOMINATOR) as u64;
+    let next_sqrt_price = match amount_in_delta(target) {
+        Some(amount_to_target) if amount_to_target <= amount_net => target,
+        _ => get_next_sqrt_price(sqrt_price, liquidity, amount_net, true, a_to_b)?,
+    };
+    let amount_in = amount_in_delta(next_sqrt_price)?;
+    let amount_out = amount_out_delta(next_sqrt_price)?;
+    // Reaching the target, the fee is charged on the input it took, else it's the rest.
+    let fee_amount = if next_sqrt_price == target {
+        let fee = div_round_up_if(
+            U256::from(amount_in) * U256::from(fee_rate),
+            U256::from(FEE_RATE_DENOMINATOR - fee_rate),
+            true,
+        )?;
+        to_u64(fee)?
+    } else {
+        amount_remaining.checked_sub(amount_in)?
+    };
+    Some(SwapStep {
+        amount_in,
+        amount_out,
+        fee_amount,
+        next_sqrt_price,
+    })
+}
+
+fn shift_word_left(value: U256) -> Option<U256> {
+    value.checked_mul(U256::from(1u128 << 64))
+}
+
+fn div_round_up_if(numerator: U256, denominator: U256, round_up: bool) -> Option<U256> {
+    if denominator.is_zero() {
+        return None;
+    }
+    let (quotient, remainder) = numerator.div_mod(denominator);
+    if round_up && !remainder.is_zero() {
+        quotient.checked_add(U256::one())
+    } else {
+        Some(quotient)
+    }
+}
+
+fn to_u64(value: U256) -> Option<u64> {
+    (value <= U256::from(u64::MAX)).then(|| value.as_u64())
+}
+
+// The initialized ticks a swap crosses in order, ending with the edge of the last tick
+// array the swap can reach, where the program stops its search too.
+fn ticks_in_direction(
+    whirlpool: &Whirlpool,
+    tick_arrays: &[TickArray],
+    a_to_b: bool,
+) -> Vec<(i32, Option<Tick>)> {
+    let tick_spacing = whirlpool.tick_spacing as i32;
+    let current = whirlpool.tick_current_index;
+    let mut ticks: Vec<(i32, Option<Tick>)> = tick_arrays
+        .iter()
+        .take(MAX_TICK_ARRAYS)
+        .flat_map(|array| {
+            array
+                .ticks
+                .iter()
+                .enumerate()
+                .filter(|(_, tick)| tick.initialized)
+                .map(move |(i, tick)| (array.start_tick_index + i as i32 * tick_spacing, *tick))
+        })
+        .filter(|(index, _)| {
+            if a_to_b {
+                *index <= current
+            } else {
+                *index > current
+            }
+        })
+        .map(|(index, tick)| (index, Some(tick)))
+        .collect();
+    if a_to_b {
+        ticks.sort_by_key(|(index, _)| std::cmp::Reverse(*index));
+    } else {
+        ticks.sort_by_key(|(index, _)| *index);
+    }
+
+    let ticks_in_array = TICK_ARRAY_SIZE * tick_spacing;
+    let edge = tick_arrays
+        .iter()
+        .take(MAX_TICK_ARRAYS)
+        .map(|array| {
+            if a_to_b {
+                array.start_tick_index
+            } else {
+                array.start_tick_index + ticks_in_array - 1
+            }
+        })
+        .reduce(|a, b| if a_to_b { a.min(b) } else { a.max(b) });
+    if let Some(edge) = edge {
+        if ticks.last().is_none_or(|(index, _)| *index != edge) {
+            ticks.push((edge, None));
+        }
+    }
+    ticks
+}
+
+fn read<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
+    data[offset..offset + N].try_into().unwrap()
+}
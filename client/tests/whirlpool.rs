@@ -0,0 +1,130 @@
+use dexaggregator_client::whirlpool::*;
+use solana_program::pubkey::Pubkey;
+
+const LIQUIDITY: u128 = 1_000_000_000_000;
+// 0.3%.
+const FEE_RATE: u16 = 3_000;
+
+fn whirlpool() -> Whirlpool {
+    Whirlpool {
+        tick_spacing: 64,
+        fee_rate: FEE_RATE,
+        liquidity: LIQUIDITY,
+        sqrt_price: 1 << 64,
+        tick_current_index: 0,
+        token_mint_a: Pubkey::new_unique(),
+        token_vault_a: Pubkey::new_unique(),
+        token_mint_b: Pubkey::new_unique(),
+        token_vault_b: Pubkey::new_unique(),
+    }
+}
+
+fn tick_arrays(whirlpool: &Whirlpool, a_to_b: bool) -> Vec<TickArray> {
+    whirlpool
+        .tick_array_start_indexes(a_to_b)
+        .into_iter()
+        .map(|start_tick_index| TickArray {
+            start_tick_index,
+            ticks: vec![Tick::default(); TICK_ARRAY_SIZE as usize],
+        })
+        .collect()
+}
+
+#[test]
+fn quotes_within_a_single_range() {
+    let whirlpool = whirlpool();
+    let amount_in = 1_000_000;
+
+    let quote =
+        quote_exact_in(&whirlpool, &tick_arrays(&whirlpool, true), amount_in, true).unwrap();
+
+    // At a price of 1, L / (L + x) of the net input is paid out.
+    let net_in = amount_in as f64 * (1.0 - FEE_RATE as f64 / 1_000_000.0);
+    let expected = net_in * LIQUIDITY as f64 / (LIQUIDITY as f64 + net_in);
+    assert_eq!(quote.amount_in, amount_in);
+    assert!((quote.amount_out as f64 - expected).abs() <= 1.0);
+    assert_eq!(quote.ticks_crossed, 0);
+    assert!(quote.sqrt_price < whirlpool.sqrt_price);
+}
+
+#[test]
+fn crossing_a_tick_changes_liquidity() {
+    let whirlpool = whirlpool();
+    let amount_in = 10_000_000_000;
+    let unchanged =
+        quote_exact_in(&whirlpool, &tick_arrays(&whirlpool, true), amount_in, true).unwrap();
+
+    // Half the liquidity ends a range below the current price.
+    let mut arrays = tick_arrays(&whirlpool, true);
+    let below = &mut arrays[1];
+    below.ticks[TICK_ARRAY_SIZE as usize - 1] = Tick {
+        initialized: true,
+        liquidity_net: LIQUIDITY as i128 / 2,
+    };
+    let quote = quote_exact_in(&whirlpool, &arrays, amount_in, true).unwrap();
+
+    assert_eq!(quote.ticks_crossed, 1);
+    assert!(quote.amount_out < unchanged.amount_out);
+}
+
+#[test]
+fn tick_arrays_follow_the_swap_direction() {
+    let whirlpool = whirlpool();
+    assert_eq!(
+        whirlpool.tick_array_start_indexes(true),
+        [0, -5_632, -11_264]
+    );
+    assert_eq!(
+        whirlpool.tick_array_start_indexes(false),
+        [0, 5_632, 11_264]
+    );
+    assert_eq!(tick_array_start_index(-1, 64), -5_632);
+}
+
+#[test]
+fn sqrt_prices_of_ticks_match_the_program_bounds() {
+    assert_eq!(sqrt_price_from_tick_index(0), 1 << 64);
+    assert_eq!(
+        sqrt_price_from_tick_index(MIN_TICK_INDEX),
+        MIN_SQRT_PRICE_X64
+    );
+    assert_eq!(
+        sqrt_price_from_tick_index(MAX_TICK_INDEX),
+        MAX_SQRT_PRICE_X64
+    );
+    // A tick is a 0.01% move of the price, about 0.005% of its square root.
+    let up = sqrt_price_from_tick_index(1);
+    let down = sqrt_price_from_tick_index(-1);
+    assert_eq!(up - (1 << 64), 922_314_146_408_234);
+    assert!(down < 1 << 64 && (1 << 64) - down < up - (1 << 64));
+}
+
+#[test]
+fn amount_deltas_round_as_asked() {
+    let (one, two) = (1u128 << 64, 2u128 << 64);
+    // 1 / 1 - 1 / 2 of the liquidity in a, 2 - 1 of it in b.
+    assert_eq!(get_amount_delta_a(one, two, 1_001, false), Some(500));
+    assert_eq!(get_amount_delta_a(two, one, 1_001, true), Some(501));
+    assert_eq!(get_amount_delta_b(one, two, 1_001, true), Some(1_001));
+    assert_eq!(get_amount_delta_b(one, one + 1, 1_001, false), Some(0));
+    assert_eq!(get_amount_delta_b(one, one + 1, 1_001, true), Some(1));
+    // More than a u64 of output doesn't fit.
+    assert_eq!(
+        get_amount_delta_b(one, two, u64::MAX as u128 + 1, false),
+        None
+    );
+}
+
+#[test]
+fn next_sqrt_prices_never_pay_out_more_than_the_input_buys() {
+    let sqrt_price = 1 << 64;
+    for amount in [1, 999, 1_000_000, 123_456_789] {
+        // a in moves the price down, rounded up, and b in moves it up, rounded down.
+        let next = get_next_sqrt_price(sqrt_price, LIQUIDITY, amount, true, true).unwrap();
+        assert!(next < sqrt_price);
+        assert!(get_amount_delta_a(sqrt_price, next, LIQUIDITY, true).unwrap() <= amount);
+        let next = get_next_sqrt_price(sqrt_price, LIQUIDITY, amount, true, false).unwrap();
+        assert!(next >= sqrt_price);
+        assert!(get_amount_delta_b(sqrt_price, next, LIQUIDITY, true).unwrap() <= amount);
+    }
+}
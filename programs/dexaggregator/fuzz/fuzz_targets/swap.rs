@@ -0,0 +1,201 @@
+//! Random `swap` sequences against a simulated Serum order book, checking the fee and safety
+//! math the program applies around each SendTake.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use dexaggregator::math::{apply_safety_checks, coin_lots, fee_amount, split_fee};
+use dexaggregator::ErrorCode;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+struct Level {
+    price_lots: u16,
+    size_lots: u16,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Bid { amount_in: u64, amount_out_min: u64 },
+    Ask { amount_in: u64, amount_out_min: u64 },
+    RestBid(Level),
+    RestAsk(Level),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    coin_lot_size: u16,
+    pc_lot_size: u16,
+    fee_bps: u16,
+    bids: Vec<Level>,
+    asks: Vec<Level>,
+    ops: Vec<Op>,
+}
+
+// One side of the book, best price first.
+struct BookSide {
+    levels: Vec<Level>,
+    descending: bool,
+}
+
+impl BookSide {
+    fn new(levels: Vec<Level>, descending: bool) -> Self {
+        let mut side = BookSide {
+            levels: vec![],
+            descending,
+        };
+        levels.into_iter().for_each(|level| side.rest(level));
+        side
+    }
+
+    fn rest(&mut self, level: Level) {
+        if level.price_lots == 0 || level.size_lots == 0 {
+            return;
+        }
+        let position = self.levels.partition_point(|resting| {
+            if self.descending {
+                resting.price_lots >= level.price_lots
+            } else {
+                resting.price_lots <= level.price_lots
+            }
+        });
+        self.levels.insert(position, level);
+    }
+
+    fn size_lots(&self) -> u128 {
+        self.levels
+            .iter()
+            .map(|level| level.size_lots as u128)
+            .sum()
+    }
+}
+
+struct Market {
+    coin_lot_size: u64,
+    pc_lot_size: u64,
+    bids: BookSide,
+    asks: BookSide,
+}
+
+impl Market {
+    // Takes asks with up to `max_pc` native quote, returns the (pc, coin) traded.
+    fn take_asks(&mut self, max_pc: u64) -> (u64, u64) {
+        let (mut pc, mut coin_lots_filled) = (0u128, 0u128);
+        let mut remaining = max_pc as u128;
+        while let Some(level) = self.asks.levels.first_mut() {
+            let lot_price = level.price_lots as u128 * self.pc_lot_size as u128;
+            let lots = (remaining / lot_price).min(level.size_lots as u128);
+            if lots == 0 {
+                break;
+            }
+            remaining -= lots * lot_price;
+            pc += lots * lot_price;
+            coin_lots_filled += lots;
+            level.size_lots -= lots as u16;
+            if level.size_lots == 0 {
+                self.asks.levels.remove(0);
+            }
+        }
+        (
+            pc as u64,
+            (coin_lots_filled * self.coin_lot_size as u128) as u64,
+        )
+    }
+
+    // Takes bids with up to `max_coin` native base, returns the (coin, pc) traded.
+    fn take_bids(&mut self, max_coin: u64) -> Result<(u64, u64), ErrorCode> {
+        let mut remaining = coin_lots(self.coin_lot_size, max_coin)? as u128;
+        let (mut pc, mut coin_lots_filled) = (0u128, 0u128);
+        while let Some(level) = self.bids.levels.first_mut() {
+            let lots = remaining.min(level.size_lots as u128);
+            if lots == 0 {
+                break;
+            }
+            remaining -= lots;
+            pc += lots * level.price_lots as u128 * self.pc_lot_size as u128;
+            coin_lots_filled += lots;
+            level.size_lots -= lots as u16;
+            if level.size_lots == 0 {
+                self.bids.levels.remove(0);
+            }
+        }
+        Ok((
+            (coin_lots_filled * self.coin_lot_size as u128) as u64,
+            pc as u64,
+        ))
+    }
+}
+
+fuzz_target!(|input: Input| {
+    // A zero coin lot size is fuzzed, the program has to fail those swaps cleanly.
+    if input.pc_lot_size == 0 {
+        return;
+    }
+    let fee_bps = input.fee_bps % 10_001;
+    let mut market = Market {
+        coin_lot_size: input.coin_lot_size as u64,
+        pc_lot_size: input.pc_lot_size as u64,
+        bids: BookSide::new(input.bids, true),
+        asks: BookSide::new(input.asks, false),
+    };
+
+    for op in input.ops {
+        match op {
+            Op::Bid {
+                amount_in,
+                amount_out_min,
+            } => {
+                // The minimum output is sized in lots before the SendTake.
+                if let Err(code) = coin_lots(market.coin_lot_size, amount_out_min) {
+                    assert!(matches!(code, ErrorCode::InvalidLotSize));
+                    assert_eq!(market.coin_lot_size, 0);
+                    continue;
+                }
+                let book_before = market.asks.levels.clone();
+                let coin_before = market.asks.size_lots();
+                let (fee, amount_in_routed) = split_fee(amount_in, fee_bps);
+                assert_eq!(fee + amount_in_routed, amount_in);
+
+                let (from_amount, to_amount) = market.take_asks(amount_in_routed);
+                assert_eq!(
+                    (coin_before - market.asks.size_lots()) * market.coin_lot_size as u128,
+                    to_amount as u128
+                );
+                match apply_safety_checks(amount_in_routed, amount_out_min, from_amount, to_amount)
+                {
+                    Ok(()) => {
+                        assert!(fee + from_amount <= amount_in);
+                        assert!(to_amount >= amount_out_min && to_amount > 0);
+                    }
+                    // The transaction reverts, leaving the book as it was.
+                    Err(_) => market.asks.levels = book_before,
+                }
+            }
+            Op::Ask {
+                amount_in,
+                amount_out_min,
+            } => {
+                let book_before = market.bids.levels.clone();
+                let (from_amount, to_amount) = match market.take_bids(amount_in) {
+                    Ok(traded) => traded,
+                    Err(code) => {
+                        assert!(matches!(code, ErrorCode::InvalidLotSize));
+                        assert_eq!(market.coin_lot_size, 0);
+                        assert_eq!(market.bids.levels.len(), book_before.len());
+                        continue;
+                    }
+                };
+                assert!(from_amount <= amount_in);
+                match apply_safety_checks(amount_in, amount_out_min, from_amount, to_amount) {
+                    Ok(()) => {
+                        let fee = fee_amount(to_amount, fee_bps);
+                        assert!(fee <= to_amount);
+                        assert!(to_amount >= amount_out_min && to_amount > 0);
+                    }
+                    Err(_) => market.bids.levels = book_before,
+                }
+            }
+            Op::RestBid(level) => market.bids.rest(level),
+            Op::RestAsk(level) => market.asks.rest(level),
+        }
+    }
+});
@@ -2,12 +2,21 @@ use anchor_lang::prelude::*;
 use anchor_lang::solana_program::account_info::AccountInfo;
 use anchor_lang::solana_program::program_error::ProgramError;
 use anchor_lang::{context::CpiContext, Accounts, ToAccountInfos};
-use serum_dex::matching::Side;
+use serum_dex::instruction::SelfTradeBehavior;
+use serum_dex::matching::{OrderType, Side};
 use solana_program::entrypoint::ProgramResult;
 use std::num::NonZeroU64;
 
 pub use serum_dex;
 
+pub mod openbook_v2;
+pub mod phoenix;
+pub(crate) mod serum;
+pub mod token_swap;
+pub mod whirlpool;
+
+use crate::{RouteLeg, Venue};
+
 // #[cfg(feature = "mainnet")]
 anchor_lang::solana_program::declare_id!("EoTcMgcDRTJVZDMZWBoU6rhYHZfkNTVEAfz3uUJRcYGj");
 
@@ -89,3 +98,303 @@ pub struct SendTake<'info> {
     /// CHECK: Safe
     pub vault_signer: AccountInfo<'info>,
 }
+
+#[allow(clippy::too_many_arguments)]
+pub fn new_order_v3<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, NewOrderV3<'info>>,
+    side: Side,
+    limit_price: NonZeroU64,
+    max_coin_qty: NonZeroU64,
+    max_native_pc_qty_including_fees: NonZeroU64,
+    self_trade_behavior: SelfTradeBehavior,
+    order_type: OrderType,
+    client_order_id: u64,
+    limit: u16,
+) -> ProgramResult {
+    let referral = ctx.remaining_accounts.get(0);
+    let ix = serum_dex::instruction::new_order(
+        ctx.accounts.market.key,
+        ctx.accounts.open_orders.key,
+        ctx.accounts.request_queue.key,
+        ctx.accounts.event_queue.key,
+        ctx.accounts.market_bids.key,
+        ctx.accounts.market_asks.key,
+        ctx.accounts.order_payer_token_account.key,
+        ctx.accounts.open_orders_authority.key,
+        ctx.accounts.coin_vault.key,
+        ctx.accounts.pc_vault.key,
+        ctx.accounts.token_program.key,
+        ctx.accounts.rent.key,
+        referral.map(|r| r.key),
+        &ID,
+        side,
+        limit_price,
+        max_coin_qty,
+        order_type,
+        client_order_id,
+        self_trade_behavior,
+        limit,
+        max_native_pc_qty_including_fees,
+        i64::MAX,
+    )
+    .map_err(|pe| ProgramError::from(pe))?;
+    solana_program::program::invoke_signed(
+        &ix,
+        &ToAccountInfos::to_account_infos(&ctx),
+        ctx.signer_seeds,
+    )?;
+    Ok(())
+}
+
+pub fn settle_funds<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, SettleFunds<'info>>,
+) -> ProgramResult {
+    let referral = ctx.remaining_accounts.get(0);
+    let ix = serum_dex::instruction::settle_funds(
+        &ID,
+        ctx.accounts.market.key,
+        ctx.accounts.token_program.key,
+        ctx.accounts.open_orders.key,
+        ctx.accounts.open_orders_authority.key,
+        ctx.accounts.coin_vault.key,
+        ctx.accounts.coin_wallet.key,
+        ctx.accounts.pc_vault.key,
+        ctx.accounts.pc_wallet.key,
+        referral.map(|r| r.key),
+        ctx.accounts.vault_signer.key,
+    )
+    .map_err(|pe| ProgramError::from(pe))?;
+    solana_program::program::invoke_signed(
+        &ix,
+        &ToAccountInfos::to_account_infos(&ctx),
+        ctx.signer_seeds,
+    )?;
+    Ok(())
+}
+
+pub fn cancel_order_v2<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, CancelOrderV2<'info>>,
+    side: Side,
+    order_id: u128,
+) -> ProgramResult {
+    let ix = serum_dex::instruction::cancel_order(
+        &ID,
+        ctx.accounts.market.key,
+        ctx.accounts.market_bids.key,
+        ctx.accounts.market_asks.key,
+        ctx.accounts.open_orders.key,
+        ctx.accounts.open_orders_authority.key,
+        ctx.accounts.event_queue.key,
+        side,
+        order_id,
+    )
+    .map_err(|pe| ProgramError::from(pe))?;
+    solana_program::program::invoke_signed(
+        &ix,
+        &ToAccountInfos::to_account_infos(&ctx),
+        ctx.signer_seeds,
+    )?;
+    Ok(())
+}
+
+pub fn init_open_orders<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, InitOpenOrders<'info>>,
+) -> ProgramResult {
+    let ix = serum_dex::instruction::init_open_orders(
+        &ID,
+        ctx.accounts.open_orders.key,
+        ctx.accounts.authority.key,
+        ctx.accounts.market.key,
+        ctx.remaining_accounts.first().map(|acc| acc.key),
+    )
+    .map_err(|pe| ProgramError::from(pe))?;
+    solana_program::program::invoke_signed(
+        &ix,
+        &ToAccountInfos::to_account_infos(&ctx),
+        ctx.signer_seeds,
+    )?;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct NewOrderV3<'info> {
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub open_orders: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub request_queue: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub event_queue: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub market_bids: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub market_asks: AccountInfo<'info>,
+    // Token account where funds are transferred from for the order. If
+    // posting a bid market A/B, then this is the SPL token account for B.
+    /// CHECK: Safe
+    pub order_payer_token_account: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub open_orders_authority: AccountInfo<'info>,
+    // Also known as the "base" currency. For a given A/B market,
+    // this is the vault for the A mint.
+    /// CHECK: Safe
+    pub coin_vault: AccountInfo<'info>,
+    // Also known as the "quote" currency. For a given A/B market,
+    // this is the vault for the B mint.
+    /// CHECK: Safe
+    pub pc_vault: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub rent: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SettleFunds<'info> {
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub open_orders: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub open_orders_authority: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub coin_vault: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub pc_vault: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub coin_wallet: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub pc_wallet: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub vault_signer: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrderV2<'info> {
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub market_bids: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub market_asks: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub open_orders: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub open_orders_authority: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub event_queue: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitOpenOrders<'info> {
+    /// CHECK: Safe
+    pub open_orders: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub authority: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub rent: AccountInfo<'info>,
+}
+
+/// A venue a swap leg can be routed through.
+pub trait DexAdapter<'info> {
+    /// The user's token accounts the leg swaps (from, to) for `side`.
+    fn token_accounts(&self, side: crate::Side) -> (AccountInfo<'info>, AccountInfo<'info>);
+
+    /// Swaps all of `amount_in`, failing if less than `min_amount_out` is received.
+    fn swap_exact_in(
+        &self,
+        side: crate::Side,
+        amount_in: u64,
+        min_amount_out: u64,
+    ) -> ProgramResult;
+
+    /// Swaps for `amount_out`, failing if more than `max_amount_in` is needed.
+    fn swap_exact_out(
+        &self,
+        side: crate::Side,
+        amount_out: u64,
+        max_amount_in: u64,
+    ) -> ProgramResult;
+
+    /// Estimates the output of swapping `amount_in`, from the venue's on-chain state.
+    fn quote(&self, side: crate::Side, amount_in: u64) -> std::result::Result<u64, ProgramError>;
+}
+
+// A route leg's adapter, with the accounts left for the following legs.
+type LoadedAdapter<'a, 'info> = (Box<dyn DexAdapter<'info> + 'info>, &'a [AccountInfo<'info>]);
+
+/// Builds the adapter of a route `leg` on its venue from the front of `accounts`, with
+/// `legs_left` legs left in the route including this one. Each leg's accounts end with its
+/// venue's program, which must be the one the adapter was written for.
+pub(crate) fn load_adapter<'a, 'info>(
+    leg: &RouteLeg,
+    wallet_owner: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    accounts: &'a [AccountInfo<'info>],
+    legs_left: u64,
+) -> std::result::Result<LoadedAdapter<'a, 'info>, ProgramError> {
+    match leg.venue {
+        Venue::Serum => {
+            let (leg_accounts, rest) = split_leg_accounts(accounts, serum::LEG_ACCOUNTS, &ID)?;
+            let mut adapter = serum::OrderbookClient::from_leg_accounts(
+                leg_accounts,
+                wallet_owner,
+                token_program,
+                legs_left,
+            );
+            adapter.match_limit = leg.match_limit;
+            Ok((Box::new(adapter), rest))
+        }
+        Venue::OpenBookV2 => {
+            let (leg, rest) =
+                split_leg_accounts(accounts, openbook_v2::LEG_ACCOUNTS, &openbook_v2::ID)?;
+            let adapter = openbook_v2::OpenBookV2Client::from_leg_accounts(
+                leg,
+                wallet_owner,
+                token_program,
+                system_program,
+            );
+            Ok((Box::new(adapter), rest))
+        }
+        Venue::TokenSwap => {
+            let (leg, rest) =
+                split_leg_accounts(accounts, token_swap::LEG_ACCOUNTS, &token_swap::ID)?;
+            let adapter =
+                token_swap::TokenSwapClient::from_leg_accounts(leg, wallet_owner, token_program);
+            Ok((Box::new(adapter), rest))
+        }
+        Venue::Whirlpool => {
+            let (leg, rest) =
+                split_leg_accounts(accounts, whirlpool::LEG_ACCOUNTS, &whirlpool::ID)?;
+            let adapter =
+                whirlpool::WhirlpoolClient::from_leg_accounts(leg, wallet_owner, token_program);
+            Ok((Box::new(adapter), rest))
+        }
+        Venue::Phoenix => {
+            let (leg, rest) = split_leg_accounts(accounts, phoenix::LEG_ACCOUNTS, &phoenix::ID)?;
+            let adapter =
+                phoenix::PhoenixClient::from_leg_accounts(leg, wallet_owner, token_program);
+            Ok((Box::new(adapter), rest))
+        }
+    }
+}
+
+fn split_leg_accounts<'a, 'info>(
+    accounts: &'a [AccountInfo<'info>],
+    len: usize,
+    program_id: &Pubkey,
+) -> std::result::Result<(&'a [AccountInfo<'info>], &'a [AccountInfo<'info>]), ProgramError> {
+    if accounts.len() < len {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+    if accounts[len - 1].key != program_id {
+        return Err(error!(crate::ErrorCode::InvalidDexProgram).into());
+    }
+    Ok(accounts.split_at(len))
+}
@@ -0,0 +1,301 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::{context::CpiContext, Accounts, ToAccountInfos, ToAccountMetas};
+use anchor_spl::token;
+use solana_program::entrypoint::ProgramResult;
+
+use super::DexAdapter;
+use crate::Side;
+
+anchor_lang::solana_program::declare_id!("opnb2LAfJYbRMAHHvqjCwQxanZn7ReEHp1k81EohpZb");
+
+// Anchor discriminator of `place_take_order`, i.e. sha256("global:place_take_order")[..8].
+const PLACE_TAKE_ORDER_DISCRIMINATOR: [u8; 8] = [3, 44, 71, 3, 26, 199, 203, 85];
+
+// `PlaceOrderType::ImmediateOrCancel`.
+const ORDER_TYPE_IMMEDIATE_OR_CANCEL: u8 = 1;
+
+// Offsets of the lot sizes in the zero copy `Market` account, after the discriminator (8),
+// bump, decimals and padding (8), market authority (32), time expiry (8), collect fee admin
+// (32), the three optional admins (3 * 32), name (16), bids, asks and event heap (3 * 32),
+// the two optional oracles (2 * 32), `OracleConfig` (88) and `StablePriceModel` (288).
+const MARKET_QUOTE_LOT_SIZE_OFFSET: usize =
+    8 + 8 + 32 + 8 + 32 + 3 * 32 + 16 + 3 * 32 + 2 * 32 + 88 + 288;
+const MARKET_BASE_LOT_SIZE_OFFSET: usize = MARKET_QUOTE_LOT_SIZE_OFFSET + 8;
+
+// Accounts of an OpenBook v2 leg in a route: market, market authority, bids, asks,
+// base vault, quote vault, event heap, user base account, user quote account, oracle a,
+// oracle b, open orders admin and the OpenBook v2 program. Pass the program id for the
+// optional oracles and admin if the market has none.
+pub(crate) const LEG_ACCOUNTS: usize = 13;
+
+#[derive(AnchorSerialize)]
+struct PlaceTakeOrderArgs {
+    side: u8,
+    price_lots: i64,
+    max_base_lots: i64,
+    max_quote_lots_including_fees: i64,
+    order_type: u8,
+    limit: u8,
+}
+
+pub fn place_take_order<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, PlaceTakeOrder<'info>>,
+    side: Side,
+    price_lots: i64,
+    max_base_lots: i64,
+    max_quote_lots_including_fees: i64,
+    limit: u8,
+) -> ProgramResult {
+    let args = PlaceTakeOrderArgs {
+        side: match side {
+            Side::Bid => 0,
+            Side::Ask => 1,
+        },
+        price_lots,
+        max_base_lots,
+        max_quote_lots_including_fees,
+        order_type: ORDER_TYPE_IMMEDIATE_OR_CANCEL,
+        limit,
+    };
+    let mut data = PLACE_TAKE_ORDER_DISCRIMINATOR.to_vec();
+    args.serialize(&mut data)
+        .map_err(|_| ProgramError::InvalidInstructionData)?;
+    let ix = Instruction {
+        program_id: *ctx.program.key,
+        accounts: ctx.accounts.to_account_metas(None),
+        data,
+    };
+    solana_program::program::invoke_signed(
+        &ix,
+        &ToAccountInfos::to_account_infos(&ctx),
+        ctx.signer_seeds,
+    )?;
+    Ok(())
+}
+
+#[derive(Accounts, Clone)]
+pub struct PlaceTakeOrder<'info> {
+    /// CHECK: Safe
+    #[account(mut, signer)]
+    pub signer: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut, signer)]
+    pub penalty_payer: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub market: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub market_authority: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub bids: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub asks: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub market_base_vault: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub market_quote_vault: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub event_heap: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub user_base_account: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub user_quote_account: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub oracle_a: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub oracle_b: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub system_program: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub open_orders_admin: AccountInfo<'info>,
+}
+
+// Client for taking liquidity from an OpenBook v2 market.
+#[derive(Clone)]
+pub(crate) struct OpenBookV2Client<'info> {
+    pub(crate) accounts: PlaceTakeOrder<'info>,
+    // The OpenBook v2 program
+    /// CHECK: Safe
+    pub(crate) openbook_program: AccountInfo<'info>,
+}
+
+impl<'info> OpenBookV2Client<'info> {
+    /// Builds the client for a route leg from its accounts, in the order of `LEG_ACCOUNTS`.
+    pub(crate) fn from_leg_accounts(
+        accounts: &[AccountInfo<'info>],
+        wallet_owner: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+        system_program: &AccountInfo<'info>,
+    ) -> Self {
+        OpenBookV2Client {
+            accounts: PlaceTakeOrder {
+                signer: wallet_owner.clone(),
+                penalty_payer: wallet_owner.clone(),
+                market: accounts[0].clone(),
+                market_authority: accounts[1].clone(),
+                bids: accounts[2].clone(),
+                asks: accounts[3].clone(),
+                market_base_vault: accounts[4].clone(),
+                market_quote_vault: accounts[5].clone(),
+                event_heap: accounts[6].clone(),
+                user_base_account: accounts[7].clone(),
+                user_quote_account: accounts[8].clone(),
+                oracle_a: accounts[9].clone(),
+                oracle_b: accounts[10].clone(),
+                token_program: token_program.clone(),
+                system_program: system_program.clone(),
+                open_orders_admin: accounts[11].clone(),
+            },
+            openbook_program: accounts[12].clone(),
+        }
+    }
+
+    /// The market's (base, quote) lot sizes, both positive.
+    fn lot_sizes(&self) -> std::result::Result<(u64, u64), ProgramError> {
+        let market = &self.accounts.market;
+        if market.owner != self.openbook_program.key {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let data = market.try_borrow_data()?;
+        if data.len() < MARKET_BASE_LOT_SIZE_OFFSET + 8 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let read_i64 =
+            |offset: usize| i64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let base_lot_size = read_i64(MARKET_BASE_LOT_SIZE_OFFSET);
+        let quote_lot_size = read_i64(MARKET_QUOTE_LOT_SIZE_OFFSET);
+        if base_lot_size <= 0 || quote_lot_size <= 0 {
+            return Err(error!(crate::ErrorCode::InvalidLotSize).into());
+        }
+        Ok((base_lot_size as u64, quote_lot_size as u64))
+    }
+
+    /// Execute PlaceTakeOrder on OpenBook v2 via CPI
+    fn take(
+        &self,
+        side: Side,
+        price_lots: i64,
+        max_base_lots: i64,
+        max_quote_lots_including_fees: i64,
+    ) -> ProgramResult {
+        // Upper bound on the number of orders matched before giving up.
+        let limit = u8::MAX;
+        let ctx = CpiContext::new(self.openbook_program.clone(), self.accounts.clone());
+
+        msg!("PlaceTakeOrder CPI: side: {:?}, price_lots: {}, max_base_lots: {}, max_quote_lots_including_fees: {}, limit: {}", side, price_lots, max_base_lots, max_quote_lots_including_fees, limit);
+        place_take_order(
+            ctx,
+            side,
+            price_lots,
+            max_base_lots,
+            max_quote_lots_including_fees,
+            limit,
+        )
+    }
+
+    /// Takes as specified, failing if the user receives less than `min_amount_out`.
+    fn take_checked(
+        &self,
+        side: Side,
+        price_lots: i64,
+        max_base_lots: i64,
+        max_quote_lots_including_fees: i64,
+        min_amount_out: u64,
+    ) -> ProgramResult {
+        let (_, to_token) = self.token_accounts(side);
+        let to_amount_before = token::accessor::amount(&to_token)?;
+        self.take(
+            side,
+            price_lots,
+            max_base_lots,
+            max_quote_lots_including_fees,
+        )?;
+        let to_amount_after = token::accessor::amount(&to_token)?;
+        if to_amount_after < to_amount_before.saturating_add(min_amount_out) {
+            return Err(error!(crate::ErrorCode::SwapTokenAmountLessThanMin).into());
+        }
+        Ok(())
+    }
+}
+
+// Whole lots in `amount` of a positive `lot_size`, capped to OpenBook's signed quantities.
+fn lots(amount: u64, lot_size: u64) -> std::result::Result<i64, ProgramError> {
+    let lots = amount
+        .checked_div(lot_size)
+        .ok_or_else(|| ProgramError::from(error!(crate::ErrorCode::InvalidLotSize)))?;
+    Ok(lots.min(i64::MAX as u64) as i64)
+}
+
+impl<'info> DexAdapter<'info> for OpenBookV2Client<'info> {
+    fn token_accounts(&self, side: Side) -> (AccountInfo<'info>, AccountInfo<'info>) {
+        match side {
+            Side::Bid => (
+                self.accounts.user_quote_account.clone(),
+                self.accounts.user_base_account.clone(),
+            ),
+            Side::Ask => (
+                self.accounts.user_base_account.clone(),
+                self.accounts.user_quote_account.clone(),
+            ),
+        }
+    }
+
+    fn swap_exact_in(&self, side: Side, amount_in: u64, min_amount_out: u64) -> ProgramResult {
+        let (base_lot_size, quote_lot_size) = self.lot_sizes()?;
+        match side {
+            Side::Bid => self.take_checked(
+                side,
+                i64::MAX,
+                i64::MAX,
+                lots(amount_in, quote_lot_size)?,
+                min_amount_out,
+            ),
+            Side::Ask => self.take_checked(
+                side,
+                1,
+                lots(amount_in, base_lot_size)?,
+                i64::MAX,
+                min_amount_out,
+            ),
+        }
+    }
+
+    // An ask caps the quote traded at `amount_out`, the taker fee then comes off it.
+    fn swap_exact_out(&self, side: Side, amount_out: u64, max_amount_in: u64) -> ProgramResult {
+        let (base_lot_size, quote_lot_size) = self.lot_sizes()?;
+        match side {
+            Side::Bid => {
+                let base_lots = lots(amount_out, base_lot_size)?;
+                self.take_checked(
+                    side,
+                    i64::MAX,
+                    base_lots,
+                    lots(max_amount_in, quote_lot_size)?,
+                    (base_lots as u64).saturating_mul(base_lot_size),
+                )
+            }
+            Side::Ask => self.take_checked(
+                side,
+                1,
+                lots(max_amount_in, base_lot_size)?,
+                lots(amount_out, quote_lot_size)?,
+                0,
+            ),
+        }
+    }
+
+    // Walking the OpenBook v2 book sides on-chain is not supported, quote off-chain.
+    fn quote(&self, _side: Side, _amount_in: u64) -> std::result::Result<u64, ProgramError> {
+        Err(error!(crate::ErrorCode::QuoteUnavailable).into())
+    }
+}
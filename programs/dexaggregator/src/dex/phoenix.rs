@@ -0,0 +1,309 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::{context::CpiContext, Accounts, ToAccountInfos, ToAccountMetas};
+use anchor_spl::token;
+use solana_program::entrypoint::ProgramResult;
+
+use super::DexAdapter;
+use crate::math::coin_lots;
+use crate::Side;
+
+anchor_lang::solana_program::declare_id!("PhoeNiXZ8ByJGLkxNfZRnkUfjvmuYqLR89jKjXAbgXc");
+
+// `PhoenixInstruction::Swap` tag.
+const SWAP_TAG: u8 = 0;
+
+// Offsets of the lot sizes in the `MarketHeader` of a market account.
+const MARKET_BASE_LOT_SIZE_OFFSET: usize = 112;
+const MARKET_QUOTE_LOT_SIZE_OFFSET: usize = 192;
+
+// Accounts of a Phoenix leg in a route: market, base vault, quote vault, log authority,
+// user base account, user quote account and the Phoenix program.
+pub(crate) const LEG_ACCOUNTS: usize = 7;
+
+// `SelfTradeBehavior::DecrementTake`.
+const SELF_TRADE_DECREMENT_TAKE: u8 = 2;
+
+// `OrderPacket::ImmediateOrCancel`, the only packet a swap accepts.
+#[derive(AnchorSerialize)]
+enum OrderPacket {
+    _PostOnly,
+    _Limit,
+    ImmediateOrCancel {
+        side: u8,
+        price_in_ticks: Option<u64>,
+        num_base_lots: u64,
+        num_quote_lots: u64,
+        min_base_lots_to_fill: u64,
+        min_quote_lots_to_fill: u64,
+        self_trade_behavior: u8,
+        match_limit: Option<u64>,
+        client_order_id: u128,
+        use_only_deposited_funds: bool,
+        last_valid_slot: Option<u64>,
+        last_valid_unix_timestamp_in_seconds: Option<u64>,
+    },
+}
+
+pub fn swap<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, PhoenixSwap<'info>>,
+    side: Side,
+    num_base_lots: u64,
+    num_quote_lots: u64,
+    min_base_lots_to_fill: u64,
+    min_quote_lots_to_fill: u64,
+) -> ProgramResult {
+    let order_packet = OrderPacket::ImmediateOrCancel {
+        side: match side {
+            Side::Bid => 0,
+            Side::Ask => 1,
+        },
+        price_in_ticks: None,
+        num_base_lots,
+        num_quote_lots,
+        min_base_lots_to_fill,
+        min_quote_lots_to_fill,
+        self_trade_behavior: SELF_TRADE_DECREMENT_TAKE,
+        match_limit: None,
+        client_order_id: 0,
+        use_only_deposited_funds: false,
+        last_valid_slot: None,
+        last_valid_unix_timestamp_in_seconds: None,
+    };
+    let mut data = vec![SWAP_TAG];
+    order_packet
+        .serialize(&mut data)
+        .map_err(|_| ProgramError::InvalidInstructionData)?;
+    let ix = Instruction {
+        program_id: *ctx.program.key,
+        accounts: ctx.accounts.to_account_metas(None),
+        data,
+    };
+    solana_program::program::invoke_signed(
+        &ix,
+        &ToAccountInfos::to_account_infos(&ctx),
+        ctx.signer_seeds,
+    )?;
+    Ok(())
+}
+
+#[derive(Accounts, Clone)]
+pub struct PhoenixSwap<'info> {
+    /// CHECK: Safe
+    pub phoenix_program: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub log_authority: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub market: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(signer)]
+    pub trader: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub base_account: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub quote_account: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub base_vault: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub quote_vault: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+// Client for sending immediate-or-cancel swaps to a Phoenix market.
+#[derive(Clone)]
+pub(crate) struct PhoenixClient<'info> {
+    pub(crate) accounts: PhoenixSwap<'info>,
+}
+
+impl<'info> PhoenixClient<'info> {
+    /// Builds the client for a route leg from its accounts, in the order of `LEG_ACCOUNTS`.
+    pub(crate) fn from_leg_accounts(
+        accounts: &[AccountInfo<'info>],
+        wallet_owner: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+    ) -> Self {
+        PhoenixClient {
+            accounts: PhoenixSwap {
+                phoenix_program: accounts[6].clone(),
+                log_authority: accounts[3].clone(),
+                market: accounts[0].clone(),
+                trader: wallet_owner.clone(),
+                base_account: accounts[4].clone(),
+                quote_account: accounts[5].clone(),
+                base_vault: accounts[1].clone(),
+                quote_vault: accounts[2].clone(),
+                token_program: token_program.clone(),
+            },
+        }
+    }
+
+    /// 'Swap' from quote to base - Bid
+    pub(crate) fn bid(
+        &self,
+        max_quote_amount_input: u64,
+        min_base_amount_output: u64,
+    ) -> ProgramResult {
+        let (base_lot_size, quote_lot_size) = self.lot_sizes()?;
+        self.swap_checked(
+            Side::Bid,
+            0,
+            coin_lots(quote_lot_size, max_quote_amount_input).map_err(|code| error!(code))?,
+            coin_lots(base_lot_size, min_base_amount_output).map_err(|code| error!(code))?,
+            0,
+            max_quote_amount_input,
+            min_base_amount_output,
+        )
+    }
+
+    /// 'Swap' from base to quote - Ask
+    pub(crate) fn ask(
+        &self,
+        max_base_amount_input: u64,
+        min_quote_amount_output: u64,
+    ) -> ProgramResult {
+        let (base_lot_size, quote_lot_size) = self.lot_sizes()?;
+        self.swap_checked(
+            Side::Ask,
+            coin_lots(base_lot_size, max_base_amount_input).map_err(|code| error!(code))?,
+            0,
+            0,
+            coin_lots(quote_lot_size, min_quote_amount_output).map_err(|code| error!(code))?,
+            max_base_amount_input,
+            min_quote_amount_output,
+        )
+    }
+
+    /// The market's (base, quote) lot sizes, both positive.
+    fn lot_sizes(&self) -> std::result::Result<(u64, u64), ProgramError> {
+        let market = &self.accounts.market;
+        if market.owner != self.accounts.phoenix_program.key {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let data = market.try_borrow_data()?;
+        if data.len() < MARKET_QUOTE_LOT_SIZE_OFFSET + 8 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let base_lot_size = read_u64(MARKET_BASE_LOT_SIZE_OFFSET);
+        let quote_lot_size = read_u64(MARKET_QUOTE_LOT_SIZE_OFFSET);
+        if base_lot_size == 0 || quote_lot_size == 0 {
+            return Err(error!(crate::ErrorCode::InvalidLotSize).into());
+        }
+        Ok((base_lot_size, quote_lot_size))
+    }
+
+    /// Execute Swap on Phoenix via CPI
+    fn swap(
+        &self,
+        side: Side,
+        num_base_lots: u64,
+        num_quote_lots: u64,
+        min_base_lots_to_fill: u64,
+        min_quote_lots_to_fill: u64,
+    ) -> ProgramResult {
+        let ctx = CpiContext::new(self.accounts.phoenix_program.clone(), self.accounts.clone());
+
+        msg!("Phoenix Swap CPI: side: {:?}, num_base_lots: {}, num_quote_lots: {}, min_base_lots_to_fill: {}, min_quote_lots_to_fill: {}", side, num_base_lots, num_quote_lots, min_base_lots_to_fill, min_quote_lots_to_fill);
+        swap(
+            ctx,
+            side,
+            num_base_lots,
+            num_quote_lots,
+            min_base_lots_to_fill,
+            min_quote_lots_to_fill,
+        )
+    }
+
+    /// Swaps as specified, failing unless the user's balance deltas show at most
+    /// `max_amount_in` spent and at least `min_amount_out` received.
+    #[allow(clippy::too_many_arguments)]
+    fn swap_checked(
+        &self,
+        side: Side,
+        num_base_lots: u64,
+        num_quote_lots: u64,
+        min_base_lots_to_fill: u64,
+        min_quote_lots_to_fill: u64,
+        max_amount_in: u64,
+        min_amount_out: u64,
+    ) -> ProgramResult {
+        let (from_token, to_token) = self.token_accounts(side);
+        let from_amount_before = token::accessor::amount(&from_token)?;
+        let to_amount_before = token::accessor::amount(&to_token)?;
+        self.swap(
+            side,
+            num_base_lots,
+            num_quote_lots,
+            min_base_lots_to_fill,
+            min_quote_lots_to_fill,
+        )?;
+        let from_amount_after = token::accessor::amount(&from_token)?;
+        let to_amount_after = token::accessor::amount(&to_token)?;
+        if from_amount_before > from_amount_after.saturating_add(max_amount_in) {
+            return Err(error!(crate::ErrorCode::SwapTokenAmountExceedsMax).into());
+        }
+        if to_amount_after < to_amount_before.saturating_add(min_amount_out) {
+            return Err(error!(crate::ErrorCode::SwapTokenAmountLessThanMin).into());
+        }
+        Ok(())
+    }
+}
+
+impl<'info> DexAdapter<'info> for PhoenixClient<'info> {
+    fn token_accounts(&self, side: Side) -> (AccountInfo<'info>, AccountInfo<'info>) {
+        match side {
+            Side::Bid => (
+                self.accounts.quote_account.clone(),
+                self.accounts.base_account.clone(),
+            ),
+            Side::Ask => (
+                self.accounts.base_account.clone(),
+                self.accounts.quote_account.clone(),
+            ),
+        }
+    }
+
+    fn swap_exact_in(&self, side: Side, amount_in: u64, min_amount_out: u64) -> ProgramResult {
+        match side {
+            Side::Bid => self.bid(amount_in, min_amount_out),
+            Side::Ask => self.ask(amount_in, min_amount_out),
+        }
+    }
+
+    // Phoenix sizes an immediate-or-cancel order by either side, so the output is targeted
+    // in lots, rounded up to cover `amount_out`, and the input checked against
+    // `max_amount_in` after the fill.
+    fn swap_exact_out(&self, side: Side, amount_out: u64, max_amount_in: u64) -> ProgramResult {
+        let (base_lot_size, quote_lot_size) = self.lot_sizes()?;
+        match side {
+            Side::Bid => {
+                let base_lots = amount_out.div_ceil(base_lot_size);
+                self.swap_checked(side, base_lots, 0, base_lots, 0, max_amount_in, amount_out)
+            }
+            Side::Ask => {
+                let quote_lots = amount_out.div_ceil(quote_lot_size);
+                self.swap_checked(
+                    side,
+                    0,
+                    quote_lots,
+                    0,
+                    quote_lots,
+                    max_amount_in,
+                    amount_out,
+                )
+            }
+        }
+    }
+
+    // Walking the Phoenix order tree on-chain is not supported, quote off-chain.
+    fn quote(&self, _side: Side, _amount_in: u64) -> std::result::Result<u64, ProgramError> {
+        Err(error!(crate::ErrorCode::QuoteUnavailable).into())
+    }
+}
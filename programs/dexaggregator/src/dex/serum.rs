@@ -0,0 +1,626 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token;
+use serum_dex::fees::FeeTier;
+use serum_dex::instruction::SelfTradeBehavior;
+use serum_dex::matching::OrderType;
+use serum_dex::state::MarketState;
+use solana_program::entrypoint::ProgramResult;
+use std::num::NonZeroU64;
+
+use super::DexAdapter;
+use crate::math::{self, coin_lots};
+use crate::{FillPolicy, MarketAccounts, Side};
+
+// Accounts of a Serum leg in a route: market, request queue, event queue, bids, asks,
+// coin vault, pc vault, vault signer, coin wallet, pc wallet and the DEX program.
+pub(crate) const LEG_ACCOUNTS: usize = 11;
+
+// Client for sending orders to the Serum DEX.
+#[derive(Clone)]
+pub(crate) struct OrderbookClient<'info> {
+    // The DEX market
+    /// CHECK: Safe
+    pub(crate) market: MarketAccounts<'info>,
+    // The swap user
+    /// CHECK: Safe
+    pub(crate) wallet_owner: AccountInfo<'info>,
+    // The user's token account for the 'price' currency
+    /// CHECK: Safe
+    pub(crate) pc_wallet: AccountInfo<'info>,
+    // The Serum DEX program
+    /// CHECK: Safe
+    pub(crate) dex_program: AccountInfo<'info>,
+    // The token program
+    /// CHECK: Safe
+    pub(crate) token_program: AccountInfo<'info>,
+    // Token account receiving the Serum referral rebate, in the quote mint
+    /// CHECK: Safe
+    pub(crate) referral: Option<AccountInfo<'info>>,
+    // The market's base lot size from its `MarketCache`, loaded from the market if not given
+    pub(crate) coin_lot_size: Option<u64>,
+    // The user's PDA open orders account, to trade with NewOrderV3 instead of SendTake
+    pub(crate) open_orders: Option<OpenOrdersAccounts<'info>>,
+    // Seeds of the wallet owner when it is a PDA of this program, e.g. a DCA order escrow
+    pub(crate) wallet_owner_seeds: Option<Vec<Vec<u8>>>,
+    // Most orders a take may match, derived from the remaining compute budget if not given
+    pub(crate) match_limit: Option<u16>,
+    // Takes left in the instruction, this one included, sharing the remaining compute budget
+    pub(crate) takes_left: u64,
+    // How much of an ask must fill, fill-or-kill on the coin if not given
+    pub(crate) fill_policy: Option<FillPolicy>,
+}
+
+// Size of a Serum `OpenOrders` account, with the DEX's padding.
+const OPEN_ORDERS_LEN: usize = std::mem::size_of::<serum_dex::state::OpenOrders>() + 12;
+
+// A PDA open orders account of the aggregator, seeded by market and user. It is its own
+// authority, so the aggregator signs for it when placing orders and settling.
+#[derive(Clone)]
+pub(crate) struct OpenOrdersAccounts<'info> {
+    /// CHECK: Safe
+    pub(crate) open_orders: AccountInfo<'info>,
+    pub(crate) bump: u8,
+    /// CHECK: Safe
+    pub(crate) rent: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub(crate) system_program: AccountInfo<'info>,
+    // The delegate of the wallet paying for orders, if it has one, approved again after them
+    /// CHECK: Safe
+    pub(crate) wallet_delegate: Option<AccountInfo<'info>>,
+}
+
+impl<'info> OrderbookClient<'info> {
+    /// Builds the client for a route leg from its accounts, in the order of `LEG_ACCOUNTS`.
+    pub(crate) fn from_leg_accounts(
+        accounts: &[AccountInfo<'info>],
+        wallet_owner: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+        takes_left: u64,
+    ) -> Self {
+        OrderbookClient {
+            market: MarketAccounts {
+                market: accounts[0].clone(),
+                request_queue: accounts[1].clone(),
+                event_queue: accounts[2].clone(),
+                market_bids: accounts[3].clone(),
+                market_asks: accounts[4].clone(),
+                coin_vault: accounts[5].clone(),
+                pc_vault: accounts[6].clone(),
+                vault_signer: accounts[7].clone(),
+                coin_wallet: accounts[8].clone(),
+            },
+            pc_wallet: accounts[9].clone(),
+            dex_program: accounts[10].clone(),
+            wallet_owner: wallet_owner.clone(),
+            token_program: token_program.clone(),
+            referral: None,
+            coin_lot_size: None,
+            open_orders: None,
+            wallet_owner_seeds: None,
+            match_limit: None,
+            takes_left,
+            fill_policy: None,
+        }
+    }
+
+    /// 'Swap' from pc to coin - Bid
+    pub(crate) fn bid(
+        &self,
+        max_pc_amount_input: u64,
+        min_coin_amount_output: u64,
+    ) -> ProgramResult {
+        let limit_price = u64::MAX;
+        let max_coin_qty = u64::MAX;
+        let max_native_pc_qty_including_fees = max_pc_amount_input;
+        let min_coin_qty = coin_lots(self.coin_lot_size()?, min_coin_amount_output)
+            .map_err(|code| error!(code))?;
+        let min_native_pc_qty = 0;
+        self.send_take_cpi(
+            Side::Bid,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty_including_fees,
+            min_coin_qty,
+            min_native_pc_qty,
+        )
+    }
+
+    /// 'Swap' from coin to pc - Ask
+    pub(crate) fn ask(
+        &self,
+        max_coin_amount_input: u64,
+        min_pc_amount_output: u64,
+    ) -> ProgramResult {
+        let limit_price = 1;
+        let max_coin_qty =
+            coin_lots(self.coin_lot_size()?, max_coin_amount_input).map_err(|code| error!(code))?;
+        let max_native_pc_qty_including_fees = u64::MAX;
+        // A partial fill's minimum is checked by the caller, the DEX only allows none or all.
+        let min_coin_qty = match self.fill_policy {
+            Some(FillPolicy::AllowPartial { .. }) => 0,
+            _ => max_coin_qty,
+        };
+        let min_native_pc_qty = min_pc_amount_output;
+        self.send_take_cpi(
+            Side::Ask,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty_including_fees,
+            min_coin_qty,
+            min_native_pc_qty,
+        )
+    }
+
+    /// The most orders a take may match: the caller's limit, or what the remaining compute
+    /// budget affords while leaving enough for the takes after this one.
+    fn match_limit(&self) -> u16 {
+        self.match_limit.unwrap_or_else(|| {
+            math::match_limit(
+                solana_program::compute_units::sol_remaining_compute_units(),
+                self.takes_left,
+            )
+        })
+    }
+
+    /// The market's base lot size, from the market cache if one was passed.
+    pub(crate) fn coin_lot_size(&self) -> std::result::Result<u64, ProgramError> {
+        if let Some(coin_lot_size) = self.coin_lot_size {
+            return Ok(coin_lot_size);
+        }
+        let market = MarketState::load(&self.market.market, &super::ID, false)?;
+        Ok(market.coin_lot_size)
+    }
+
+    /// Execute SendTake on the Serum DEX via CPI
+    pub(crate) fn send_take_cpi(
+        &self,
+        side: Side,
+        limit_price: u64,
+        max_coin_qty: u64,
+        max_native_pc_qty_including_fees: u64,
+        min_coin_qty: u64,
+        min_native_pc_qty: u64,
+    ) -> ProgramResult {
+        if let Some(open_orders) = &self.open_orders {
+            return self.new_order_cpi(
+                open_orders,
+                side,
+                limit_price,
+                max_coin_qty,
+                max_native_pc_qty_including_fees,
+                min_coin_qty,
+                min_native_pc_qty,
+            );
+        }
+        let cpi_accounts = super::SendTake {
+            market: self.market.market.clone(),
+            request_queue: self.market.request_queue.clone(),
+            event_queue: self.market.event_queue.clone(),
+            market_bids: self.market.market_bids.clone(),
+            market_asks: self.market.market_asks.clone(),
+            coin_wallet: self.market.coin_wallet.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            wallet_owner: self.wallet_owner.clone(),
+            coin_vault: self.market.coin_vault.clone(),
+            pc_vault: self.market.pc_vault.clone(),
+            token_program: self.token_program.clone(),
+            vault_signer: self.market.vault_signer.clone(),
+        };
+        // Limit is the dex's custom compute budge parameter, setting an upper
+        // bound on the number of matching cycles the program can perform
+        // before giving up and posting the remaining unmatched order.
+        let limit = self.match_limit();
+        let wallet_owner_seeds: Vec<&[u8]> = self
+            .wallet_owner_seeds
+            .iter()
+            .flatten()
+            .map(Vec::as_slice)
+            .collect();
+        let signer_seeds: &[&[&[u8]]] = if wallet_owner_seeds.is_empty() {
+            &[]
+        } else {
+            &[&wallet_owner_seeds[..]]
+        };
+        let mut ctx =
+            CpiContext::new_with_signer(self.dex_program.clone(), cpi_accounts, signer_seeds);
+        if let Some(referral) = &self.referral {
+            ctx = ctx.with_remaining_accounts(vec![referral.clone()]);
+        }
+
+        msg!("SendTake CPI: side: {:?}, limit_price: {}, max_coin_qty: {}, max_native_pc_qty_including_fees: {}, min_coin_qty: {}, min_native_pc_qty: {}, limit: {}", side, limit_price, max_coin_qty, max_native_pc_qty_including_fees, min_coin_qty, min_native_pc_qty, limit);
+        super::send_take(
+            ctx,
+            side.into(),
+            non_zero(limit_price)?,
+            non_zero(max_coin_qty)?,
+            non_zero(max_native_pc_qty_including_fees)?,
+            min_coin_qty,
+            min_native_pc_qty,
+            limit,
+        )
+    }
+}
+
+impl<'info> OrderbookClient<'info> {
+    /// Trades through the user's PDA open orders account, for markets without SendTake: an
+    /// ImmediateOrCancel NewOrderV3 settled back to the user's wallets in the same instruction.
+    #[allow(clippy::too_many_arguments)]
+    fn new_order_cpi(
+        &self,
+        open_orders: &OpenOrdersAccounts<'info>,
+        side: Side,
+        limit_price: u64,
+        max_coin_qty: u64,
+        max_native_pc_qty_including_fees: u64,
+        min_coin_qty: u64,
+        min_native_pc_qty: u64,
+    ) -> ProgramResult {
+        let (receiver, min_amount_out) = match side {
+            Side::Bid => (
+                &self.market.coin_wallet,
+                min_coin_qty.saturating_mul(self.coin_lot_size()?),
+            ),
+            Side::Ask => (&self.pc_wallet, min_native_pc_qty),
+        };
+        let received_before = token::accessor::amount(receiver)?;
+
+        self.place_order(
+            open_orders,
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty_including_fees,
+            OrderType::ImmediateOrCancel,
+            0,
+        )?;
+        self.settle_funds_cpi(open_orders)?;
+
+        let received_after = token::accessor::amount(receiver)?;
+        if received_after < received_before.saturating_add(min_amount_out) {
+            return Err(error!(crate::ErrorCode::SwapTokenAmountLessThanMin).into());
+        }
+        Ok(())
+    }
+
+    /// Places a NewOrderV3 from the user's PDA open orders account, paid out of the user's
+    /// wallet, creating the open orders account on first use. A delegate the wallet already
+    /// had is approved again for its allowance once the order is placed, and must be given.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn place_order(
+        &self,
+        open_orders: &OpenOrdersAccounts<'info>,
+        side: Side,
+        limit_price: u64,
+        max_coin_qty: u64,
+        max_native_pc_qty_including_fees: u64,
+        order_type: OrderType,
+        client_order_id: u64,
+    ) -> ProgramResult {
+        let market_key = self.market.market.key();
+        let wallet_owner_key = self.wallet_owner.key();
+        let seeds = open_orders_seeds(&market_key, &wallet_owner_key, &open_orders.bump);
+        self.init_open_orders_if_needed(open_orders, &seeds)?;
+
+        let (payer, max_amount_in) = match side {
+            Side::Bid => (&self.pc_wallet, max_native_pc_qty_including_fees),
+            Side::Ask => (
+                &self.market.coin_wallet,
+                max_coin_qty.saturating_mul(self.coin_lot_size()?),
+            ),
+        };
+
+        // The open orders account pays for the order out of the user's wallet as its delegate,
+        // in place of the wallet's own delegate until the order is placed.
+        let wallet = token::TokenAccount::try_deserialize(&mut &payer.try_borrow_data()?[..])?;
+        let previous_delegate = match Option::<Pubkey>::from(wallet.delegate) {
+            Some(delegate) => {
+                let wallet_delegate = open_orders
+                    .wallet_delegate
+                    .as_ref()
+                    .filter(|wallet_delegate| *wallet_delegate.key == delegate)
+                    .ok_or_else(|| error!(crate::ErrorCode::WalletDelegateRequired))?;
+                Some((wallet_delegate, wallet.delegated_amount))
+            }
+            None => None,
+        };
+        token::approve(
+            CpiContext::new(
+                self.token_program.clone(),
+                token::Approve {
+                    to: payer.clone(),
+                    delegate: open_orders.open_orders.clone(),
+                    authority: self.wallet_owner.clone(),
+                },
+            ),
+            max_amount_in,
+        )?;
+
+        let cpi_accounts = super::NewOrderV3 {
+            market: self.market.market.clone(),
+            open_orders: open_orders.open_orders.clone(),
+            request_queue: self.market.request_queue.clone(),
+            event_queue: self.market.event_queue.clone(),
+            market_bids: self.market.market_bids.clone(),
+            market_asks: self.market.market_asks.clone(),
+            order_payer_token_account: payer.clone(),
+            open_orders_authority: open_orders.open_orders.clone(),
+            coin_vault: self.market.coin_vault.clone(),
+            pc_vault: self.market.pc_vault.clone(),
+            token_program: self.token_program.clone(),
+            rent: open_orders.rent.clone(),
+        };
+        let limit = self.match_limit();
+        let signer_seeds = &[&seeds[..]];
+        let ctx = CpiContext::new_with_signer(self.dex_program.clone(), cpi_accounts, signer_seeds);
+
+        msg!("NewOrderV3 CPI: side: {:?}, limit_price: {}, max_coin_qty: {}, max_native_pc_qty_including_fees: {}, order_type: {:?}, limit: {}", side, limit_price, max_coin_qty, max_native_pc_qty_including_fees, order_type, limit);
+        super::new_order_v3(
+            ctx,
+            side.into(),
+            non_zero(limit_price)?,
+            non_zero(max_coin_qty)?,
+            non_zero(max_native_pc_qty_including_fees)?,
+            SelfTradeBehavior::DecrementTake,
+            order_type,
+            client_order_id,
+            limit,
+        )?;
+
+        // Drop whatever allowance the order left unspent, giving the wallet's delegate back
+        // its own.
+        match previous_delegate {
+            Some((delegate, delegated_amount)) => token::approve(
+                CpiContext::new(
+                    self.token_program.clone(),
+                    token::Approve {
+                        to: payer.clone(),
+                        delegate: delegate.clone(),
+                        authority: self.wallet_owner.clone(),
+                    },
+                ),
+                delegated_amount,
+            ),
+            None => token::revoke(CpiContext::new(
+                self.token_program.clone(),
+                token::Revoke {
+                    source: payer.clone(),
+                    authority: self.wallet_owner.clone(),
+                },
+            )),
+        }
+        .map_err(Into::into)
+    }
+
+    /// Cancels an order resting from the user's PDA open orders account.
+    pub(crate) fn cancel_order_cpi(
+        &self,
+        open_orders: &OpenOrdersAccounts<'info>,
+        side: Side,
+        order_id: u128,
+    ) -> ProgramResult {
+        let market_key = self.market.market.key();
+        let wallet_owner_key = self.wallet_owner.key();
+        let seeds = open_orders_seeds(&market_key, &wallet_owner_key, &open_orders.bump);
+        let cpi_accounts = super::CancelOrderV2 {
+            market: self.market.market.clone(),
+            market_bids: self.market.market_bids.clone(),
+            market_asks: self.market.market_asks.clone(),
+            open_orders: open_orders.open_orders.clone(),
+            open_orders_authority: open_orders.open_orders.clone(),
+            event_queue: self.market.event_queue.clone(),
+        };
+        let signer_seeds = &[&seeds[..]];
+        let ctx = CpiContext::new_with_signer(self.dex_program.clone(), cpi_accounts, signer_seeds);
+
+        msg!(
+            "CancelOrderV2 CPI: side: {:?}, order_id: {}",
+            side,
+            order_id
+        );
+        super::cancel_order_v2(ctx, side.into(), order_id)
+    }
+
+    /// Settles the free balances of the user's PDA open orders account to the user's wallets.
+    pub(crate) fn settle_funds_cpi(
+        &self,
+        open_orders: &OpenOrdersAccounts<'info>,
+    ) -> ProgramResult {
+        let market_key = self.market.market.key();
+        let wallet_owner_key = self.wallet_owner.key();
+        let seeds = open_orders_seeds(&market_key, &wallet_owner_key, &open_orders.bump);
+        let cpi_accounts = super::SettleFunds {
+            market: self.market.market.clone(),
+            open_orders: open_orders.open_orders.clone(),
+            open_orders_authority: open_orders.open_orders.clone(),
+            coin_vault: self.market.coin_vault.clone(),
+            pc_vault: self.market.pc_vault.clone(),
+            coin_wallet: self.market.coin_wallet.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            vault_signer: self.market.vault_signer.clone(),
+            token_program: self.token_program.clone(),
+        };
+        let signer_seeds = &[&seeds[..]];
+        let mut ctx =
+            CpiContext::new_with_signer(self.dex_program.clone(), cpi_accounts, signer_seeds);
+        if let Some(referral) = &self.referral {
+            ctx = ctx.with_remaining_accounts(vec![referral.clone()]);
+        }
+        super::settle_funds(ctx)
+    }
+
+    /// Creates and initializes the PDA open orders account on the user's first trade.
+    fn init_open_orders_if_needed(
+        &self,
+        open_orders: &OpenOrdersAccounts<'info>,
+        seeds: &[&[u8]],
+    ) -> ProgramResult {
+        if open_orders.open_orders.owner == self.dex_program.key {
+            return Ok(());
+        }
+        let rent = Rent::get()?;
+        solana_program::program::invoke_signed(
+            &solana_program::system_instruction::create_account(
+                self.wallet_owner.key,
+                open_orders.open_orders.key,
+                rent.minimum_balance(OPEN_ORDERS_LEN),
+                OPEN_ORDERS_LEN as u64,
+                self.dex_program.key,
+            ),
+            &[
+                self.wallet_owner.clone(),
+                open_orders.open_orders.clone(),
+                open_orders.system_program.clone(),
+            ],
+            &[seeds],
+        )?;
+        super::init_open_orders(CpiContext::new_with_signer(
+            self.dex_program.clone(),
+            super::InitOpenOrders {
+                open_orders: open_orders.open_orders.clone(),
+                authority: open_orders.open_orders.clone(),
+                market: self.market.market.clone(),
+                rent: open_orders.rent.clone(),
+            },
+            &[seeds],
+        ))
+    }
+}
+
+// Seeds of the user's PDA open orders account for a market.
+fn open_orders_seeds<'a>(
+    market: &'a Pubkey,
+    wallet_owner: &'a Pubkey,
+    bump: &'a u8,
+) -> [&'a [u8]; 4] {
+    [
+        b"open_orders",
+        market.as_ref(),
+        wallet_owner.as_ref(),
+        std::slice::from_ref(bump),
+    ]
+}
+
+// An order's price or quantity as the DEX's non-zero one. A slice of less than a lot, or a
+// price below a tick, rounds to zero and fails instead of panicking, so e.g. a TWAP band
+// carries it forward to a later crank.
+fn non_zero(value: u64) -> std::result::Result<NonZeroU64, ProgramError> {
+    NonZeroU64::new(value).ok_or_else(|| error!(crate::ErrorCode::OrderBelowLotSize).into())
+}
+
+// Offsets of the native coin and pc free balances in an `OpenOrders` account, after the
+// DEX's head padding.
+const OPEN_ORDERS_COIN_FREE_OFFSET: usize = 77;
+const OPEN_ORDERS_PC_FREE_OFFSET: usize = 93;
+
+/// The (coin, pc) free balances of an open orders account, zero before it is created.
+pub(crate) fn open_orders_free(
+    open_orders: &AccountInfo,
+) -> std::result::Result<(u64, u64), ProgramError> {
+    let data = open_orders.try_borrow_data()?;
+    if data.len() < OPEN_ORDERS_PC_FREE_OFFSET + 8 {
+        return Ok((0, 0));
+    }
+    let read_u64 = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    Ok((
+        read_u64(OPEN_ORDERS_COIN_FREE_OFFSET),
+        read_u64(OPEN_ORDERS_PC_FREE_OFFSET),
+    ))
+}
+
+/// The best price on the book of `side`, in pc lots per coin lot, with the market's (coin,
+/// pc) lot sizes. `None` if that side of the book is empty.
+pub(crate) fn best_price(
+    market: &MarketAccounts,
+    side: Side,
+) -> std::result::Result<(Option<u64>, u64, u64), ProgramError> {
+    let market_state = MarketState::load(&market.market, &super::ID, false)?;
+    let price = match side {
+        Side::Bid => {
+            let bids = market_state.load_bids_mut(&market.market_bids)?;
+            bids.find_max()
+                .and_then(|handle| bids.get(handle)?.as_leaf().map(|leaf| leaf.price().get()))
+        }
+        Side::Ask => {
+            let asks = market_state.load_asks_mut(&market.market_asks)?;
+            asks.find_min()
+                .and_then(|handle| asks.get(handle)?.as_leaf().map(|leaf| leaf.price().get()))
+        }
+    };
+    Ok((price, market_state.coin_lot_size, market_state.pc_lot_size))
+}
+
+impl<'info> DexAdapter<'info> for OrderbookClient<'info> {
+    fn token_accounts(&self, side: Side) -> (AccountInfo<'info>, AccountInfo<'info>) {
+        match side {
+            Side::Bid => (self.pc_wallet.clone(), self.market.coin_wallet.clone()),
+            Side::Ask => (self.market.coin_wallet.clone(), self.pc_wallet.clone()),
+        }
+    }
+
+    fn swap_exact_in(&self, side: Side, amount_in: u64, min_amount_out: u64) -> ProgramResult {
+        match side {
+            Side::Bid => self.bid(amount_in, min_amount_out),
+            Side::Ask => self.ask(amount_in, min_amount_out),
+        }
+    }
+
+    // SendTake can't target a quote amount when selling, so an ask sells up to
+    // `max_amount_in` and only guarantees at least `amount_out`.
+    fn swap_exact_out(&self, side: Side, amount_out: u64, max_amount_in: u64) -> ProgramResult {
+        let coin_lot_size = self.coin_lot_size()?;
+        match side {
+            Side::Bid => {
+                let coin_qty = coin_lots(coin_lot_size, amount_out).map_err(|code| error!(code))?;
+                self.send_take_cpi(Side::Bid, u64::MAX, coin_qty, max_amount_in, coin_qty, 0)
+            }
+            Side::Ask => {
+                let max_coin_qty =
+                    coin_lots(coin_lot_size, max_amount_in).map_err(|code| error!(code))?;
+                self.send_take_cpi(Side::Ask, 1, max_coin_qty, u64::MAX, 0, amount_out)
+            }
+        }
+    }
+
+    // Top of book estimate: fills at the best price, up to the size resting there.
+    fn quote(&self, side: Side, amount_in: u64) -> std::result::Result<u64, ProgramError> {
+        let market = MarketState::load(&self.market.market, &super::ID, false)?;
+        let fee_tier = FeeTier::from_srm_and_msrm_balances(&self.market.market.key, 0, 0);
+        let coin_lot_size = market.coin_lot_size;
+        let pc_lot_size = market.pc_lot_size;
+
+        let best = match side {
+            Side::Bid => {
+                let asks = market.load_asks_mut(&self.market.market_asks)?;
+                asks.find_min()
+                    .and_then(|handle| asks.get(handle)?.as_leaf())
+                    .map(|leaf| (leaf.price().get(), leaf.quantity()))
+            }
+            Side::Ask => {
+                let bids = market.load_bids_mut(&self.market.market_bids)?;
+                bids.find_max()
+                    .and_then(|handle| bids.get(handle)?.as_leaf())
+                    .map(|leaf| (leaf.price().get(), leaf.quantity()))
+            }
+        };
+        let Some((price_lots, size_lots)) = best else {
+            return Ok(0);
+        };
+        let lot_price = price_lots.saturating_mul(pc_lot_size);
+
+        let amount_out = match side {
+            Side::Bid => {
+                let pc_in = amount_in.saturating_sub(fee_tier.taker_fee(amount_in));
+                let lots = pc_in.checked_div(lot_price).unwrap_or(0).min(size_lots);
+                lots.saturating_mul(coin_lot_size)
+            }
+            Side::Ask => {
+                let lots = coin_lots(coin_lot_size, amount_in)
+                    .map_err(|code| error!(code))?
+                    .min(size_lots);
+                let pc_out = lots.saturating_mul(lot_price);
+                pc_out.saturating_sub(fee_tier.taker_fee(pc_out))
+            }
+        };
+        Ok(amount_out)
+    }
+}
@@ -0,0 +1,303 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::{context::CpiContext, Accounts, ToAccountInfos, ToAccountMetas};
+use anchor_spl::token;
+use solana_program::entrypoint::ProgramResult;
+
+use super::DexAdapter;
+use crate::Side;
+
+anchor_lang::solana_program::declare_id!("SwaPpA9LAaLfeLi3a68M4DjnLqgtticKg6CnyNwgAC8");
+
+// `SwapInstruction::Swap` tag.
+const SWAP_TAG: u8 = 1;
+
+// Offsets in the versioned `SwapV1` pool state, version byte included.
+const POOL_TOKEN_A_OFFSET: usize = 35;
+const POOL_TOKEN_B_OFFSET: usize = 67;
+const TRADE_FEE_NUMERATOR_OFFSET: usize = 227;
+const TRADE_FEE_DENOMINATOR_OFFSET: usize = 235;
+const OWNER_TRADE_FEE_NUMERATOR_OFFSET: usize = 243;
+const OWNER_TRADE_FEE_DENOMINATOR_OFFSET: usize = 251;
+const CURVE_TYPE_OFFSET: usize = 291;
+
+// `CurveType::ConstantProduct`.
+const CURVE_TYPE_CONSTANT_PRODUCT: u8 = 0;
+
+// Accounts of a token-swap leg in a route: pool, pool authority, pool token a account,
+// pool token b account, pool mint, pool fee account, user token a account, user token b
+// account and the token-swap program. An ask sells token a for token b, a bid buys it.
+pub(crate) const LEG_ACCOUNTS: usize = 9;
+
+pub fn swap<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, TokenSwap<'info>>,
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> ProgramResult {
+    let mut data = vec![SWAP_TAG];
+    data.extend_from_slice(&amount_in.to_le_bytes());
+    data.extend_from_slice(&minimum_amount_out.to_le_bytes());
+    let ix = Instruction {
+        program_id: *ctx.program.key,
+        accounts: ctx.accounts.to_account_metas(None),
+        data,
+    };
+    solana_program::program::invoke_signed(
+        &ix,
+        &ToAccountInfos::to_account_infos(&ctx),
+        ctx.signer_seeds,
+    )?;
+    Ok(())
+}
+
+#[derive(Accounts, Clone)]
+pub struct TokenSwap<'info> {
+    /// CHECK: Safe
+    pub pool: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub pool_authority: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(signer)]
+    pub user_transfer_authority: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub source: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pool_source: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pool_destination: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub destination: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pool_mint: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pool_fee_account: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+// Client for swapping through an SPL token-swap constant product pool.
+#[derive(Clone)]
+pub(crate) struct TokenSwapClient<'info> {
+    // The pool state
+    /// CHECK: Safe
+    pub(crate) pool: AccountInfo<'info>,
+    // The pool's PDA authority
+    /// CHECK: Safe
+    pub(crate) pool_authority: AccountInfo<'info>,
+    // The pool's token a and token b reserves
+    /// CHECK: Safe
+    pub(crate) pool_token_a: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub(crate) pool_token_b: AccountInfo<'info>,
+    // The pool LP mint and the account collecting owner fees in it
+    /// CHECK: Safe
+    pub(crate) pool_mint: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub(crate) pool_fee_account: AccountInfo<'info>,
+    // The swap user and their token a and token b accounts
+    /// CHECK: Safe
+    pub(crate) wallet_owner: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub(crate) user_token_a: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub(crate) user_token_b: AccountInfo<'info>,
+    // The token program
+    /// CHECK: Safe
+    pub(crate) token_program: AccountInfo<'info>,
+    // The token-swap program
+    /// CHECK: Safe
+    pub(crate) swap_program: AccountInfo<'info>,
+}
+
+// A constant product pool's reserves in the direction of a swap, with its fees.
+struct PoolState {
+    reserve_in: u64,
+    reserve_out: u64,
+    trade_fee: (u64, u64),
+    owner_trade_fee: (u64, u64),
+}
+
+impl<'info> TokenSwapClient<'info> {
+    /// Builds the client for a route leg from its accounts, in the order of `LEG_ACCOUNTS`.
+    pub(crate) fn from_leg_accounts(
+        accounts: &[AccountInfo<'info>],
+        wallet_owner: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+    ) -> Self {
+        TokenSwapClient {
+            pool: accounts[0].clone(),
+            pool_authority: accounts[1].clone(),
+            pool_token_a: accounts[2].clone(),
+            pool_token_b: accounts[3].clone(),
+            pool_mint: accounts[4].clone(),
+            pool_fee_account: accounts[5].clone(),
+            user_token_a: accounts[6].clone(),
+            user_token_b: accounts[7].clone(),
+            swap_program: accounts[8].clone(),
+            wallet_owner: wallet_owner.clone(),
+            token_program: token_program.clone(),
+        }
+    }
+
+    // The pool's (source, destination) reserve accounts for `side`.
+    fn pool_accounts(&self, side: Side) -> (AccountInfo<'info>, AccountInfo<'info>) {
+        match side {
+            Side::Bid => (self.pool_token_b.clone(), self.pool_token_a.clone()),
+            Side::Ask => (self.pool_token_a.clone(), self.pool_token_b.clone()),
+        }
+    }
+
+    /// Reads the reserves and fees of the pool, checking it is a constant product pool of
+    /// the given reserve accounts.
+    fn pool_state(&self, side: Side) -> std::result::Result<PoolState, ProgramError> {
+        if self.pool.owner != self.swap_program.key {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let data = self.pool.try_borrow_data()?;
+        if data.len() <= CURVE_TYPE_OFFSET {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let read_pubkey = |offset: usize| Pubkey::try_from(&data[offset..offset + 32]).unwrap();
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        if read_pubkey(POOL_TOKEN_A_OFFSET) != *self.pool_token_a.key
+            || read_pubkey(POOL_TOKEN_B_OFFSET) != *self.pool_token_b.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if data[CURVE_TYPE_OFFSET] != CURVE_TYPE_CONSTANT_PRODUCT {
+            return Err(error!(crate::ErrorCode::QuoteUnavailable).into());
+        }
+
+        let (pool_source, pool_destination) = self.pool_accounts(side);
+        Ok(PoolState {
+            reserve_in: token::accessor::amount(&pool_source)?,
+            reserve_out: token::accessor::amount(&pool_destination)?,
+            trade_fee: (
+                read_u64(TRADE_FEE_NUMERATOR_OFFSET),
+                read_u64(TRADE_FEE_DENOMINATOR_OFFSET),
+            ),
+            owner_trade_fee: (
+                read_u64(OWNER_TRADE_FEE_NUMERATOR_OFFSET),
+                read_u64(OWNER_TRADE_FEE_DENOMINATOR_OFFSET),
+            ),
+        })
+    }
+
+    /// Execute Swap on the token-swap program via CPI
+    fn swap(&self, side: Side, amount_in: u64, minimum_amount_out: u64) -> ProgramResult {
+        let (source, destination) = self.token_accounts(side);
+        let (pool_source, pool_destination) = self.pool_accounts(side);
+        let ctx = CpiContext::new(
+            self.swap_program.clone(),
+            TokenSwap {
+                pool: self.pool.clone(),
+                pool_authority: self.pool_authority.clone(),
+                user_transfer_authority: self.wallet_owner.clone(),
+                source,
+                pool_source,
+                pool_destination,
+                destination,
+                pool_mint: self.pool_mint.clone(),
+                pool_fee_account: self.pool_fee_account.clone(),
+                token_program: self.token_program.clone(),
+            },
+        );
+
+        msg!(
+            "TokenSwap CPI: side: {:?}, amount_in: {}, minimum_amount_out: {}",
+            side,
+            amount_in,
+            minimum_amount_out
+        );
+        swap(ctx, amount_in, minimum_amount_out)
+    }
+}
+
+// Fee of the token-swap program on `amount`, rounded up to at least 1 when charged.
+fn pool_fee(amount: u64, (numerator, denominator): (u64, u64)) -> u64 {
+    if numerator == 0 || denominator == 0 || amount == 0 {
+        return 0;
+    }
+    let fee = (amount as u128 * numerator as u128 / denominator as u128) as u64;
+    fee.max(1)
+}
+
+// Output of swapping `amount_in` through the pool, as computed by the token-swap program.
+fn constant_product_out(pool: &PoolState, amount_in: u64) -> u64 {
+    let fees = pool_fee(amount_in, pool.trade_fee) + pool_fee(amount_in, pool.owner_trade_fee);
+    let amount_in = amount_in.saturating_sub(fees) as u128;
+    if amount_in == 0 {
+        return 0;
+    }
+    let reserve_in = pool.reserve_in as u128;
+    let reserve_out = pool.reserve_out as u128;
+    let invariant = reserve_in * reserve_out;
+    let new_reserve_in = reserve_in + amount_in;
+    let new_reserve_out = (invariant + new_reserve_in - 1) / new_reserve_in;
+    reserve_out.saturating_sub(new_reserve_out) as u64
+}
+
+// Smallest input swapping to at least `amount_out`, `None` if the pool cannot provide it.
+fn constant_product_in(pool: &PoolState, amount_out: u64) -> Option<u64> {
+    if amount_out >= pool.reserve_out {
+        return None;
+    }
+    let reserve_in = pool.reserve_in as u128;
+    let reserve_out = pool.reserve_out as u128;
+    let amount_out = amount_out as u128;
+    let amount_in_less_fees =
+        (reserve_in * amount_out + reserve_out - amount_out - 1) / (reserve_out - amount_out);
+    // Gross up for both fees, then step past their rounding.
+    let fraction = |(numerator, denominator): (u64, u64)| match denominator {
+        0 => (0, 1),
+        _ => (numerator as u128, denominator as u128),
+    };
+    let (trade_numerator, trade_denominator) = fraction(pool.trade_fee);
+    let (owner_numerator, owner_denominator) = fraction(pool.owner_trade_fee);
+    let denominator = trade_denominator * owner_denominator;
+    let kept = denominator
+        .checked_sub(trade_numerator * owner_denominator)?
+        .checked_sub(owner_numerator * trade_denominator)
+        .filter(|kept| *kept > 0)?;
+    let mut amount_in = u64::try_from(amount_in_less_fees * denominator / kept).ok()?;
+    while (constant_product_out(pool, amount_in) as u128) < amount_out {
+        amount_in = amount_in.checked_add(1)?;
+    }
+    Some(amount_in)
+}
+
+impl<'info> DexAdapter<'info> for TokenSwapClient<'info> {
+    fn token_accounts(&self, side: Side) -> (AccountInfo<'info>, AccountInfo<'info>) {
+        match side {
+            Side::Bid => (self.user_token_b.clone(), self.user_token_a.clone()),
+            Side::Ask => (self.user_token_a.clone(), self.user_token_b.clone()),
+        }
+    }
+
+    fn swap_exact_in(&self, side: Side, amount_in: u64, min_amount_out: u64) -> ProgramResult {
+        self.swap(side, amount_in, min_amount_out)
+    }
+
+    // The token-swap program only swaps exact inputs, so the input is solved from reserves.
+    fn swap_exact_out(&self, side: Side, amount_out: u64, max_amount_in: u64) -> ProgramResult {
+        let pool = self.pool_state(side)?;
+        let amount_in = constant_product_in(&pool, amount_out)
+            .ok_or(error!(crate::ErrorCode::SwapTokenAmountExceedsMax))?;
+        if amount_in > max_amount_in {
+            return Err(error!(crate::ErrorCode::SwapTokenAmountExceedsMax).into());
+        }
+        self.swap(side, amount_in, amount_out)
+    }
+
+    fn quote(&self, side: Side, amount_in: u64) -> std::result::Result<u64, ProgramError> {
+        let pool = self.pool_state(side)?;
+        Ok(constant_product_out(&pool, amount_in))
+    }
+}
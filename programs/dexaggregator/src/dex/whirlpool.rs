@@ -0,0 +1,184 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::{context::CpiContext, Accounts, ToAccountInfos, ToAccountMetas};
+use solana_program::entrypoint::ProgramResult;
+
+use super::DexAdapter;
+use crate::Side;
+
+anchor_lang::solana_program::declare_id!("whirLbMiicVdio4qvUfM5KAg6Ct8VwpYzGff3uctyCc");
+
+// Anchor discriminator of `swap`, i.e. sha256("global:swap")[..8].
+const SWAP_DISCRIMINATOR: [u8; 8] = [248, 198, 158, 145, 225, 117, 135, 200];
+
+// Bounds of the Q64.64 square root price, to swap without a price limit.
+const MIN_SQRT_PRICE_X64: u128 = 4_295_048_016;
+const MAX_SQRT_PRICE_X64: u128 = 79_226_673_515_401_279_992_447_579_055;
+
+// Accounts of a Whirlpool leg in a route: whirlpool, token vault a, token vault b, the
+// three tick arrays the swap may cross in its direction, oracle, user token a account,
+// user token b account and the Whirlpool program. An ask sells token a for token b, a bid
+// buys it. Quote with the off-chain client to find the tick arrays.
+pub(crate) const LEG_ACCOUNTS: usize = 10;
+
+#[derive(AnchorSerialize)]
+struct SwapArgs {
+    amount: u64,
+    other_amount_threshold: u64,
+    sqrt_price_limit: u128,
+    amount_specified_is_input: bool,
+    a_to_b: bool,
+}
+
+pub fn swap<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, WhirlpoolSwap<'info>>,
+    amount: u64,
+    other_amount_threshold: u64,
+    amount_specified_is_input: bool,
+    a_to_b: bool,
+) -> ProgramResult {
+    let args = SwapArgs {
+        amount,
+        other_amount_threshold,
+        sqrt_price_limit: if a_to_b {
+            MIN_SQRT_PRICE_X64
+        } else {
+            MAX_SQRT_PRICE_X64
+        },
+        amount_specified_is_input,
+        a_to_b,
+    };
+    let mut data = SWAP_DISCRIMINATOR.to_vec();
+    args.serialize(&mut data)
+        .map_err(|_| ProgramError::InvalidInstructionData)?;
+    let ix = Instruction {
+        program_id: *ctx.program.key,
+        accounts: ctx.accounts.to_account_metas(None),
+        data,
+    };
+    solana_program::program::invoke_signed(
+        &ix,
+        &ToAccountInfos::to_account_infos(&ctx),
+        ctx.signer_seeds,
+    )?;
+    Ok(())
+}
+
+#[derive(Accounts, Clone)]
+pub struct WhirlpoolSwap<'info> {
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(signer)]
+    pub token_authority: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub whirlpool: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub token_owner_account_a: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub token_vault_a: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub token_owner_account_b: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub token_vault_b: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub tick_array_0: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub tick_array_1: AccountInfo<'info>,
+    /// CHECK: Safe
+    #[account(mut)]
+    pub tick_array_2: AccountInfo<'info>,
+    /// CHECK: Safe
+    pub oracle: AccountInfo<'info>,
+}
+
+// Client for swapping through a Whirlpool concentrated liquidity pool.
+#[derive(Clone)]
+pub(crate) struct WhirlpoolClient<'info> {
+    pub(crate) accounts: WhirlpoolSwap<'info>,
+    // The Whirlpool program
+    /// CHECK: Safe
+    pub(crate) whirlpool_program: AccountInfo<'info>,
+}
+
+impl<'info> WhirlpoolClient<'info> {
+    /// Builds the client for a route leg from its accounts, in the order of `LEG_ACCOUNTS`.
+    pub(crate) fn from_leg_accounts(
+        accounts: &[AccountInfo<'info>],
+        wallet_owner: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+    ) -> Self {
+        WhirlpoolClient {
+            accounts: WhirlpoolSwap {
+                token_program: token_program.clone(),
+                token_authority: wallet_owner.clone(),
+                whirlpool: accounts[0].clone(),
+                token_vault_a: accounts[1].clone(),
+                token_vault_b: accounts[2].clone(),
+                tick_array_0: accounts[3].clone(),
+                tick_array_1: accounts[4].clone(),
+                tick_array_2: accounts[5].clone(),
+                oracle: accounts[6].clone(),
+                token_owner_account_a: accounts[7].clone(),
+                token_owner_account_b: accounts[8].clone(),
+            },
+            whirlpool_program: accounts[9].clone(),
+        }
+    }
+
+    /// Execute Swap on the Whirlpool program via CPI
+    fn swap(
+        &self,
+        side: Side,
+        amount: u64,
+        other_amount_threshold: u64,
+        amount_specified_is_input: bool,
+    ) -> ProgramResult {
+        let a_to_b = side == Side::Ask;
+        let ctx = CpiContext::new(self.whirlpool_program.clone(), self.accounts.clone());
+
+        msg!("Whirlpool Swap CPI: side: {:?}, amount: {}, other_amount_threshold: {}, amount_specified_is_input: {}", side, amount, other_amount_threshold, amount_specified_is_input);
+        swap(
+            ctx,
+            amount,
+            other_amount_threshold,
+            amount_specified_is_input,
+            a_to_b,
+        )
+    }
+}
+
+impl<'info> DexAdapter<'info> for WhirlpoolClient<'info> {
+    fn token_accounts(&self, side: Side) -> (AccountInfo<'info>, AccountInfo<'info>) {
+        match side {
+            Side::Bid => (
+                self.accounts.token_owner_account_b.clone(),
+                self.accounts.token_owner_account_a.clone(),
+            ),
+            Side::Ask => (
+                self.accounts.token_owner_account_a.clone(),
+                self.accounts.token_owner_account_b.clone(),
+            ),
+        }
+    }
+
+    fn swap_exact_in(&self, side: Side, amount_in: u64, min_amount_out: u64) -> ProgramResult {
+        self.swap(side, amount_in, min_amount_out, true)
+    }
+
+    fn swap_exact_out(&self, side: Side, amount_out: u64, max_amount_in: u64) -> ProgramResult {
+        self.swap(side, amount_out, max_amount_in, false)
+    }
+
+    // Walking the tick arrays on-chain is too costly, quote with the off-chain client.
+    fn quote(&self, _side: Side, _amount_in: u64) -> std::result::Result<u64, ProgramError> {
+        Err(error!(crate::ErrorCode::QuoteUnavailable).into())
+    }
+}
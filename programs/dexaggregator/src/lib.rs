@@ -1,14 +1,38 @@
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
 use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::memo::{self, Memo};
 use anchor_spl::token::{self, Mint, Token, TokenAccount};
 use serum_dex::state::MarketState;
-use solana_program::entrypoint::ProgramResult;
-use std::num::NonZeroU64;
-mod dex;
+use solana_program::program_option::COption;
+use solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+pub mod dex;
+pub mod math;
+pub mod oracle;
+
+use dex::serum::{OpenOrdersAccounts, OrderbookClient};
+use dex::DexAdapter;
+use math::{apply_safety_checks, fee_amount, split_fee};
 
 declare_id!("FUXgqvNpxNN87NKXyyz5u6UHe8ywEzWNsn63KUHCP96B");
 
 pub const USDC_MINT: Pubkey = pubkey!("8yvpPAk8avJitZbczXMya9tjZZa4s4txK7FCHEFNaoP");
+// Protocol fee charged on swaps, in basis points, before any volume discount.
+pub const DEFAULT_FEE_BPS: u16 = 100;
+pub const MAX_FEE_TIERS: usize = 8;
+// Layout version of `State`, bumped whenever fields are appended, see `migrate_state`.
+pub const STATE_VERSION: u8 = 1;
+// Zeroed bytes at the end of `State` for fields added later without a realloc.
+pub const STATE_RESERVED_BYTES: usize = 64;
+// Number of trailing days counted towards a user's fee tier volume.
+pub const VOLUME_WINDOW_DAYS: usize = 30;
+// Scale of prices in orders, e.g. `DcaOrder::min_price`, the minimum output per unit of input.
+pub const PRICE_SCALE: u64 = 1_000_000;
+// Most a book-priced trigger may fill below its threshold, in basis points, see `create_trigger`.
+pub const MAX_BOOK_TRIGGER_SLIPPAGE_BPS: u16 = 500;
+const SECONDS_PER_DAY: i64 = 86_400;
 #[program]
 pub mod dexaggregator {
 
@@ -16,10 +40,135 @@ pub mod dexaggregator {
 
     use super::*;
 
-    // initialize with owner publickey
+    // initialize the canonical state with owner publickey, callable by the upgrade
+    // authority only
     pub fn initialize(ctx: Context<Initialize>, owner: Pubkey) -> Result<()> {
         let state = &mut ctx.accounts.state;
         state.owner = owner;
+        state.fee_bps = DEFAULT_FEE_BPS;
+        state.fee_tiers = vec![];
+        state.version = STATE_VERSION;
+        Ok(())
+    }
+
+    // grow the state of an earlier layout to the current one, filling the new fields with
+    // their defaults, or move a legacy state created with a keypair into the canonical PDA,
+    // closing it, callable by the upgrade authority only, who pays the rent of the added
+    // space
+    pub fn migrate_state(ctx: Context<MigrateState>) -> Result<()> {
+        let state_info = ctx.accounts.state.to_account_info();
+        let source = ctx.accounts.legacy_state.as_ref().unwrap_or(&state_info);
+        let old_len = source.data_len();
+        let new_len = 8 + State::LEN;
+        let mut data = source.try_borrow_data()?.to_vec();
+        require!(
+            old_len >= 8 + 32 && data[..8] == State::DISCRIMINATOR,
+            ErrorCode::InvalidState
+        );
+        // Fields are only appended, so the old data reads back with the new ones zeroed.
+        data.resize(old_len.max(new_len), 0);
+        let mut state = State::try_deserialize(&mut &data[..])?;
+        match &ctx.accounts.legacy_state {
+            Some(_) => require!(
+                state_info.owner == &System::id(),
+                ErrorCode::StateAlreadyExists
+            ),
+            None => require!(state.version < STATE_VERSION, ErrorCode::StateUpToDate),
+        }
+
+        let authority = ctx.accounts.authority.to_account_info();
+        let system_program = ctx.accounts.system_program.to_account_info();
+        let rent = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(state_info.lamports());
+        if rent > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    system_program.clone(),
+                    anchor_lang::system_program::Transfer {
+                        from: authority.clone(),
+                        to: state_info.clone(),
+                    },
+                ),
+                rent,
+            )?;
+        }
+        match &ctx.accounts.legacy_state {
+            Some(legacy_state) => {
+                // Allocated and assigned rather than created, in case the PDA was funded.
+                let signer_seeds: &[&[&[u8]]] = &[&[b"state", &[ctx.bumps.state]]];
+                anchor_lang::system_program::allocate(
+                    CpiContext::new_with_signer(
+                        system_program.clone(),
+                        anchor_lang::system_program::Allocate {
+                            account_to_allocate: state_info.clone(),
+                        },
+                        signer_seeds,
+                    ),
+                    new_len as u64,
+                )?;
+                anchor_lang::system_program::assign(
+                    CpiContext::new_with_signer(
+                        system_program,
+                        anchor_lang::system_program::Assign {
+                            account_to_assign: state_info.clone(),
+                        },
+                        signer_seeds,
+                    ),
+                    &crate::ID,
+                )?;
+
+                **authority.try_borrow_mut_lamports()? += legacy_state.lamports();
+                **legacy_state.try_borrow_mut_lamports()? = 0;
+                legacy_state.assign(&System::id());
+                legacy_state.realloc(0, false)?;
+            }
+            None if old_len < new_len => state_info.realloc(new_len, true)?,
+            None => {}
+        }
+
+        // A state from before fees were configurable charges the default fee.
+        if old_len < 8 + 32 + 2 {
+            state.fee_bps = DEFAULT_FEE_BPS;
+        }
+        state.version = STATE_VERSION;
+        state.try_serialize(&mut &mut state_info.try_borrow_mut_data()?[..])?;
+
+        emit!(StateMigrated {
+            state: state_info.key(),
+            from_len: old_len as u32,
+            version: STATE_VERSION,
+            legacy_state: ctx.accounts.legacy_state.as_ref().map(Key::key),
+        });
+        Ok(())
+    }
+
+    // set the base fee and the volume discount tiers
+    pub fn set_fees(
+        ctx: Context<UpdateState>,
+        fee_bps: u16,
+        fee_tiers: Vec<FeeTier>,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.owner.key() == ctx.accounts.state.owner,
+            ErrorCode::Unauthorized
+        );
+        require!(fee_bps <= 10_000, ErrorCode::InvalidFeeTiers);
+        require!(fee_tiers.len() <= MAX_FEE_TIERS, ErrorCode::InvalidFeeTiers);
+        require!(
+            fee_tiers
+                .windows(2)
+                .all(|pair| pair[0].min_volume < pair[1].min_volume),
+            ErrorCode::InvalidFeeTiers
+        );
+        require!(
+            fee_tiers.iter().all(|tier| tier.fee_bps <= fee_bps),
+            ErrorCode::InvalidFeeTiers
+        );
+
+        let state = &mut ctx.accounts.state;
+        state.fee_bps = fee_bps;
+        state.fee_tiers = fee_tiers;
         Ok(())
     }
 
@@ -40,9 +189,40 @@ pub mod dexaggregator {
 
     //  Similarly, when side is 'ask', amount -> A, amount_out_min -> B, the implied price (of A) is amount_out_min/amount.
 
+    //  The protocol fee is taken in the quote currency, at the lowest of the base fee and the
+    //  tiers in `State` the user's trailing 30 day volume in `UserStats` qualifies for.
+
+    //  Passing the market's `MarketCache`, see `cache_market`, avoids loading the market account.
+
+    //  If `State::market_allowlist_enabled` is set, the market must have a `MarketConfig`.
+
+    //  Wallets and calling programs with a `FeeExemption` pay no fee, routing all of `amount_in_max`.
+
+    //  If a `referral` token account of the treasury is given, Serum pays its referral rebate
+    //  on the taker fee into it. It must be in the market's quote currency.
+
+    //  `execution_mode`, else the market's `MarketConfig`, selects NewOrderV3 execution for
+    //  markets without SendTake. It needs the user's `open_orders` PDA, created on first use,
+    //  and approves it to spend the input for the order.
+
+    //  `match_limit` caps the orders Serum matches. Without it, the limit is what the remaining
+    //  compute budget affords, so fragmented books fill partially instead of failing.
+
+    //  `fill_policy` sets how much of the input must fill, see `FillPolicy`. A bid allowed to
+    //  fill partially pays the fee on the part that fills. The `SwapFilled` event reports the
+    //  input left unfilled.
+
+    //  With a `recipient` token account, e.g. a merchant's, the output lands in the user's
+    //  wallet and exactly the amount received, after an ask's fee, is forwarded to it. A
+    //  `memo`, e.g. an invoice reference, is logged with the SPL memo program.
+
     // / * `side`           - The direction to swap.
     // / * `amount_in_max`  - The max input  amount to swap "from".
     // / * `amount_out_min` - The minimum output amount of the "to" token, the instruction fails if execution would result in less.
+    // / * `execution_mode` - SendTake or NewOrderV3, defaulting to the market config's mode.
+    // / * `match_limit`    - The most orders to match, defaulting to what the compute budget affords.
+    // / * `fill_policy`    - Fill-or-kill or a minimum partial fill of the input.
+    // / * `memo`           - Memo to log with the swap, needing the `memo_program`.
 
     #[access_control(is_valid_swap(&ctx))]
     pub fn swap<'info>(
@@ -50,16 +230,44 @@ pub mod dexaggregator {
         side: Side,
         amount_in_max: u64,
         amount_out_min: u64,
+        execution_mode: Option<ExecutionMode>,
+        match_limit: Option<u16>,
+        fill_policy: Option<FillPolicy>,
+        memo: Option<String>,
     ) -> Result<()> {
         msg!("Serum Swap Instruction: Swap");
         msg!(
-            "Inputs: side: {:?}, amount_in_max: {}, amount_out_min: {}",
+            "Inputs: side: {:?}, amount_in_max: {}, amount_out_min: {}, execution_mode: {:?}, match_limit: {:?}, fill_policy: {:?}, memo: {:?}",
             side,
             amount_in_max,
-            amount_out_min
+            amount_out_min,
+            execution_mode,
+            match_limit,
+            fill_policy,
+            memo
         );
+        if let Some(FillPolicy::AllowPartial { min_fill_bps }) = fill_policy {
+            require!(min_fill_bps <= 10_000, ErrorCode::InvalidFillPolicy);
+        }
+
+        let mut orderbook: OrderbookClient<'info> = (&*ctx.accounts).into();
+        orderbook.match_limit = match_limit;
+        orderbook.fill_policy = fill_policy;
+        let execution_mode = execution_mode
+            .or(ctx
+                .accounts
+                .market_config
+                .as_ref()
+                .map(|market_config| market_config.execution_mode))
+            .unwrap_or_default();
+        if execution_mode == ExecutionMode::NewOrder {
+            orderbook.open_orders = Some(ctx.accounts.open_orders_accounts()?);
+        }
 
-        let orderbook: OrderbookClient<'info> = (&*ctx.accounts).into();
+        // Fee tier from the user's trailing volume, unless exempt.
+        let day = Clock::get()?.unix_timestamp / SECONDS_PER_DAY;
+        let fee_bps = ctx.accounts.fee_bps(day)?;
+        msg!("Protocol fee: {} bps", fee_bps);
 
         // Side determines swap direction.
         let (from_token, to_token) = match side {
@@ -67,20 +275,18 @@ pub mod dexaggregator {
             Side::Ask => (&ctx.accounts.market.coin_wallet, &ctx.accounts.pc_wallet),
         };
 
-        // Sent some percent token to treasury
-        if side == Side::Bid {
-            token::transfer(
-                CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.pc_wallet.to_account_info(),
-                        to: ctx.accounts.treasury_usdc.to_account_info(),
-                        authority: ctx.accounts.pc_wallet.to_account_info(),
-                    },
-                ),
-                amount_in_max * 10 / 1000,
-            )?;
-        }
+        // Sent some percent token to treasury, for a partial bid once it fills
+        let fee_on_fill =
+            side == Side::Bid && matches!(fill_policy, Some(FillPolicy::AllowPartial { .. }));
+        let (bid_fee, amount_in_routed) = match side {
+            Side::Bid if fee_on_fill => (0, split_fee(amount_in_max, fee_bps).1),
+            Side::Bid => {
+                let (fee, amount_in_routed) = split_fee(amount_in_max, fee_bps);
+                ctx.accounts.transfer_fee(fee)?;
+                (fee, amount_in_routed)
+            }
+            Side::Ask => (0, amount_in_max),
+        };
 
         // Token balances before the trade.
         let from_amount_before = token::accessor::amount(from_token)?;
@@ -88,8 +294,8 @@ pub mod dexaggregator {
 
         // Execute the swap.
         match side {
-            Side::Bid => orderbook.bid(amount_in_max * 990 / 1000, amount_out_min)?,
-            Side::Ask => orderbook.ask(amount_in_max, amount_out_min)?,
+            Side::Bid => orderbook.bid(amount_in_routed, amount_out_min)?,
+            Side::Ask => orderbook.ask(amount_in_routed, amount_out_min)?,
         };
 
         // Token balances after the trade.
@@ -100,29 +306,71 @@ pub mod dexaggregator {
         let from_amount = from_amount_before.checked_sub(from_amount_after).unwrap();
         let to_amount = to_amount_after.checked_sub(to_amount_before).unwrap();
 
-        // Sent some percent token to treasury
-        if side == Side::Ask {
-            token::transfer(
-                CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.pc_wallet.to_account_info(),
-                        to: ctx.accounts.treasury_usdc.to_account_info(),
-                        authority: ctx.accounts.pc_wallet.to_account_info(),
-                    },
+        if let Some(fill_policy) = fill_policy {
+            require!(
+                math::meets_fill_policy(
+                    fill_policy,
+                    side,
+                    amount_in_routed,
+                    from_amount,
+                    to_amount,
+                    orderbook.coin_lot_size()?,
                 ),
-                to_amount * 10 / 1000,
-            )?;
+                ErrorCode::FillBelowMinimum
+            );
         }
+        let bid_fee = if fee_on_fill {
+            let fee = fee_amount(from_amount, fee_bps);
+            ctx.accounts.transfer_fee(fee)?;
+            fee
+        } else {
+            bid_fee
+        };
+
+        // Sent some percent token to treasury
+        let ask_fee = match side {
+            Side::Bid => 0,
+            Side::Ask => {
+                let fee = fee_amount(to_amount, fee_bps);
+                ctx.accounts.transfer_fee(fee)?;
+                fee
+            }
+        };
 
         // Safety checks.
-        apply_safety_checks(
-            amount_in_max * 990 / 1000,
-            amount_out_min,
-            from_amount,
-            to_amount,
+        apply_safety_checks(amount_in_routed, amount_out_min, from_amount, to_amount)?;
+
+        // Quote volume of the trade, with and without the fee.
+        let (volume_in, volume_out) = match side {
+            Side::Bid => (from_amount.checked_add(bid_fee).unwrap(), from_amount),
+            Side::Ask => (to_amount, to_amount),
+        };
+        let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        let wallet_owner = ctx.accounts.wallet_owner.key();
+        ctx.accounts
+            .user_stats
+            .record_trade(wallet_owner, day, &quote_mint, volume_in, volume_out);
+
+        send_to_recipient(
+            &ctx.accounts.token_program,
+            to_token,
+            &ctx.accounts.recipient,
+            &ctx.accounts.wallet_owner,
+            to_amount - ask_fee,
+            &ctx.accounts.memo_program,
+            memo,
         )?;
 
+        emit!(SwapFilled {
+            wallet_owner,
+            market: ctx.accounts.market.market.key(),
+            side,
+            amount_in: amount_in_max,
+            filled: from_amount,
+            unfilled: amount_in_max - bid_fee - from_amount,
+            amount_out: to_amount - ask_fee,
+        });
+
         Ok(())
     }
 
@@ -135,28 +383,56 @@ pub mod dexaggregator {
     ///
     /// 1. Selling A to USD(x) on A/USD(x) market using SendTake.
     /// 2. Buying B using the proceed USD(x) on B/USD(x) market using SendTake.
+    ///
+    /// Without a leg's match limit, the first leg matches at most half of what the remaining
+    /// compute budget affords, leaving the rest to the second.
+    ///
+    /// The USD(x) the second leg leaves over from lot size rounding stays with the user,
+    /// goes to the treasury or buys more B, see `SpillPolicy`. `TransitiveSwapFilled`
+    /// reports it.
+    ///
+    /// With a `recipient`, the B received is forwarded to it from the user's wallet, with the
+    /// `memo` logged as in `swap`.
 
-    /// * `amount_in_max`  - The max input  amount to swap "from".
-    /// * `amount_out_min` - The minimum output amount of the "to" token, the instruction fails if execution would result in less.
+    /// * `amount_in_max`    - The max input  amount to swap "from".
+    /// * `amount_out_min`   - The minimum output amount of the "to" token, the instruction fails if execution would result in less.
+    /// * `from_match_limit` - The most orders to match selling A.
+    /// * `to_match_limit`   - The most orders to match buying B.
+    /// * `spill_policy`     - Where the spilled USD(x) goes, left with the user by default.
+    /// * `memo`             - Memo to log with the swap, needing the `memo_program`.
 
     #[access_control(is_valid_swap_transitive(&ctx))]
     pub fn swap_transitive<'info>(
         ctx: Context<'_, '_, '_, 'info, SwapTransitive<'info>>,
         amount_in_max: u64,
         amount_out_min: u64,
+        from_match_limit: Option<u16>,
+        to_match_limit: Option<u16>,
+        spill_policy: Option<SpillPolicy>,
+        memo: Option<String>,
     ) -> Result<()> {
         msg!("Serum Swap Instruction: Swap Transitive");
         msg!(
-            "Inputs: amount_in_max: {}, amount_out_min: {}",
+            "Inputs: amount_in_max: {}, amount_out_min: {}, from_match_limit: {:?}, to_match_limit: {:?}, spill_policy: {:?}, memo: {:?}",
             amount_in_max,
-            amount_out_min
+            amount_out_min,
+            from_match_limit,
+            to_match_limit,
+            spill_policy,
+            memo
         );
+
+        // Fee tier from the user's trailing volume, unless exempt.
+        let day = Clock::get()?.unix_timestamp / SECONDS_PER_DAY;
+        let fee_bps = ctx.accounts.fee_bps(day)?;
+        msg!("Protocol fee: {} bps", fee_bps);
+
         // Leg 1 : A -> USD(x)
         let (from_amount, sell_proceeds) = {
             let coin_before = token::accessor::amount(&ctx.accounts.from.coin_wallet)?;
             let pc_before = token::accessor::amount(&ctx.accounts.pc_wallet)?;
 
-            let orderbook: OrderbookClient<'info> = ctx.accounts.orderbook_from();
+            let orderbook: OrderbookClient<'info> = ctx.accounts.orderbook_from(from_match_limit);
             orderbook.ask(amount_in_max, 0)?;
 
             let coin_after = token::accessor::amount(&ctx.accounts.from.coin_wallet)?;
@@ -167,124 +443,3581 @@ pub mod dexaggregator {
             )
         };
 
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.pc_wallet.to_account_info(),
-                    to: ctx.accounts.treasury_usdc.to_account_info(),
-                    authority: ctx.accounts.pc_wallet.to_account_info(),
-                },
-            ),
-            sell_proceeds * 10 / 1000,
-        )?;
+        let fee = fee_amount(sell_proceeds, fee_bps);
+        ctx.accounts.transfer_fee(fee)?;
 
         // Leg 2 : USD(x) -> B
-        let (to_amount, buy_proceeds) = {
-            let coin_before = token::accessor::amount(&ctx.accounts.to.coin_wallet)?;
-            let pc_before = token::accessor::amount(&ctx.accounts.pc_wallet)?;
-
-            let orderbook: OrderbookClient<'info> = ctx.accounts.orderbook_to();
-            orderbook.bid(sell_proceeds * 990 / 1000, amount_out_min)?;
-
-            let coin_after = token::accessor::amount(&ctx.accounts.to.coin_wallet)?;
-            let pc_after = token::accessor::amount(&ctx.accounts.pc_wallet)?;
-            (
-                coin_after.checked_sub(coin_before).unwrap(),
-                pc_before.checked_sub(pc_after).unwrap(),
-            )
-        };
+        let intermediate = sell_proceeds.checked_sub(fee).unwrap();
+        let (mut to_amount, mut buy_proceeds) =
+            ctx.accounts
+                .buy(to_match_limit, intermediate, amount_out_min)?;
 
         // USD(x) spills due to rounding errors of the lot size.
-        let spill_amount = sell_proceeds.checked_sub(buy_proceeds).unwrap();
+        let mut spill_amount = intermediate.checked_sub(buy_proceeds).unwrap();
+        let spill_policy = spill_policy.unwrap_or_default();
+        match spill_policy {
+            SpillPolicy::Leave => {}
+            SpillPolicy::Treasury => ctx.accounts.transfer_fee(spill_amount)?,
+            SpillPolicy::Swap => {
+                let (best_ask, _, pc_lot_size) =
+                    dex::serum::best_price(&ctx.accounts.to, Side::Ask)?;
+                let lot_cost = best_ask.map(|price_lots| price_lots.saturating_mul(pc_lot_size));
+                if lot_cost.is_some_and(|lot_cost| spill_amount > lot_cost) {
+                    let (spill_to_amount, spill_spent) =
+                        ctx.accounts.buy(to_match_limit, spill_amount, 0)?;
+                    to_amount = to_amount.checked_add(spill_to_amount).unwrap();
+                    buy_proceeds = buy_proceeds.checked_add(spill_spent).unwrap();
+                    spill_amount = spill_amount.checked_sub(spill_spent).unwrap();
+                }
+            }
+        }
         msg!("Intermediate token spill amount: {:?}", spill_amount);
 
         // Safety checks.
         apply_safety_checks(amount_in_max, amount_out_min, from_amount, to_amount)?;
 
+        // Quote volume of the trade, received selling A and spent buying B.
+        let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        let wallet_owner = ctx.accounts.wallet_owner.key();
+        ctx.accounts.user_stats.record_trade(
+            wallet_owner,
+            day,
+            &quote_mint,
+            sell_proceeds,
+            buy_proceeds,
+        );
+
+        send_to_recipient(
+            &ctx.accounts.token_program,
+            &ctx.accounts.to.coin_wallet,
+            &ctx.accounts.recipient,
+            &ctx.accounts.wallet_owner,
+            to_amount,
+            &ctx.accounts.memo_program,
+            memo,
+        )?;
+
+        emit!(TransitiveSwapFilled {
+            wallet_owner,
+            from_market: ctx.accounts.from.market.key(),
+            to_market: ctx.accounts.to.market.key(),
+            amount_in: from_amount,
+            intermediate: sell_proceeds,
+            fee,
+            amount_out: to_amount,
+            spill: spill_amount,
+            spill_policy,
+        });
+
         Ok(())
     }
 
-    // withdraw from treasury
-    pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
-        // Check if the signer is the program owner
+    // restrict swaps to registered markets, or lift the restriction
+    pub fn set_market_allowlist(ctx: Context<UpdateState>, enabled: bool) -> Result<()> {
         require!(
             ctx.accounts.owner.key() == ctx.accounts.state.owner,
             ErrorCode::Unauthorized
         );
+        ctx.accounts.state.market_allowlist_enabled = enabled;
+        Ok(())
+    }
 
-        let authority_bump = ctx.bumps.treasury;
-        let authority_seeds: &[&[u8]] = &[b"treasury", &[authority_bump]];
-        let signer_seeds = &[&authority_seeds[..]];
+    // approve a Serum market, caching its metadata
+    pub fn register_market(
+        ctx: Context<RegisterMarket>,
+        execution_mode: ExecutionMode,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.owner.key() == ctx.accounts.state.owner,
+            ErrorCode::Unauthorized
+        );
+
+        let market_key = ctx.accounts.market.key();
+        let dex_program = ctx.accounts.dex_program.key();
+        let market = MarketState::load(&ctx.accounts.market, &dex_program, false)
+            .map_err(ProgramError::from)?;
+        let vault_signer = serum_dex::state::gen_vault_signer_key(
+            market.vault_signer_nonce,
+            &market_key,
+            &dex_program,
+        )?;
+
+        let market_config = &mut ctx.accounts.market_config;
+        market_config.market = market_key;
+        market_config.dex_program = dex_program;
+        market_config.coin_mint = pubkey_from_words(market.coin_mint);
+        market_config.pc_mint = pubkey_from_words(market.pc_mint);
+        market_config.coin_lot_size = market.coin_lot_size;
+        market_config.pc_lot_size = market.pc_lot_size;
+        market_config.vault_signer = vault_signer;
+        market_config.execution_mode = execution_mode;
+        Ok(())
+    }
+
+    pub fn deregister_market(ctx: Context<DeregisterMarket>) -> Result<()> {
+        require!(
+            ctx.accounts.owner.key() == ctx.accounts.state.owner,
+            ErrorCode::Unauthorized
+        );
+        Ok(())
+    }
+
+    /// Function: `swap_route`
+
+    /// Swap across a chain of legs, each on any supported venue, e.g. A -> USD(x) on Serum
+    /// then USD(x) -> B on another DEX. Each leg's output is the next leg's input.
+    ///
+    /// The accounts of every leg follow in `remaining_accounts`, in leg order, laid out as
+    /// the leg's venue expects. With the market allowlist enabled, a Serum leg is followed
+    /// by the market's `MarketConfig` PDA. The fee is taken from the output, in the output
+    /// mint. The route counts towards the user's volume if it starts or ends in USDC.
+
+    /// * `legs`           - The venue, side and Serum match limit of every leg.
+    /// * `amount_in`      - The input amount of the first leg.
+    /// * `amount_out_min` - The minimum output amount after the fee, the instruction fails if execution would result in less.
 
+    pub fn swap_route<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapRoute<'info>>,
+        legs: Vec<RouteLeg>,
+        amount_in: u64,
+        amount_out_min: u64,
+    ) -> Result<()> {
+        msg!("Serum Swap Instruction: Swap Route");
+        msg!(
+            "Inputs: legs: {:?}, amount_in: {}, amount_out_min: {}",
+            legs,
+            amount_in,
+            amount_out_min
+        );
+        require!(!legs.is_empty(), ErrorCode::EmptyRoute);
+
+        let day = Clock::get()?.unix_timestamp / SECONDS_PER_DAY;
+        let fee_bps = ctx.accounts.fee_bps(day)?;
+        msg!("Protocol fee: {} bps", fee_bps);
+
+        let mut accounts: &[AccountInfo<'info>] = ctx.remaining_accounts;
+        let mut leg_amount_in = amount_in;
+        let mut route_from: Option<AccountInfo<'info>> = None;
+        let mut route_to: Option<AccountInfo<'info>> = None;
+        let mut from_amount = 0;
+        for (index, leg) in legs.iter().enumerate() {
+            let (adapter, rest) = dex::load_adapter(
+                leg,
+                &ctx.accounts.wallet_owner,
+                &ctx.accounts.token_program,
+                &ctx.accounts.system_program.to_account_info(),
+                accounts,
+                (legs.len() - index) as u64,
+            )?;
+            accounts = match leg.venue {
+                Venue::Serum => _is_registered_leg(
+                    &ctx.accounts.state,
+                    &accounts[..dex::serum::LEG_ACCOUNTS],
+                    &ctx.accounts.wallet_owner,
+                    &ctx.accounts.token_program,
+                    rest,
+                )?,
+                _ => rest,
+            };
+
+            let (from_token, to_token) = adapter.token_accounts(leg.side);
+            if let Some(previous_to) = &route_to {
+                require_keys_eq!(
+                    previous_to.key(),
+                    from_token.key(),
+                    ErrorCode::RouteLegsNotChained
+                );
+            }
+
+            let from_amount_before = token::accessor::amount(&from_token)?;
+            let to_amount_before = token::accessor::amount(&to_token)?;
+            adapter.swap_exact_in(leg.side, leg_amount_in, 0)?;
+            let from_amount_after = token::accessor::amount(&from_token)?;
+            let to_amount_after = token::accessor::amount(&to_token)?;
+
+            if route_from.is_none() {
+                from_amount = from_amount_before.checked_sub(from_amount_after).unwrap();
+                route_from = Some(from_token);
+            }
+            leg_amount_in = to_amount_after.checked_sub(to_amount_before).unwrap();
+            route_to = Some(to_token);
+        }
+        let (route_from, route_to) = (route_from.unwrap(), route_to.unwrap());
+        _is_valid_swap(&route_from, &route_to)?;
+        require_keys_eq!(
+            token::accessor::mint(&route_to)?,
+            ctx.accounts.output_mint.key(),
+            ErrorCode::RouteOutputMintMismatch
+        );
+
+        // Sent some percent token to treasury
+        let to_amount = leg_amount_in;
+        let fee = fee_amount(to_amount, fee_bps);
+        transfer_fee(
+            &ctx.accounts.token_program,
+            &route_to,
+            &ctx.accounts.treasury_output.to_account_info(),
+            &ctx.accounts.wallet_owner,
+            fee,
+        )?;
+
+        // Safety checks.
+        apply_safety_checks(
+            amount_in,
+            amount_out_min,
+            from_amount,
+            to_amount.checked_sub(fee).unwrap(),
+        )?;
+
+        // Quote volume of the route, the input if it starts in USDC, else the output.
+        let input_mint = token::accessor::mint(&route_from)?;
+        let (quote_mint, volume_in, volume_out) = if input_mint == USDC_MINT {
+            (input_mint, from_amount, from_amount)
+        } else {
+            (ctx.accounts.output_mint.key(), to_amount, to_amount)
+        };
+        let wallet_owner = ctx.accounts.wallet_owner.key();
+        ctx.accounts
+            .user_stats
+            .record_trade(wallet_owner, day, &quote_mint, volume_in, volume_out);
+
+        Ok(())
+    }
+
+    /// Function: `batch_swap`
+
+    /// Swaps for many users in one instruction, e.g. a relayer executing the intents it
+    /// collected. Each spec is a `swap` through SendTake on the user's Serum market.
+    ///
+    /// The accounts of every spec follow in `remaining_accounts`, in spec order: the user,
+    /// the user's `UserStats` PDA, the user's `Intent` PDA, then the accounts of a Serum route
+    /// leg, followed by the market's `MarketConfig` PDA if the market allowlist is enabled. A
+    /// user either signs the transaction, passing the program id for the intent, or approves
+    /// the `[b"batch_delegate"]` PDA as delegate of the input wallet for `amount_in_max`, and
+    /// of the quote wallet for an ask's fee. A delegated spec must match the intent the user
+    /// created, swapping its mints and input for at least its minimum output before its
+    /// expiry, and closes the intent to the user, who is then writable. Fees and slippage
+    /// apply per user, at the user's fee tier once they have stats. Fee exemptions don't
+    /// apply.
+    ///
+    /// With `skip_failed`, a spec whose user neither authorized nor funded it is skipped,
+    /// emitting `BatchSwapSkipped`, instead of failing the batch. A swap failing once sent,
+    /// e.g. on its slippage, still fails the batch, as a failed CPI can't be recovered from.
+
+    /// * `specs`       - The side and amounts of every user's swap.
+    /// * `skip_failed` - Whether to skip the specs of unauthorized or unfunded users.
+
+    pub fn batch_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, BatchSwap<'info>>,
+        specs: Vec<BatchSwapSpec>,
+        skip_failed: bool,
+    ) -> Result<()> {
+        msg!("Serum Swap Instruction: Batch Swap");
+        msg!("Inputs: specs: {:?}, skip_failed: {}", specs, skip_failed);
+        require!(!specs.is_empty(), ErrorCode::EmptyBatch);
+
+        let clock = Clock::get()?;
+        let day = clock.unix_timestamp / SECONDS_PER_DAY;
+        let delegate_seeds = vec![b"batch_delegate".to_vec(), vec![ctx.bumps.delegate]];
+        let token_program = ctx.accounts.token_program.to_account_info();
+        let mut accounts: &[AccountInfo<'info>] = ctx.remaining_accounts;
+        for (index, spec) in specs.iter().enumerate() {
+            if accounts.len() < BATCH_SWAP_ACCOUNTS {
+                return Err(ProgramError::NotEnoughAccountKeys.into());
+            }
+            let (user_accounts, rest) = accounts.split_at(BATCH_SWAP_ACCOUNTS);
+            accounts = rest;
+
+            let (wallet_owner, user_stats, intent) =
+                (&user_accounts[0], &user_accounts[1], &user_accounts[2]);
+            let mut orderbook = OrderbookClient::from_leg_accounts(
+                &user_accounts[3..],
+                wallet_owner,
+                &token_program,
+                (specs.len() - index) as u64,
+            );
+            let coin_wallet = load_token_account(&orderbook.market.coin_wallet)?;
+            let pc_wallet = load_token_account(&orderbook.pc_wallet)?;
+            let (user_stats_address, _) = Pubkey::find_program_address(
+                &[b"user_stats", wallet_owner.key.as_ref()],
+                &crate::ID,
+            );
+            require!(
+                orderbook.dex_program.key() == dex::ID
+                    && user_stats.key() == user_stats_address
+                    && coin_wallet.owner == wallet_owner.key()
+                    && pc_wallet.owner == wallet_owner.key()
+                    && coin_wallet.mint != pc_wallet.mint
+                    && pc_wallet.mint == ctx.accounts.treasury_usdc.mint,
+                ErrorCode::BatchAccountsMismatch
+            );
+            let market_config = if ctx.accounts.state.market_allowlist_enabled {
+                let Some((market_config, rest)) = accounts.split_first() else {
+                    return Err(ErrorCode::MarketNotRegistered.into());
+                };
+                accounts = rest;
+                Some(Box::new(
+                    Account::<MarketConfig>::try_from(market_config)
+                        .map_err(|_| error!(ErrorCode::MarketNotRegistered))?,
+                ))
+            } else {
+                None
+            };
+            _is_registered_market(
+                &ctx.accounts.state,
+                &orderbook.market,
+                &orderbook.dex_program,
+                &market_config,
+            )?;
+
+            let (from_wallet, to_wallet) = match spec.side {
+                Side::Bid => (&pc_wallet, &coin_wallet),
+                Side::Ask => (&coin_wallet, &pc_wallet),
+            };
+            let intent = match batch_swap_authority(
+                wallet_owner,
+                intent,
+                &ctx.accounts.delegate.key(),
+                (from_wallet, to_wallet),
+                &pc_wallet,
+                spec,
+                clock.unix_timestamp,
+            ) {
+                Ok(intent) => intent,
+                Err(error) if skip_failed => {
+                    msg!("Skipping batch swap {}: {}", index, error);
+                    emit!(BatchSwapSkipped {
+                        index: index as u16,
+                        wallet_owner: wallet_owner.key(),
+                        error: error.into(),
+                    });
+                    continue;
+                }
+                Err(error) => return Err(error.into()),
+            };
+            let mut amount_out_min = spec.amount_out_min;
+            if let Some(intent) = &intent {
+                orderbook.wallet_owner = ctx.accounts.delegate.clone();
+                orderbook.wallet_owner_seeds = Some(delegate_seeds.clone());
+                amount_out_min = amount_out_min.max(intent.min_amount_out);
+            }
+
+            // Fee tier from the user's trailing volume, once the user has stats.
+            let mut stats = load_user_stats(user_stats)?;
+            let volume = stats.as_ref().map_or(0, |stats| stats.window_volume(day));
+            let fee_bps = ctx.accounts.state.fee_bps_for_volume(volume);
+
+            let (from_amount, to_amount, fee) = swap_for_user(
+                &orderbook,
+                &ctx.accounts.treasury_usdc.to_account_info(),
+                spec.side,
+                spec.amount_in_max,
+                amount_out_min,
+                fee_bps,
+            )?;
+            let (bid_fee, ask_fee) = match spec.side {
+                Side::Bid => (fee, 0),
+                Side::Ask => (0, fee),
+            };
+
+            if let Some(stats) = &mut stats {
+                let (volume_in, volume_out) = match spec.side {
+                    Side::Bid => (from_amount.checked_add(bid_fee).unwrap(), from_amount),
+                    Side::Ask => (to_amount, to_amount),
+                };
+                stats.record_trade(
+                    wallet_owner.key(),
+                    day,
+                    &pc_wallet.mint,
+                    volume_in,
+                    volume_out,
+                );
+                store_user_stats(user_stats, stats)?;
+            }
+            if let Some(intent) = intent {
+                intent.close(wallet_owner.clone())?;
+            }
+
+            emit!(SwapFilled {
+                wallet_owner: wallet_owner.key(),
+                market: orderbook.market.market.key(),
+                side: spec.side,
+                amount_in: spec.amount_in_max,
+                filled: from_amount,
+                unfilled: spec.amount_in_max - bid_fee - from_amount,
+                amount_out: to_amount - ask_fee,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Function: `place_limit_order`
+
+    /// Rests a limit order on a Serum market from the user's PDA open orders account for the
+    /// market, created on first use. The order is paid out of the user's wallets, which are
+    /// also where `settle` pays fills out, less the protocol fee.
+
+    /// * `side`                              - The order's side.
+    /// * `limit_price`                       - The limit price, in pc lots per coin lot.
+    /// * `max_coin_qty`                      - The max size, in coin lots.
+    /// * `max_native_pc_qty_including_fees`  - The max pc spent on a bid, Serum fees included.
+    /// * `client_order_id`                   - The user's id for the order.
+
+    #[access_control(is_valid_limit_order(&ctx))]
+    pub fn place_limit_order(
+        ctx: Context<LimitOrder>,
+        side: Side,
+        limit_price: u64,
+        max_coin_qty: u64,
+        max_native_pc_qty_including_fees: u64,
+        client_order_id: u64,
+    ) -> Result<()> {
+        msg!("Serum Swap Instruction: Place Limit Order");
+        msg!(
+            "Inputs: side: {:?}, limit_price: {}, max_coin_qty: {}, max_native_pc_qty_including_fees: {}, client_order_id: {}",
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty_including_fees,
+            client_order_id
+        );
+        require!(
+            limit_price > 0 && max_coin_qty > 0 && max_native_pc_qty_including_fees > 0,
+            ErrorCode::ZeroSwap
+        );
+
+        let orderbook = ctx.accounts.orderbook(ctx.bumps.open_orders);
+        let open_orders = orderbook.open_orders.as_ref().unwrap();
+        let (coin_free_before, pc_free_before) =
+            dex::serum::open_orders_free(&ctx.accounts.open_orders)?;
+        orderbook.place_order(
+            open_orders,
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty_including_fees,
+            serum_dex::matching::OrderType::Limit,
+            client_order_id,
+        )?;
+        let (coin_free_after, pc_free_after) =
+            dex::serum::open_orders_free(&ctx.accounts.open_orders)?;
+
+        // Serum pays for an order out of free funds first, which may be refunds.
+        let ledger = &mut ctx.accounts.order_ledger;
+        ledger.wallet_owner = ctx.accounts.wallet_owner.key();
+        ledger.market = ctx.accounts.market.market.key();
+        match side {
+            Side::Bid => ledger.spend_pc(pc_free_before.saturating_sub(pc_free_after)),
+            Side::Ask => ledger.spend_coin(coin_free_before.saturating_sub(coin_free_after)),
+        }
+        Ok(())
+    }
+
+    /// Function: `cancel_order`
+
+    /// Cancels a resting order of the user's PDA open orders account. The funds it locked are
+    /// released to the account's free balance, and settle back to the user without a fee.
+
+    /// * `side`     - The order's side.
+    /// * `order_id` - The DEX's id for the order.
+
+    #[access_control(is_valid_limit_order(&ctx))]
+    pub fn cancel_order(ctx: Context<LimitOrder>, side: Side, order_id: u128) -> Result<()> {
+        msg!("Serum Swap Instruction: Cancel Order");
+        msg!("Inputs: side: {:?}, order_id: {}", side, order_id);
+
+        let orderbook = ctx.accounts.orderbook(ctx.bumps.open_orders);
+        let open_orders = orderbook.open_orders.as_ref().unwrap();
+        let (coin_free_before, pc_free_before) =
+            dex::serum::open_orders_free(&ctx.accounts.open_orders)?;
+        orderbook.cancel_order_cpi(open_orders, side, order_id)?;
+        let (coin_free_after, pc_free_after) =
+            dex::serum::open_orders_free(&ctx.accounts.open_orders)?;
+
+        let ledger = &mut ctx.accounts.order_ledger;
+        ledger.wallet_owner = ctx.accounts.wallet_owner.key();
+        ledger.market = ctx.accounts.market.market.key();
+        ledger.refunded_coin = ledger
+            .refunded_coin
+            .checked_add(coin_free_after.saturating_sub(coin_free_before))
+            .unwrap();
+        ledger.refunded_pc = ledger
+            .refunded_pc
+            .checked_add(pc_free_after.saturating_sub(pc_free_before))
+            .unwrap();
+        Ok(())
+    }
+
+    /// Function: `settle`
+
+    /// Settles the user's PDA open orders account for a market into the user's wallets. The
+    /// protocol fee is charged on the proceeds of fills, at the same tier as `swap`, in the
+    /// currency received. Refunds of cancelled orders are fee free.
+
+    pub fn settle(ctx: Context<SettleOrders>) -> Result<()> {
+        msg!("Serum Swap Instruction: Settle");
+
+        let day = Clock::get()?.unix_timestamp / SECONDS_PER_DAY;
+        let fee_bps = ctx.accounts.fee_bps(day)?;
+        msg!("Protocol fee: {} bps", fee_bps);
+
+        let coin_wallet = &ctx.accounts.market.coin_wallet;
+        let pc_wallet = &ctx.accounts.pc_wallet;
+        let coin_before = token::accessor::amount(coin_wallet)?;
+        let pc_before = token::accessor::amount(pc_wallet)?;
+
+        let orderbook = ctx.accounts.orderbook(ctx.bumps.open_orders);
+        orderbook.settle_funds_cpi(orderbook.open_orders.as_ref().unwrap())?;
+
+        let coin_settled = token::accessor::amount(coin_wallet)?
+            .checked_sub(coin_before)
+            .unwrap();
+        let pc_settled = token::accessor::amount(pc_wallet)?
+            .checked_sub(pc_before)
+            .unwrap();
+
+        let ledger = &mut ctx.accounts.order_ledger;
+        let coin_refund = coin_settled.min(ledger.refunded_coin);
+        let pc_refund = pc_settled.min(ledger.refunded_pc);
+        ledger.refunded_coin -= coin_refund;
+        ledger.refunded_pc -= pc_refund;
+
+        let coin_fee = fee_amount(coin_settled - coin_refund, fee_bps);
+        let pc_fee = fee_amount(pc_settled - pc_refund, fee_bps);
+        msg!(
+            "Settled: coin: {} (fee {}), pc: {} (fee {})",
+            coin_settled,
+            coin_fee,
+            pc_settled,
+            pc_fee
+        );
+        transfer_fee(
+            &ctx.accounts.token_program,
+            coin_wallet,
+            &ctx.accounts.treasury_coin.to_account_info(),
+            &ctx.accounts.wallet_owner,
+            coin_fee,
+        )?;
+        transfer_fee(
+            &ctx.accounts.token_program,
+            pc_wallet,
+            &ctx.accounts.treasury_pc.to_account_info(),
+            &ctx.accounts.wallet_owner,
+            pc_fee,
+        )?;
+        Ok(())
+    }
+
+    /// Function: `create_dca`
+
+    /// Creates a DCA order swapping `amount_per_fill` of the input token on a Serum market
+    /// every `interval_seconds`, `fill_count` times. The input for every fill is escrowed in
+    /// the order up front, along with a lamport bounty per fill for the keeper executing it.
+
+    /// * `seed`             - Distinguishes the user's DCA orders.
+    /// * `side`             - The direction to swap on the market.
+    /// * `amount_per_fill`  - The input swapped each fill, protocol fee included.
+    /// * `interval_seconds` - The minimum time between fills.
+    /// * `min_price`        - The minimum output per unit of input of a fill, scaled by `PRICE_SCALE`.
+    /// * `fill_count`       - The number of fills.
+    /// * `bounty_lamports`  - The lamports paid to the keeper of each fill.
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_dca(
+        ctx: Context<CreateDca>,
+        seed: u64,
+        side: Side,
+        amount_per_fill: u64,
+        interval_seconds: i64,
+        min_price: u64,
+        fill_count: u64,
+        bounty_lamports: u64,
+    ) -> Result<()> {
+        msg!("Serum Swap Instruction: Create DCA");
+        require!(
+            amount_per_fill > 0 && fill_count > 0,
+            ErrorCode::InvalidDcaOrder
+        );
+        require!(interval_seconds > 0, ErrorCode::InvalidDcaOrder);
+        require_keys_neq!(
+            ctx.accounts.input_mint.key(),
+            ctx.accounts.output_mint.key(),
+            ErrorCode::SwapTokensCannotMatch
+        );
+
+        let escrow_amount = amount_per_fill
+            .checked_mul(fill_count)
+            .ok_or(ErrorCode::InvalidDcaOrder)?;
+        let bounty_amount = bounty_lamports
+            .checked_mul(fill_count)
+            .ok_or(ErrorCode::InvalidDcaOrder)?;
         token::transfer(
-            CpiContext::new_with_signer(
+            CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.treasury.to_account_info(),
-                    to: ctx.accounts.owner.to_account_info(),
-                    authority: ctx.accounts.treasury.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.source.to_account_info(),
+                    to: ctx.accounts.escrow.to_account_info(),
+                    authority: ctx.accounts.wallet_owner.to_account_info(),
                 },
-                signer_seeds,
             ),
-            ctx.accounts.treasury_usdc.amount,
+            escrow_amount,
         )?;
+        if bounty_amount > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.wallet_owner.to_account_info(),
+                        to: ctx.accounts.dca_order.to_account_info(),
+                    },
+                ),
+                bounty_amount,
+            )?;
+        }
+
+        let dca_order = &mut ctx.accounts.dca_order;
+        dca_order.wallet_owner = ctx.accounts.wallet_owner.key();
+        dca_order.market = ctx.accounts.market.key();
+        dca_order.escrow = ctx.accounts.escrow.key();
+        dca_order.output_vault = ctx.accounts.output_vault.key();
+        dca_order.destination = ctx.accounts.destination.key();
+        dca_order.seed = seed;
+        dca_order.side = side;
+        dca_order.amount_per_fill = amount_per_fill;
+        dca_order.interval_seconds = interval_seconds;
+        dca_order.min_price = min_price;
+        dca_order.remaining_fills = fill_count;
+        dca_order.next_fill_ts = Clock::get()?.unix_timestamp;
+        dca_order.bounty_lamports = bounty_lamports;
+        dca_order.bump = ctx.bumps.dca_order;
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(init, payer = user, space = 8 + 32)]
-    pub state: Account<'info, State>,
-    #[account(mut)]
-    pub user: Signer<'info>,
+    /// Function: `execute_dca`
+
+    /// Executes the next fill of a DCA order once its interval has elapsed, callable by
+    /// anyone. The fill is a SendTake swap signed by the order, charged the protocol fee at
+    /// the user's tier in the quote currency, with the output forwarded to the user. The
+    /// keeper is paid the order's bounty.
+
+    #[access_control(is_valid_escrowed_fill(&ctx.accounts.fill()))]
+    pub fn execute_dca<'info>(ctx: Context<'_, '_, '_, 'info, ExecuteDca<'info>>) -> Result<()> {
+        msg!("Serum Swap Instruction: Execute DCA");
+
+        let now = Clock::get()?.unix_timestamp;
+        let dca_order = &ctx.accounts.dca_order;
+        require!(dca_order.remaining_fills > 0, ErrorCode::DcaOrderFilled);
+        require!(
+            now >= dca_order.next_fill_ts,
+            ErrorCode::DcaIntervalNotElapsed
+        );
+        let side = dca_order.side;
+        let amount_in = dca_order.amount_per_fill;
+
+        let day = now / SECONDS_PER_DAY;
+        let fill = ctx.accounts.fill();
+        let fee_bps = fill.fee_bps(day)?;
+        msg!("Protocol fee: {} bps", fee_bps);
+
+        // Bids pay the fee out of the input, asks out of the output, both in the quote.
+        let (bid_fee, amount_in_routed) = match side {
+            Side::Bid => {
+                let (fee, amount_in_routed) = split_fee(amount_in, fee_bps);
+                fill.transfer_signed(&ctx.accounts.escrow.to_account_info(), fee)?;
+                (fee, amount_in_routed)
+            }
+            Side::Ask => (0, amount_in),
+        };
+        let amount_out_min = math::min_amount_out(amount_in_routed, dca_order.min_price);
+
+        let escrow = ctx.accounts.escrow.to_account_info();
+        let output_vault = ctx.accounts.output_vault.to_account_info();
+        let from_amount_before = token::accessor::amount(&escrow)?;
+        let to_amount_before = token::accessor::amount(&output_vault)?;
+
+        let orderbook = fill.orderbook();
+        match side {
+            Side::Bid => orderbook.bid(amount_in_routed, amount_out_min)?,
+            Side::Ask => orderbook.ask(amount_in_routed, amount_out_min)?,
+        };
+
+        let from_amount = from_amount_before
+            .checked_sub(token::accessor::amount(&escrow)?)
+            .unwrap();
+        let to_amount = token::accessor::amount(&output_vault)?
+            .checked_sub(to_amount_before)
+            .unwrap();
+        apply_safety_checks(amount_in_routed, amount_out_min, from_amount, to_amount)?;
+
+        let ask_fee = match side {
+            Side::Bid => 0,
+            Side::Ask => {
+                let fee = fee_amount(to_amount, fee_bps);
+                fill.transfer_signed(&output_vault, fee)?;
+                fee
+            }
+        };
+        let amount_out = to_amount.checked_sub(ask_fee).unwrap();
+        fill.forward_output(amount_out)?;
+        msg!("DCA fill: in: {}, out: {}", from_amount, amount_out);
+
+        let wallet_owner = ctx.accounts.dca_order.wallet_owner;
+        let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        if let Some(mut user_stats) = load_user_stats(&ctx.accounts.user_stats)? {
+            let (volume_in, volume_out) = match side {
+                Side::Bid => (from_amount.checked_add(bid_fee).unwrap(), from_amount),
+                Side::Ask => (to_amount, to_amount),
+            };
+            user_stats.record_trade(wallet_owner, day, &quote_mint, volume_in, volume_out);
+            store_user_stats(&ctx.accounts.user_stats, &user_stats)?;
+        }
+
+        let dca_order = &mut ctx.accounts.dca_order;
+        dca_order.remaining_fills -= 1;
+        dca_order.next_fill_ts = now.checked_add(dca_order.interval_seconds).unwrap();
+
+        // The keeper's bounty, out of the lamports escrowed for it.
+        let bounty = dca_order.bounty_lamports;
+        if bounty > 0 {
+            let dca_order = dca_order.to_account_info();
+            **dca_order.try_borrow_mut_lamports()? -= bounty;
+            **ctx.accounts.keeper.try_borrow_mut_lamports()? += bounty;
+        }
+        Ok(())
+    }
+
+    /// Function: `cancel_dca`
+
+    /// Cancels a DCA order, refunding the escrowed input and unpaid bounties to the user and
+    /// closing the order's accounts.
+
+    pub fn cancel_dca(ctx: Context<CancelDca>) -> Result<()> {
+        msg!("Serum Swap Instruction: Cancel DCA");
+
+        let refund = ctx.accounts.escrow.amount;
+        msg!(
+            "Refund: {}, remaining fills: {}",
+            refund,
+            ctx.accounts.dca_order.remaining_fills
+        );
+        let accounts = &ctx.accounts;
+        close_escrowed_order(
+            &accounts.dca_order,
+            &accounts.wallet_owner.to_account_info(),
+            &accounts.escrow,
+            &accounts.output_vault,
+            &accounts.refund_account,
+            &accounts.destination,
+            &accounts.token_program.to_account_info(),
+        )
+    }
+
+    /// Function: `create_twap`
+
+    /// Creates a TWAP order swapping `total_amount` of the input token on a Serum market
+    /// between `start_slot` and `end_slot`. The window is split in bands of `band_slots`
+    /// slots, and fills by `execute_twap` are capped to an even share of the size per band,
+    /// with whatever a band left unfilled carried forward to the next.
+
+    /// * `seed`         - Distinguishes the user's TWAP orders.
+    /// * `side`         - The direction to swap on the market.
+    /// * `total_amount` - The input to swap over the window, protocol fee included.
+    /// * `start_slot`   - The first slot of the window.
+    /// * `end_slot`     - The last slot of the window.
+    /// * `band_slots`   - The slots per band.
+    /// * `min_price`    - The minimum output per unit of input of a fill, scaled by `PRICE_SCALE`.
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_twap(
+        ctx: Context<CreateTwap>,
+        seed: u64,
+        side: Side,
+        total_amount: u64,
+        start_slot: u64,
+        end_slot: u64,
+        band_slots: u64,
+        min_price: u64,
+    ) -> Result<()> {
+        msg!("Serum Swap Instruction: Create TWAP");
+        require!(
+            total_amount > 0 && band_slots > 0 && start_slot <= end_slot,
+            ErrorCode::InvalidTwapOrder
+        );
+        require!(end_slot >= Clock::get()?.slot, ErrorCode::InvalidTwapOrder);
+        require_keys_neq!(
+            ctx.accounts.input_mint.key(),
+            ctx.accounts.output_mint.key(),
+            ErrorCode::SwapTokensCannotMatch
+        );
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.source.to_account_info(),
+                    to: ctx.accounts.escrow.to_account_info(),
+                    authority: ctx.accounts.wallet_owner.to_account_info(),
+                },
+            ),
+            total_amount,
+        )?;
+
+        let twap_order = &mut ctx.accounts.twap_order;
+        twap_order.wallet_owner = ctx.accounts.wallet_owner.key();
+        twap_order.market = ctx.accounts.market.key();
+        twap_order.escrow = ctx.accounts.escrow.key();
+        twap_order.output_vault = ctx.accounts.output_vault.key();
+        twap_order.destination = ctx.accounts.destination.key();
+        twap_order.seed = seed;
+        twap_order.side = side;
+        twap_order.total_amount = total_amount;
+        twap_order.start_slot = start_slot;
+        twap_order.end_slot = end_slot;
+        twap_order.band_slots = band_slots;
+        twap_order.min_price = min_price;
+        twap_order.bump = ctx.bumps.twap_order;
+        Ok(())
+    }
+
+    /// Function: `execute_twap`
+
+    /// Cranks a TWAP order, callable by anyone within its window. Swaps the order's unfilled
+    /// share of the size up to the current band with SendTake, accepting a partial fill, and
+    /// updates the order's average execution price. The protocol fee is charged in the quote
+    /// currency on what filled.
+
+    #[access_control(is_valid_escrowed_fill(&ctx.accounts.fill()))]
+    pub fn execute_twap<'info>(ctx: Context<'_, '_, '_, 'info, ExecuteTwap<'info>>) -> Result<()> {
+        msg!("Serum Swap Instruction: Execute TWAP");
+
+        let clock = Clock::get()?;
+        let twap_order = &ctx.accounts.twap_order;
+        require!(
+            clock.slot >= twap_order.start_slot && clock.slot <= twap_order.end_slot,
+            ErrorCode::TwapOutsideWindow
+        );
+        let side = twap_order.side;
+        let amount_in = twap_order
+            .band_target(clock.slot)
+            .saturating_sub(twap_order.input_filled)
+            .min(ctx.accounts.escrow.amount);
+        require!(amount_in > 0, ErrorCode::TwapBandFilled);
+        msg!("TWAP fill: slot: {}, amount_in: {}", clock.slot, amount_in);
+
+        let day = clock.unix_timestamp / SECONDS_PER_DAY;
+        let fill = ctx.accounts.fill();
+        let fee_bps = fill.fee_bps(day)?;
+        msg!("Protocol fee: {} bps", fee_bps);
+
+        // Bids leave room for the fee on the input, charged on the part that fills.
+        let escrow = ctx.accounts.escrow.to_account_info();
+        let output_vault = ctx.accounts.output_vault.to_account_info();
+        let amount_in_routed = match side {
+            Side::Bid => split_fee(amount_in, fee_bps).1,
+            Side::Ask => amount_in,
+        };
+
+        let from_amount_before = token::accessor::amount(&escrow)?;
+        let to_amount_before = token::accessor::amount(&output_vault)?;
+
+        // Partial fills are expected, the leftover carries forward to the next crank.
+        let orderbook = fill.orderbook();
+        match side {
+            Side::Bid => orderbook.bid(amount_in_routed, 0)?,
+            Side::Ask => orderbook.ask(amount_in_routed, 0)?,
+        };
+
+        let from_amount = from_amount_before
+            .checked_sub(token::accessor::amount(&escrow)?)
+            .unwrap();
+        let to_amount = token::accessor::amount(&output_vault)?
+            .checked_sub(to_amount_before)
+            .unwrap();
+        apply_safety_checks(amount_in_routed, 0, from_amount, to_amount)?;
+        if !math::meets_min_price(from_amount, to_amount, ctx.accounts.twap_order.min_price) {
+            return Err(ErrorCode::SwapTokenAmountLessThanMin.into());
+        }
+
+        // Fees in the quote, out of the input of bids and the output of asks.
+        let (bid_fee, ask_fee) = match side {
+            Side::Bid => {
+                let fee = fee_amount(from_amount, fee_bps);
+                fill.transfer_signed(&escrow, fee)?;
+                (fee, 0)
+            }
+            Side::Ask => {
+                let fee = fee_amount(to_amount, fee_bps);
+                fill.transfer_signed(&output_vault, fee)?;
+                (0, fee)
+            }
+        };
+        let amount_out = to_amount.checked_sub(ask_fee).unwrap();
+        fill.forward_output(amount_out)?;
+
+        let wallet_owner = ctx.accounts.twap_order.wallet_owner;
+        let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        if let Some(mut user_stats) = load_user_stats(&ctx.accounts.user_stats)? {
+            let (volume_in, volume_out) = match side {
+                Side::Bid => (from_amount.checked_add(bid_fee).unwrap(), from_amount),
+                Side::Ask => (to_amount, to_amount),
+            };
+            user_stats.record_trade(wallet_owner, day, &quote_mint, volume_in, volume_out);
+            store_user_stats(&ctx.accounts.user_stats, &user_stats)?;
+        }
+
+        // What a partial fill left unspent stays in the escrow for later bands.
+        let input_spent = from_amount.checked_add(bid_fee).unwrap();
+        let twap_order = &mut ctx.accounts.twap_order;
+        twap_order.record_fill(side, input_spent, from_amount, to_amount);
+        msg!(
+            "TWAP filled: {} of {}, average price: {}",
+            twap_order.input_filled,
+            twap_order.total_amount,
+            twap_order.average_price
+        );
+        Ok(())
+    }
+
+    /// Function: `cancel_twap`
+
+    /// Cancels a TWAP order, refunding the unfilled input to the user and closing the
+    /// order's accounts. This is also how the leftover of an expired order is reclaimed.
+
+    pub fn cancel_twap(ctx: Context<CancelTwap>) -> Result<()> {
+        msg!("Serum Swap Instruction: Cancel TWAP");
+
+        let refund = ctx.accounts.escrow.amount;
+        msg!(
+            "Refund: {}, filled: {} of {}",
+            refund,
+            ctx.accounts.twap_order.input_filled,
+            ctx.accounts.twap_order.total_amount
+        );
+        let accounts = &ctx.accounts;
+        close_escrowed_order(
+            &accounts.twap_order,
+            &accounts.wallet_owner.to_account_info(),
+            &accounts.escrow,
+            &accounts.output_vault,
+            &accounts.refund_account,
+            &accounts.destination,
+            &accounts.token_program.to_account_info(),
+        )
+    }
+
+    /// Function: `create_trigger`
+
+    /// Creates a stop-loss or take-profit order: a swap of escrowed input on a Serum market
+    /// executed by any keeper once a price crosses `threshold`. The price is a Pyth oracle's,
+    /// or the best bid or ask of the market's book, in quote per base token scaled by
+    /// `PRICE_SCALE`. A lamport bounty is escrowed for the keeper.
+    ///
+    /// Anyone can move a book's best bid or ask with a small order and trade against the
+    /// swap it triggers, so book-priced orders need a `min_amount_out` no more than
+    /// `MAX_BOOK_TRIGGER_SLIPPAGE_BPS` below the output at `threshold`. Orders that must fill
+    /// whatever the price, e.g. a stop-loss in a crash, trigger on an oracle instead.
+
+    /// * `seed`            - Distinguishes the user's trigger orders.
+    /// * `side`            - The direction to swap on the market.
+    /// * `amount_in`       - The input to swap, protocol fee included.
+    /// * `min_amount_out`  - The minimum output of the swap.
+    /// * `price_source`    - The price the condition is on.
+    /// * `direction`       - Whether the order triggers above or below the threshold.
+    /// * `threshold`       - The trigger price, scaled by `PRICE_SCALE`.
+    /// * `bounty_lamports` - The lamports paid to the keeper.
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_trigger(
+        ctx: Context<CreateTrigger>,
+        seed: u64,
+        side: Side,
+        amount_in: u64,
+        min_amount_out: u64,
+        price_source: PriceSource,
+        direction: TriggerDirection,
+        threshold: u64,
+        bounty_lamports: u64,
+    ) -> Result<()> {
+        msg!("Serum Swap Instruction: Create Trigger");
+        require!(amount_in > 0, ErrorCode::InvalidTriggerOrder);
+        require_keys_neq!(
+            ctx.accounts.input_mint.key(),
+            ctx.accounts.output_mint.key(),
+            ErrorCode::SwapTokensCannotMatch
+        );
+        let oracle = match (price_source, &ctx.accounts.oracle) {
+            (PriceSource::Oracle, Some(oracle)) => {
+                oracle::OraclePrice::load(oracle)?;
+                oracle.key()
+            }
+            (PriceSource::Oracle, None) => return Err(ErrorCode::InvalidOracle.into()),
+            _ => Pubkey::default(),
+        };
+        let (base_mint, quote_mint) = match side {
+            Side::Bid => (&ctx.accounts.output_mint, &ctx.accounts.input_mint),
+            Side::Ask => (&ctx.accounts.input_mint, &ctx.accounts.output_mint),
+        };
+        if price_source != PriceSource::Oracle {
+            let amount_at_threshold = math::amount_at_price(
+                side,
+                amount_in,
+                threshold,
+                base_mint.decimals,
+                quote_mint.decimals,
+            );
+            let (_, min_amount_out_floor) =
+                split_fee(amount_at_threshold, MAX_BOOK_TRIGGER_SLIPPAGE_BPS);
+            require!(
+                min_amount_out >= min_amount_out_floor,
+                ErrorCode::InvalidTriggerOrder
+            );
+        }
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.source.to_account_info(),
+                    to: ctx.accounts.escrow.to_account_info(),
+                    authority: ctx.accounts.wallet_owner.to_account_info(),
+                },
+            ),
+            amount_in,
+        )?;
+        if bounty_lamports > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.wallet_owner.to_account_info(),
+                        to: ctx.accounts.trigger_order.to_account_info(),
+                    },
+                ),
+                bounty_lamports,
+            )?;
+        }
+
+        let trigger_order = &mut ctx.accounts.trigger_order;
+        trigger_order.wallet_owner = ctx.accounts.wallet_owner.key();
+        trigger_order.market = ctx.accounts.market.key();
+        trigger_order.escrow = ctx.accounts.escrow.key();
+        trigger_order.output_vault = ctx.accounts.output_vault.key();
+        trigger_order.destination = ctx.accounts.destination.key();
+        trigger_order.seed = seed;
+        trigger_order.side = side;
+        trigger_order.amount_in = amount_in;
+        trigger_order.min_amount_out = min_amount_out;
+        trigger_order.price_source = price_source;
+        trigger_order.oracle = oracle;
+        trigger_order.direction = direction;
+        trigger_order.threshold = threshold;
+        trigger_order.base_decimals = base_mint.decimals;
+        trigger_order.quote_decimals = quote_mint.decimals;
+        trigger_order.bounty_lamports = bounty_lamports;
+        trigger_order.triggered = false;
+        trigger_order.bump = ctx.bumps.trigger_order;
+        Ok(())
+    }
+
+    /// Function: `trigger`
+
+    /// Executes a trigger order whose condition holds, callable by anyone. The swap is a
+    /// SendTake signed by the order, charged the protocol fee at the user's tier in the quote
+    /// currency, with the output forwarded to the user. The keeper is paid the bounty.
+
+    #[access_control(is_valid_escrowed_fill(&ctx.accounts.fill()))]
+    pub fn trigger<'info>(ctx: Context<'_, '_, '_, 'info, ExecuteTrigger<'info>>) -> Result<()> {
+        msg!("Serum Swap Instruction: Trigger");
+
+        let clock = Clock::get()?;
+        let trigger_order = &ctx.accounts.trigger_order;
+        require!(!trigger_order.triggered, ErrorCode::TriggerOrderExecuted);
+        let price = ctx.accounts.trigger_price(clock.slot)?;
+        msg!(
+            "Trigger price: {}, threshold: {} ({:?})",
+            price,
+            trigger_order.threshold,
+            trigger_order.direction
+        );
+        require!(
+            trigger_order.is_triggered_at(price),
+            ErrorCode::TriggerConditionNotMet
+        );
+        let side = trigger_order.side;
+        let amount_in = trigger_order.amount_in;
+        let amount_out_min = trigger_order.min_amount_out;
+
+        let day = clock.unix_timestamp / SECONDS_PER_DAY;
+        let fill = ctx.accounts.fill();
+        let fee_bps = fill.fee_bps(day)?;
+        msg!("Protocol fee: {} bps", fee_bps);
+
+        // Bids pay the fee out of the input, asks out of the output, both in the quote.
+        let escrow = ctx.accounts.escrow.to_account_info();
+        let output_vault = ctx.accounts.output_vault.to_account_info();
+        let (bid_fee, amount_in_routed) = match side {
+            Side::Bid => {
+                let (fee, amount_in_routed) = split_fee(amount_in, fee_bps);
+                fill.transfer_signed(&escrow, fee)?;
+                (fee, amount_in_routed)
+            }
+            Side::Ask => (0, amount_in),
+        };
+
+        let from_amount_before = token::accessor::amount(&escrow)?;
+        let to_amount_before = token::accessor::amount(&output_vault)?;
+
+        let orderbook = fill.orderbook();
+        match side {
+            Side::Bid => orderbook.bid(amount_in_routed, amount_out_min)?,
+            Side::Ask => orderbook.ask(amount_in_routed, amount_out_min)?,
+        };
+
+        let from_amount = from_amount_before
+            .checked_sub(token::accessor::amount(&escrow)?)
+            .unwrap();
+        let to_amount = token::accessor::amount(&output_vault)?
+            .checked_sub(to_amount_before)
+            .unwrap();
+        apply_safety_checks(amount_in_routed, amount_out_min, from_amount, to_amount)?;
+
+        let ask_fee = match side {
+            Side::Bid => 0,
+            Side::Ask => {
+                let fee = fee_amount(to_amount, fee_bps);
+                fill.transfer_signed(&output_vault, fee)?;
+                fee
+            }
+        };
+        let amount_out = to_amount.checked_sub(ask_fee).unwrap();
+        fill.forward_output(amount_out)?;
+        msg!("Triggered: in: {}, out: {}", from_amount, amount_out);
+
+        let wallet_owner = ctx.accounts.trigger_order.wallet_owner;
+        let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        if let Some(mut user_stats) = load_user_stats(&ctx.accounts.user_stats)? {
+            let (volume_in, volume_out) = match side {
+                Side::Bid => (from_amount.checked_add(bid_fee).unwrap(), from_amount),
+                Side::Ask => (to_amount, to_amount),
+            };
+            user_stats.record_trade(wallet_owner, day, &quote_mint, volume_in, volume_out);
+            store_user_stats(&ctx.accounts.user_stats, &user_stats)?;
+        }
+
+        // Whatever input the swap left unspent is refunded by `cancel_trigger`.
+        let trigger_order = &mut ctx.accounts.trigger_order;
+        trigger_order.triggered = true;
+
+        let bounty = trigger_order.bounty_lamports;
+        if bounty > 0 {
+            let trigger_order = trigger_order.to_account_info();
+            **trigger_order.try_borrow_mut_lamports()? -= bounty;
+            **ctx.accounts.keeper.try_borrow_mut_lamports()? += bounty;
+        }
+        Ok(())
+    }
+
+    /// Function: `cancel_trigger`
+
+    /// Cancels a trigger order, or closes an executed one, refunding the escrowed input and
+    /// unpaid bounty to the user and closing the order's accounts.
+
+    pub fn cancel_trigger(ctx: Context<CancelTrigger>) -> Result<()> {
+        msg!("Serum Swap Instruction: Cancel Trigger");
+
+        let refund = ctx.accounts.escrow.amount;
+        msg!(
+            "Refund: {}, triggered: {}",
+            refund,
+            ctx.accounts.trigger_order.triggered
+        );
+        let accounts = &ctx.accounts;
+        close_escrowed_order(
+            &accounts.trigger_order,
+            &accounts.wallet_owner.to_account_info(),
+            &accounts.escrow,
+            &accounts.output_vault,
+            &accounts.refund_account,
+            &accounts.destination,
+            &accounts.token_program.to_account_info(),
+        )
+    }
+
+    /// Function: `create_intent`
+
+    /// Records a swap the user signs for a whitelisted executor to run through
+    /// `swap_delegated`, e.g. gasless or at a limit price. The input is not escrowed: the user
+    /// approves the `[b"intent_delegate"]` PDA for it, and for the fee if selling into the
+    /// quote, or the `[b"batch_delegate"]` PDA for a relayer's `batch_swap`.
+
+    /// * `seed`           - Distinguishes the user's intents.
+    /// * `amount_in`      - The input to swap, protocol fee included.
+    /// * `min_amount_out` - The minimum output of the swap.
+    /// * `expiry`         - The unix timestamp the intent can no longer execute from.
+
+    pub fn create_intent(
+        ctx: Context<CreateIntent>,
+        seed: u64,
+        amount_in: u64,
+        min_amount_out: u64,
+        expiry: i64,
+    ) -> Result<()> {
+        msg!("Serum Swap Instruction: Create Intent");
+        require!(amount_in > 0, ErrorCode::InvalidIntent);
+        require!(
+            expiry > Clock::get()?.unix_timestamp,
+            ErrorCode::InvalidIntent
+        );
+        require_keys_neq!(
+            ctx.accounts.input_mint.key(),
+            ctx.accounts.output_mint.key(),
+            ErrorCode::SwapTokensCannotMatch
+        );
+
+        let intent = &mut ctx.accounts.intent;
+        intent.wallet_owner = ctx.accounts.wallet_owner.key();
+        intent.seed = seed;
+        intent.input_mint = ctx.accounts.input_mint.key();
+        intent.output_mint = ctx.accounts.output_mint.key();
+        intent.amount_in = amount_in;
+        intent.min_amount_out = min_amount_out;
+        intent.expiry = expiry;
+        intent.bump = ctx.bumps.intent;
+        Ok(())
+    }
+
+    /// Function: `swap_delegated`
+
+    /// Executes an intent, callable by a whitelisted executor only. The swap is a SendTake on
+    /// a market of the intent's mints, signed by the delegate out of the user's wallets and
+    /// charged the protocol fee at the user's tier in the quote currency. The intent is closed,
+    /// refunding its rent to the user.
+
+    #[access_control(is_valid_swap_delegated(&ctx))]
+    pub fn swap_delegated<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapDelegated<'info>>,
+    ) -> Result<()> {
+        msg!("Serum Swap Instruction: Swap Delegated");
+
+        let clock = Clock::get()?;
+        let intent = &ctx.accounts.intent;
+        require!(
+            clock.unix_timestamp < intent.expiry,
+            ErrorCode::IntentExpired
+        );
+        let side = if intent.input_mint == token::accessor::mint(&ctx.accounts.pc_wallet)? {
+            Side::Bid
+        } else {
+            Side::Ask
+        };
+        let amount_in = intent.amount_in;
+        let amount_out_min = intent.min_amount_out;
+
+        let day = clock.unix_timestamp / SECONDS_PER_DAY;
+        let fee_bps = ctx.accounts.fee_bps(day)?;
+        msg!("Protocol fee: {} bps", fee_bps);
+
+        let orderbook = ctx.accounts.orderbook(ctx.bumps.delegate);
+        let (from_amount, to_amount, fee) = swap_for_user(
+            &orderbook,
+            &ctx.accounts.treasury_pc.to_account_info(),
+            side,
+            amount_in,
+            amount_out_min,
+            fee_bps,
+        )?;
+        let (bid_fee, ask_fee) = match side {
+            Side::Bid => (fee, 0),
+            Side::Ask => (0, fee),
+        };
+
+        let wallet_owner = ctx.accounts.wallet_owner.key();
+        let quote_mint = token::accessor::mint(&ctx.accounts.pc_wallet)?;
+        if let Some(mut user_stats) = load_user_stats(&ctx.accounts.user_stats)? {
+            let (volume_in, volume_out) = match side {
+                Side::Bid => (from_amount.checked_add(bid_fee).unwrap(), from_amount),
+                Side::Ask => (to_amount, to_amount),
+            };
+            user_stats.record_trade(wallet_owner, day, &quote_mint, volume_in, volume_out);
+            store_user_stats(&ctx.accounts.user_stats, &user_stats)?;
+        }
+
+        emit!(SwapFilled {
+            wallet_owner,
+            market: ctx.accounts.market.market.key(),
+            side,
+            amount_in,
+            filled: from_amount,
+            unfilled: amount_in - bid_fee - from_amount,
+            amount_out: to_amount - ask_fee,
+        });
+        Ok(())
+    }
+
+    /// Function: `cancel_intent`
+
+    /// Cancels an intent, refunding its rent to the user. The user's approval of the delegate
+    /// is theirs to revoke.
+
+    pub fn cancel_intent(_ctx: Context<CancelIntent>) -> Result<()> {
+        msg!("Serum Swap Instruction: Cancel Intent");
+        Ok(())
+    }
+
+    // cache the market metadata used by swaps, callable by anyone
+    pub fn cache_market(ctx: Context<CacheMarket>) -> Result<()> {
+        let market_key = ctx.accounts.market.key();
+        let dex_program = ctx.accounts.dex_program.key();
+        let market = MarketState::load(&ctx.accounts.market, &dex_program, false)
+            .map_err(ProgramError::from)?;
+        let vault_signer = serum_dex::state::gen_vault_signer_key(
+            market.vault_signer_nonce,
+            &market_key,
+            &dex_program,
+        )?;
+
+        let market_cache = &mut ctx.accounts.market_cache;
+        market_cache.market = market_key;
+        market_cache.dex_program = dex_program;
+        market_cache.coin_mint = pubkey_from_words(market.coin_mint);
+        market_cache.pc_mint = pubkey_from_words(market.pc_mint);
+        market_cache.coin_vault = pubkey_from_words(market.coin_vault);
+        market_cache.pc_vault = pubkey_from_words(market.pc_vault);
+        market_cache.coin_lot_size = market.coin_lot_size;
+        market_cache.pc_lot_size = market.pc_lot_size;
+        market_cache.vault_signer_nonce = market.vault_signer_nonce;
+        market_cache.vault_signer = vault_signer;
+        Ok(())
+    }
+
+    // exempt a wallet or a calling program from the protocol fee
+    pub fn add_fee_exemption(ctx: Context<AddFeeExemption>, exempt: Pubkey) -> Result<()> {
+        require!(
+            ctx.accounts.owner.key() == ctx.accounts.state.owner,
+            ErrorCode::Unauthorized
+        );
+        ctx.accounts.fee_exemption.exempt = exempt;
+        Ok(())
+    }
+
+    pub fn remove_fee_exemption(ctx: Context<RemoveFeeExemption>) -> Result<()> {
+        require!(
+            ctx.accounts.owner.key() == ctx.accounts.state.owner,
+            ErrorCode::Unauthorized
+        );
+        Ok(())
+    }
+
+    // whitelist an executor of users' intents
+    pub fn add_executor(ctx: Context<AddExecutor>, executor: Pubkey) -> Result<()> {
+        require!(
+            ctx.accounts.owner.key() == ctx.accounts.state.owner,
+            ErrorCode::Unauthorized
+        );
+        ctx.accounts.executor_entry.executor = executor;
+        Ok(())
+    }
+
+    pub fn remove_executor(ctx: Context<RemoveExecutor>) -> Result<()> {
+        require!(
+            ctx.accounts.owner.key() == ctx.accounts.state.owner,
+            ErrorCode::Unauthorized
+        );
+        Ok(())
+    }
+
+    // withdraw from treasury
+    pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
+        // Check if the signer is the program owner
+        require!(
+            ctx.accounts.owner.key() == ctx.accounts.state.owner,
+            ErrorCode::Unauthorized
+        );
+
+        let authority_bump = ctx.bumps.treasury;
+        let authority_seeds: &[&[u8]] = &[b"treasury", &[authority_bump]];
+        let signer_seeds = &[&authority_seeds[..]];
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.treasury.to_account_info(),
+                    to: ctx.accounts.owner.to_account_info(),
+                    authority: ctx.accounts.treasury.to_account_info(),
+                },
+                signer_seeds,
+            ),
+            ctx.accounts.treasury_usdc.amount,
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    // The one canonical state, the only `State` accepted, see `migrate_state` for those
+    // created with a keypair before it became a PDA
+    #[account(init, payer = user, space = 8 + State::LEN, seeds = [b"state"], bump)]
+    pub state: Account<'info, State>,
+    // The program's upgrade authority
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::Dexaggregator>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(user.key())
+            @ ErrorCode::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct State {
+    pub owner: Pubkey,
+    // Protocol fee in basis points for users below every volume tier.
+    pub fee_bps: u16,
+    // Discounted fees by trailing volume, ordered by `min_volume` ascending.
+    pub fee_tiers: Vec<FeeTier>,
+    // Whether swaps are restricted to markets with a `MarketConfig`.
+    pub market_allowlist_enabled: bool,
+    // Layout the account was written with, `STATE_VERSION` once migrated.
+    pub version: u8,
+    // Space for future fields, zero until a version defines them.
+    pub reserved: [u8; STATE_RESERVED_BYTES],
+}
+
+impl State {
+    pub const LEN: usize = 32 + 2 + 4 + MAX_FEE_TIERS * FeeTier::LEN + 1 + 1 + STATE_RESERVED_BYTES;
+
+    // Returns the lowest fee the user's trailing quote volume qualifies for.
+    pub fn fee_bps_for_volume(&self, volume: u64) -> u16 {
+        self.fee_tiers
+            .iter()
+            .filter(|tier| volume >= tier.min_volume)
+            .map(|tier| tier.fee_bps)
+            .fold(self.fee_bps, u16::min)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FeeTier {
+    // Trailing quote volume, in native units, needed to qualify.
+    pub min_volume: u64,
+    pub fee_bps: u16,
+}
+
+impl FeeTier {
+    pub const LEN: usize = 8 + 2;
+}
+
+#[derive(Accounts)]
+pub struct MigrateState<'info> {
+    // The canonical state, of any earlier layout, which may not deserialize before the
+    // realloc, or not created yet if `legacy_state` moves into it
+    /// CHECK: Safe, the discriminator and owner are checked in the instruction
+    #[account(
+        mut,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: AccountInfo<'info>,
+    // A state created with a keypair before the state became a PDA, closed once moved
+    /// CHECK: Safe, the discriminator is checked in the instruction
+    #[account(mut, owner = crate::ID)]
+    pub legacy_state: Option<AccountInfo<'info>>,
+    // The program's upgrade authority, paying the rent and refunded the legacy state's
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::Dexaggregator>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(authority.key())
+            @ ErrorCode::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateState<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Account<'info, State>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterMarket<'info> {
+    #[account(
+        has_one = owner,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Account<'info, State>,
+    // The Serum market to approve
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    // The Serum DEX program owning the market
+    /// CHECK: Safe
+    pub dex_program: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + MarketConfig::LEN,
+        seeds = [
+            b"market",
+            market.key().as_ref()
+        ],
+        bump,
+    )]
+    pub market_config: Account<'info, MarketConfig>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeregisterMarket<'info> {
+    #[account(
+        has_one = owner,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Account<'info, State>,
+    #[account(
+        mut,
+        close = owner,
+        seeds = [
+            b"market",
+            market_config.market.as_ref()
+        ],
+        bump,
+    )]
+    pub market_config: Account<'info, MarketConfig>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+// A market approved for swaps, with the metadata read at registration.
+#[account]
+pub struct MarketConfig {
+    pub market: Pubkey,
+    pub dex_program: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub vault_signer: Pubkey,
+    // How swaps on the market execute, unless a swap selects the mode itself.
+    pub execution_mode: ExecutionMode,
+}
+
+impl MarketConfig {
+    pub const LEN: usize = 32 * 4 + 8 + 8 + 32 + 1;
+}
+
+#[derive(Accounts)]
+pub struct CacheMarket<'info> {
+    // The Serum market to cache
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    // The Serum DEX program owning the market
+    /// CHECK: Safe
+    pub dex_program: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + MarketCache::LEN,
+        seeds = [
+            b"market_cache",
+            market.key().as_ref()
+        ],
+        bump,
+    )]
+    pub market_cache: Account<'info, MarketCache>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+// Market metadata needed by swaps, so they don't have to load the market account.
+#[account]
+pub struct MarketCache {
+    pub market: Pubkey,
+    pub dex_program: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub coin_vault: Pubkey,
+    pub pc_vault: Pubkey,
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub vault_signer_nonce: u64,
+    pub vault_signer: Pubkey,
+}
+
+impl MarketCache {
+    pub const LEN: usize = 32 * 6 + 8 * 3 + 32;
+}
+
+#[derive(Accounts)]
+#[instruction(exempt: Pubkey)]
+pub struct AddFeeExemption<'info> {
+    #[account(
+        has_one = owner,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Account<'info, State>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + FeeExemption::LEN,
+        seeds = [
+            b"fee_exemption",
+            exempt.as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: Account<'info, FeeExemption>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveFeeExemption<'info> {
+    #[account(
+        has_one = owner,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Account<'info, State>,
+    #[account(
+        mut,
+        close = owner,
+        seeds = [
+            b"fee_exemption",
+            fee_exemption.exempt.as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: Account<'info, FeeExemption>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+// A wallet, or a program calling this one, that pays no protocol fee.
+#[account]
+pub struct FeeExemption {
+    pub exempt: Pubkey,
+}
+
+impl FeeExemption {
+    pub const LEN: usize = 32;
+}
+
+#[derive(Accounts)]
+#[instruction(executor: Pubkey)]
+pub struct AddExecutor<'info> {
+    #[account(
+        has_one = owner,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Account<'info, State>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Executor::LEN,
+        seeds = [
+            b"executor",
+            executor.as_ref()
+        ],
+        bump,
+    )]
+    pub executor_entry: Account<'info, Executor>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveExecutor<'info> {
+    #[account(
+        has_one = owner,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Account<'info, State>,
+    #[account(
+        mut,
+        close = owner,
+        seeds = [
+            b"executor",
+            executor_entry.executor.as_ref()
+        ],
+        bump,
+    )]
+    pub executor_entry: Account<'info, Executor>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+// A wallet allowed to execute users' intents with `swap_delegated`.
+#[account]
+pub struct Executor {
+    pub executor: Pubkey,
+}
+
+impl Executor {
+    pub const LEN: usize = 32;
+}
+
+// Per user trading volume.
+#[account]
+pub struct UserStats {
+    pub wallet_owner: Pubkey,
+    // Quote paid in on bids, fees included, and received on asks, in native USDC units.
+    pub volume_in: u64,
+    // Quote traded on the market, spent on bids and received on asks, in native USDC units.
+    pub volume_out: u64,
+    pub trade_count: u64,
+    // Day (unix timestamp / 86400) of the most recent trade.
+    pub last_day: i64,
+    // Quote volume per day, indexed by day modulo the window length.
+    pub daily_volume: [u64; VOLUME_WINDOW_DAYS],
+}
+
+impl UserStats {
+    pub const LEN: usize = 32 + 8 + 8 + 8 + 8 + 8 * VOLUME_WINDOW_DAYS;
+
+    // Quote volume over the window of days ending on `day`.
+    pub fn window_volume(&self, day: i64) -> u64 {
+        let window = VOLUME_WINDOW_DAYS as i64;
+        let first_day = (day - window + 1).max(self.last_day - window + 1);
+        (first_day..=self.last_day)
+            .map(|d| self.daily_volume[d.rem_euclid(window) as usize])
+            .fold(0, u64::saturating_add)
+    }
+
+    // Counts a trade, and its quote volume if quoted in USDC, the currency the fee tiers
+    // are set in.
+    pub fn record_trade(
+        &mut self,
+        wallet_owner: Pubkey,
+        day: i64,
+        quote_mint: &Pubkey,
+        volume_in: u64,
+        volume_out: u64,
+    ) {
+        let (volume_in, volume_out) = if *quote_mint == USDC_MINT {
+            (volume_in, volume_out)
+        } else {
+            (0, 0)
+        };
+        let window = VOLUME_WINDOW_DAYS as i64;
+        self.wallet_owner = wallet_owner;
+        let day = day.max(self.last_day);
+        // Clear the buckets of the days without trades since the last one.
+        let stale_days = (day - self.last_day).min(window);
+        for d in (day - stale_days + 1)..=day {
+            self.daily_volume[d.rem_euclid(window) as usize] = 0;
+        }
+        self.last_day = day;
+
+        let bucket = &mut self.daily_volume[day.rem_euclid(window) as usize];
+        *bucket = bucket.saturating_add(volume_in);
+        self.volume_in = self.volume_in.saturating_add(volume_in);
+        self.volume_out = self.volume_out.saturating_add(volume_out);
+        self.trade_count = self.trade_count.saturating_add(1);
+    }
+}
+
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    // The single A/B market to swap on
+    /// CHECK: Safe
+    pub market: MarketAccounts<'info>,
+    // The registry entry of the market, required if the market allowlist is enabled
+    pub market_config: Option<Box<Account<'info, MarketConfig>>>,
+    // The cached market metadata, saving a load of the market account
+    pub market_cache: Option<Box<Account<'info, MarketCache>>>,
+    // The swap user
+    /// CHECK: Safe
+    #[account(signer, mut)]
+    pub wallet_owner: AccountInfo<'info>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The swap user's trading volume
+    #[account(
+        init_if_needed,
+        payer = wallet_owner,
+        space = 8 + UserStats::LEN,
+        seeds = [
+            b"user_stats",
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_stats: Box<Account<'info, UserStats>>,
+    // Fee exemption of the swap user
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: Option<Box<Account<'info, FeeExemption>>>,
+    // Fee exemption of the program calling this one
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            program_fee_exemption.exempt.as_ref()
+        ],
+        bump,
+    )]
+    pub program_fee_exemption: Option<Box<Account<'info, FeeExemption>>>,
+    // The instructions sysvar, to find the calling program
+    /// CHECK: Safe
+    #[account(address = solana_program::sysvar::instructions::ID)]
+    pub instructions: Option<AccountInfo<'info>>,
+
+    pub mint_a: Box<Account<'info, Mint>>,
+    // The treasury wallet account
+    /// CHECK: safe
+    #[account(
+        mut,
+        seeds = [
+            b"treasury"
+        ],
+        bump,
+    )]
+    pub treasury: AccountInfo<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = wallet_owner,
+        associated_token::mint = mint_a,
+        associated_token::authority = treasury,
+    )]
+    pub treasury_usdc: Box<Account<'info, TokenAccount>>,
+    // The treasury's quote mint token account, passed to Serum as the referrer
+    #[account(mut, token::authority = treasury)]
+    pub referral: Option<Box<Account<'info, TokenAccount>>>,
+    // The user's token account for the 'price' currency
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pc_wallet: AccountInfo<'info>,
+    // Token account the output is forwarded to, e.g. a merchant's
+    #[account(mut)]
+    pub recipient: Option<Box<Account<'info, TokenAccount>>>,
+    // The SPL memo program, to log a memo with the swap
+    pub memo_program: Option<Program<'info, Memo>>,
+    // The user's open orders account for the market, a PDA, for NewOrderV3 execution
+    /// CHECK: Safe
+    #[account(mut)]
+    pub open_orders: Option<AccountInfo<'info>>,
+    pub rent: Option<Sysvar<'info, Rent>>,
+    // The delegate of the user's paying wallet, if it has one, for NewOrderV3 execution
+    /// CHECK: Safe
+    pub wallet_delegate: Option<AccountInfo<'info>>,
+
+    /// Solana ecosystem accounts
+    pub system_program: Program<'info, System>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+
+    // The Serum DEX program
+    /// CHECK: Safe
+    pub dex_program: AccountInfo<'info>,
+    // The token program
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> Swap<'info> {
+    // Protocol fee for the swap user on `day`, zero if exempt.
+    fn fee_bps(&self, day: i64) -> Result<u16> {
+        if is_fee_exempt(
+            &self.fee_exemption,
+            &self.program_fee_exemption,
+            &self.instructions,
+        )? {
+            return Ok(0);
+        }
+        Ok(self
+            .state
+            .fee_bps_for_volume(self.user_stats.window_volume(day)))
+    }
+
+    // Sends the protocol fee from the user's 'price' currency wallet to the treasury.
+    fn transfer_fee(&self, amount: u64) -> Result<()> {
+        transfer_fee(
+            &self.token_program,
+            &self.pc_wallet,
+            &self.treasury_usdc.to_account_info(),
+            &self.wallet_owner,
+            amount,
+        )
+    }
+
+    // The user's open orders PDA for the market, for NewOrderV3 execution.
+    fn open_orders_accounts(&self) -> Result<OpenOrdersAccounts<'info>> {
+        let (Some(open_orders), Some(rent)) = (&self.open_orders, &self.rent) else {
+            return Err(ErrorCode::OpenOrdersRequired.into());
+        };
+        let (address, bump) = Pubkey::find_program_address(
+            &[
+                b"open_orders",
+                self.market.market.key.as_ref(),
+                self.wallet_owner.key.as_ref(),
+            ],
+            &crate::ID,
+        );
+        require_keys_eq!(open_orders.key(), address, ErrorCode::InvalidOpenOrders);
+        Ok(OpenOrdersAccounts {
+            open_orders: open_orders.clone(),
+            bump,
+            rent: rent.to_account_info(),
+            system_program: self.system_program.to_account_info(),
+            wallet_delegate: self.wallet_delegate.clone(),
+        })
+    }
+}
+
+impl<'info> From<&Swap<'info>> for OrderbookClient<'info> {
+    fn from(accounts: &Swap<'info>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: accounts.market.clone(),
+            wallet_owner: accounts.wallet_owner.clone(),
+            pc_wallet: accounts.pc_wallet.clone(),
+            dex_program: accounts.dex_program.clone(),
+            token_program: accounts.token_program.clone(),
+            referral: accounts
+                .referral
+                .as_ref()
+                .map(|referral| referral.to_account_info()),
+            coin_lot_size: accounts
+                .market_cache
+                .as_ref()
+                .map(|market_cache| market_cache.coin_lot_size),
+            open_orders: None,
+            wallet_owner_seeds: None,
+            match_limit: None,
+            takes_left: 1,
+            fill_policy: None,
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct SwapTransitive<'info> {
+    // The first A/B market to swap on, A -> B, ask
+    pub from: MarketAccounts<'info>,
+    // The second C/B market to swap on, B -> C, bid
+    pub to: MarketAccounts<'info>,
+    // The registry entries of the markets, required if the market allowlist is enabled
+    pub from_market_config: Option<Box<Account<'info, MarketConfig>>>,
+    pub to_market_config: Option<Box<Account<'info, MarketConfig>>>,
+    // The cached metadata of the markets, saving a load of the market accounts
+    pub from_market_cache: Option<Box<Account<'info, MarketCache>>>,
+    pub to_market_cache: Option<Box<Account<'info, MarketCache>>>,
+    // The swap user
+    /// CHECK: Safe
+    #[account(signer, mut)]
+    pub wallet_owner: AccountInfo<'info>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The swap user's trading volume
+    #[account(
+        init_if_needed,
+        payer = wallet_owner,
+        space = 8 + UserStats::LEN,
+        seeds = [
+            b"user_stats",
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_stats: Box<Account<'info, UserStats>>,
+    // Fee exemption of the swap user
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: Option<Box<Account<'info, FeeExemption>>>,
+    // Fee exemption of the program calling this one
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            program_fee_exemption.exempt.as_ref()
+        ],
+        bump,
+    )]
+    pub program_fee_exemption: Option<Box<Account<'info, FeeExemption>>>,
+    // The instructions sysvar, to find the calling program
+    /// CHECK: Safe
+    #[account(address = solana_program::sysvar::instructions::ID)]
+    pub instructions: Option<AccountInfo<'info>>,
+    // The user's token account for the 'price' currency
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pc_wallet: AccountInfo<'info>,
+    // The treasury wallet account
+    /// CHECK: safe
+    #[account(
+        mut,
+        seeds = [
+            b"treasury"
+        ],
+        bump,
+    )]
+    pub treasury: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        associated_token::mint = USDC_MINT,
+        associated_token::authority = treasury,
+    )]
+    pub treasury_usdc: Box<Account<'info, TokenAccount>>,
+    // The treasury's quote mint token account, passed to Serum as the referrer
+    #[account(mut, token::authority = treasury)]
+    pub referral: Option<Box<Account<'info, TokenAccount>>>,
+    // Token account the output is forwarded to, e.g. a merchant's
+    #[account(mut)]
+    pub recipient: Option<Box<Account<'info, TokenAccount>>>,
+    // The SPL memo program, to log a memo with the swap
+    pub memo_program: Option<Program<'info, Memo>>,
+    // The Serum DEX program
+    /// CHECK: Safe
+    pub dex_program: AccountInfo<'info>,
+    // The token program
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> SwapTransitive<'info> {
+    // Protocol fee for the swap user on `day`, zero if exempt.
+    fn fee_bps(&self, day: i64) -> Result<u16> {
+        if is_fee_exempt(
+            &self.fee_exemption,
+            &self.program_fee_exemption,
+            &self.instructions,
+        )? {
+            return Ok(0);
+        }
+        Ok(self
+            .state
+            .fee_bps_for_volume(self.user_stats.window_volume(day)))
+    }
+
+    // Sends the protocol fee from the user's 'price' currency wallet to the treasury.
+    fn transfer_fee(&self, amount: u64) -> Result<()> {
+        transfer_fee(
+            &self.token_program,
+            &self.pc_wallet,
+            &self.treasury_usdc.to_account_info(),
+            &self.wallet_owner,
+            amount,
+        )
+    }
+
+    // Buys on the `to` market with up to `amount` of USD(x), returning the B received and
+    // the USD(x) spent.
+    fn buy(
+        &self,
+        match_limit: Option<u16>,
+        amount: u64,
+        amount_out_min: u64,
+    ) -> Result<(u64, u64)> {
+        let coin_before = token::accessor::amount(&self.to.coin_wallet)?;
+        let pc_before = token::accessor::amount(&self.pc_wallet)?;
+
+        self.orderbook_to(match_limit).bid(amount, amount_out_min)?;
+
+        let coin_after = token::accessor::amount(&self.to.coin_wallet)?;
+        let pc_after = token::accessor::amount(&self.pc_wallet)?;
+        Ok((
+            coin_after.checked_sub(coin_before).unwrap(),
+            pc_before.checked_sub(pc_after).unwrap(),
+        ))
+    }
+
+    fn orderbook_from(&self, match_limit: Option<u16>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: self.from.clone(),
+            coin_lot_size: self
+                .from_market_cache
+                .as_ref()
+                .map(|market_cache| market_cache.coin_lot_size),
+            wallet_owner: self.wallet_owner.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            referral: self
+                .referral
+                .as_ref()
+                .map(|referral| referral.to_account_info()),
+            open_orders: None,
+            wallet_owner_seeds: None,
+            match_limit,
+            // Leaves the second leg its share of the compute budget.
+            takes_left: 2,
+            fill_policy: None,
+        }
+    }
+    fn orderbook_to(&self, match_limit: Option<u16>) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: self.to.clone(),
+            coin_lot_size: self
+                .to_market_cache
+                .as_ref()
+                .map(|market_cache| market_cache.coin_lot_size),
+            wallet_owner: self.wallet_owner.clone(),
+            pc_wallet: self.pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            referral: self
+                .referral
+                .as_ref()
+                .map(|referral| referral.to_account_info()),
+            open_orders: None,
+            wallet_owner_seeds: None,
+            match_limit,
+            takes_left: 1,
+            fill_policy: None,
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct SwapRoute<'info> {
+    // The swap user
+    /// CHECK: Safe
+    #[account(signer, mut)]
+    pub wallet_owner: AccountInfo<'info>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The swap user's trading volume, setting the fee tier
+    #[account(
+        init_if_needed,
+        payer = wallet_owner,
+        space = 8 + UserStats::LEN,
+        seeds = [
+            b"user_stats",
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_stats: Box<Account<'info, UserStats>>,
+    // Fee exemption of the swap user
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: Option<Box<Account<'info, FeeExemption>>>,
+    // Fee exemption of the program calling this one
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            program_fee_exemption.exempt.as_ref()
+        ],
+        bump,
+    )]
+    pub program_fee_exemption: Option<Box<Account<'info, FeeExemption>>>,
+    // The instructions sysvar, to find the calling program
+    /// CHECK: Safe
+    #[account(address = solana_program::sysvar::instructions::ID)]
+    pub instructions: Option<AccountInfo<'info>>,
+    // The mint of the route's last leg output
+    pub output_mint: Box<Account<'info, Mint>>,
+    // The treasury wallet account
+    /// CHECK: safe
+    #[account(
+        seeds = [
+            b"treasury"
+        ],
+        bump,
+    )]
+    pub treasury: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = wallet_owner,
+        associated_token::mint = output_mint,
+        associated_token::authority = treasury,
+    )]
+    pub treasury_output: Box<Account<'info, TokenAccount>>,
+    /// Solana ecosystem accounts
+    pub system_program: Program<'info, System>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    // The token program
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> SwapRoute<'info> {
+    // Protocol fee for the swap user on `day`, zero if exempt.
+    fn fee_bps(&self, day: i64) -> Result<u16> {
+        if is_fee_exempt(
+            &self.fee_exemption,
+            &self.program_fee_exemption,
+            &self.instructions,
+        )? {
+            return Ok(0);
+        }
+        Ok(self
+            .state
+            .fee_bps_for_volume(self.user_stats.window_volume(day)))
+    }
+}
+
+// Accounts of a `batch_swap` spec: the user, the user's `UserStats` and `Intent` PDAs and a
+// Serum leg.
+const BATCH_SWAP_ACCOUNTS: usize = 3 + dex::serum::LEG_ACCOUNTS;
+
+#[derive(Accounts)]
+pub struct BatchSwap<'info> {
+    // The relayer sending the batch
+    pub relayer: Signer<'info>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The delegate users approve to swap their tokens without signing
+    /// CHECK: Safe
+    #[account(
+        mut,
+        seeds = [
+            b"batch_delegate"
+        ],
+        bump,
+    )]
+    pub delegate: AccountInfo<'info>,
+    // The treasury wallet account
+    /// CHECK: safe
+    #[account(
+        seeds = [
+            b"treasury"
+        ],
+        bump,
+    )]
+    pub treasury: AccountInfo<'info>,
+    // The treasury's token account for the markets' quote mint
+    #[account(mut, token::authority = treasury)]
+    pub treasury_usdc: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Swaps for a user through `orderbook`, which trades as the user or as the delegate they
+// approved, charging the fee from their quote wallet to `treasury_pc` like `swap`. Returns
+// the input spent, the output received before an ask's fee, and the fee.
+fn swap_for_user<'info>(
+    orderbook: &OrderbookClient<'info>,
+    treasury_pc: &AccountInfo<'info>,
+    side: Side,
+    amount_in_max: u64,
+    amount_out_min: u64,
+    fee_bps: u16,
+) -> Result<(u64, u64, u64)> {
+    // Sent some percent token to treasury
+    let (bid_fee, amount_in_routed) = match side {
+        Side::Bid => split_fee(amount_in_max, fee_bps),
+        Side::Ask => (0, amount_in_max),
+    };
+    transfer_fee_for(orderbook, treasury_pc, bid_fee)?;
+
+    let (from_token, to_token) = orderbook.token_accounts(side);
+    let from_amount_before = token::accessor::amount(&from_token)?;
+    let to_amount_before = token::accessor::amount(&to_token)?;
+    orderbook.swap_exact_in(side, amount_in_routed, amount_out_min)?;
+    let from_amount_after = token::accessor::amount(&from_token)?;
+    let to_amount_after = token::accessor::amount(&to_token)?;
+
+    let from_amount = from_amount_before.checked_sub(from_amount_after).unwrap();
+    let to_amount = to_amount_after.checked_sub(to_amount_before).unwrap();
+
+    // Sent some percent token to treasury
+    let ask_fee = match side {
+        Side::Bid => 0,
+        Side::Ask => fee_amount(to_amount, fee_bps),
+    };
+    transfer_fee_for(orderbook, treasury_pc, ask_fee)?;
+
+    // Safety checks.
+    apply_safety_checks(amount_in_routed, amount_out_min, from_amount, to_amount)?;
+
+    Ok((from_amount, to_amount, bid_fee + ask_fee))
+}
+
+// Sends a user's protocol fee from their quote wallet to the treasury, signed like the
+// orderbook's takes.
+fn transfer_fee_for<'info>(
+    orderbook: &OrderbookClient<'info>,
+    treasury_pc: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds: Vec<&[u8]> = orderbook
+        .wallet_owner_seeds
+        .iter()
+        .flatten()
+        .map(Vec::as_slice)
+        .collect();
+    let signer_seeds: &[&[&[u8]]] = if seeds.is_empty() { &[] } else { &[&seeds[..]] };
+    token::transfer(
+        CpiContext::new_with_signer(
+            orderbook.token_program.clone(),
+            token::Transfer {
+                from: orderbook.pc_wallet.clone(),
+                to: treasury_pc.clone(),
+                authority: orderbook.wallet_owner.clone(),
+            },
+            signer_seeds,
+        ),
+        amount,
+    )
+}
+
+// Reads a token account of a `batch_swap` user.
+fn load_token_account(account: &AccountInfo) -> Result<TokenAccount> {
+    require_keys_eq!(*account.owner, token::ID, ErrorCode::BatchAccountsMismatch);
+    TokenAccount::try_deserialize(&mut &account.try_borrow_data()?[..])
+}
+
+// The intent of a `batch_swap` user who authorized `spec` through the delegate rather than
+// signing, failing if they did neither, the intent doesn't allow `spec` at `now`, or they
+// can't fund it.
+fn batch_swap_authority<'info>(
+    wallet_owner: &AccountInfo<'info>,
+    intent: &AccountInfo<'info>,
+    delegate: &Pubkey,
+    (from_wallet, to_wallet): (&TokenAccount, &TokenAccount),
+    pc_wallet: &TokenAccount,
+    spec: &BatchSwapSpec,
+    now: i64,
+) -> std::result::Result<Option<Account<'info, Intent>>, ErrorCode> {
+    if from_wallet.amount < spec.amount_in_max {
+        return Err(ErrorCode::InsufficientBalance);
+    }
+    if wallet_owner.is_signer {
+        return Ok(None);
+    }
+    let delegated = |wallet: &TokenAccount, amount: u64| {
+        wallet.delegate == COption::Some(*delegate) && wallet.delegated_amount >= amount
+    };
+    // An ask's fee is taken from the proceeds in the quote wallet.
+    let authorized = delegated(from_wallet, spec.amount_in_max)
+        && (spec.side == Side::Bid || delegated(pc_wallet, 0));
+    if !authorized {
+        return Err(ErrorCode::BatchSwapUnauthorized);
+    }
+
+    let intent =
+        Account::<Intent>::try_from(intent).map_err(|_| ErrorCode::IntentAccountsMismatch)?;
+    let intent_address = Pubkey::create_program_address(
+        &[
+            b"intent",
+            wallet_owner.key.as_ref(),
+            &intent.seed.to_le_bytes(),
+            &[intent.bump],
+        ],
+        &crate::ID,
+    );
+    if intent_address != Ok(intent.key())
+        || intent.wallet_owner != wallet_owner.key()
+        || intent.input_mint != from_wallet.mint
+        || intent.output_mint != to_wallet.mint
+        || intent.amount_in != spec.amount_in_max
+    {
+        return Err(ErrorCode::IntentAccountsMismatch);
+    }
+    if now >= intent.expiry {
+        return Err(ErrorCode::IntentExpired);
+    }
+    Ok(Some(intent))
+}
+
+#[derive(Accounts)]
+pub struct LimitOrder<'info> {
+    // The market the order rests on
+    pub market: MarketAccounts<'info>,
+    // The registry entry of the market, required if the market allowlist is enabled
+    pub market_config: Option<Box<Account<'info, MarketConfig>>>,
+    // The order's user
+    /// CHECK: Safe
+    #[account(signer, mut)]
+    pub wallet_owner: AccountInfo<'info>,
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The user's token account for the 'price' currency
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pc_wallet: AccountInfo<'info>,
+    // The user's open orders account for the market, owned by the DEX
+    /// CHECK: Safe
+    #[account(
+        mut,
+        seeds = [
+            b"open_orders",
+            market.market.key().as_ref(),
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub open_orders: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = wallet_owner,
+        space = 8 + OrderLedger::LEN,
+        seeds = [
+            b"order_ledger",
+            market.market.key().as_ref(),
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub order_ledger: Box<Account<'info, OrderLedger>>,
+    // The delegate of the wallet paying for the order, if it has one
+    /// CHECK: Safe
+    pub wallet_delegate: Option<AccountInfo<'info>>,
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+    // The Serum DEX program
+    /// CHECK: Safe
+    pub dex_program: AccountInfo<'info>,
+    // The token program
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> LimitOrder<'info> {
+    fn orderbook(&self, open_orders_bump: u8) -> OrderbookClient<'info> {
+        orderbook_with_open_orders(
+            &self.market,
+            &self.wallet_owner,
+            &self.pc_wallet,
+            &self.open_orders,
+            open_orders_bump,
+            self.wallet_delegate.as_ref(),
+            &self.rent.to_account_info(),
+            &self.system_program.to_account_info(),
+            &self.dex_program,
+            &self.token_program,
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct SettleOrders<'info> {
+    // The market to settle
+    pub market: MarketAccounts<'info>,
+    // The orders' user
+    /// CHECK: Safe
+    #[account(signer, mut)]
+    pub wallet_owner: AccountInfo<'info>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The user's trading volume, setting the fee tier
+    #[account(
+        seeds = [
+            b"user_stats",
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_stats: Option<Box<Account<'info, UserStats>>>,
+    // Fee exemption of the user
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: Option<Box<Account<'info, FeeExemption>>>,
+    // Fee exemption of the program calling this one
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            program_fee_exemption.exempt.as_ref()
+        ],
+        bump,
+    )]
+    pub program_fee_exemption: Option<Box<Account<'info, FeeExemption>>>,
+    // The instructions sysvar, to find the calling program
+    /// CHECK: Safe
+    #[account(address = solana_program::sysvar::instructions::ID)]
+    pub instructions: Option<AccountInfo<'info>>,
+    // The user's token account for the 'price' currency
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pc_wallet: AccountInfo<'info>,
+    // The user's open orders account for the market, owned by the DEX
+    /// CHECK: Safe
+    #[account(
+        mut,
+        seeds = [
+            b"open_orders",
+            market.market.key().as_ref(),
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub open_orders: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"order_ledger",
+            market.market.key().as_ref(),
+            wallet_owner.key().as_ref()
+        ],
+        bump,
+    )]
+    pub order_ledger: Box<Account<'info, OrderLedger>>,
+    pub coin_mint: Box<Account<'info, Mint>>,
+    pub pc_mint: Box<Account<'info, Mint>>,
+    // The treasury wallet account
+    /// CHECK: safe
+    #[account(
+        seeds = [
+            b"treasury"
+        ],
+        bump,
+    )]
+    pub treasury: AccountInfo<'info>,
+    #[account(
+        init_if_needed,
+        payer = wallet_owner,
+        associated_token::mint = coin_mint,
+        associated_token::authority = treasury,
+    )]
+    pub treasury_coin: Box<Account<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = wallet_owner,
+        associated_token::mint = pc_mint,
+        associated_token::authority = treasury,
+    )]
+    pub treasury_pc: Box<Account<'info, TokenAccount>>,
+    pub rent: Sysvar<'info, Rent>,
+    /// Solana ecosystem accounts
+    pub system_program: Program<'info, System>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    // The Serum DEX program
+    /// CHECK: Safe
+    pub dex_program: AccountInfo<'info>,
+    // The token program
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> SettleOrders<'info> {
+    // Protocol fee for the user on `day`, zero if exempt.
+    fn fee_bps(&self, day: i64) -> Result<u16> {
+        if is_fee_exempt(
+            &self.fee_exemption,
+            &self.program_fee_exemption,
+            &self.instructions,
+        )? {
+            return Ok(0);
+        }
+        let volume = self
+            .user_stats
+            .as_ref()
+            .map_or(0, |user_stats| user_stats.window_volume(day));
+        Ok(self.state.fee_bps_for_volume(volume))
+    }
+
+    fn orderbook(&self, open_orders_bump: u8) -> OrderbookClient<'info> {
+        orderbook_with_open_orders(
+            &self.market,
+            &self.wallet_owner,
+            &self.pc_wallet,
+            &self.open_orders,
+            open_orders_bump,
+            None,
+            &self.rent.to_account_info(),
+            &self.system_program.to_account_info(),
+            &self.dex_program,
+            &self.token_program,
+        )
+    }
+}
+
+// Fee free balances of a user's PDA open orders account, released by cancelled orders and
+// not yet settled or spent on new orders.
+#[account]
+pub struct OrderLedger {
+    pub wallet_owner: Pubkey,
+    pub market: Pubkey,
+    pub refunded_coin: u64,
+    pub refunded_pc: u64,
+}
+
+impl OrderLedger {
+    pub const LEN: usize = 32 + 32 + 8 + 8;
+
+    // Free funds spent on an order are refunds first.
+    fn spend_coin(&mut self, amount: u64) {
+        self.refunded_coin = self.refunded_coin.saturating_sub(amount);
+    }
+
+    fn spend_pc(&mut self, amount: u64) {
+        self.refunded_pc = self.refunded_pc.saturating_sub(amount);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn orderbook_with_open_orders<'info>(
+    market: &MarketAccounts<'info>,
+    wallet_owner: &AccountInfo<'info>,
+    pc_wallet: &AccountInfo<'info>,
+    open_orders: &AccountInfo<'info>,
+    open_orders_bump: u8,
+    wallet_delegate: Option<&AccountInfo<'info>>,
+    rent: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    dex_program: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+) -> OrderbookClient<'info> {
+    OrderbookClient {
+        market: market.clone(),
+        wallet_owner: wallet_owner.clone(),
+        pc_wallet: pc_wallet.clone(),
+        dex_program: dex_program.clone(),
+        token_program: token_program.clone(),
+        referral: None,
+        coin_lot_size: None,
+        open_orders: Some(OpenOrdersAccounts {
+            open_orders: open_orders.clone(),
+            bump: open_orders_bump,
+            rent: rent.clone(),
+            system_program: system_program.clone(),
+            wallet_delegate: wallet_delegate.cloned(),
+        }),
+        wallet_owner_seeds: None,
+        match_limit: None,
+        takes_left: 1,
+        fill_policy: None,
+    }
+}
+
+// An order escrowing its input in token accounts it owns, a PDA of the program seeded by its
+// kind, user and seed, filled out of its escrow into its output vault by keepers: DCA, TWAP
+// and trigger orders.
+trait EscrowedOrder: AccountSerialize + AccountDeserialize + Clone {
+    // Seed of the order's kind.
+    const SEED: &'static [u8];
+    // Error of a fill's accounts not being the order's.
+    const ACCOUNTS_MISMATCH: ErrorCode;
+
+    fn keys(&self) -> EscrowedOrderKeys;
+
+    fn signer_seeds(&self) -> [Vec<u8>; 4] {
+        let keys = self.keys();
+        [
+            Self::SEED.to_vec(),
+            keys.wallet_owner.to_bytes().to_vec(),
+            keys.seed.to_le_bytes().to_vec(),
+            vec![keys.bump],
+        ]
+    }
+}
+
+// The fields every escrowed order starts with.
+struct EscrowedOrderKeys {
+    wallet_owner: Pubkey,
+    market: Pubkey,
+    escrow: Pubkey,
+    output_vault: Pubkey,
+    seed: u64,
+    side: Side,
+    bump: u8,
+}
+
+// The accounts of a keeper's fill of an escrowed order, borrowed from its instruction's.
+struct EscrowedFill<'a, 'info, T: EscrowedOrder> {
+    order: &'a Account<'info, T>,
+    market: &'a MarketAccounts<'info>,
+    market_config: &'a Option<Box<Account<'info, MarketConfig>>>,
+    market_cache: &'a Option<Box<Account<'info, MarketCache>>>,
+    pc_wallet: &'a AccountInfo<'info>,
+    output_vault: &'a Account<'info, TokenAccount>,
+    destination: &'a Account<'info, TokenAccount>,
+    state: &'a State,
+    user_stats: &'a AccountInfo<'info>,
+    fee_exemption: &'a AccountInfo<'info>,
+    treasury_pc: &'a Account<'info, TokenAccount>,
+    dex_program: &'a AccountInfo<'info>,
+    token_program: &'a AccountInfo<'info>,
+}
+
+impl<'a, 'info, T: EscrowedOrder> EscrowedFill<'a, 'info, T> {
+    // Protocol fee for the order's user on `day`, zero if exempt.
+    fn fee_bps(&self, day: i64) -> Result<u16> {
+        if has_fee_exemption(self.fee_exemption) {
+            return Ok(0);
+        }
+        let volume =
+            load_user_stats(self.user_stats)?.map_or(0, |user_stats| user_stats.window_volume(day));
+        Ok(self.state.fee_bps_for_volume(volume))
+    }
+
+    // Sends the protocol fee from one of the order's token accounts to the treasury.
+    fn transfer_signed(&self, from: &AccountInfo<'info>, amount: u64) -> Result<()> {
+        transfer_from_escrowed_order(
+            self.order,
+            self.token_program,
+            from,
+            &self.treasury_pc.to_account_info(),
+            amount,
+        )
+    }
+
+    // Forwards the output of a fill to the user.
+    fn forward_output(&self, amount: u64) -> Result<()> {
+        transfer_from_escrowed_order(
+            self.order,
+            self.token_program,
+            &self.output_vault.to_account_info(),
+            &self.destination.to_account_info(),
+            amount,
+        )
+    }
+
+    // SendTake client trading from the escrow, signed by the order.
+    fn orderbook(&self) -> OrderbookClient<'info> {
+        OrderbookClient {
+            market: self.market.clone(),
+            wallet_owner: self.order.to_account_info(),
+            pc_wallet: self.pc_wallet.clone(),
+            dex_program: self.dex_program.clone(),
+            token_program: self.token_program.clone(),
+            referral: None,
+            coin_lot_size: self
+                .market_cache
+                .as_ref()
+                .map(|market_cache| market_cache.coin_lot_size),
+            open_orders: None,
+            wallet_owner_seeds: Some(self.order.signer_seeds().to_vec()),
+            match_limit: None,
+            takes_left: 1,
+            fill_policy: None,
+        }
+    }
+}
+
+// Transfers `amount` out of one of an escrowed order's token accounts, signed by the order.
+fn transfer_from_escrowed_order<'info, T: EscrowedOrder>(
+    order: &Account<'info, T>,
+    token_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let seeds = order.signer_seeds();
+    let seeds: Vec<&[u8]> = seeds.iter().map(Vec::as_slice).collect();
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.clone(),
+            token::Transfer {
+                from: from.clone(),
+                to: to.clone(),
+                authority: order.to_account_info(),
+            },
+            &[&seeds[..]],
+        ),
+        amount,
+    )
+}
+
+// Refunds the escrow of a cancelled order and forwards what its output vault holds, closing
+// both and returning their rent to the user.
+fn close_escrowed_order<'info, T: EscrowedOrder>(
+    order: &Account<'info, T>,
+    wallet_owner: &AccountInfo<'info>,
+    escrow: &Account<'info, TokenAccount>,
+    output_vault: &Account<'info, TokenAccount>,
+    refund_account: &Account<'info, TokenAccount>,
+    destination: &Account<'info, TokenAccount>,
+    token_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let seeds = order.signer_seeds();
+    let seeds: Vec<&[u8]> = seeds.iter().map(Vec::as_slice).collect();
+    for (account, to) in [(escrow, refund_account), (output_vault, destination)] {
+        transfer_from_escrowed_order(
+            order,
+            token_program,
+            &account.to_account_info(),
+            &to.to_account_info(),
+            account.amount,
+        )?;
+        token::close_account(CpiContext::new_with_signer(
+            token_program.clone(),
+            token::CloseAccount {
+                account: account.to_account_info(),
+                destination: wallet_owner.clone(),
+                authority: order.to_account_info(),
+            },
+            &[&seeds[..]],
+        ))?;
+    }
+    Ok(())
+}
+
+// A recurring swap of escrowed input on a Serum market, filled by keepers.
+#[account]
+pub struct DcaOrder {
+    pub wallet_owner: Pubkey,
+    pub market: Pubkey,
+    // The order's token account escrowing the input of the remaining fills.
+    pub escrow: Pubkey,
+    // The order's token account receiving fills, forwarded to `destination` less the fee.
+    pub output_vault: Pubkey,
+    // The user's token account for the output.
+    pub destination: Pubkey,
+    pub seed: u64,
+    pub side: Side,
+    pub amount_per_fill: u64,
+    pub interval_seconds: i64,
+    // Minimum output per unit of input of a fill, scaled by `PRICE_SCALE`.
+    pub min_price: u64,
+    pub remaining_fills: u64,
+    // Earliest unix timestamp of the next fill.
+    pub next_fill_ts: i64,
+    // Lamports paid to the keeper of each fill.
+    pub bounty_lamports: u64,
+    pub bump: u8,
+}
+
+impl DcaOrder {
+    pub const LEN: usize = 32 * 5 + 8 + 1 + 8 + 8 + 8 + 8 + 8 + 8 + 1;
+}
+
+impl EscrowedOrder for DcaOrder {
+    const SEED: &'static [u8] = b"dca_order";
+    const ACCOUNTS_MISMATCH: ErrorCode = ErrorCode::DcaAccountsMismatch;
+
+    fn keys(&self) -> EscrowedOrderKeys {
+        EscrowedOrderKeys {
+            wallet_owner: self.wallet_owner,
+            market: self.market,
+            escrow: self.escrow,
+            output_vault: self.output_vault,
+            seed: self.seed,
+            side: self.side,
+            bump: self.bump,
+        }
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(seed: u64)]
+pub struct CreateDca<'info> {
+    #[account(mut)]
+    pub wallet_owner: Signer<'info>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        space = 8 + DcaOrder::LEN,
+        seeds = [
+            b"dca_order",
+            wallet_owner.key().as_ref(),
+            &seed.to_le_bytes()
+        ],
+        bump,
+    )]
+    pub dca_order: Box<Account<'info, DcaOrder>>,
+    // The Serum market the order fills on, checked against the market accounts on each fill
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    pub input_mint: Box<Account<'info, Mint>>,
+    pub output_mint: Box<Account<'info, Mint>>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        associated_token::mint = input_mint,
+        associated_token::authority = dca_order,
+    )]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        associated_token::mint = output_mint,
+        associated_token::authority = dca_order,
+    )]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    // The user's token account funding the escrow
+    #[account(mut, token::mint = input_mint, token::authority = wallet_owner)]
+    pub source: Box<Account<'info, TokenAccount>>,
+    // The user's token account receiving the fills
+    #[account(token::mint = output_mint)]
+    pub destination: Box<Account<'info, TokenAccount>>,
     pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteDca<'info> {
+    // Anyone executing a due fill, paid the order's bounty
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"dca_order",
+            dca_order.wallet_owner.as_ref(),
+            &dca_order.seed.to_le_bytes()
+        ],
+        bump = dca_order.bump,
+        has_one = escrow,
+        has_one = output_vault,
+        has_one = destination,
+    )]
+    pub dca_order: Box<Account<'info, DcaOrder>>,
+    // The order's market, with the escrow or output vault as the coin wallet by side
+    pub market: MarketAccounts<'info>,
+    // The registry entry of the market, required if the market allowlist is enabled
+    pub market_config: Option<Box<Account<'info, MarketConfig>>>,
+    // The cached market metadata, saving a load of the market account
+    pub market_cache: Option<Box<Account<'info, MarketCache>>>,
+    // The escrow or output vault by side, for the 'price' currency
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pc_wallet: AccountInfo<'info>,
+    #[account(mut)]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The user's trading volume, setting the fee tier and recording the fill once the user
+    // has traded
+    /// CHECK: Safe, loaded by `load_user_stats` if created
+    #[account(
+        mut,
+        seeds = [
+            b"user_stats",
+            dca_order.wallet_owner.as_ref()
+        ],
+        bump,
+    )]
+    pub user_stats: AccountInfo<'info>,
+    // Fee exemption of the user, exempting the fill if created
+    /// CHECK: Safe
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            dca_order.wallet_owner.as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: AccountInfo<'info>,
+    // The treasury wallet account
+    /// CHECK: safe
+    #[account(
+        seeds = [
+            b"treasury"
+        ],
+        bump,
+    )]
+    pub treasury: AccountInfo<'info>,
+    // The treasury's token account for the market's quote mint
+    #[account(mut, token::authority = treasury)]
+    pub treasury_pc: Box<Account<'info, TokenAccount>>,
+    // The Serum DEX program
+    /// CHECK: Safe
+    pub dex_program: AccountInfo<'info>,
+    // The token program
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> ExecuteDca<'info> {
+    fn fill(&self) -> EscrowedFill<'_, 'info, DcaOrder> {
+        EscrowedFill {
+            order: &self.dca_order,
+            market: &self.market,
+            market_config: &self.market_config,
+            market_cache: &self.market_cache,
+            pc_wallet: &self.pc_wallet,
+            output_vault: &self.output_vault,
+            destination: &self.destination,
+            state: &self.state,
+            user_stats: &self.user_stats,
+            fee_exemption: &self.fee_exemption,
+            treasury_pc: &self.treasury_pc,
+            dex_program: &self.dex_program,
+            token_program: &self.token_program,
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct CancelDca<'info> {
+    #[account(mut)]
+    pub wallet_owner: Signer<'info>,
+    #[account(
+        mut,
+        close = wallet_owner,
+        seeds = [
+            b"dca_order",
+            wallet_owner.key().as_ref(),
+            &dca_order.seed.to_le_bytes()
+        ],
+        bump = dca_order.bump,
+        has_one = wallet_owner,
+        has_one = escrow,
+        has_one = output_vault,
+        has_one = destination,
+    )]
+    pub dca_order: Box<Account<'info, DcaOrder>>,
+    #[account(mut)]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    // The user's token account receiving the refund of the escrow
+    #[account(mut, token::mint = escrow.mint)]
+    pub refund_account: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
 }
 
+// A swap of escrowed input on a Serum market spread over a slot window, cranked by keepers.
 #[account]
-pub struct State {
-    pub owner: Pubkey,
+pub struct TwapOrder {
+    pub wallet_owner: Pubkey,
+    pub market: Pubkey,
+    // The order's token account escrowing the unfilled input.
+    pub escrow: Pubkey,
+    // The order's token account receiving fills, forwarded to `destination` less the fee.
+    pub output_vault: Pubkey,
+    // The user's token account for the output.
+    pub destination: Pubkey,
+    pub seed: u64,
+    pub side: Side,
+    pub total_amount: u64,
+    pub start_slot: u64,
+    pub end_slot: u64,
+    pub band_slots: u64,
+    // Minimum output per unit of input of a fill, scaled by `PRICE_SCALE`.
+    pub min_price: u64,
+    // Input spent so far, protocol fee included.
+    pub input_filled: u64,
+    // Native base and quote traded so far, protocol fee excluded.
+    pub base_filled: u64,
+    pub quote_filled: u64,
+    // Quote per base of the fills so far, scaled by `PRICE_SCALE`, to compare to an oracle.
+    pub average_price: u64,
+    pub bump: u8,
+}
+
+impl TwapOrder {
+    pub const LEN: usize = 32 * 5 + 8 + 1 + 8 * 9 + 1;
+
+    // Input the schedule allows filled by the end of the band holding `slot`.
+    pub fn band_target(&self, slot: u64) -> u64 {
+        let bands = (self.end_slot - self.start_slot) / self.band_slots + 1;
+        let band = (slot.saturating_sub(self.start_slot) / self.band_slots + 1).min(bands);
+        (self.total_amount as u128 * band as u128 / bands as u128) as u64
+    }
+
+    fn record_fill(&mut self, side: Side, input_spent: u64, from_amount: u64, to_amount: u64) {
+        let (base, quote) = match side {
+            Side::Bid => (to_amount, from_amount),
+            Side::Ask => (from_amount, to_amount),
+        };
+        self.input_filled = self.input_filled.saturating_add(input_spent);
+        self.base_filled = self.base_filled.saturating_add(base);
+        self.quote_filled = self.quote_filled.saturating_add(quote);
+        self.average_price = math::price(self.quote_filled, self.base_filled);
+    }
+}
+
+impl EscrowedOrder for TwapOrder {
+    const SEED: &'static [u8] = b"twap_order";
+    const ACCOUNTS_MISMATCH: ErrorCode = ErrorCode::TwapAccountsMismatch;
+
+    fn keys(&self) -> EscrowedOrderKeys {
+        EscrowedOrderKeys {
+            wallet_owner: self.wallet_owner,
+            market: self.market,
+            escrow: self.escrow,
+            output_vault: self.output_vault,
+            seed: self.seed,
+            side: self.side,
+            bump: self.bump,
+        }
+    }
 }
 
 #[derive(Accounts)]
-pub struct Swap<'info> {
-    // The single A/B market to swap on
+#[instruction(seed: u64)]
+pub struct CreateTwap<'info> {
+    #[account(mut)]
+    pub wallet_owner: Signer<'info>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        space = 8 + TwapOrder::LEN,
+        seeds = [
+            b"twap_order",
+            wallet_owner.key().as_ref(),
+            &seed.to_le_bytes()
+        ],
+        bump,
+    )]
+    pub twap_order: Box<Account<'info, TwapOrder>>,
+    // The Serum market the order fills on, checked against the market accounts on each fill
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    pub input_mint: Box<Account<'info, Mint>>,
+    pub output_mint: Box<Account<'info, Mint>>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        associated_token::mint = input_mint,
+        associated_token::authority = twap_order,
+    )]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        associated_token::mint = output_mint,
+        associated_token::authority = twap_order,
+    )]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    // The user's token account funding the escrow
+    #[account(mut, token::mint = input_mint, token::authority = wallet_owner)]
+    pub source: Box<Account<'info, TokenAccount>>,
+    // The user's token account receiving the fills
+    #[account(token::mint = output_mint)]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTwap<'info> {
+    // Anyone cranking the order
+    pub keeper: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"twap_order",
+            twap_order.wallet_owner.as_ref(),
+            &twap_order.seed.to_le_bytes()
+        ],
+        bump = twap_order.bump,
+        has_one = escrow,
+        has_one = output_vault,
+        has_one = destination,
+    )]
+    pub twap_order: Box<Account<'info, TwapOrder>>,
+    // The order's market, with the escrow or output vault as the coin wallet by side
+    pub market: MarketAccounts<'info>,
+    // The registry entry of the market, required if the market allowlist is enabled
+    pub market_config: Option<Box<Account<'info, MarketConfig>>>,
+    // The cached market metadata, saving a load of the market account
+    pub market_cache: Option<Box<Account<'info, MarketCache>>>,
+    // The escrow or output vault by side, for the 'price' currency
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pc_wallet: AccountInfo<'info>,
+    #[account(mut)]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The user's trading volume, setting the fee tier and recording the fill once the user
+    // has traded
+    /// CHECK: Safe, loaded by `load_user_stats` if created
+    #[account(
+        mut,
+        seeds = [
+            b"user_stats",
+            twap_order.wallet_owner.as_ref()
+        ],
+        bump,
+    )]
+    pub user_stats: AccountInfo<'info>,
+    // Fee exemption of the user, exempting the fill if created
+    /// CHECK: Safe
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            twap_order.wallet_owner.as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: AccountInfo<'info>,
+    // The treasury wallet account
+    /// CHECK: safe
+    #[account(
+        seeds = [
+            b"treasury"
+        ],
+        bump,
+    )]
+    pub treasury: AccountInfo<'info>,
+    // The treasury's token account for the market's quote mint
+    #[account(mut, token::authority = treasury)]
+    pub treasury_pc: Box<Account<'info, TokenAccount>>,
+    // The Serum DEX program
+    /// CHECK: Safe
+    pub dex_program: AccountInfo<'info>,
+    // The token program
+    /// CHECK: Safe
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> ExecuteTwap<'info> {
+    fn fill(&self) -> EscrowedFill<'_, 'info, TwapOrder> {
+        EscrowedFill {
+            order: &self.twap_order,
+            market: &self.market,
+            market_config: &self.market_config,
+            market_cache: &self.market_cache,
+            pc_wallet: &self.pc_wallet,
+            output_vault: &self.output_vault,
+            destination: &self.destination,
+            state: &self.state,
+            user_stats: &self.user_stats,
+            fee_exemption: &self.fee_exemption,
+            treasury_pc: &self.treasury_pc,
+            dex_program: &self.dex_program,
+            token_program: &self.token_program,
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct CancelTwap<'info> {
+    #[account(mut)]
+    pub wallet_owner: Signer<'info>,
+    #[account(
+        mut,
+        close = wallet_owner,
+        seeds = [
+            b"twap_order",
+            wallet_owner.key().as_ref(),
+            &twap_order.seed.to_le_bytes()
+        ],
+        bump = twap_order.bump,
+        has_one = wallet_owner,
+        has_one = escrow,
+        has_one = output_vault,
+        has_one = destination,
+    )]
+    pub twap_order: Box<Account<'info, TwapOrder>>,
+    #[account(mut)]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    // The user's token account receiving the refund of the escrow
+    #[account(mut, token::mint = escrow.mint)]
+    pub refund_account: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+// A swap of escrowed input on a Serum market executed once a price crosses a threshold,
+// for stop-losses and take-profits.
+#[account]
+pub struct TriggerOrder {
+    pub wallet_owner: Pubkey,
+    pub market: Pubkey,
+    // The order's token account escrowing the input.
+    pub escrow: Pubkey,
+    // The order's token account receiving the fill, forwarded to `destination` less the fee.
+    pub output_vault: Pubkey,
+    // The user's token account for the output.
+    pub destination: Pubkey,
+    pub seed: u64,
+    pub side: Side,
+    pub amount_in: u64,
+    pub min_amount_out: u64,
+    pub price_source: PriceSource,
+    // The Pyth price account, if the price source is an oracle.
+    pub oracle: Pubkey,
+    pub direction: TriggerDirection,
+    // Quote per base token, scaled by `PRICE_SCALE`.
+    pub threshold: u64,
+    // Decimals of the market's mints, to compare book prices to the threshold.
+    pub base_decimals: u8,
+    pub quote_decimals: u8,
+    // Lamports paid to the keeper executing the order.
+    pub bounty_lamports: u64,
+    pub triggered: bool,
+    pub bump: u8,
+}
+
+impl TriggerOrder {
+    pub const LEN: usize = 32 * 5 + 8 + 1 + 8 + 8 + 1 + 32 + 1 + 8 + 1 + 1 + 8 + 1 + 1;
+
+    pub fn is_triggered_at(&self, price: u64) -> bool {
+        match self.direction {
+            TriggerDirection::Above => price >= self.threshold,
+            TriggerDirection::Below => price <= self.threshold,
+        }
+    }
+}
+
+impl EscrowedOrder for TriggerOrder {
+    const SEED: &'static [u8] = b"trigger_order";
+    const ACCOUNTS_MISMATCH: ErrorCode = ErrorCode::TriggerAccountsMismatch;
+
+    fn keys(&self) -> EscrowedOrderKeys {
+        EscrowedOrderKeys {
+            wallet_owner: self.wallet_owner,
+            market: self.market,
+            escrow: self.escrow,
+            output_vault: self.output_vault,
+            seed: self.seed,
+            side: self.side,
+            bump: self.bump,
+        }
+    }
+}
+
+// The price a trigger order's condition is on.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PriceSource {
+    // The aggregate price of a Pyth price account.
+    Oracle,
+    // The best bid on the market's book. Manipulable, so bounded by the order's minimum output.
+    BestBid,
+    // The best ask on the market's book. Manipulable, so bounded by the order's minimum output.
+    BestAsk,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TriggerDirection {
+    // Triggers at or above the threshold, e.g. a take-profit of a long position.
+    Above,
+    // Triggers at or below the threshold, e.g. a stop-loss of a long position.
+    Below,
+}
+
+#[derive(Accounts)]
+#[instruction(seed: u64)]
+pub struct CreateTrigger<'info> {
+    #[account(mut)]
+    pub wallet_owner: Signer<'info>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        space = 8 + TriggerOrder::LEN,
+        seeds = [
+            b"trigger_order",
+            wallet_owner.key().as_ref(),
+            &seed.to_le_bytes()
+        ],
+        bump,
+    )]
+    pub trigger_order: Box<Account<'info, TriggerOrder>>,
+    // The Serum market the order fills on, checked against the market accounts on trigger
+    /// CHECK: Safe
+    pub market: AccountInfo<'info>,
+    // The Pyth price account, for an oracle price source
+    /// CHECK: Safe
+    pub oracle: Option<AccountInfo<'info>>,
+    pub input_mint: Box<Account<'info, Mint>>,
+    pub output_mint: Box<Account<'info, Mint>>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        associated_token::mint = input_mint,
+        associated_token::authority = trigger_order,
+    )]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        associated_token::mint = output_mint,
+        associated_token::authority = trigger_order,
+    )]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    // The user's token account funding the escrow
+    #[account(mut, token::mint = input_mint, token::authority = wallet_owner)]
+    pub source: Box<Account<'info, TokenAccount>>,
+    // The user's token account receiving the fill
+    #[account(token::mint = output_mint)]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTrigger<'info> {
+    // Anyone executing a triggered order, paid the order's bounty
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"trigger_order",
+            trigger_order.wallet_owner.as_ref(),
+            &trigger_order.seed.to_le_bytes()
+        ],
+        bump = trigger_order.bump,
+        has_one = escrow,
+        has_one = output_vault,
+        has_one = destination,
+    )]
+    pub trigger_order: Box<Account<'info, TriggerOrder>>,
+    // The Pyth price account, for an oracle price source
     /// CHECK: Safe
+    #[account(address = trigger_order.oracle)]
+    pub oracle: Option<AccountInfo<'info>>,
+    // The order's market, with the escrow or output vault as the coin wallet by side
     pub market: MarketAccounts<'info>,
-    // The swap user
+    // The registry entry of the market, required if the market allowlist is enabled
+    pub market_config: Option<Box<Account<'info, MarketConfig>>>,
+    // The cached market metadata, saving a load of the market account
+    pub market_cache: Option<Box<Account<'info, MarketCache>>>,
+    // The escrow or output vault by side, for the 'price' currency
+    /// CHECK: Safe
+    #[account(mut)]
+    pub pc_wallet: AccountInfo<'info>,
+    #[account(mut)]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The user's trading volume, setting the fee tier and recording the fill once the user
+    // has traded
+    /// CHECK: Safe, loaded by `load_user_stats` if created
+    #[account(
+        mut,
+        seeds = [
+            b"user_stats",
+            trigger_order.wallet_owner.as_ref()
+        ],
+        bump,
+    )]
+    pub user_stats: AccountInfo<'info>,
+    // Fee exemption of the user, exempting the fill if created
     /// CHECK: Safe
-    #[account(signer, mut)]
-    pub wallet_owner: AccountInfo<'info>,
-
-    pub mint_a: Box<Account<'info, Mint>>,
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            trigger_order.wallet_owner.as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: AccountInfo<'info>,
     // The treasury wallet account
     /// CHECK: safe
     #[account(
-        mut,
         seeds = [
             b"treasury"
         ],
         bump,
     )]
     pub treasury: AccountInfo<'info>,
-
-    #[account(
-        init_if_needed,
-        payer = wallet_owner,
-        associated_token::mint = mint_a,
-        associated_token::authority = treasury,
-    )]
-    pub treasury_usdc: Box<Account<'info, TokenAccount>>,
-    // The user's token account for the 'price' currency
-    /// CHECK: Safe
-    #[account(mut)]
-    pub pc_wallet: AccountInfo<'info>,
-
-    /// Solana ecosystem accounts
-    pub system_program: Program<'info, System>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-
+    // The treasury's token account for the market's quote mint
+    #[account(mut, token::authority = treasury)]
+    pub treasury_pc: Box<Account<'info, TokenAccount>>,
     // The Serum DEX program
     /// CHECK: Safe
     pub dex_program: AccountInfo<'info>,
@@ -293,78 +4026,276 @@ pub struct Swap<'info> {
     pub token_program: AccountInfo<'info>,
 }
 
-impl<'info> From<&Swap<'info>> for OrderbookClient<'info> {
-    fn from(accounts: &Swap<'info>) -> OrderbookClient<'info> {
-        OrderbookClient {
-            market: accounts.market.clone(),
-            wallet_owner: accounts.wallet_owner.clone(),
-            pc_wallet: accounts.pc_wallet.clone(),
-            dex_program: accounts.dex_program.clone(),
-            token_program: accounts.token_program.clone(),
+impl<'info> ExecuteTrigger<'info> {
+    // The order's price source in quote per base token, scaled by `PRICE_SCALE`.
+    fn trigger_price(&self, slot: u64) -> Result<u64> {
+        let trigger_order = &self.trigger_order;
+        let book_side = match trigger_order.price_source {
+            PriceSource::Oracle => {
+                let oracle = self.oracle.as_ref().ok_or(ErrorCode::InvalidOracle)?;
+                let price = oracle::OraclePrice::load(oracle)?;
+                require!(
+                    slot.saturating_sub(price.publish_slot) <= oracle::MAX_STALENESS_SLOTS,
+                    ErrorCode::OraclePriceUnavailable
+                );
+                return price
+                    .scaled(PRICE_SCALE)
+                    .ok_or(ErrorCode::OraclePriceUnavailable.into());
+            }
+            PriceSource::BestBid => Side::Bid,
+            PriceSource::BestAsk => Side::Ask,
+        };
+        let (price_lots, coin_lot_size, pc_lot_size) =
+            dex::serum::best_price(&self.market, book_side)?;
+        let price_lots = price_lots.ok_or(ErrorCode::TriggerConditionNotMet)?;
+        // pc lots per coin lot, to quote per base token.
+        let numerator = price_lots as u128
+            * pc_lot_size as u128
+            * 10u128.pow(trigger_order.base_decimals as u32)
+            * PRICE_SCALE as u128;
+        let denominator = coin_lot_size as u128 * 10u128.pow(trigger_order.quote_decimals as u32);
+        Ok(u64::try_from(numerator / denominator).unwrap_or(u64::MAX))
+    }
+
+    fn fill(&self) -> EscrowedFill<'_, 'info, TriggerOrder> {
+        EscrowedFill {
+            order: &self.trigger_order,
+            market: &self.market,
+            market_config: &self.market_config,
+            market_cache: &self.market_cache,
+            pc_wallet: &self.pc_wallet,
+            output_vault: &self.output_vault,
+            destination: &self.destination,
+            state: &self.state,
+            user_stats: &self.user_stats,
+            fee_exemption: &self.fee_exemption,
+            treasury_pc: &self.treasury_pc,
+            dex_program: &self.dex_program,
+            token_program: &self.token_program,
         }
     }
 }
 
 #[derive(Accounts)]
-pub struct SwapTransitive<'info> {
-    // The first A/B market to swap on, A -> B, ask
-    pub from: MarketAccounts<'info>,
-    // The second C/B market to swap on, B -> C, bid
-    pub to: MarketAccounts<'info>,
-    // The swap user
+pub struct CancelTrigger<'info> {
+    #[account(mut)]
+    pub wallet_owner: Signer<'info>,
+    #[account(
+        mut,
+        close = wallet_owner,
+        seeds = [
+            b"trigger_order",
+            wallet_owner.key().as_ref(),
+            &trigger_order.seed.to_le_bytes()
+        ],
+        bump = trigger_order.bump,
+        has_one = wallet_owner,
+        has_one = escrow,
+        has_one = output_vault,
+        has_one = destination,
+    )]
+    pub trigger_order: Box<Account<'info, TriggerOrder>>,
+    #[account(mut)]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub output_vault: Box<Account<'info, TokenAccount>>,
+    // The user's token account receiving the refund of the escrow
+    #[account(mut, token::mint = escrow.mint)]
+    pub refund_account: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+// A swap the user signed for a whitelisted executor to run out of the user's wallets
+// through the delegate.
+#[account]
+pub struct Intent {
+    pub wallet_owner: Pubkey,
+    pub seed: u64,
+    pub input_mint: Pubkey,
+    pub output_mint: Pubkey,
+    pub amount_in: u64,
+    pub min_amount_out: u64,
+    // Unix timestamp the intent can no longer execute from.
+    pub expiry: i64,
+    pub bump: u8,
+}
+
+impl Intent {
+    pub const LEN: usize = 32 + 8 + 32 + 32 + 8 + 8 + 8 + 1;
+}
+
+#[derive(Accounts)]
+#[instruction(seed: u64)]
+pub struct CreateIntent<'info> {
+    #[account(mut)]
+    pub wallet_owner: Signer<'info>,
+    #[account(
+        init,
+        payer = wallet_owner,
+        space = 8 + Intent::LEN,
+        seeds = [
+            b"intent",
+            wallet_owner.key().as_ref(),
+            &seed.to_le_bytes()
+        ],
+        bump,
+    )]
+    pub intent: Box<Account<'info, Intent>>,
+    pub input_mint: Box<Account<'info, Mint>>,
+    pub output_mint: Box<Account<'info, Mint>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SwapDelegated<'info> {
+    // The whitelisted executor running the intent
+    pub executor: Signer<'info>,
+    #[account(
+        seeds = [
+            b"executor",
+            executor.key().as_ref()
+        ],
+        bump,
+    )]
+    pub executor_entry: Box<Account<'info, Executor>>,
+    #[account(
+        mut,
+        close = wallet_owner,
+        seeds = [
+            b"intent",
+            wallet_owner.key().as_ref(),
+            &intent.seed.to_le_bytes()
+        ],
+        bump = intent.bump,
+        has_one = wallet_owner,
+    )]
+    pub intent: Box<Account<'info, Intent>>,
+    // The intent's user, refunded its rent
     /// CHECK: Safe
-    #[account(signer)]
+    #[account(mut)]
     pub wallet_owner: AccountInfo<'info>,
+    // The delegate the user approved, trading out of the user's wallets
+    /// CHECK: Safe
+    #[account(
+        mut,
+        seeds = [
+            b"intent_delegate"
+        ],
+        bump,
+    )]
+    pub delegate: AccountInfo<'info>,
+    // The market, with the user's wallet for the 'coin' currency
+    pub market: MarketAccounts<'info>,
+    // The registry entry of the market, required if the market allowlist is enabled
+    pub market_config: Option<Box<Account<'info, MarketConfig>>>,
+    // The cached market metadata, saving a load of the market account
+    pub market_cache: Option<Box<Account<'info, MarketCache>>>,
     // The user's token account for the 'price' currency
     /// CHECK: Safe
     #[account(mut)]
     pub pc_wallet: AccountInfo<'info>,
+    // The program config holding the fee tiers
+    #[account(
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
+    pub state: Box<Account<'info, State>>,
+    // The user's trading volume, setting the fee tier and recording the swap once the user
+    // has traded
+    /// CHECK: Safe, loaded by `load_user_stats` if created
+    #[account(
+        mut,
+        seeds = [
+            b"user_stats",
+            intent.wallet_owner.as_ref()
+        ],
+        bump,
+    )]
+    pub user_stats: AccountInfo<'info>,
+    // Fee exemption of the user, exempting the swap if created
+    /// CHECK: Safe
+    #[account(
+        seeds = [
+            b"fee_exemption",
+            intent.wallet_owner.as_ref()
+        ],
+        bump,
+    )]
+    pub fee_exemption: AccountInfo<'info>,
     // The treasury wallet account
     /// CHECK: safe
     #[account(
-        mut,
         seeds = [
             b"treasury"
         ],
         bump,
     )]
     pub treasury: AccountInfo<'info>,
-
-    #[account(
-        mut,
-        associated_token::mint = USDC_MINT,
-        associated_token::authority = treasury,
-    )]
-    pub treasury_usdc: Box<Account<'info, TokenAccount>>,
-    // The Serum DEX program
+    // The treasury's token account for the market's quote mint
+    #[account(mut, token::authority = treasury)]
+    pub treasury_pc: Box<Account<'info, TokenAccount>>,
+    // The Serum DEX program, fixed as the delegate signs for it
     /// CHECK: Safe
+    #[account(address = dex::ID)]
     pub dex_program: AccountInfo<'info>,
-    // The token program
-    /// CHECK: Safe
-    pub token_program: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
 }
 
-impl<'info> SwapTransitive<'info> {
-    fn orderbook_from(&self) -> OrderbookClient<'info> {
-        OrderbookClient {
-            market: self.from.clone(),
-            wallet_owner: self.wallet_owner.clone(),
-            pc_wallet: self.pc_wallet.clone(),
-            dex_program: self.dex_program.clone(),
-            token_program: self.token_program.clone(),
+impl<'info> SwapDelegated<'info> {
+    // Protocol fee for the intent's user on `day`, zero if exempt.
+    fn fee_bps(&self, day: i64) -> Result<u16> {
+        if has_fee_exemption(&self.fee_exemption) {
+            return Ok(0);
         }
+        let volume = load_user_stats(&self.user_stats)?
+            .map_or(0, |user_stats| user_stats.window_volume(day));
+        Ok(self.state.fee_bps_for_volume(volume))
     }
-    fn orderbook_to(&self) -> OrderbookClient<'info> {
+
+    // SendTake client trading from the user's wallets, signed by the delegate.
+    fn orderbook(&self, delegate_bump: u8) -> OrderbookClient<'info> {
         OrderbookClient {
-            market: self.to.clone(),
-            wallet_owner: self.wallet_owner.clone(),
+            market: self.market.clone(),
+            wallet_owner: self.delegate.clone(),
             pc_wallet: self.pc_wallet.clone(),
             dex_program: self.dex_program.clone(),
-            token_program: self.token_program.clone(),
+            token_program: self.token_program.to_account_info(),
+            referral: None,
+            coin_lot_size: self
+                .market_cache
+                .as_ref()
+                .map(|market_cache| market_cache.coin_lot_size),
+            open_orders: None,
+            wallet_owner_seeds: Some(vec![b"intent_delegate".to_vec(), vec![delegate_bump]]),
+            match_limit: None,
+            takes_left: 1,
+            fill_policy: None,
         }
     }
 }
 
+#[derive(Accounts)]
+pub struct CancelIntent<'info> {
+    #[account(mut)]
+    pub wallet_owner: Signer<'info>,
+    #[account(
+        mut,
+        close = wallet_owner,
+        seeds = [
+            b"intent",
+            wallet_owner.key().as_ref(),
+            &intent.seed.to_le_bytes()
+        ],
+        bump = intent.bump,
+        has_one = wallet_owner,
+    )]
+    pub intent: Box<Account<'info, Intent>>,
+}
+
 // Market accounts are the accounts used to place orders against the dex minus
 // common accounts, i.e., program ids, sysvars
 #[derive(Accounts, Clone)]
@@ -411,7 +4342,14 @@ pub struct MarketAccounts<'info> {
 
 #[derive(Accounts)]
 pub struct Withdraw<'info> {
-    #[account(mut, has_one = owner)]
+    #[account(
+        mut,
+        has_one = owner,
+        seeds = [
+            b"state"
+        ],
+        bump,
+    )]
     pub state: Account<'info, State>,
     // treasury wallet
     /// CHECK: safe
@@ -438,120 +4376,183 @@ pub struct Withdraw<'info> {
 
     pub token_program: Program<'info, Token>,
 }
-// Client for sending orders to the Serum DEX.
-#[derive(Clone)]
-struct OrderbookClient<'info> {
-    // The DEX market
-    /// CHECK: Safe
-    market: MarketAccounts<'info>,
-    // The swap user
-    /// CHECK: Safe
-    wallet_owner: AccountInfo<'info>,
-    // The user's token account for the 'price' currency
-    /// CHECK: Safe
-    pc_wallet: AccountInfo<'info>,
-    // The Serum DEX program
-    /// CHECK: Safe
-    dex_program: AccountInfo<'info>,
-    // The token program
-    /// CHECK: Safe
-    token_program: AccountInfo<'info>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Bid,
+    Ask,
 }
 
-impl<'info> OrderbookClient<'info> {
-    /// 'Swap' from pc to coin - Bid
-    fn bid(&self, max_pc_amount_input: u64, min_coin_amount_output: u64) -> ProgramResult {
-        let limit_price = u64::MAX;
-        let max_coin_qty = u64::MAX;
-        let max_native_pc_qty_including_fees = max_pc_amount_input;
-        let min_coin_qty = {
-            let market = MarketState::load(&self.market.market, &dex::ID, false)?;
-            coin_lots(&market, min_coin_amount_output)
-        };
-        let min_native_pc_qty = 0;
-        self.send_take_cpi(
-            Side::Bid,
-            limit_price,
-            max_coin_qty,
-            max_native_pc_qty_including_fees,
-            min_coin_qty,
-            min_native_pc_qty,
-        )
+// How much of a swap's input must fill. Without one, asks fill or kill and bids may fill
+// partially, down to `amount_out_min`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FillPolicy {
+    // All of the input fills, up to what is left short of a coin lot.
+    FillOrKill,
+    // At least `min_fill_bps` of the input fills, the rest stays with the user.
+    AllowPartial { min_fill_bps: u16 },
+}
+
+// Where `swap_transitive` sends the intermediate quote its buy leaves over.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SpillPolicy {
+    // Left in the user's quote wallet.
+    #[default]
+    Leave,
+    // Sent to the treasury.
+    Treasury,
+    // Bid again on the `to` market, if it covers a lot at the best ask.
+    Swap,
+}
+
+// How a Serum swap executes. `NewOrder` is for forks and older deployments without
+// SendTake: an ImmediateOrCancel NewOrderV3 through the user's PDA open orders account for
+// the market, settled back to the user in the same instruction.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExecutionMode {
+    #[default]
+    SendTake,
+    NewOrder,
+}
+
+// The DEX a route leg executes on.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Venue {
+    Serum,
+    OpenBookV2,
+    // An SPL token-swap constant product pool, token a being the leg's base.
+    TokenSwap,
+    // A Whirlpool concentrated liquidity pool, token a being the leg's base.
+    Whirlpool,
+    Phoenix,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RouteLeg {
+    pub venue: Venue,
+    pub side: Side,
+    // Most orders a Serum leg's take may match, derived from the remaining compute budget
+    // if not given. Ignored by the other venues.
+    pub match_limit: Option<u16>,
+}
+
+// A user's swap in a `batch_swap`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BatchSwapSpec {
+    pub side: Side,
+    pub amount_in_max: u64,
+    pub amount_out_min: u64,
+}
+
+// Whether the swap user's exemption is given, or the program exemption given covers the
+// calling program.
+fn is_fee_exempt<'info>(
+    fee_exemption: &Option<Box<Account<'info, FeeExemption>>>,
+    program_fee_exemption: &Option<Box<Account<'info, FeeExemption>>>,
+    instructions: &Option<AccountInfo<'info>>,
+) -> Result<bool> {
+    if fee_exemption.is_some() {
+        return Ok(true);
     }
+    let Some(program_fee_exemption) = program_fee_exemption else {
+        return Ok(false);
+    };
+    Ok(calling_program(instructions)? == Some(program_fee_exemption.exempt))
+}
 
-    /// 'Swap' from coin to pc - Ask
-    fn ask(&self, max_coin_amount_input: u64, min_pc_amount_output: u64) -> ProgramResult {
-        let limit_price = 1;
-        let max_coin_qty = {
-            let market = MarketState::load(&self.market.market, &dex::ID, false)?;
-            coin_lots(&market, max_coin_amount_input)
-        };
-        let max_native_pc_qty_including_fees = u64::MAX;
-        let min_coin_qty = max_coin_qty;
-        let min_native_pc_qty = min_pc_amount_output;
-        self.send_take_cpi(
-            Side::Ask,
-            limit_price,
-            max_coin_qty,
-            max_native_pc_qty_including_fees,
-            min_coin_qty,
-            min_native_pc_qty,
-        )
+// Whether the fee exemption PDA passed for a user exists, i.e. the user is exempt.
+fn has_fee_exemption(fee_exemption: &AccountInfo) -> bool {
+    fee_exemption.owner == &crate::ID && !fee_exemption.data_is_empty()
+}
+
+// The stats of a user, if the user stats PDA passed for them was created by a swap.
+fn load_user_stats(user_stats: &AccountInfo) -> Result<Option<UserStats>> {
+    if user_stats.owner != &crate::ID {
+        return Ok(None);
     }
+    let stats = UserStats::try_deserialize(&mut &user_stats.try_borrow_data()?[..])?;
+    Ok(Some(stats))
+}
 
-    /// Execute SendTake on the Serum DEX via CPI
-    fn send_take_cpi(
-        &self,
-        side: Side,
-        limit_price: u64,
-        max_coin_qty: u64,
-        max_native_pc_qty_including_fees: u64,
-        min_coin_qty: u64,
-        min_native_pc_qty: u64,
-    ) -> ProgramResult {
-        let cpi_accounts = dex::SendTake {
-            market: self.market.market.clone(),
-            request_queue: self.market.request_queue.clone(),
-            event_queue: self.market.event_queue.clone(),
-            market_bids: self.market.market_bids.clone(),
-            market_asks: self.market.market_asks.clone(),
-            coin_wallet: self.market.coin_wallet.clone(),
-            pc_wallet: self.pc_wallet.clone(),
-            wallet_owner: self.wallet_owner.clone(),
-            coin_vault: self.market.coin_vault.clone(),
-            pc_vault: self.market.pc_vault.clone(),
-            token_program: self.token_program.clone(),
-            vault_signer: self.market.vault_signer.clone(),
-        };
-        // Limit is the dex's custom compute budge parameter, setting an upper
-        // bound on the number of matching cycles the program can perform
-        // before giving up and posting the remaining unmatched order.
-        let limit = 65535;
-        let ctx = CpiContext::new(self.dex_program.clone(), cpi_accounts);
-
-        msg!("SendTake CPI: side: {:?}, limit_price: {}, max_coin_qty: {}, max_native_pc_qty_including_fees: {}, min_coin_qty: {}, min_native_pc_qty: {}, limit: {}", side, limit_price, max_coin_qty, max_native_pc_qty_including_fees, min_coin_qty, min_native_pc_qty, limit);
-        dex::send_take(
-            ctx,
-            side.into(),
-            NonZeroU64::new(limit_price).unwrap(),
-            NonZeroU64::new(max_coin_qty).unwrap(),
-            NonZeroU64::new(max_native_pc_qty_including_fees).unwrap(),
-            min_coin_qty,
-            min_native_pc_qty,
-            limit,
-        )
+// Writes back the stats loaded by `load_user_stats`.
+fn store_user_stats(user_stats: &AccountInfo, stats: &UserStats) -> Result<()> {
+    stats.try_serialize(&mut &mut user_stats.try_borrow_mut_data()?[..])
+}
+
+// The program of the top level instruction, if this program is invoked through CPI.
+fn calling_program(instructions: &Option<AccountInfo>) -> Result<Option<Pubkey>> {
+    let Some(instructions) = instructions else {
+        return Ok(None);
+    };
+    let current_index = load_current_index_checked(instructions)?;
+    let current_ix = load_instruction_at_checked(current_index as usize, instructions)?;
+    if current_ix.program_id == crate::ID {
+        return Ok(None);
     }
+    Ok(Some(current_ix.program_id))
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Debug, PartialEq)]
-pub enum Side {
-    Bid,
-    Ask,
+// Forwards a swap's output from the user's wallet to the recipient, if given, and logs the
+// memo, if given.
+fn send_to_recipient<'info>(
+    token_program: &AccountInfo<'info>,
+    to_token: &AccountInfo<'info>,
+    recipient: &Option<Box<Account<'info, TokenAccount>>>,
+    wallet_owner: &AccountInfo<'info>,
+    amount: u64,
+    memo_program: &Option<Program<'info, Memo>>,
+    memo: Option<String>,
+) -> Result<()> {
+    if let Some(recipient) = recipient {
+        require_keys_eq!(
+            recipient.mint,
+            token::accessor::mint(to_token)?,
+            ErrorCode::RecipientMintMismatch
+        );
+        token::transfer(
+            CpiContext::new(
+                token_program.clone(),
+                token::Transfer {
+                    from: to_token.clone(),
+                    to: recipient.to_account_info(),
+                    authority: wallet_owner.clone(),
+                },
+            ),
+            amount,
+        )?;
+    }
+    if let Some(memo) = memo {
+        let memo_program = memo_program
+            .as_ref()
+            .ok_or(ErrorCode::MemoProgramRequired)?;
+        memo::build_memo(
+            CpiContext::new(memo_program.to_account_info(), memo::BuildMemo {}),
+            memo.as_bytes(),
+        )?;
+    }
+    Ok(())
 }
 
-// Returns the amount of lots for the base currency of a trade with `size`.
-fn coin_lots(market: &MarketState, size: u64) -> u64 {
-    size.checked_div(market.coin_lot_size).unwrap()
+fn transfer_fee<'info>(
+    token_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    treasury: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    token::transfer(
+        CpiContext::new(
+            token_program.clone(),
+            token::Transfer {
+                from: from.clone(),
+                to: treasury.clone(),
+                authority: authority.clone(),
+            },
+        ),
+        amount,
+    )
 }
 
 impl From<Side> for serum_dex::matching::Side {
@@ -563,13 +4564,136 @@ impl From<Side> for serum_dex::matching::Side {
     }
 }
 
+// Serum stores pubkeys as `[u64; 4]`.
+fn pubkey_from_words(words: [u64; 4]) -> Pubkey {
+    let mut bytes = [0u8; 32];
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(words.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    Pubkey::new_from_array(bytes)
+}
+
 // Access control modifiers.
 fn is_valid_swap(ctx: &Context<Swap>) -> Result<()> {
-    _is_valid_swap(&ctx.accounts.market.coin_wallet, &ctx.accounts.pc_wallet)
+    _is_valid_swap(&ctx.accounts.market.coin_wallet, &ctx.accounts.pc_wallet)?;
+    _is_valid_referral(&ctx.accounts.market.market, &ctx.accounts.referral)?;
+    _is_registered_market(
+        &ctx.accounts.state,
+        &ctx.accounts.market,
+        &ctx.accounts.dex_program,
+        &ctx.accounts.market_config,
+    )?;
+    _is_valid_market_cache(
+        &ctx.accounts.market,
+        &ctx.accounts.dex_program,
+        &ctx.accounts.market_cache,
+    )
+}
+
+fn is_valid_limit_order(ctx: &Context<LimitOrder>) -> Result<()> {
+    _is_valid_swap(&ctx.accounts.market.coin_wallet, &ctx.accounts.pc_wallet)?;
+    _is_registered_market(
+        &ctx.accounts.state,
+        &ctx.accounts.market,
+        &ctx.accounts.dex_program,
+        &ctx.accounts.market_config,
+    )
+}
+
+// Validates the market accounts of an escrowed order's fill are the order's, trading out of
+// its escrow into its output vault.
+fn is_valid_escrowed_fill<T: EscrowedOrder>(fill: &EscrowedFill<T>) -> Result<()> {
+    let keys = fill.order.keys();
+    require_keys_eq!(fill.market.market.key(), keys.market, T::ACCOUNTS_MISMATCH);
+    let (coin_wallet, pc_wallet) = match keys.side {
+        Side::Bid => (keys.output_vault, keys.escrow),
+        Side::Ask => (keys.escrow, keys.output_vault),
+    };
+    require_keys_eq!(
+        fill.market.coin_wallet.key(),
+        coin_wallet,
+        T::ACCOUNTS_MISMATCH
+    );
+    require_keys_eq!(fill.pc_wallet.key(), pc_wallet, T::ACCOUNTS_MISMATCH);
+    let pc_mint = token::accessor::mint(fill.pc_wallet)?;
+    require_keys_eq!(fill.treasury_pc.mint, pc_mint, T::ACCOUNTS_MISMATCH);
+    _is_registered_market(
+        fill.state,
+        fill.market,
+        fill.dex_program,
+        fill.market_config,
+    )?;
+    _is_valid_market_cache(fill.market, fill.dex_program, fill.market_cache)
+}
+
+fn is_valid_swap_delegated(ctx: &Context<SwapDelegated>) -> Result<()> {
+    let accounts = &ctx.accounts;
+    let intent = &accounts.intent;
+    let coin_wallet = &accounts.market.coin_wallet;
+    let pc_wallet = &accounts.pc_wallet;
+    let (coin_mint, pc_mint) = (
+        token::accessor::mint(coin_wallet)?,
+        token::accessor::mint(pc_wallet)?,
+    );
+    let mints = (intent.input_mint, intent.output_mint);
+    require!(
+        mints == (pc_mint, coin_mint) || mints == (coin_mint, pc_mint),
+        ErrorCode::IntentAccountsMismatch
+    );
+    require_keys_eq!(
+        token::accessor::authority(coin_wallet)?,
+        intent.wallet_owner,
+        ErrorCode::IntentAccountsMismatch
+    );
+    require_keys_eq!(
+        token::accessor::authority(pc_wallet)?,
+        intent.wallet_owner,
+        ErrorCode::IntentAccountsMismatch
+    );
+    require_keys_eq!(
+        accounts.treasury_pc.mint,
+        pc_mint,
+        ErrorCode::IntentAccountsMismatch
+    );
+    _is_registered_market(
+        &accounts.state,
+        &accounts.market,
+        &accounts.dex_program,
+        &accounts.market_config,
+    )?;
+    _is_valid_market_cache(
+        &accounts.market,
+        &accounts.dex_program,
+        &accounts.market_cache,
+    )
 }
 
 fn is_valid_swap_transitive(ctx: &Context<SwapTransitive>) -> Result<()> {
-    _is_valid_swap(&ctx.accounts.from.coin_wallet, &ctx.accounts.to.coin_wallet)
+    _is_valid_swap(&ctx.accounts.from.coin_wallet, &ctx.accounts.to.coin_wallet)?;
+    _is_valid_referral(&ctx.accounts.from.market, &ctx.accounts.referral)?;
+    _is_valid_referral(&ctx.accounts.to.market, &ctx.accounts.referral)?;
+    _is_registered_market(
+        &ctx.accounts.state,
+        &ctx.accounts.from,
+        &ctx.accounts.dex_program,
+        &ctx.accounts.from_market_config,
+    )?;
+    _is_registered_market(
+        &ctx.accounts.state,
+        &ctx.accounts.to,
+        &ctx.accounts.dex_program,
+        &ctx.accounts.to_market_config,
+    )?;
+    _is_valid_market_cache(
+        &ctx.accounts.from,
+        &ctx.accounts.dex_program,
+        &ctx.accounts.from_market_cache,
+    )?;
+    _is_valid_market_cache(
+        &ctx.accounts.to,
+        &ctx.accounts.dex_program,
+        &ctx.accounts.to_market_cache,
+    )
 }
 
 // Validates the tokens being swapped are of different mints.
@@ -582,21 +4706,89 @@ fn _is_valid_swap<'info>(from: &AccountInfo<'info>, to: &AccountInfo<'info>) ->
     Ok(())
 }
 
-// Safety checks.
-fn apply_safety_checks(
-    amount_in_max: u64,
-    amount_out_min: u64,
-    from_amount: u64,
-    to_amount: u64,
+// Validates the referral account, if given, is in the market's quote currency.
+fn _is_valid_referral<'info>(
+    market: &AccountInfo<'info>,
+    referral: &Option<Box<Account<'info, TokenAccount>>>,
+) -> Result<()> {
+    if let Some(referral) = referral {
+        let pc_mint = {
+            let market = MarketState::load(market, &dex::ID, false).map_err(ProgramError::from)?;
+            pubkey_from_words(market.pc_mint)
+        };
+        if referral.mint != pc_mint {
+            return Err(ErrorCode::InvalidReferralMint.into());
+        }
+    }
+    Ok(())
+}
+
+// Validates the market's registry entry, which is required if the allowlist is enabled.
+fn _is_registered_market<'info>(
+    state: &State,
+    market: &MarketAccounts<'info>,
+    dex_program: &AccountInfo<'info>,
+    market_config: &Option<Box<Account<'info, MarketConfig>>>,
 ) -> Result<()> {
-    if amount_in_max < from_amount {
-        return Err(ErrorCode::SwapTokenAmountExceedsMax.into());
+    let Some(market_config) = market_config else {
+        if state.market_allowlist_enabled {
+            return Err(ErrorCode::MarketNotRegistered.into());
+        }
+        return Ok(());
+    };
+    if market_config.market != market.market.key()
+        || market_config.dex_program != dex_program.key()
+        || market_config.vault_signer != market.vault_signer.key()
+    {
+        return Err(ErrorCode::MarketConfigMismatch.into());
     }
-    if amount_out_min > to_amount {
-        return Err(ErrorCode::SwapTokenAmountLessThanMin.into());
+    Ok(())
+}
+
+// Validates the registry entry of a Serum route leg's market, taken from the accounts after
+// the leg if the allowlist is enabled. Returns the accounts left.
+fn _is_registered_leg<'a, 'info>(
+    state: &State,
+    leg_accounts: &[AccountInfo<'info>],
+    wallet_owner: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    rest: &'a [AccountInfo<'info>],
+) -> Result<&'a [AccountInfo<'info>]> {
+    if !state.market_allowlist_enabled {
+        return Ok(rest);
     }
-    if to_amount == 0 {
-        return Err(ErrorCode::ZeroSwap.into());
+    let Some((market_config, rest)) = rest.split_first() else {
+        return Err(ErrorCode::MarketNotRegistered.into());
+    };
+    let market_config = Account::<MarketConfig>::try_from(market_config)
+        .map_err(|_| error!(ErrorCode::MarketNotRegistered))?;
+    let orderbook =
+        OrderbookClient::from_leg_accounts(leg_accounts, wallet_owner, token_program, 1);
+    _is_registered_market(
+        state,
+        &orderbook.market,
+        &orderbook.dex_program,
+        &Some(Box::new(market_config)),
+    )?;
+    Ok(rest)
+}
+
+// Validates the market cache, if given, was built from the passed market accounts.
+fn _is_valid_market_cache<'info>(
+    market: &MarketAccounts<'info>,
+    dex_program: &AccountInfo<'info>,
+    market_cache: &Option<Box<Account<'info, MarketCache>>>,
+) -> Result<()> {
+    let Some(market_cache) = market_cache else {
+        return Ok(());
+    };
+    if market_cache.market != market.market.key()
+        || market_cache.dex_program != dex_program.key()
+        || market_cache.coin_vault != market.coin_vault.key()
+        || market_cache.pc_vault != market.pc_vault.key()
+        || market_cache.vault_signer != market.vault_signer.key()
+    {
+        return Err(ErrorCode::MarketCacheMismatch.into());
     }
     Ok(())
 }
@@ -613,4 +4805,145 @@ pub enum ErrorCode {
     ZeroSwap,
     #[msg["You are not owner of this program"]]
     Unauthorized,
+    #[msg("The referral account must hold the market's quote mint")]
+    InvalidReferralMint,
+    #[msg("Fee tiers must be sorted by volume and not exceed the base fee")]
+    InvalidFeeTiers,
+    #[msg("The market is not registered")]
+    MarketNotRegistered,
+    #[msg("The market config does not match the market accounts")]
+    MarketConfigMismatch,
+    #[msg("The market cache does not match the market accounts")]
+    MarketCacheMismatch,
+    #[msg("A route needs at least one leg")]
+    EmptyRoute,
+    #[msg("Each route leg must swap from the previous leg's output account")]
+    RouteLegsNotChained,
+    #[msg("The route output does not match the output mint")]
+    RouteOutputMintMismatch,
+    #[msg("The venue does not support on-chain quotes")]
+    QuoteUnavailable,
+    #[msg("NewOrderV3 execution needs the user's open orders account and the rent sysvar")]
+    OpenOrdersRequired,
+    #[msg("The open orders account is not the user's PDA for the market")]
+    InvalidOpenOrders,
+    #[msg("A DCA order needs a positive amount, fill count and interval")]
+    InvalidDcaOrder,
+    #[msg("The DCA order has no fills left")]
+    DcaOrderFilled,
+    #[msg("The DCA order's interval has not elapsed since its last fill")]
+    DcaIntervalNotElapsed,
+    #[msg("The accounts do not match the DCA order")]
+    DcaAccountsMismatch,
+    #[msg("A TWAP order needs a positive size and band, and a window not yet over")]
+    InvalidTwapOrder,
+    #[msg("The current slot is outside the TWAP order's window")]
+    TwapOutsideWindow,
+    #[msg("The TWAP order has filled its share of the size up to the current band")]
+    TwapBandFilled,
+    #[msg("The accounts do not match the TWAP order")]
+    TwapAccountsMismatch,
+    #[msg("A trigger order needs a positive amount, and a minimum output near its threshold if book-priced")]
+    InvalidTriggerOrder,
+    #[msg("The oracle is not a Pyth price account")]
+    InvalidOracle,
+    #[msg("The oracle price is not trading or is stale")]
+    OraclePriceUnavailable,
+    #[msg("The trigger order's price condition does not hold")]
+    TriggerConditionNotMet,
+    #[msg("The trigger order has already executed")]
+    TriggerOrderExecuted,
+    #[msg("The accounts do not match the trigger order")]
+    TriggerAccountsMismatch,
+    #[msg("The fill policy's minimum fill is over 100%")]
+    InvalidFillPolicy,
+    #[msg("Less of the input filled than the fill policy requires")]
+    FillBelowMinimum,
+    #[msg("A batch swap needs at least one spec")]
+    EmptyBatch,
+    #[msg("The accounts of a batch swap spec do not match its user")]
+    BatchAccountsMismatch,
+    #[msg("The user neither signed nor delegated the swap's input to the program")]
+    BatchSwapUnauthorized,
+    #[msg("The input wallet holds less than the max amount input")]
+    InsufficientBalance,
+    #[msg("An intent needs a positive amount and an expiry in the future")]
+    InvalidIntent,
+    #[msg("The intent has expired")]
+    IntentExpired,
+    #[msg("The accounts do not match the intent")]
+    IntentAccountsMismatch,
+    #[msg("The recipient must hold the swap's output mint")]
+    RecipientMintMismatch,
+    #[msg("A memo needs the memo program")]
+    MemoProgramRequired,
+    #[msg("The account is not a state")]
+    InvalidState,
+    #[msg("The state is already at the current version")]
+    StateUpToDate,
+    #[msg("The route leg's program is not its venue's")]
+    InvalidDexProgram,
+    #[msg("The market's lot sizes must be positive")]
+    InvalidLotSize,
+    #[msg("The order is less than a lot or priced below a tick")]
+    OrderBelowLotSize,
+    #[msg("The canonical state already exists")]
+    StateAlreadyExists,
+    #[msg("The paying wallet's delegate must be given to restore its allowance")]
+    WalletDelegateRequired,
+}
+
+// A swap's fill, with the input left unfilled.
+#[event]
+pub struct SwapFilled {
+    pub wallet_owner: Pubkey,
+    pub market: Pubkey,
+    pub side: Side,
+    // The swap's input, `amount_in_max`
+    pub amount_in: u64,
+    // Input spent by the fill
+    pub filled: u64,
+    // Input left with the user
+    pub unfilled: u64,
+    // Output received, after an ask's fee
+    pub amount_out: u64,
+}
+
+// A `batch_swap` spec skipped for failing its checks.
+#[event]
+pub struct BatchSwapSkipped {
+    // Position of the spec in the batch
+    pub index: u16,
+    pub wallet_owner: Pubkey,
+    // The `ErrorCode` the spec failed with
+    pub error: u32,
+}
+
+// A transitive swap's fill, with the intermediate quote it spilled.
+#[event]
+pub struct TransitiveSwapFilled {
+    pub wallet_owner: Pubkey,
+    pub from_market: Pubkey,
+    pub to_market: Pubkey,
+    // Base of the `from` market sold
+    pub amount_in: u64,
+    // Quote received for it, before the fee
+    pub intermediate: u64,
+    pub fee: u64,
+    // Base of the `to` market bought
+    pub amount_out: u64,
+    // Quote left over after the buy, sent where `spill_policy` says
+    pub spill: u64,
+    pub spill_policy: SpillPolicy,
+}
+
+// A state grown to the current layout by `migrate_state`.
+#[event]
+pub struct StateMigrated {
+    pub state: Pubkey,
+    // Data length before the realloc, discriminator included
+    pub from_len: u32,
+    pub version: u8,
+    // The keypair state moved into `state` and closed, if any
+    pub legacy_state: Option<Pubkey>,
 }
@@ -0,0 +1,127 @@
+use crate::{ErrorCode, FillPolicy, Side, PRICE_SCALE};
+
+/// Returns the protocol fee on `amount` at `fee_bps`, rounded down.
+pub fn fee_amount(amount: u64, fee_bps: u16) -> u64 {
+    (amount as u128 * fee_bps as u128 / 10_000) as u64
+}
+
+/// Splits `amount` into the protocol fee and the rest routed to the market.
+pub fn split_fee(amount: u64, fee_bps: u16) -> (u64, u64) {
+    let fee = fee_amount(amount, fee_bps);
+    (fee, amount - fee)
+}
+
+/// Returns the amount of lots for the base currency of a trade with `size`, failing on a
+/// market without a lot size.
+pub fn coin_lots(coin_lot_size: u64, size: u64) -> Result<u64, ErrorCode> {
+    size.checked_div(coin_lot_size)
+        .ok_or(ErrorCode::InvalidLotSize)
+}
+
+/// Minimum output for `amount_in` at `min_price`, scaled by `PRICE_SCALE`, saturating.
+pub fn min_amount_out(amount_in: u64, min_price: u64) -> u64 {
+    let amount_out = amount_in as u128 * min_price as u128 / PRICE_SCALE as u128;
+    u64::try_from(amount_out).unwrap_or(u64::MAX)
+}
+
+/// Output for `amount_in` on `side` at `price` in quote per base token, scaled by
+/// `PRICE_SCALE`, between native amounts of the given decimals. Rounded down, saturating.
+pub fn amount_at_price(
+    side: Side,
+    amount_in: u64,
+    price: u64,
+    base_decimals: u8,
+    quote_decimals: u8,
+) -> u64 {
+    let base_unit = 10u128.pow(base_decimals as u32);
+    let quote_unit = 10u128.pow(quote_decimals as u32);
+    let amount_out = match side {
+        // Quote in, base out.
+        Side::Bid => {
+            if price == 0 {
+                return u64::MAX;
+            }
+            amount_in as u128 * PRICE_SCALE as u128 * base_unit / (price as u128 * quote_unit)
+        }
+        // Base in, quote out.
+        Side::Ask => {
+            amount_in as u128 * price as u128 * quote_unit / (PRICE_SCALE as u128 * base_unit)
+        }
+    };
+    u64::try_from(amount_out).unwrap_or(u64::MAX)
+}
+
+/// Whether `amount_out` for `amount_in` is at least `min_price`, scaled by `PRICE_SCALE`.
+pub fn meets_min_price(amount_in: u64, amount_out: u64, min_price: u64) -> bool {
+    amount_out as u128 * PRICE_SCALE as u128 >= amount_in as u128 * min_price as u128
+}
+
+/// Quote per base, scaled by `PRICE_SCALE`, saturating. Zero without any base.
+pub fn price(quote: u64, base: u64) -> u64 {
+    if base == 0 {
+        return 0;
+    }
+    u64::try_from(quote as u128 * PRICE_SCALE as u128 / base as u128).unwrap_or(u64::MAX)
+}
+
+/// Safety checks on the amounts a swap actually spent and received.
+pub fn apply_safety_checks(
+    amount_in_max: u64,
+    amount_out_min: u64,
+    from_amount: u64,
+    to_amount: u64,
+) -> Result<(), ErrorCode> {
+    if amount_in_max < from_amount {
+        return Err(ErrorCode::SwapTokenAmountExceedsMax);
+    }
+    if amount_out_min > to_amount {
+        return Err(ErrorCode::SwapTokenAmountLessThanMin);
+    }
+    if to_amount == 0 {
+        return Err(ErrorCode::ZeroSwap);
+    }
+    Ok(())
+}
+
+/// Compute units Serum spends per order a take matches, including its event queue entry.
+pub const COMPUTE_UNITS_PER_MATCH: u64 = 3_000;
+/// Compute units of a take besides matching: the CPI, loading the market and the transfers.
+pub const TAKE_COMPUTE_UNITS: u64 = 25_000;
+/// Compute units the instruction needs after its last take, for fees and user stats.
+pub const SETTLE_COMPUTE_UNITS: u64 = 20_000;
+
+/// The most orders a take may match so `takes_left` takes, this one included, fit in the
+/// `remaining_compute_units`, sharing them evenly. At least one, at most Serum's maximum.
+pub fn match_limit(remaining_compute_units: u64, takes_left: u64) -> u16 {
+    let per_take = remaining_compute_units.saturating_sub(SETTLE_COMPUTE_UNITS) / takes_left.max(1);
+    let matches = per_take.saturating_sub(TAKE_COMPUTE_UNITS) / COMPUTE_UNITS_PER_MATCH;
+    matches.clamp(1, u16::MAX as u64) as u16
+}
+
+/// Whether a fill spending `from_amount` of `amount_in` for `to_amount` satisfies
+/// `fill_policy`. Fill-or-kill leaves less of the input than a coin lot's worth.
+pub fn meets_fill_policy(
+    fill_policy: FillPolicy,
+    side: Side,
+    amount_in: u64,
+    from_amount: u64,
+    to_amount: u64,
+    coin_lot_size: u64,
+) -> bool {
+    let unfilled = amount_in.saturating_sub(from_amount) as u128;
+    match fill_policy {
+        FillPolicy::FillOrKill => match side {
+            // The quote left over can't buy a lot at the fill's average price, i.e.
+            // `unfilled < coin_lot_size * from_amount / to_amount`.
+            Side::Bid => {
+                unfilled == 0
+                    || unfilled * (to_amount as u128)
+                        < (from_amount as u128) * (coin_lot_size as u128)
+            }
+            Side::Ask => unfilled < coin_lot_size as u128,
+        },
+        FillPolicy::AllowPartial { min_fill_bps } => {
+            from_amount as u128 * 10_000 >= amount_in as u128 * min_fill_bps as u128
+        }
+    }
+}
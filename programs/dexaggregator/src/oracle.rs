@@ -0,0 +1,83 @@
+use anchor_lang::prelude::*;
+
+/// Pyth's oracle program, the owner of the price accounts triggers read.
+pub const PYTH_PROGRAM_ID: Pubkey = pubkey!("FsJ3A3u2vn5cTVofAjvy6y5kwABJAqYWpe4975bi2epH");
+
+// Pyth's price account magic number, version and type.
+const MAGIC: u32 = 0xa1b2c3d4;
+const VERSION_2: u32 = 2;
+const ACCOUNT_TYPE_PRICE: u32 = 3;
+
+// Offsets in a Pyth v2 price account.
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 4;
+const ACCOUNT_TYPE_OFFSET: usize = 8;
+const EXPO_OFFSET: usize = 20;
+const AGG_PRICE_OFFSET: usize = 208;
+const AGG_STATUS_OFFSET: usize = 224;
+const AGG_PUB_SLOT_OFFSET: usize = 232;
+
+// `PriceStatus::Trading`.
+const STATUS_TRADING: u32 = 1;
+
+/// Slots after which an aggregate price is too stale to trigger on.
+pub const MAX_STALENESS_SLOTS: u64 = 25;
+
+/// The aggregate price of a Pyth v2 price account, `price * 10^expo`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OraclePrice {
+    pub price: i64,
+    pub expo: i32,
+    pub publish_slot: u64,
+}
+
+impl OraclePrice {
+    /// Loads the aggregate price, failing unless the account is a trading price account owned
+    /// by the Pyth program.
+    pub fn load(oracle: &AccountInfo) -> Result<Self> {
+        if oracle.owner != &PYTH_PROGRAM_ID {
+            return Err(crate::ErrorCode::InvalidOracle.into());
+        }
+        let data = oracle.try_borrow_data()?;
+        if data.len() < AGG_PUB_SLOT_OFFSET + 8
+            || read_u32(&data, MAGIC_OFFSET) != MAGIC
+            || read_u32(&data, VERSION_OFFSET) != VERSION_2
+            || read_u32(&data, ACCOUNT_TYPE_OFFSET) != ACCOUNT_TYPE_PRICE
+        {
+            return Err(crate::ErrorCode::InvalidOracle.into());
+        }
+        if read_u32(&data, AGG_STATUS_OFFSET) != STATUS_TRADING {
+            return Err(crate::ErrorCode::OraclePriceUnavailable.into());
+        }
+        Ok(OraclePrice {
+            price: i64::from_le_bytes(
+                data[AGG_PRICE_OFFSET..AGG_PRICE_OFFSET + 8]
+                    .try_into()
+                    .unwrap(),
+            ),
+            expo: i32::from_le_bytes(data[EXPO_OFFSET..EXPO_OFFSET + 4].try_into().unwrap()),
+            publish_slot: u64::from_le_bytes(
+                data[AGG_PUB_SLOT_OFFSET..AGG_PUB_SLOT_OFFSET + 8]
+                    .try_into()
+                    .unwrap(),
+            ),
+        })
+    }
+
+    /// The price scaled by `scale`, rounded down, or `None` if negative or out of range.
+    pub fn scaled(&self, scale: u64) -> Option<u64> {
+        let price = u128::try_from(self.price).ok()?;
+        let scaled = if self.expo >= 0 {
+            price
+                .checked_mul(10u128.checked_pow(self.expo as u32)?)?
+                .checked_mul(scale as u128)?
+        } else {
+            price.checked_mul(scale as u128)? / 10u128.checked_pow(self.expo.unsigned_abs())?
+        };
+        u64::try_from(scaled).ok()
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
@@ -0,0 +1,354 @@
+#![allow(dead_code)]
+
+pub mod openbook_v2;
+pub mod phoenix;
+pub mod serum;
+pub mod token_swap;
+pub mod whirlpool;
+
+use std::path::PathBuf;
+
+use anchor_lang::{AccountDeserialize, InstructionData, ToAccountMetas};
+use dexaggregator::UserStats;
+use solana_program_test::{BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::{Account, AccountSharedData, WritableAccount},
+    bpf_loader_upgradeable,
+    compute_budget::ComputeBudgetInstruction,
+    hash::hash,
+    instruction::{AccountMeta, Instruction, InstructionError},
+    program_option::COption,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::{Keypair, Signer},
+    system_instruction, system_program,
+    transaction::{Transaction, TransactionError},
+};
+
+// Directory of the `.so` fixtures of third party programs, dumped from their clusters by
+// `yarn fixtures`, see `scripts/fetch-fixtures.sh`.
+pub fn fixtures_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+// Fails the test unless the `<name>.so` fixture is present, rather than passing untested.
+pub fn require_fixture(name: &str) {
+    let path = fixtures_dir().join(format!("{}.so", name));
+    assert!(
+        path.exists(),
+        "{} not found, fetch the fixtures with `yarn fixtures`",
+        path.display()
+    );
+}
+
+// Program test with the dexaggregator program built by `anchor build`, deployed upgradeable
+// without an upgrade authority until `set_upgrade_authority`.
+pub fn program_test() -> ProgramTest {
+    if std::env::var("SBF_OUT_DIR").is_err() && std::env::var("BPF_OUT_DIR").is_err() {
+        let deploy_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../target/deploy");
+        std::env::set_var("SBF_OUT_DIR", deploy_dir);
+    }
+    let deploy_dir = std::env::var("SBF_OUT_DIR")
+        .or_else(|_| std::env::var("BPF_OUT_DIR"))
+        .unwrap();
+    let elf = std::fs::read(PathBuf::from(deploy_dir).join("dexaggregator.so"))
+        .expect("dexaggregator.so not found, build the program with `anchor build`");
+
+    // `UpgradeableLoaderState::Program`, the u32 tag 2 and the ProgramData address.
+    let mut program = 2u32.to_le_bytes().to_vec();
+    program.extend_from_slice(program_data().as_ref());
+    // `UpgradeableLoaderState::ProgramData`, the u32 tag 3, the deployment slot and no
+    // upgrade authority, padded to the ELF's offset.
+    let mut program_data_account = 3u32.to_le_bytes().to_vec();
+    program_data_account.extend_from_slice(&0u64.to_le_bytes());
+    program_data_account.resize(
+        bpf_loader_upgradeable::UpgradeableLoaderState::size_of_programdata_metadata(),
+        0,
+    );
+    program_data_account.extend_from_slice(&elf);
+
+    let mut program_test = ProgramTest::default();
+    program_test.prefer_bpf(true);
+    let rent = Rent::default();
+    for (address, data, executable) in [
+        (dexaggregator::ID, program, true),
+        (program_data(), program_data_account, false),
+    ] {
+        program_test.add_account(
+            address,
+            Account {
+                lamports: rent.minimum_balance(data.len()),
+                data,
+                owner: bpf_loader_upgradeable::ID,
+                executable,
+                rent_epoch: 0,
+            },
+        );
+    }
+    program_test
+}
+
+pub async fn process(
+    ctx: &mut ProgramTestContext,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), BanksClientError> {
+    let payer = ctx.payer.insecure_clone();
+    let mut all_signers = vec![&payer];
+    all_signers.extend_from_slice(signers);
+    let blockhash = ctx.banks_client.get_latest_blockhash().await?;
+    let tx = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&payer.pubkey()),
+        &all_signers,
+        blockhash,
+    );
+    ctx.banks_client.process_transaction(tx).await
+}
+
+// Creates a rent exempt, zeroed `account` of `space` bytes owned by `owner`.
+pub async fn create_account(
+    ctx: &mut ProgramTestContext,
+    account: &Keypair,
+    space: usize,
+    owner: &Pubkey,
+) {
+    let rent = ctx.banks_client.get_rent().await.unwrap();
+    let ix = system_instruction::create_account(
+        &ctx.payer.pubkey(),
+        &account.pubkey(),
+        rent.minimum_balance(space),
+        space as u64,
+        owner,
+    );
+    process(ctx, &[ix], &[account]).await.unwrap();
+}
+
+// Creates a mint at a fixed address, e.g. `USDC_MINT`, with the payer as its authority.
+pub fn create_mint_at(ctx: &mut ProgramTestContext, address: &Pubkey, decimals: u8) {
+    let mint = spl_token::state::Mint {
+        mint_authority: COption::Some(ctx.payer.pubkey()),
+        supply: 0,
+        decimals,
+        is_initialized: true,
+        freeze_authority: COption::None,
+    };
+    let mut account =
+        AccountSharedData::new(1_000_000_000, spl_token::state::Mint::LEN, &spl_token::ID);
+    spl_token::state::Mint::pack(mint, account.data_as_mut_slice()).unwrap();
+    ctx.set_account(address, &account);
+}
+
+pub async fn create_mint(ctx: &mut ProgramTestContext, decimals: u8) -> Pubkey {
+    let mint = Keypair::new();
+    create_account(ctx, &mint, spl_token::state::Mint::LEN, &spl_token::ID).await;
+    let ix = spl_token::instruction::initialize_mint(
+        &spl_token::ID,
+        &mint.pubkey(),
+        &ctx.payer.pubkey(),
+        None,
+        decimals,
+    )
+    .unwrap();
+    process(ctx, &[ix], &[]).await.unwrap();
+    mint.pubkey()
+}
+
+// Creates the associated token account of `owner` for `mint`.
+pub async fn create_token_account(
+    ctx: &mut ProgramTestContext,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Pubkey {
+    let ix = spl_associated_token_account::instruction::create_associated_token_account(
+        &ctx.payer.pubkey(),
+        owner,
+        mint,
+        &spl_token::ID,
+    );
+    process(ctx, &[ix], &[]).await.unwrap();
+    spl_associated_token_account::get_associated_token_address(owner, mint)
+}
+
+pub async fn mint_to(ctx: &mut ProgramTestContext, mint: &Pubkey, account: &Pubkey, amount: u64) {
+    let ix = spl_token::instruction::mint_to(
+        &spl_token::ID,
+        mint,
+        account,
+        &ctx.payer.pubkey(),
+        &[],
+        amount,
+    )
+    .unwrap();
+    process(ctx, &[ix], &[]).await.unwrap();
+}
+
+pub async fn token_balance(ctx: &mut ProgramTestContext, account: &Pubkey) -> u64 {
+    let account = ctx
+        .banks_client
+        .get_account(*account)
+        .await
+        .unwrap()
+        .unwrap();
+    spl_token::state::Account::unpack(&account.data)
+        .unwrap()
+        .amount
+}
+
+// The delegate of a token account and its allowance, if it has one.
+pub async fn token_delegate(
+    ctx: &mut ProgramTestContext,
+    account: &Pubkey,
+) -> Option<(Pubkey, u64)> {
+    let account = ctx
+        .banks_client
+        .get_account(*account)
+        .await
+        .unwrap()
+        .unwrap();
+    let account = spl_token::state::Account::unpack(&account.data).unwrap();
+    Option::<Pubkey>::from(account.delegate).map(|delegate| (delegate, account.delegated_amount))
+}
+
+pub async fn load_user_stats(ctx: &mut ProgramTestContext, wallet_owner: &Pubkey) -> UserStats {
+    let account = ctx
+        .banks_client
+        .get_account(user_stats(wallet_owner))
+        .await
+        .unwrap()
+        .unwrap();
+    UserStats::try_deserialize(&mut account.data.as_slice()).unwrap()
+}
+
+// Makes `authority` the upgrade authority of the program, which `initialize` and
+// `migrate_state` are gated on.
+pub async fn set_upgrade_authority(ctx: &mut ProgramTestContext, authority: &Pubkey) {
+    let program_data = program_data();
+    let mut account = ctx
+        .banks_client
+        .get_account(program_data)
+        .await
+        .unwrap()
+        .expect("the program isn't deployed upgradeable");
+    // `UpgradeableLoaderState::ProgramData`: the u32 tag 3, the deployment slot, then the
+    // optional upgrade authority.
+    assert_eq!(account.data[..4], 3u32.to_le_bytes());
+    account.data[12] = 1;
+    account.data[13..45].copy_from_slice(authority.as_ref());
+    ctx.set_account(&program_data, &account.into());
+}
+
+// Initializes the program state, owned by the payer, at its default fees. The payer is
+// made the upgrade authority to sign for it.
+pub async fn initialize_state(ctx: &mut ProgramTestContext) -> Pubkey {
+    let payer = ctx.payer.pubkey();
+    set_upgrade_authority(ctx, &payer).await;
+    let state = state();
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::Initialize {
+            state,
+            user: payer,
+            program: dexaggregator::ID,
+            program_data: program_data(),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::Initialize {
+            owner: ctx.payer.pubkey(),
+        }
+        .data(),
+    };
+    process(ctx, &[ix], &[]).await.unwrap();
+    state
+}
+
+// Anchor discriminator of `name`, i.e. sha256("<namespace>:<name>")[..8], computed here
+// rather than copied from the program under test.
+pub fn discriminator(namespace: &str, name: &str) -> [u8; 8] {
+    hash(format!("{}:{}", namespace, name).as_bytes()).to_bytes()[..8]
+        .try_into()
+        .unwrap()
+}
+
+pub fn state() -> Pubkey {
+    Pubkey::find_program_address(&[b"state"], &dexaggregator::ID).0
+}
+
+pub fn program_data() -> Pubkey {
+    bpf_loader_upgradeable::get_program_data_address(&dexaggregator::ID)
+}
+
+pub fn treasury() -> Pubkey {
+    Pubkey::find_program_address(&[b"treasury"], &dexaggregator::ID).0
+}
+
+pub fn user_stats(wallet_owner: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"user_stats", wallet_owner.as_ref()], &dexaggregator::ID).0
+}
+
+// `SwapRoute` accounts of `wallet_owner` for a route ending in `output_mint`, to extend with
+// the legs' accounts.
+pub fn swap_route_accounts(wallet_owner: &Pubkey, output_mint: &Pubkey) -> Vec<AccountMeta> {
+    let treasury = treasury();
+    dexaggregator::accounts::SwapRoute {
+        wallet_owner: *wallet_owner,
+        state: state(),
+        user_stats: user_stats(wallet_owner),
+        fee_exemption: None,
+        program_fee_exemption: None,
+        instructions: None,
+        output_mint: *output_mint,
+        treasury,
+        treasury_output: spl_associated_token_account::get_associated_token_address(
+            &treasury,
+            output_mint,
+        ),
+        system_program: system_program::ID,
+        associated_token_program: spl_associated_token_account::ID,
+        token_program: spl_token::ID,
+    }
+    .to_account_metas(None)
+}
+
+// Asserts the transaction failed with an Anchor or program `ErrorCode`.
+pub fn assert_error(result: Result<(), BanksClientError>, code: impl Into<u32>) {
+    let code = code.into();
+    match result.unwrap_err().unwrap() {
+        TransactionError::InstructionError(_, InstructionError::Custom(error)) => {
+            assert_eq!(error, code)
+        }
+        error => panic!("expected custom error {}, got {:?}", code, error),
+    }
+}
+
+// Compute units `instructions` consume under the maximum limit, simulated without committing.
+pub async fn compute_units(
+    ctx: &mut ProgramTestContext,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> u64 {
+    let payer = ctx.payer.insecure_clone();
+    let mut all_signers = vec![&payer];
+    all_signers.extend_from_slice(signers);
+    let mut all_instructions = vec![ComputeBudgetInstruction::set_compute_unit_limit(1_400_000)];
+    all_instructions.extend_from_slice(instructions);
+    let blockhash = ctx.banks_client.get_latest_blockhash().await.unwrap();
+    let tx = Transaction::new_signed_with_payer(
+        &all_instructions,
+        Some(&payer.pubkey()),
+        &all_signers,
+        blockhash,
+    );
+    let simulation = ctx.banks_client.simulate_transaction(tx).await.unwrap();
+    let details = simulation.simulation_details.unwrap();
+    if let Some(Err(error)) = simulation.result {
+        panic!(
+            "simulation failed: {:?}\n{}",
+            error,
+            details.logs.join("\n")
+        );
+    }
+    details.units_consumed
+}
@@ -0,0 +1,383 @@
+// A stand-in for the OpenBook v2 program, deployed at the aggregator's `openbook_v2::ID`.
+// Its book sides each hold a single level, its price and size in lots, and
+// `place_take_order` fills immediate-or-cancel orders against it, charging the market's
+// taker fee. The instruction, the `Market` layout and the market authority PDA are those of
+// the OpenBook v2 program.
+
+use anchor_lang::{AnchorDeserialize, AnchorSerialize};
+use dexaggregator::dex::openbook_v2;
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    instruction::AccountMeta,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use super::*;
+
+// `PlaceOrderType::ImmediateOrCancel`.
+const ORDER_TYPE_IMMEDIATE_OR_CANCEL: u8 = 1;
+// Scale of the market's fees, in millionths.
+const FEES_SCALE_FACTOR: u128 = 1_000_000;
+// Stand-in error for an order whose limit doesn't cross the level.
+const PRICE_NOT_CROSSED: u32 = 0;
+
+#[derive(AnchorDeserialize)]
+struct PlaceTakeOrderArgs {
+    side: u8,
+    price_lots: i64,
+    max_base_lots: i64,
+    max_quote_lots_including_fees: i64,
+    order_type: u8,
+    _limit: u8,
+}
+
+// The OpenBook v2 program's zero copy `Market`, field for field after its discriminator.
+// Its optional admins and oracles are zeroed when unset.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+struct Market {
+    bump: u8,
+    base_decimals: u8,
+    quote_decimals: u8,
+    padding1: [u8; 5],
+    market_authority: Pubkey,
+    time_expiry: i64,
+    collect_fee_admin: Pubkey,
+    open_orders_admin: Pubkey,
+    consume_events_admin: Pubkey,
+    close_market_admin: Pubkey,
+    name: [u8; 16],
+    bids: Pubkey,
+    asks: Pubkey,
+    event_heap: Pubkey,
+    oracle_a: Pubkey,
+    oracle_b: Pubkey,
+    conf_filter: f64,
+    max_staleness_slots: i64,
+    oracle_config_reserved: [u8; 72],
+    stable_price_model: [u8; 288],
+    quote_lot_size: i64,
+    base_lot_size: i64,
+    seq_num: u64,
+    registration_time: i64,
+    maker_fee: i64,
+    taker_fee: i64,
+    fees_accrued: u128,
+    fees_to_referrers: u128,
+    referrer_rebates_accrued: u64,
+    fees_available: u64,
+    maker_volume: u128,
+    taker_volume_wo_oo: u128,
+    base_mint: Pubkey,
+    quote_mint: Pubkey,
+    market_base_vault: Pubkey,
+    base_deposit_total: u64,
+    market_quote_vault: Pubkey,
+    quote_deposit_total: u64,
+    reserved: [u8; 128],
+}
+
+// A stand-in book side's single level.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+struct Level {
+    price_lots: i64,
+    base_lots: i64,
+}
+
+pub fn add_openbook_v2_standin(program_test: &mut ProgramTest) {
+    // Builtins are only added when BPF programs are not preferred.
+    program_test.prefer_bpf(false);
+    program_test.add_program("openbook_v2_standin", openbook_v2::ID, processor!(process));
+    program_test.prefer_bpf(true);
+}
+
+pub struct StandinMarket {
+    pub market: Pubkey,
+    pub market_authority: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_heap: Pubkey,
+    pub base_vault: Pubkey,
+    pub quote_vault: Pubkey,
+}
+
+impl StandinMarket {
+    // The market's leg accounts, in the order of `openbook_v2::LEG_ACCOUNTS`, without
+    // oracles or an open orders admin.
+    pub fn leg(&self, user_base: Pubkey, user_quote: Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.market, false),
+            AccountMeta::new_readonly(self.market_authority, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+            AccountMeta::new(self.base_vault, false),
+            AccountMeta::new(self.quote_vault, false),
+            AccountMeta::new(self.event_heap, false),
+            AccountMeta::new(user_base, false),
+            AccountMeta::new(user_quote, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+        ]
+    }
+}
+
+fn set_program_account(ctx: &mut ProgramTestContext, address: &Pubkey, data: Vec<u8>) {
+    let account = Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: openbook_v2::ID,
+        executable: false,
+        rent_epoch: 0,
+    };
+    ctx.set_account(address, &account.into());
+}
+
+// A market of the stand-in with `bid` and `ask` resting as (price lots, base lots), its
+// vaults seeded to cover them.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_standin_market(
+    ctx: &mut ProgramTestContext,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+    base_lot_size: i64,
+    quote_lot_size: i64,
+    taker_fee: i64,
+    bid: (i64, i64),
+    ask: (i64, i64),
+) -> StandinMarket {
+    let market = Pubkey::new_unique();
+    let (market_authority, bump) =
+        Pubkey::find_program_address(&[b"Market", market.as_ref()], &openbook_v2::ID);
+    let bids = Pubkey::new_unique();
+    let asks = Pubkey::new_unique();
+    let event_heap = Pubkey::new_unique();
+    let base_vault = create_token_account(ctx, base_mint, &market_authority).await;
+    let quote_vault = create_token_account(ctx, quote_mint, &market_authority).await;
+    mint_to(ctx, base_mint, &base_vault, (ask.1 * base_lot_size) as u64).await;
+    mint_to(
+        ctx,
+        quote_mint,
+        &quote_vault,
+        (bid.0 * bid.1 * quote_lot_size) as u64,
+    )
+    .await;
+
+    let state = Market {
+        bump,
+        base_decimals: 6,
+        quote_decimals: 6,
+        padding1: [0; 5],
+        market_authority,
+        time_expiry: 0,
+        collect_fee_admin: Pubkey::new_unique(),
+        open_orders_admin: Pubkey::default(),
+        consume_events_admin: Pubkey::default(),
+        close_market_admin: Pubkey::default(),
+        name: *b"BASE-QUOTE\0\0\0\0\0\0",
+        bids,
+        asks,
+        event_heap,
+        oracle_a: Pubkey::default(),
+        oracle_b: Pubkey::default(),
+        conf_filter: 0.1,
+        max_staleness_slots: -1,
+        oracle_config_reserved: [0; 72],
+        stable_price_model: [0; 288],
+        quote_lot_size,
+        base_lot_size,
+        seq_num: 0,
+        registration_time: 0,
+        maker_fee: 0,
+        taker_fee,
+        fees_accrued: 0,
+        fees_to_referrers: 0,
+        referrer_rebates_accrued: 0,
+        fees_available: 0,
+        maker_volume: 0,
+        taker_volume_wo_oo: 0,
+        base_mint: *base_mint,
+        quote_mint: *quote_mint,
+        market_base_vault: base_vault,
+        base_deposit_total: 0,
+        market_quote_vault: quote_vault,
+        quote_deposit_total: 0,
+        reserved: [0; 128],
+    };
+    let mut data = discriminator("account", "Market").to_vec();
+    state.serialize(&mut data).unwrap();
+    set_program_account(ctx, &market, data);
+    for (address, (price_lots, base_lots)) in [(bids, bid), (asks, ask)] {
+        let level = Level {
+            price_lots,
+            base_lots,
+        };
+        set_program_account(ctx, &address, level.try_to_vec().unwrap());
+    }
+    set_program_account(ctx, &event_heap, vec![0; 8]);
+
+    StandinMarket {
+        market,
+        market_authority,
+        bids,
+        asks,
+        event_heap,
+        base_vault,
+        quote_vault,
+    }
+}
+
+// Taker fee on `quote` native, rounded up as OpenBook v2 does.
+pub fn taker_fee(quote: u64, taker_fee: i64) -> u64 {
+    (quote as u128 * taker_fee as u128).div_ceil(FEES_SCALE_FACTOR) as u64
+}
+
+fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() < 8 || data[..8] != discriminator("global", "place_take_order") {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let args = PlaceTakeOrderArgs::try_from_slice(&data[8..])?;
+    if args.order_type != ORDER_TYPE_IMMEDIATE_OR_CANCEL
+        || args.price_lots <= 0
+        || args.max_base_lots < 0
+        || args.max_quote_lots_including_fees < 0
+    {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts = &mut accounts.iter();
+    let signer = next_account_info(accounts)?;
+    let _penalty_payer = next_account_info(accounts)?;
+    let market = next_account_info(accounts)?;
+    let market_authority = next_account_info(accounts)?;
+    let bids = next_account_info(accounts)?;
+    let asks = next_account_info(accounts)?;
+    let market_base_vault = next_account_info(accounts)?;
+    let market_quote_vault = next_account_info(accounts)?;
+    let event_heap = next_account_info(accounts)?;
+    let user_base_account = next_account_info(accounts)?;
+    let user_quote_account = next_account_info(accounts)?;
+    let _oracle_a = next_account_info(accounts)?;
+    let _oracle_b = next_account_info(accounts)?;
+    let token_program = next_account_info(accounts)?;
+    let _system_program = next_account_info(accounts)?;
+    let _open_orders_admin = next_account_info(accounts)?;
+
+    if market.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let state = {
+        let data = market.try_borrow_data()?;
+        if data.len() < 8 || data[..8] != discriminator("account", "Market") {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Market::deserialize(&mut &data[8..])?
+    };
+    if *market_authority.key != state.market_authority
+        || *bids.key != state.bids
+        || *asks.key != state.asks
+        || *event_heap.key != state.event_heap
+        || *market_base_vault.key != state.market_base_vault
+        || *market_quote_vault.key != state.market_quote_vault
+        || *token_program.key != spl_token::ID
+    {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if !signer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let base_lot_size = state.base_lot_size as u64;
+    let quote_lot_size = state.quote_lot_size as u64;
+    let (base_lots, quote, fee) = match args.side {
+        // A bid takes the ask level at or below its price, for its quote less the fee.
+        0 => {
+            let level = Level::deserialize(&mut &asks.try_borrow_data()?[..])?;
+            if level.price_lots > args.price_lots {
+                return Err(ProgramError::Custom(PRICE_NOT_CROSSED));
+            }
+            let max_quote_lots = (args.max_quote_lots_including_fees as u128 * FEES_SCALE_FACTOR
+                / (FEES_SCALE_FACTOR + state.taker_fee as u128))
+                as i64;
+            let base_lots = args
+                .max_base_lots
+                .min(max_quote_lots / level.price_lots)
+                .min(level.base_lots);
+            let quote = (base_lots * level.price_lots) as u64 * quote_lot_size;
+            (base_lots, quote, taker_fee(quote, state.taker_fee))
+        }
+        // An ask takes the bid level at or above its price, the fee coming off its quote.
+        1 => {
+            let level = Level::deserialize(&mut &bids.try_borrow_data()?[..])?;
+            if level.price_lots < args.price_lots {
+                return Err(ProgramError::Custom(PRICE_NOT_CROSSED));
+            }
+            let base_lots = args
+                .max_base_lots
+                .min(args.max_quote_lots_including_fees / level.price_lots)
+                .min(level.base_lots);
+            let quote = (base_lots * level.price_lots) as u64 * quote_lot_size;
+            (base_lots, quote, taker_fee(quote, state.taker_fee))
+        }
+        _ => return Err(ProgramError::InvalidInstructionData),
+    };
+    let base = base_lots as u64 * base_lot_size;
+
+    let (user_source, vault_source, amount_in, vault_destination, user_destination, amount_out) =
+        match args.side {
+            0 => (
+                user_quote_account,
+                market_quote_vault,
+                quote + fee,
+                market_base_vault,
+                user_base_account,
+                base,
+            ),
+            _ => (
+                user_base_account,
+                market_base_vault,
+                base,
+                market_quote_vault,
+                user_quote_account,
+                quote - fee,
+            ),
+        };
+    invoke(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            user_source.key,
+            vault_source.key,
+            signer.key,
+            &[],
+            amount_in,
+        )?,
+        &[
+            user_source.clone(),
+            vault_source.clone(),
+            signer.clone(),
+            token_program.clone(),
+        ],
+    )?;
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            vault_destination.key,
+            user_destination.key,
+            market_authority.key,
+            &[],
+            amount_out,
+        )?,
+        &[
+            vault_destination.clone(),
+            user_destination.clone(),
+            market_authority.clone(),
+            token_program.clone(),
+        ],
+        &[&[b"Market", market.key.as_ref(), &[state.bump]]],
+    )
+}
@@ -0,0 +1,327 @@
+// A stand-in for the Phoenix program, deployed at the aggregator's `phoenix::ID`. Its markets
+// quote a single level on both sides, deep enough for any order, so immediate-or-cancel
+// swaps fill at the header's tick size, read as quote lots per base lot, without fees. The
+// `Swap` instruction, the market header, the vault PDAs and the log authority are those of
+// the Phoenix program.
+
+use anchor_lang::{AnchorDeserialize, AnchorSerialize};
+use dexaggregator::dex::phoenix;
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    instruction::AccountMeta,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use super::*;
+
+// `PhoenixInstruction::Swap` tag.
+const SWAP_TAG: u8 = 0;
+// Stand-in error for an order missing its minimum fill.
+const MIN_FILL_NOT_MET: u32 = 0;
+
+#[derive(AnchorDeserialize)]
+enum OrderPacket {
+    PostOnly,
+    Limit,
+    ImmediateOrCancel {
+        side: u8,
+        price_in_ticks: Option<u64>,
+        num_base_lots: u64,
+        num_quote_lots: u64,
+        min_base_lots_to_fill: u64,
+        min_quote_lots_to_fill: u64,
+        self_trade_behavior: u8,
+        match_limit: Option<u64>,
+        client_order_id: u128,
+        use_only_deposited_funds: bool,
+        last_valid_slot: Option<u64>,
+        last_valid_unix_timestamp_in_seconds: Option<u64>,
+    },
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+struct TokenParams {
+    decimals: u32,
+    vault_bump: u32,
+    mint_key: Pubkey,
+    vault_key: Pubkey,
+}
+
+// The Phoenix program's `MarketHeader`, field for field, at the start of a market account.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+struct MarketHeader {
+    discriminant: u64,
+    status: u64,
+    bids_size: u64,
+    asks_size: u64,
+    num_seats: u64,
+    base_params: TokenParams,
+    base_lot_size: u64,
+    quote_params: TokenParams,
+    quote_lot_size: u64,
+    tick_size_in_quote_lots_per_base_unit: u64,
+    authority: Pubkey,
+    fee_recipient: Pubkey,
+    market_sequence_number: u64,
+    successor: Pubkey,
+    raw_base_units_per_base_unit: u32,
+    _padding1: u32,
+    _padding2: [u64; 32],
+}
+
+pub fn add_phoenix_standin(program_test: &mut ProgramTest) {
+    // Builtins are only added when BPF programs are not preferred.
+    program_test.prefer_bpf(false);
+    program_test.add_program("phoenix_standin", phoenix::ID, processor!(process));
+    program_test.prefer_bpf(true);
+}
+
+pub struct Market {
+    pub market: Pubkey,
+    pub base_vault: Pubkey,
+    pub quote_vault: Pubkey,
+}
+
+impl Market {
+    // The market's leg accounts, in the order of `phoenix::LEG_ACCOUNTS`.
+    pub fn leg(&self, user_base: Pubkey, user_quote: Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.base_vault, false),
+            AccountMeta::new(self.quote_vault, false),
+            AccountMeta::new_readonly(log_authority(), false),
+            AccountMeta::new(user_base, false),
+            AccountMeta::new(user_quote, false),
+            AccountMeta::new_readonly(phoenix::ID, false),
+        ]
+    }
+}
+
+pub fn log_authority() -> Pubkey {
+    Pubkey::find_program_address(&[b"log"], &phoenix::ID).0
+}
+
+pub fn vault(market: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault", market.as_ref(), mint.as_ref()], &phoenix::ID)
+}
+
+// Creates a market of the stand-in filling at `price` quote lots per base lot, with its
+// vaults seeded with `base_amount` and `quote_amount`.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_standin_market(
+    ctx: &mut ProgramTestContext,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+    base_lot_size: u64,
+    quote_lot_size: u64,
+    price: u64,
+    base_amount: u64,
+    quote_amount: u64,
+) -> Market {
+    let market = Pubkey::new_unique();
+    let (base_vault, base_vault_bump) = vault(&market, base_mint);
+    let (quote_vault, quote_vault_bump) = vault(&market, quote_mint);
+    set_token_account(ctx, &base_vault, base_mint, &base_vault, base_amount);
+    set_token_account(ctx, &quote_vault, quote_mint, &quote_vault, quote_amount);
+
+    let header = MarketHeader {
+        discriminant: 0,
+        // `MarketStatus::Active`.
+        status: 1,
+        bids_size: 512,
+        asks_size: 512,
+        num_seats: 128,
+        base_params: TokenParams {
+            decimals: 6,
+            vault_bump: base_vault_bump as u32,
+            mint_key: *base_mint,
+            vault_key: base_vault,
+        },
+        base_lot_size,
+        quote_params: TokenParams {
+            decimals: 6,
+            vault_bump: quote_vault_bump as u32,
+            mint_key: *quote_mint,
+            vault_key: quote_vault,
+        },
+        quote_lot_size,
+        tick_size_in_quote_lots_per_base_unit: price,
+        authority: Pubkey::new_unique(),
+        fee_recipient: Pubkey::new_unique(),
+        market_sequence_number: 0,
+        successor: Pubkey::new_unique(),
+        raw_base_units_per_base_unit: 1,
+        _padding1: 0,
+        _padding2: [0; 32],
+    };
+    let account = Account {
+        lamports: 1_000_000_000,
+        data: header.try_to_vec().unwrap(),
+        owner: phoenix::ID,
+        executable: false,
+        rent_epoch: 0,
+    };
+    ctx.set_account(&market, &account.into());
+
+    Market {
+        market,
+        base_vault,
+        quote_vault,
+    }
+}
+
+// Sets a token account at `address`, which needn't be an associated token account.
+fn set_token_account(
+    ctx: &mut ProgramTestContext,
+    address: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+) {
+    let token_account = spl_token::state::Account {
+        mint: *mint,
+        owner: *owner,
+        amount,
+        state: spl_token::state::AccountState::Initialized,
+        ..Default::default()
+    };
+    let mut account = AccountSharedData::new(
+        1_000_000_000,
+        spl_token::state::Account::LEN,
+        &spl_token::ID,
+    );
+    spl_token::state::Account::pack(token_account, account.data_as_mut_slice()).unwrap();
+    ctx.set_account(address, &account);
+}
+
+fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let (tag, packet) = data
+        .split_first()
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    if *tag != SWAP_TAG {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let OrderPacket::ImmediateOrCancel {
+        side,
+        num_base_lots,
+        num_quote_lots,
+        min_base_lots_to_fill,
+        min_quote_lots_to_fill,
+        use_only_deposited_funds: false,
+        ..
+    } = OrderPacket::try_from_slice(packet)?
+    else {
+        return Err(ProgramError::InvalidInstructionData);
+    };
+
+    let accounts = &mut accounts.iter();
+    let _phoenix_program = next_account_info(accounts)?;
+    let log_authority = next_account_info(accounts)?;
+    let market = next_account_info(accounts)?;
+    let trader = next_account_info(accounts)?;
+    let base_account = next_account_info(accounts)?;
+    let quote_account = next_account_info(accounts)?;
+    let base_vault = next_account_info(accounts)?;
+    let quote_vault = next_account_info(accounts)?;
+    let token_program = next_account_info(accounts)?;
+
+    if market.owner != program_id || !market.is_writable {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let header = MarketHeader::deserialize(&mut &market.try_borrow_data()?[..])?;
+    if *log_authority.key != Pubkey::find_program_address(&[b"log"], program_id).0
+        || *base_vault.key != header.base_params.vault_key
+        || *quote_vault.key != header.quote_params.vault_key
+        || *token_program.key != spl_token::ID
+    {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if !trader.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    // Swaps are sized by exactly one of their base and quote lots.
+    if (num_base_lots == 0) == (num_quote_lots == 0) {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let price = header.tick_size_in_quote_lots_per_base_unit;
+    let (base_lots, quote_lots) = match (side, num_base_lots) {
+        // A bid spends up to `num_quote_lots` on whole base lots.
+        (0, 0) => (num_quote_lots / price, num_quote_lots / price * price),
+        // An ask sells as many base lots as needed for `num_quote_lots`.
+        (1, 0) => (num_quote_lots.div_ceil(price), num_quote_lots),
+        (0 | 1, _) => (num_base_lots, num_base_lots * price),
+        _ => return Err(ProgramError::InvalidInstructionData),
+    };
+    if base_lots < min_base_lots_to_fill || quote_lots < min_quote_lots_to_fill {
+        return Err(ProgramError::Custom(MIN_FILL_NOT_MET));
+    }
+    let base_amount = base_lots * header.base_lot_size;
+    let quote_amount = quote_lots * header.quote_lot_size;
+
+    let (user_source, vault_source, amount_in, vault_destination, user_destination, amount_out) =
+        match side {
+            0 => (
+                quote_account,
+                quote_vault,
+                quote_amount,
+                base_vault,
+                base_account,
+                base_amount,
+            ),
+            _ => (
+                base_account,
+                base_vault,
+                base_amount,
+                quote_vault,
+                quote_account,
+                quote_amount,
+            ),
+        };
+    invoke(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            user_source.key,
+            vault_source.key,
+            trader.key,
+            &[],
+            amount_in,
+        )?,
+        &[
+            user_source.clone(),
+            vault_source.clone(),
+            trader.clone(),
+            token_program.clone(),
+        ],
+    )?;
+    let vault_params = match side {
+        0 => &header.base_params,
+        _ => &header.quote_params,
+    };
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            vault_destination.key,
+            user_destination.key,
+            vault_destination.key,
+            &[],
+            amount_out,
+        )?,
+        &[
+            vault_destination.clone(),
+            user_destination.clone(),
+            token_program.clone(),
+        ],
+        &[&[
+            b"vault",
+            market.key.as_ref(),
+            vault_params.mint_key.as_ref(),
+            &[vault_params.vault_bump as u8],
+        ]],
+    )
+}
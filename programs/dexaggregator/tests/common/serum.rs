@@ -0,0 +1,228 @@
+// Serum markets on the DEX dumped to `tests/fixtures/serum_dex.so`, deployed at the
+// aggregator's `dex::ID`.
+
+use std::mem::size_of;
+use std::num::NonZeroU64;
+
+use dexaggregator::dex::{self, serum_dex};
+use serum_dex::instruction::SelfTradeBehavior;
+use serum_dex::matching::{OrderType, Side};
+use solana_program_test::{ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    instruction::AccountMeta,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    sysvar,
+};
+
+use super::*;
+
+// Account sizes, with the DEX's 12 bytes of padding.
+const MARKET_LEN: usize = size_of::<serum_dex::state::MarketState>() + 12;
+const OPEN_ORDERS_LEN: usize = size_of::<serum_dex::state::OpenOrders>() + 12;
+const REQUEST_QUEUE_LEN: usize = 5_120 + 12;
+const EVENT_QUEUE_LEN: usize = 262_144 + 12;
+const BOOK_SIDE_LEN: usize = 65_536 + 12;
+
+// 0.001 base per coin lot and 0.000001 quote per pc lot, both mints at 6 decimals.
+pub const COIN_LOT_SIZE: u64 = 1_000;
+pub const PC_LOT_SIZE: u64 = 1;
+
+pub fn add_serum_dex(program_test: &mut ProgramTest) {
+    program_test.add_program("serum_dex", dex::ID, None);
+}
+
+pub struct SerumMarket {
+    pub market: Pubkey,
+    pub request_queue: Pubkey,
+    pub event_queue: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub coin_vault: Pubkey,
+    pub pc_vault: Pubkey,
+    pub vault_signer: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+}
+
+impl SerumMarket {
+    // The market's accounts for a swap paying out to, or from, `coin_wallet`.
+    pub fn accounts(&self, coin_wallet: Pubkey) -> dexaggregator::accounts::MarketAccounts {
+        dexaggregator::accounts::MarketAccounts {
+            market: self.market,
+            request_queue: self.request_queue,
+            event_queue: self.event_queue,
+            market_bids: self.bids,
+            market_asks: self.asks,
+            coin_vault: self.coin_vault,
+            pc_vault: self.pc_vault,
+            vault_signer: self.vault_signer,
+            coin_wallet,
+        }
+    }
+
+    // The accounts of a `Venue::Serum` route leg, in the order of `serum::LEG_ACCOUNTS`.
+    pub fn leg_accounts(&self, coin_wallet: Pubkey, pc_wallet: Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.request_queue, false),
+            AccountMeta::new(self.event_queue, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+            AccountMeta::new(self.coin_vault, false),
+            AccountMeta::new(self.pc_vault, false),
+            AccountMeta::new_readonly(self.vault_signer, false),
+            AccountMeta::new(coin_wallet, false),
+            AccountMeta::new(pc_wallet, false),
+            AccountMeta::new_readonly(dex::ID, false),
+        ]
+    }
+}
+
+pub async fn create_market(
+    ctx: &mut ProgramTestContext,
+    coin_mint: &Pubkey,
+    pc_mint: &Pubkey,
+) -> SerumMarket {
+    let market = Keypair::new();
+    let request_queue = Keypair::new();
+    let event_queue = Keypair::new();
+    let bids = Keypair::new();
+    let asks = Keypair::new();
+    create_account(ctx, &market, MARKET_LEN, &dex::ID).await;
+    create_account(ctx, &request_queue, REQUEST_QUEUE_LEN, &dex::ID).await;
+    create_account(ctx, &event_queue, EVENT_QUEUE_LEN, &dex::ID).await;
+    create_account(ctx, &bids, BOOK_SIDE_LEN, &dex::ID).await;
+    create_account(ctx, &asks, BOOK_SIDE_LEN, &dex::ID).await;
+
+    let (vault_signer_nonce, vault_signer) = (0u64..)
+        .find_map(|nonce| {
+            serum_dex::state::gen_vault_signer_key(nonce, &market.pubkey(), &dex::ID)
+                .ok()
+                .map(|vault_signer| (nonce, vault_signer))
+        })
+        .unwrap();
+    let coin_vault = create_token_account(ctx, coin_mint, &vault_signer).await;
+    let pc_vault = create_token_account(ctx, pc_mint, &vault_signer).await;
+
+    let ix = serum_dex::instruction::initialize_market(
+        &market.pubkey(),
+        &dex::ID,
+        coin_mint,
+        pc_mint,
+        &coin_vault,
+        &pc_vault,
+        None,
+        None,
+        None,
+        &bids.pubkey(),
+        &asks.pubkey(),
+        &request_queue.pubkey(),
+        &event_queue.pubkey(),
+        COIN_LOT_SIZE,
+        PC_LOT_SIZE,
+        vault_signer_nonce,
+        100,
+    )
+    .unwrap();
+    process(ctx, &[ix], &[]).await.unwrap();
+
+    SerumMarket {
+        market: market.pubkey(),
+        request_queue: request_queue.pubkey(),
+        event_queue: event_queue.pubkey(),
+        bids: bids.pubkey(),
+        asks: asks.pubkey(),
+        coin_vault,
+        pc_vault,
+        vault_signer,
+        coin_mint: *coin_mint,
+        pc_mint: *pc_mint,
+    }
+}
+
+// Rests a maker's order of `coin_lots` at `price_lots`, funded by a fresh maker wallet.
+pub async fn place_maker_order(
+    ctx: &mut ProgramTestContext,
+    market: &SerumMarket,
+    side: Side,
+    price_lots: u64,
+    coin_lots: u64,
+) {
+    let maker = Keypair::new();
+    let open_orders = Keypair::new();
+    create_account(ctx, &open_orders, OPEN_ORDERS_LEN, &dex::ID).await;
+    let pc_qty = price_lots * coin_lots * PC_LOT_SIZE;
+    let (payer_mint, amount) = match side {
+        Side::Bid => (market.pc_mint, pc_qty * 2),
+        Side::Ask => (market.coin_mint, coin_lots * COIN_LOT_SIZE),
+    };
+    let payer = create_token_account(ctx, &payer_mint, &maker.pubkey()).await;
+    mint_to(ctx, &payer_mint, &payer, amount).await;
+
+    let ix = serum_dex::instruction::new_order(
+        &market.market,
+        &open_orders.pubkey(),
+        &market.request_queue,
+        &market.event_queue,
+        &market.bids,
+        &market.asks,
+        &payer,
+        &maker.pubkey(),
+        &market.coin_vault,
+        &market.pc_vault,
+        &spl_token::ID,
+        &sysvar::rent::ID,
+        None,
+        &dex::ID,
+        side,
+        NonZeroU64::new(price_lots).unwrap(),
+        NonZeroU64::new(coin_lots).unwrap(),
+        OrderType::Limit,
+        0,
+        SelfTradeBehavior::DecrementTake,
+        u16::MAX,
+        NonZeroU64::new(pc_qty * 2).unwrap(),
+        i64::MAX,
+    )
+    .unwrap();
+    process(ctx, &[ix], &[&maker]).await.unwrap();
+}
+
+// Offsets in an `OpenOrders` account, after the DEX's 5 bytes of head padding.
+const OPEN_ORDERS_COIN_FREE_OFFSET: usize = 77;
+const OPEN_ORDERS_PC_FREE_OFFSET: usize = 93;
+const OPEN_ORDERS_FREE_SLOT_BITS_OFFSET: usize = 109;
+const OPEN_ORDERS_ORDERS_OFFSET: usize = 141;
+const OPEN_ORDERS_SLOTS: usize = 128;
+
+async fn open_orders_data(ctx: &mut ProgramTestContext, open_orders: &Pubkey) -> Vec<u8> {
+    ctx.banks_client
+        .get_account(*open_orders)
+        .await
+        .unwrap()
+        .unwrap()
+        .data
+}
+
+// The (coin, pc) free balances of an open orders account, awaiting a settle.
+pub async fn open_orders_free(ctx: &mut ProgramTestContext, open_orders: &Pubkey) -> (u64, u64) {
+    let data = open_orders_data(ctx, open_orders).await;
+    let read_u64 = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    (
+        read_u64(OPEN_ORDERS_COIN_FREE_OFFSET),
+        read_u64(OPEN_ORDERS_PC_FREE_OFFSET),
+    )
+}
+
+// The DEX ids of the orders resting on an open orders account.
+pub async fn resting_order_ids(ctx: &mut ProgramTestContext, open_orders: &Pubkey) -> Vec<u128> {
+    let data = open_orders_data(ctx, open_orders).await;
+    let read_u128 =
+        |offset: usize| u128::from_le_bytes(data[offset..offset + 16].try_into().unwrap());
+    let free_slot_bits = read_u128(OPEN_ORDERS_FREE_SLOT_BITS_OFFSET);
+    (0..OPEN_ORDERS_SLOTS)
+        .filter(|slot| free_slot_bits & (1 << slot) == 0)
+        .map(|slot| read_u128(OPEN_ORDERS_ORDERS_OFFSET + 16 * slot))
+        .collect()
+}
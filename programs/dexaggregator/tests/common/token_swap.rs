@@ -0,0 +1,258 @@
+// A stand-in for the SPL token-swap program, deployed at the aggregator's `token_swap::ID`
+// when the mainnet program isn't dumped. It reads the pool from the `SwapV1` layout below
+// and executes `Swap` on the constant product curve, with the trade and owner trade fees
+// kept in the pool instead of minting the owner's share as pool tokens.
+
+use anchor_lang::{AnchorDeserialize, AnchorSerialize};
+use dexaggregator::dex::token_swap;
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    instruction::AccountMeta,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use super::*;
+
+// `SwapInstruction::Swap` tag.
+const SWAP_TAG: u8 = 1;
+// `SwapError::ExceededSlippage`.
+const EXCEEDED_SLIPPAGE: u32 = 16;
+
+// The token-swap program's `Fees`, numerators over denominators.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct Fees {
+    pub trade_fee_numerator: u64,
+    pub trade_fee_denominator: u64,
+    pub owner_trade_fee_numerator: u64,
+    pub owner_trade_fee_denominator: u64,
+    pub owner_withdraw_fee_numerator: u64,
+    pub owner_withdraw_fee_denominator: u64,
+    pub host_fee_numerator: u64,
+    pub host_fee_denominator: u64,
+}
+
+// The token-swap program's versioned `SwapV1` pool state, field for field.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+struct SwapV1 {
+    version: u8,
+    is_initialized: bool,
+    bump_seed: u8,
+    token_program_id: Pubkey,
+    token_a: Pubkey,
+    token_b: Pubkey,
+    pool_mint: Pubkey,
+    token_a_mint: Pubkey,
+    token_b_mint: Pubkey,
+    pool_fee_account: Pubkey,
+    fees: Fees,
+    curve_type: u8,
+    curve_calculator: [u8; 32],
+}
+
+pub fn add_token_swap_standin(program_test: &mut ProgramTest) {
+    // Builtins are only added when BPF programs are not preferred.
+    program_test.prefer_bpf(false);
+    program_test.add_program("token_swap_standin", token_swap::ID, processor!(process));
+    program_test.prefer_bpf(true);
+}
+
+pub struct Pool {
+    pub pool: Pubkey,
+    pub authority: Pubkey,
+    pub token_a: Pubkey,
+    pub token_b: Pubkey,
+    pub pool_mint: Pubkey,
+    pub fee_account: Pubkey,
+}
+
+impl Pool {
+    // The pool's leg accounts, in the order of `token_swap::LEG_ACCOUNTS`.
+    pub fn leg(&self, user_token_a: Pubkey, user_token_b: Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.pool, false),
+            AccountMeta::new_readonly(self.authority, false),
+            AccountMeta::new(self.token_a, false),
+            AccountMeta::new(self.token_b, false),
+            AccountMeta::new(self.pool_mint, false),
+            AccountMeta::new(self.fee_account, false),
+            AccountMeta::new(user_token_a, false),
+            AccountMeta::new(user_token_b, false),
+            AccountMeta::new_readonly(token_swap::ID, false),
+        ]
+    }
+}
+
+// Creates a constant product pool of the stand-in seeded with `amount_a` and `amount_b`.
+pub async fn create_standin_pool(
+    ctx: &mut ProgramTestContext,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+    amount_a: u64,
+    amount_b: u64,
+    fees: Fees,
+) -> Pool {
+    let pool = Pubkey::new_unique();
+    let (authority, bump_seed) = Pubkey::find_program_address(&[pool.as_ref()], &token_swap::ID);
+    let token_a = create_token_account(ctx, mint_a, &authority).await;
+    let token_b = create_token_account(ctx, mint_b, &authority).await;
+    mint_to(ctx, mint_a, &token_a, amount_a).await;
+    mint_to(ctx, mint_b, &token_b, amount_b).await;
+    let pool_mint = create_mint(ctx, 6).await;
+    let fee_account = create_token_account(ctx, &pool_mint, &authority).await;
+
+    let state = SwapV1 {
+        version: 1,
+        is_initialized: true,
+        bump_seed,
+        token_program_id: spl_token::ID,
+        token_a,
+        token_b,
+        pool_mint,
+        token_a_mint: *mint_a,
+        token_b_mint: *mint_b,
+        pool_fee_account: fee_account,
+        fees,
+        // `CurveType::ConstantProduct`, which has no parameters.
+        curve_type: 0,
+        curve_calculator: [0; 32],
+    };
+    let data = state.try_to_vec().unwrap();
+    let account = Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: token_swap::ID,
+        executable: false,
+        rent_epoch: 0,
+    };
+    ctx.set_account(&pool, &account.into());
+
+    Pool {
+        pool,
+        authority,
+        token_a,
+        token_b,
+        pool_mint,
+        fee_account,
+    }
+}
+
+// Fee on `amount`, rounded up to at least 1 when charged, as the token-swap program does.
+fn fee(amount: u128, numerator: u64, denominator: u64) -> u128 {
+    if numerator == 0 || denominator == 0 || amount == 0 {
+        return 0;
+    }
+    (amount * numerator as u128 / denominator as u128).max(1)
+}
+
+// Output of the constant product curve for `amount_in`, after the trade and owner fees.
+pub fn constant_product_out(fees: &Fees, reserve_in: u64, reserve_out: u64, amount_in: u64) -> u64 {
+    let amount_in = amount_in as u128;
+    let fees_in = fee(
+        amount_in,
+        fees.trade_fee_numerator,
+        fees.trade_fee_denominator,
+    ) + fee(
+        amount_in,
+        fees.owner_trade_fee_numerator,
+        fees.owner_trade_fee_denominator,
+    );
+    let amount_in = amount_in.saturating_sub(fees_in);
+    let invariant = reserve_in as u128 * reserve_out as u128;
+    let new_reserve_in = reserve_in as u128 + amount_in;
+    (reserve_out as u128 - invariant.div_ceil(new_reserve_in)) as u64
+}
+
+fn token_amount(account: &AccountInfo) -> Result<u64, ProgramError> {
+    Ok(spl_token::state::Account::unpack(&account.try_borrow_data()?)?.amount)
+}
+
+fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let (tag, args) = data
+        .split_first()
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    if *tag != SWAP_TAG || args.len() != 16 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let amount_in = u64::from_le_bytes(args[..8].try_into().unwrap());
+    let minimum_amount_out = u64::from_le_bytes(args[8..].try_into().unwrap());
+
+    let accounts = &mut accounts.iter();
+    let pool = next_account_info(accounts)?;
+    let authority = next_account_info(accounts)?;
+    let user_transfer_authority = next_account_info(accounts)?;
+    let source = next_account_info(accounts)?;
+    let pool_source = next_account_info(accounts)?;
+    let pool_destination = next_account_info(accounts)?;
+    let destination = next_account_info(accounts)?;
+    let pool_mint = next_account_info(accounts)?;
+    let pool_fee_account = next_account_info(accounts)?;
+    let token_program = next_account_info(accounts)?;
+
+    if pool.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let state = SwapV1::deserialize(&mut &pool.try_borrow_data()?[..])?;
+    let pool_accounts = [*pool_source.key, *pool_destination.key];
+    if *authority.key
+        != Pubkey::create_program_address(&[pool.key.as_ref(), &[state.bump_seed]], program_id)?
+        || !(pool_accounts == [state.token_a, state.token_b]
+            || pool_accounts == [state.token_b, state.token_a])
+        || *pool_mint.key != state.pool_mint
+        || *pool_fee_account.key != state.pool_fee_account
+        || *token_program.key != state.token_program_id
+    {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if !user_transfer_authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let amount_out = constant_product_out(
+        &state.fees,
+        token_amount(pool_source)?,
+        token_amount(pool_destination)?,
+        amount_in,
+    );
+    if amount_out < minimum_amount_out {
+        return Err(ProgramError::Custom(EXCEEDED_SLIPPAGE));
+    }
+    invoke(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            source.key,
+            pool_source.key,
+            user_transfer_authority.key,
+            &[],
+            amount_in,
+        )?,
+        &[
+            source.clone(),
+            pool_source.clone(),
+            user_transfer_authority.clone(),
+            token_program.clone(),
+        ],
+    )?;
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            pool_destination.key,
+            destination.key,
+            authority.key,
+            &[],
+            amount_out,
+        )?,
+        &[
+            pool_destination.clone(),
+            destination.clone(),
+            authority.clone(),
+            token_program.clone(),
+        ],
+        &[&[pool.key.as_ref(), &[state.bump_seed]]],
+    )
+}
@@ -0,0 +1,303 @@
+// A stand-in for the Whirlpool program, deployed at the aggregator's `whirlpool::ID`. Its
+// pools hold all their liquidity at the current price, so `swap` fills any amount at the
+// pool's square root price, without fees. The instruction, the pool's fields up to its
+// vaults and the oracle PDA are those of the Whirlpool program.
+
+use anchor_lang::{AnchorDeserialize, AnchorSerialize};
+use dexaggregator::dex::whirlpool;
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    instruction::AccountMeta,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use super::*;
+
+// Bounds of the Q64.64 square root price.
+const MIN_SQRT_PRICE_X64: u128 = 4_295_048_016;
+const MAX_SQRT_PRICE_X64: u128 = 79_226_673_515_401_279_992_447_579_055;
+
+const TICK_SPACING: u16 = 64;
+
+// Stand-in errors, for a price limit on the wrong side of the pool price and for a swap
+// missing its other amount threshold.
+const INVALID_SQRT_PRICE_LIMIT: u32 = 0;
+const AMOUNT_THRESHOLD_EXCEEDED: u32 = 1;
+
+#[derive(AnchorDeserialize)]
+struct SwapArgs {
+    amount: u64,
+    other_amount_threshold: u64,
+    sqrt_price_limit: u128,
+    amount_specified_is_input: bool,
+    a_to_b: bool,
+}
+
+// The leading fields of the Whirlpool program's `Whirlpool` account, after its
+// discriminator, up to the vaults.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+struct WhirlpoolHead {
+    whirlpools_config: Pubkey,
+    whirlpool_bump: [u8; 1],
+    tick_spacing: u16,
+    tick_spacing_seed: [u8; 2],
+    fee_rate: u16,
+    protocol_fee_rate: u16,
+    liquidity: u128,
+    sqrt_price: u128,
+    tick_current_index: i32,
+    protocol_fee_owed_a: u64,
+    protocol_fee_owed_b: u64,
+    token_mint_a: Pubkey,
+    token_vault_a: Pubkey,
+    fee_growth_global_a: u128,
+    token_mint_b: Pubkey,
+    token_vault_b: Pubkey,
+}
+
+pub fn add_whirlpool_standin(program_test: &mut ProgramTest) {
+    // Builtins are only added when BPF programs are not preferred.
+    program_test.prefer_bpf(false);
+    program_test.add_program("whirlpool_standin", whirlpool::ID, processor!(process));
+    program_test.prefer_bpf(true);
+}
+
+pub struct Whirlpool {
+    pub whirlpool: Pubkey,
+    pub vault_a: Pubkey,
+    pub vault_b: Pubkey,
+    pub oracle: Pubkey,
+}
+
+impl Whirlpool {
+    // The pool's leg accounts, in the order of `whirlpool::LEG_ACCOUNTS`. The stand-in
+    // crosses no ticks, so any accounts do for the tick arrays.
+    pub fn leg(&self, user_token_a: Pubkey, user_token_b: Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.whirlpool, false),
+            AccountMeta::new(self.vault_a, false),
+            AccountMeta::new(self.vault_b, false),
+            AccountMeta::new(Pubkey::new_unique(), false),
+            AccountMeta::new(Pubkey::new_unique(), false),
+            AccountMeta::new(Pubkey::new_unique(), false),
+            AccountMeta::new_readonly(self.oracle, false),
+            AccountMeta::new(user_token_a, false),
+            AccountMeta::new(user_token_b, false),
+            AccountMeta::new_readonly(whirlpool::ID, false),
+        ]
+    }
+}
+
+// Creates a pool of the stand-in at `sqrt_price` b per a, in Q64.64, seeded with `amount_a`
+// and `amount_b`.
+pub async fn create_standin_whirlpool(
+    ctx: &mut ProgramTestContext,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+    sqrt_price: u128,
+    amount_a: u64,
+    amount_b: u64,
+) -> Whirlpool {
+    // Pools are PDAs of the program, which signs for their vaults as the pool.
+    let whirlpools_config = Pubkey::new_unique();
+    let (whirlpool, bump) = Pubkey::find_program_address(
+        &[
+            b"whirlpool",
+            whirlpools_config.as_ref(),
+            mint_a.as_ref(),
+            mint_b.as_ref(),
+            &TICK_SPACING.to_le_bytes(),
+        ],
+        &whirlpool::ID,
+    );
+    let vault_a = create_token_account(ctx, mint_a, &whirlpool).await;
+    let vault_b = create_token_account(ctx, mint_b, &whirlpool).await;
+    mint_to(ctx, mint_a, &vault_a, amount_a).await;
+    mint_to(ctx, mint_b, &vault_b, amount_b).await;
+
+    let head = WhirlpoolHead {
+        whirlpools_config,
+        whirlpool_bump: [bump],
+        tick_spacing: TICK_SPACING,
+        tick_spacing_seed: TICK_SPACING.to_le_bytes(),
+        fee_rate: 0,
+        protocol_fee_rate: 0,
+        liquidity: u64::MAX as u128,
+        sqrt_price,
+        tick_current_index: 0,
+        protocol_fee_owed_a: 0,
+        protocol_fee_owed_b: 0,
+        token_mint_a: *mint_a,
+        token_vault_a: vault_a,
+        fee_growth_global_a: 0,
+        token_mint_b: *mint_b,
+        token_vault_b: vault_b,
+    };
+    let mut data = discriminator("account", "Whirlpool").to_vec();
+    head.serialize(&mut data).unwrap();
+    let account = Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: whirlpool::ID,
+        executable: false,
+        rent_epoch: 0,
+    };
+    ctx.set_account(&whirlpool, &account.into());
+
+    Whirlpool {
+        whirlpool,
+        vault_a,
+        vault_b,
+        oracle: Pubkey::find_program_address(&[b"oracle", whirlpool.as_ref()], &whirlpool::ID).0,
+    }
+}
+
+// Price of a in b, in Q64.64, of the Q64.64 `sqrt_price`.
+fn price_x64(sqrt_price: u128) -> u128 {
+    (sqrt_price >> 32) * (sqrt_price >> 32)
+}
+
+// Amount of b for `amount_a` at `sqrt_price`, rounded down.
+pub fn b_for_a(sqrt_price: u128, amount_a: u64) -> u64 {
+    ((amount_a as u128 * price_x64(sqrt_price)) >> 64) as u64
+}
+
+// Amount of a for `amount_b` at `sqrt_price`, rounded down.
+pub fn a_for_b(sqrt_price: u128, amount_b: u64) -> u64 {
+    (((amount_b as u128) << 64) / price_x64(sqrt_price)) as u64
+}
+
+fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() < 8 || data[..8] != discriminator("global", "swap") {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let args = SwapArgs::deserialize(&mut &data[8..])?;
+
+    let accounts = &mut accounts.iter();
+    let token_program = next_account_info(accounts)?;
+    let token_authority = next_account_info(accounts)?;
+    let whirlpool = next_account_info(accounts)?;
+    let token_owner_account_a = next_account_info(accounts)?;
+    let token_vault_a = next_account_info(accounts)?;
+    let token_owner_account_b = next_account_info(accounts)?;
+    let token_vault_b = next_account_info(accounts)?;
+    let _tick_arrays = [
+        next_account_info(accounts)?,
+        next_account_info(accounts)?,
+        next_account_info(accounts)?,
+    ];
+    let oracle = next_account_info(accounts)?;
+
+    if whirlpool.owner != program_id || !whirlpool.is_writable {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let head = {
+        let data = whirlpool.try_borrow_data()?;
+        if data.len() < 8 || data[..8] != discriminator("account", "Whirlpool") {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        WhirlpoolHead::deserialize(&mut &data[8..])?
+    };
+    let (oracle_address, _) =
+        Pubkey::find_program_address(&[b"oracle", whirlpool.key.as_ref()], program_id);
+    if *token_vault_a.key != head.token_vault_a
+        || *token_vault_b.key != head.token_vault_b
+        || *oracle.key != oracle_address
+    {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if !token_authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let limit_in_bounds =
+        (MIN_SQRT_PRICE_X64..=MAX_SQRT_PRICE_X64).contains(&args.sqrt_price_limit);
+    let limit_past_price = match args.a_to_b {
+        true => args.sqrt_price_limit < head.sqrt_price,
+        false => args.sqrt_price_limit > head.sqrt_price,
+    };
+    if !limit_in_bounds || !limit_past_price {
+        return Err(ProgramError::Custom(INVALID_SQRT_PRICE_LIMIT));
+    }
+
+    let out_for_in = |amount_in| match args.a_to_b {
+        true => b_for_a(head.sqrt_price, amount_in),
+        false => a_for_b(head.sqrt_price, amount_in),
+    };
+    let in_for_out = |amount_out| match args.a_to_b {
+        true => a_for_b(head.sqrt_price, amount_out) + 1,
+        false => b_for_a(head.sqrt_price, amount_out) + 1,
+    };
+    let (amount_in, amount_out) = if args.amount_specified_is_input {
+        let amount_out = out_for_in(args.amount);
+        if amount_out < args.other_amount_threshold {
+            return Err(ProgramError::Custom(AMOUNT_THRESHOLD_EXCEEDED));
+        }
+        (args.amount, amount_out)
+    } else {
+        let amount_in = in_for_out(args.amount);
+        if amount_in > args.other_amount_threshold {
+            return Err(ProgramError::Custom(AMOUNT_THRESHOLD_EXCEEDED));
+        }
+        (amount_in, args.amount)
+    };
+
+    let (user_source, vault_source, vault_destination, user_destination) = match args.a_to_b {
+        true => (
+            token_owner_account_a,
+            token_vault_a,
+            token_vault_b,
+            token_owner_account_b,
+        ),
+        false => (
+            token_owner_account_b,
+            token_vault_b,
+            token_vault_a,
+            token_owner_account_a,
+        ),
+    };
+    invoke(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            user_source.key,
+            vault_source.key,
+            token_authority.key,
+            &[],
+            amount_in,
+        )?,
+        &[
+            user_source.clone(),
+            vault_source.clone(),
+            token_authority.clone(),
+            token_program.clone(),
+        ],
+    )?;
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            vault_destination.key,
+            user_destination.key,
+            whirlpool.key,
+            &[],
+            amount_out,
+        )?,
+        &[
+            vault_destination.clone(),
+            user_destination.clone(),
+            whirlpool.clone(),
+            token_program.clone(),
+        ],
+        &[&[
+            b"whirlpool",
+            head.whirlpools_config.as_ref(),
+            head.token_mint_a.as_ref(),
+            head.token_mint_b.as_ref(),
+            &head.tick_spacing_seed,
+            &head.whirlpool_bump,
+        ]],
+    )
+}
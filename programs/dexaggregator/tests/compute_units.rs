@@ -0,0 +1,487 @@
+//! Compute units of `swap`, `swap_transitive` and `swap_route` through Serum markets over a
+//! range of book depths, failing when a case exceeds its budget in `tests/compute_budget.txt`.
+//!
+//! Under the maximum compute unit limit the match limit derived from the remaining budget
+//! exceeds every depth, so a take matches every resting order it crosses, unless the case
+//! passes a `match_limit`. Limited asks of `swap_transitive` and `swap_route` are fill or
+//! kill, so their input is what the limit can fill, while `swap` asks fill partially. The
+//! markets are cached up front, and the `_cached` cases read their `MarketCache` PDAs,
+//! measuring what the cache saves over loading the markets. Print the measurements with
+//! `cargo test --test compute_units -- --nocapture`, they're also written to
+//! `compute_units.txt` in the target directory. Rewrite the budgets from a run with
+//! `UPDATE_COMPUTE_BUDGET=1 cargo test --test compute_units`, each set `BUDGET_HEADROOM_BPS`
+//! above its measurement.
+
+mod common;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use anchor_lang::{InstructionData, ToAccountMetas};
+use common::serum::*;
+use common::*;
+use dexaggregator::dex::{self, serum_dex};
+use dexaggregator::{FillPolicy, RouteLeg, Side, Venue, USDC_MINT};
+use solana_program_test::ProgramTestContext;
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::Signer,
+    system_program,
+};
+use spl_associated_token_account::get_associated_token_address;
+
+// Numbers of resting orders a take crosses.
+const DEPTHS: [u64; 3] = [1, 8, 32];
+// Coin lots of each resting order.
+const ORDER_LOTS: u64 = 10;
+// Headroom of a rewritten budget over its measurement.
+const BUDGET_HEADROOM_BPS: u64 = 1_000;
+
+// Best prices of the books, in pc lots per coin lot, each deeper order one lot worse.
+struct Prices {
+    ask: u64,
+    bid: u64,
+}
+
+const BTC_PRICES: Prices = Prices {
+    ask: 10_000,
+    bid: 9_000,
+};
+// Cheaper than BTC, so the proceeds of a BTC sale cross the whole ETH ask side.
+const ETH_PRICES: Prices = Prices {
+    ask: 4_000,
+    bid: 3_000,
+};
+
+struct Bench {
+    ctx: ProgramTestContext,
+    state: Pubkey,
+    btc: SerumMarket,
+    eth: SerumMarket,
+    user: Pubkey,
+    user_usdc: Pubkey,
+    user_btc: Pubkey,
+    user_eth: Pubkey,
+    treasury_usdc: Pubkey,
+    depth: u64,
+}
+
+async fn bench(depth: u64) -> Bench {
+    let mut program_test = program_test();
+    add_serum_dex(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    create_mint_at(&mut ctx, &USDC_MINT, 6);
+    let btc_mint = create_mint(&mut ctx, 6).await;
+    let eth_mint = create_mint(&mut ctx, 6).await;
+    let btc = create_market(&mut ctx, &btc_mint, &USDC_MINT).await;
+    let eth = create_market(&mut ctx, &eth_mint, &USDC_MINT).await;
+    for (market, prices) in [(&btc, &BTC_PRICES), (&eth, &ETH_PRICES)] {
+        for level in 0..depth {
+            let ask = prices.ask + level;
+            let bid = prices.bid - level;
+            place_maker_order(
+                &mut ctx,
+                market,
+                serum_dex::matching::Side::Ask,
+                ask,
+                ORDER_LOTS,
+            )
+            .await;
+            place_maker_order(
+                &mut ctx,
+                market,
+                serum_dex::matching::Side::Bid,
+                bid,
+                ORDER_LOTS,
+            )
+            .await;
+        }
+    }
+
+    let state = initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    for market in [&btc, &eth] {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::CacheMarket {
+                market: market.market,
+                dex_program: dex::ID,
+                market_cache: market_cache(&market.market),
+                payer: user,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::CacheMarket {}.data(),
+        };
+        process(&mut ctx, &[ix], &[]).await.unwrap();
+    }
+    let user_usdc = create_token_account(&mut ctx, &USDC_MINT, &user).await;
+    let user_btc = create_token_account(&mut ctx, &btc_mint, &user).await;
+    let user_eth = create_token_account(&mut ctx, &eth_mint, &user).await;
+    mint_to(&mut ctx, &USDC_MINT, &user_usdc, 1_000_000_000).await;
+    mint_to(&mut ctx, &btc_mint, &user_btc, 1_000_000_000).await;
+    mint_to(&mut ctx, &eth_mint, &user_eth, 1_000_000_000).await;
+    let treasury_usdc = create_token_account(&mut ctx, &USDC_MINT, &treasury()).await;
+
+    Bench {
+        ctx,
+        state,
+        btc,
+        eth,
+        user,
+        user_usdc,
+        user_btc,
+        user_eth,
+        treasury_usdc,
+        depth,
+    }
+}
+
+impl Bench {
+    // Quote crossing every ask of `prices`, with room for the protocol and taker fees.
+    fn bid_amount(&self, prices: &Prices) -> u64 {
+        let quote: u64 = (0..self.depth)
+            .map(|level| (prices.ask + level) * ORDER_LOTS * PC_LOT_SIZE)
+            .sum();
+        quote * 11 / 10
+    }
+
+    // Base crossing every bid.
+    fn ask_amount(&self) -> u64 {
+        self.depth * ORDER_LOTS * COIN_LOT_SIZE
+    }
+
+    // Base crossing the bids a fill-or-kill ask of `match_limit` matches can take, the DEX
+    // failing one it can't fill.
+    fn limited_ask_amount(&self, match_limit: u16) -> u64 {
+        self.depth.min(match_limit as u64) * ORDER_LOTS * COIN_LOT_SIZE
+    }
+
+    // `swap` on the BTC market, reading its `MarketCache` if `cached`. An ask with a
+    // `match_limit` may fill partially, crossing only as many bids as the limit allows.
+    fn swap(
+        &self,
+        side: Side,
+        amount_in_max: u64,
+        match_limit: Option<u16>,
+        cached: bool,
+    ) -> Instruction {
+        let fill_policy = (side == Side::Ask && match_limit.is_some())
+            .then_some(FillPolicy::AllowPartial { min_fill_bps: 0 });
+        Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::Swap {
+                market: self.btc.accounts(self.user_btc),
+                market_config: None,
+                market_cache: cached.then(|| market_cache(&self.btc.market)),
+                wallet_owner: self.user,
+                state: self.state,
+                user_stats: user_stats(&self.user),
+                fee_exemption: None,
+                program_fee_exemption: None,
+                instructions: None,
+                mint_a: USDC_MINT,
+                treasury: treasury(),
+                treasury_usdc: self.treasury_usdc,
+                referral: None,
+                pc_wallet: self.user_usdc,
+                recipient: None,
+                memo_program: None,
+                open_orders: None,
+                rent: None,
+                wallet_delegate: None,
+                system_program: system_program::ID,
+                associated_token_program: spl_associated_token_account::ID,
+                dex_program: dex::ID,
+                token_program: spl_token::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::Swap {
+                side,
+                amount_in_max,
+                amount_out_min: 0,
+                execution_mode: None,
+                match_limit,
+                fill_policy,
+                memo: None,
+            }
+            .data(),
+        }
+    }
+
+    // `swap_transitive` from BTC to ETH, reading the markets' `MarketCache`s if `cached`.
+    fn swap_transitive(
+        &self,
+        cached: bool,
+        from_match_limit: Option<u16>,
+        to_match_limit: Option<u16>,
+    ) -> Instruction {
+        let amount_in_max = from_match_limit.map_or(self.ask_amount(), |match_limit| {
+            self.limited_ask_amount(match_limit)
+        });
+        Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::SwapTransitive {
+                from: self.btc.accounts(self.user_btc),
+                to: self.eth.accounts(self.user_eth),
+                from_market_config: None,
+                to_market_config: None,
+                from_market_cache: cached.then(|| market_cache(&self.btc.market)),
+                to_market_cache: cached.then(|| market_cache(&self.eth.market)),
+                wallet_owner: self.user,
+                state: self.state,
+                user_stats: user_stats(&self.user),
+                fee_exemption: None,
+                program_fee_exemption: None,
+                instructions: None,
+                pc_wallet: self.user_usdc,
+                treasury: treasury(),
+                treasury_usdc: self.treasury_usdc,
+                referral: None,
+                recipient: None,
+                memo_program: None,
+                dex_program: dex::ID,
+                token_program: spl_token::ID,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::SwapTransitive {
+                amount_in_max,
+                amount_out_min: 0,
+                from_match_limit,
+                to_match_limit,
+                spill_policy: None,
+                memo: None,
+            }
+            .data(),
+        }
+    }
+
+    // `swap_route` through Serum legs, each limited to `match_limit` matches if given.
+    fn swap_route(
+        &self,
+        output_mint: Pubkey,
+        legs: Vec<(Side, Vec<AccountMeta>)>,
+        amount_in: u64,
+        match_limit: Option<u16>,
+    ) -> Instruction {
+        let mut accounts = dexaggregator::accounts::SwapRoute {
+            wallet_owner: self.user,
+            state: self.state,
+            user_stats: user_stats(&self.user),
+            fee_exemption: None,
+            program_fee_exemption: None,
+            instructions: None,
+            output_mint,
+            treasury: treasury(),
+            treasury_output: get_associated_token_address(&treasury(), &output_mint),
+            system_program: system_program::ID,
+            associated_token_program: spl_associated_token_account::ID,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None);
+        let legs = legs
+            .into_iter()
+            .map(|(side, leg_accounts)| {
+                accounts.extend(leg_accounts);
+                RouteLeg {
+                    venue: Venue::Serum,
+                    side,
+                    match_limit,
+                }
+            })
+            .collect();
+        Instruction {
+            program_id: dexaggregator::ID,
+            accounts,
+            data: dexaggregator::instruction::SwapRoute {
+                legs,
+                amount_in,
+                amount_out_min: 0,
+            }
+            .data(),
+        }
+    }
+
+    // Compute units of every case at this depth, each simulated against the same books.
+    async fn measure(&mut self) -> Vec<(String, u64)> {
+        let cases = [
+            (
+                "swap_bid",
+                self.swap(Side::Bid, self.bid_amount(&BTC_PRICES), None, false),
+            ),
+            (
+                "swap_ask",
+                self.swap(Side::Ask, self.ask_amount(), None, false),
+            ),
+            (
+                "swap_bid_match_limit_4",
+                self.swap(Side::Bid, self.bid_amount(&BTC_PRICES), Some(4), false),
+            ),
+            (
+                "swap_bid_cached",
+                self.swap(Side::Bid, self.bid_amount(&BTC_PRICES), None, true),
+            ),
+            (
+                "swap_ask_match_limit_4",
+                self.swap(Side::Ask, self.ask_amount(), Some(4), false),
+            ),
+            ("swap_transitive", self.swap_transitive(false, None, None)),
+            (
+                "swap_transitive_from_match_limit_4",
+                self.swap_transitive(false, Some(4), None),
+            ),
+            (
+                "swap_transitive_to_match_limit_4",
+                self.swap_transitive(false, None, Some(4)),
+            ),
+            (
+                "swap_transitive_cached",
+                self.swap_transitive(true, None, None),
+            ),
+            (
+                "swap_route_one_leg",
+                self.swap_route(
+                    self.btc.coin_mint,
+                    vec![(
+                        Side::Bid,
+                        self.btc.leg_accounts(self.user_btc, self.user_usdc),
+                    )],
+                    self.bid_amount(&BTC_PRICES),
+                    None,
+                ),
+            ),
+            (
+                "swap_route_one_leg_match_limit_4",
+                self.swap_route(
+                    self.btc.coin_mint,
+                    vec![(
+                        Side::Bid,
+                        self.btc.leg_accounts(self.user_btc, self.user_usdc),
+                    )],
+                    self.bid_amount(&BTC_PRICES),
+                    Some(4),
+                ),
+            ),
+            (
+                "swap_route_two_legs",
+                self.swap_route(
+                    self.eth.coin_mint,
+                    vec![
+                        (
+                            Side::Ask,
+                            self.btc.leg_accounts(self.user_btc, self.user_usdc),
+                        ),
+                        (
+                            Side::Bid,
+                            self.eth.leg_accounts(self.user_eth, self.user_usdc),
+                        ),
+                    ],
+                    self.ask_amount(),
+                    None,
+                ),
+            ),
+            (
+                "swap_route_two_legs_match_limit_4",
+                self.swap_route(
+                    self.eth.coin_mint,
+                    vec![
+                        (
+                            Side::Ask,
+                            self.btc.leg_accounts(self.user_btc, self.user_usdc),
+                        ),
+                        (
+                            Side::Bid,
+                            self.eth.leg_accounts(self.user_eth, self.user_usdc),
+                        ),
+                    ],
+                    self.limited_ask_amount(4),
+                    Some(4),
+                ),
+            ),
+        ];
+        let mut units = vec![];
+        for (name, ix) in cases {
+            let consumed = compute_units(&mut self.ctx, &[ix], &[]).await;
+            units.push((format!("{}_depth_{}", name, self.depth), consumed));
+        }
+        units
+    }
+}
+
+fn market_cache(market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"market_cache", market.as_ref()], &dexaggregator::ID).0
+}
+
+fn budget_path() -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compute_budget.txt")
+}
+
+// Budgets from `tests/compute_budget.txt`, by case.
+fn budgets() -> HashMap<String, u64> {
+    std::fs::read_to_string(budget_path())
+        .unwrap()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (case, budget) = line.split_once(char::is_whitespace).unwrap();
+            (case.to_string(), budget.trim().parse().unwrap())
+        })
+        .collect()
+}
+
+// Rewrites `tests/compute_budget.txt` from `measured`, keeping its header and rounding each
+// budget with its headroom up to a thousand units.
+fn write_budgets(measured: &[(String, u64)]) {
+    let mut file: String = std::fs::read_to_string(budget_path())
+        .unwrap()
+        .lines()
+        .take_while(|line| line.starts_with('#'))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    for (case, units) in measured {
+        let budget = (units * (10_000 + BUDGET_HEADROOM_BPS) / 10_000).div_ceil(1_000) * 1_000;
+        writeln!(file, "{} {}", case, budget).unwrap();
+    }
+    std::fs::write(budget_path(), file).unwrap();
+}
+
+#[tokio::test]
+async fn compute_units_within_budget() {
+    require_fixture("serum_dex");
+    let mut measured = vec![];
+    for depth in DEPTHS {
+        let mut bench = bench(depth).await;
+        measured.extend(bench.measure().await);
+    }
+    if std::env::var_os("UPDATE_COMPUTE_BUDGET").is_some() {
+        write_budgets(&measured);
+    }
+
+    let budgets = budgets();
+    let mut report = String::new();
+    let mut exceeded = vec![];
+    for (case, units) in measured {
+        let budget = *budgets.get(&case).unwrap_or_else(|| {
+            panic!(
+                "no budget for {} in tests/compute_budget.txt, rewrite it with \
+                 UPDATE_COMPUTE_BUDGET=1",
+                case
+            )
+        });
+        writeln!(report, "{:<32} {:>9} / {:>9}", case, units, budget).unwrap();
+        if units > budget {
+            exceeded.push(case);
+        }
+    }
+    println!("{}", report);
+    std::fs::write(
+        std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("compute_units.txt"),
+        &report,
+    )
+    .unwrap();
+    assert!(exceeded.is_empty(), "over budget: {:?}", exceeded);
+}
@@ -0,0 +1,180 @@
+//! Invariants of the fee, lot and slippage arithmetic.
+
+use dexaggregator::math::*;
+use dexaggregator::{ErrorCode, FillPolicy, Side, PRICE_SCALE};
+use proptest::prelude::*;
+
+proptest! {
+    #[test]
+    fn fee_and_routed_add_up_to_the_input(amount: u64, fee_bps in 0u16..=10_000) {
+        let (fee, routed) = split_fee(amount, fee_bps);
+        prop_assert_eq!(fee + routed, amount);
+        prop_assert_eq!(fee, fee_amount(amount, fee_bps));
+    }
+
+    #[test]
+    fn fee_rounds_down_by_less_than_a_unit(amount: u64, fee_bps in 0u16..=10_000) {
+        let exact = amount as u128 * fee_bps as u128;
+        let fee = fee_amount(amount, fee_bps) as u128;
+        prop_assert!(fee * 10_000 <= exact);
+        prop_assert!(exact < (fee + 1) * 10_000);
+    }
+
+    #[test]
+    fn fee_is_monotonic_in_the_amount(a: u64, b: u64, fee_bps in 0u16..=10_000) {
+        let (small, large) = (a.min(b), a.max(b));
+        prop_assert!(fee_amount(small, fee_bps) <= fee_amount(large, fee_bps));
+    }
+
+    #[test]
+    fn coin_lots_never_exceed_the_size(coin_lot_size in 1u64.., size: u64) {
+        let lots = coin_lots(coin_lot_size, size).unwrap();
+        prop_assert!(lots as u128 * coin_lot_size as u128 <= size as u128);
+        prop_assert!(size as u128 - (lots as u128 * coin_lot_size as u128) < coin_lot_size as u128);
+    }
+
+    #[test]
+    fn min_amount_out_rounds_down_the_exact_minimum(amount_in: u64, min_price: u64) {
+        let exact = amount_in as u128 * min_price as u128;
+        let amount_out = min_amount_out(amount_in, min_price) as u128;
+        if amount_out < u64::MAX as u128 {
+            prop_assert!(amount_out * PRICE_SCALE as u128 <= exact);
+            prop_assert!(exact < (amount_out + 1) * PRICE_SCALE as u128);
+        }
+    }
+
+    #[test]
+    fn fills_passing_the_checks_honor_the_min_price(
+        amount_in: u64,
+        min_price in 0..=100 * PRICE_SCALE,
+        to_amount: u64,
+    ) {
+        let amount_out_min = min_amount_out(amount_in, min_price);
+        let passed = apply_safety_checks(amount_in, amount_out_min, amount_in, to_amount).is_ok();
+        if passed && amount_out_min < u64::MAX {
+            prop_assert!(to_amount >= amount_out_min);
+            // At most a unit short of the exact minimum, from rounding it down.
+            prop_assert!(meets_min_price(amount_in, to_amount.saturating_add(1), min_price));
+        }
+    }
+
+    #[test]
+    fn price_inverts_the_scale(base in 1u64..=u32::MAX as u64, price_per_base in 0u64..=u32::MAX as u64) {
+        let quote = base * price_per_base;
+        prop_assert_eq!(price(quote, base), price_per_base * PRICE_SCALE);
+    }
+
+    #[test]
+    fn safety_checks_pass_only_within_bounds(
+        amount_in_max: u64,
+        amount_out_min: u64,
+        from_amount: u64,
+        to_amount: u64,
+    ) {
+        match apply_safety_checks(amount_in_max, amount_out_min, from_amount, to_amount) {
+            Ok(()) => {
+                prop_assert!(from_amount <= amount_in_max);
+                prop_assert!(to_amount >= amount_out_min);
+                prop_assert!(to_amount > 0);
+            }
+            Err(ErrorCode::SwapTokenAmountExceedsMax) => prop_assert!(from_amount > amount_in_max),
+            Err(ErrorCode::SwapTokenAmountLessThanMin) => prop_assert!(to_amount < amount_out_min),
+            Err(ErrorCode::ZeroSwap) => prop_assert_eq!(to_amount, 0),
+            Err(code) => prop_assert!(false, "unexpected {:?}", code),
+        }
+    }
+}
+
+#[test]
+fn coin_lots_fail_without_a_lot_size() {
+    assert!(matches!(
+        coin_lots(0, 1_000),
+        Err(ErrorCode::InvalidLotSize)
+    ));
+}
+
+#[test]
+fn price_is_zero_without_base() {
+    assert_eq!(price(u64::MAX, 0), 0);
+}
+
+#[test]
+fn min_amount_out_saturates() {
+    assert_eq!(min_amount_out(u64::MAX, u64::MAX), u64::MAX);
+}
+
+#[test]
+fn amount_at_price_converts_between_decimals() {
+    // 1 base token of 9 decimals at 20 quote of 6 decimals per base token.
+    let price = 20 * PRICE_SCALE;
+    assert_eq!(
+        amount_at_price(Side::Ask, 1_000_000_000, price, 9, 6),
+        20_000_000
+    );
+    assert_eq!(
+        amount_at_price(Side::Bid, 20_000_000, price, 9, 6),
+        1_000_000_000
+    );
+    assert_eq!(amount_at_price(Side::Bid, 1, 0, 9, 6), u64::MAX);
+}
+
+proptest! {
+    #[test]
+    fn match_limit_leaves_room_for_the_takes_left(
+        remaining_compute_units in 0u64..=1_400_000,
+        takes_left in 1u64..=4,
+    ) {
+        let limit = match_limit(remaining_compute_units, takes_left) as u64;
+        prop_assert!(limit >= 1);
+        if limit > 1 {
+            let take = TAKE_COMPUTE_UNITS + limit * COMPUTE_UNITS_PER_MATCH;
+            prop_assert!(takes_left * take + SETTLE_COMPUTE_UNITS <= remaining_compute_units);
+        }
+    }
+
+    #[test]
+    fn match_limit_grows_with_the_budget(a in 0u64..=1_400_000, b in 0u64..=1_400_000) {
+        let (small, large) = (a.min(b), a.max(b));
+        prop_assert!(match_limit(small, 2) <= match_limit(large, 2));
+        prop_assert!(match_limit(large, 2) <= match_limit(large, 1));
+    }
+}
+
+proptest! {
+    #[test]
+    fn partial_fills_meet_their_minimum(amount_in: u64, from_amount: u64, min_fill_bps in 0u16..=10_000) {
+        let from_amount = from_amount.min(amount_in);
+        let policy = FillPolicy::AllowPartial { min_fill_bps };
+        let met = meets_fill_policy(policy, Side::Bid, amount_in, from_amount, 1, 1);
+        let exact = from_amount as u128 * 10_000 >= amount_in as u128 * min_fill_bps as u128;
+        prop_assert_eq!(met, exact);
+        prop_assert!(meets_fill_policy(policy, Side::Ask, amount_in, amount_in, 1, 1));
+    }
+
+    #[test]
+    fn fill_or_kill_asks_leave_less_than_a_lot(
+        coin_lot_size in 1u64..=1_000_000,
+        lots in 0u64..=1_000_000,
+        unfilled: u64,
+    ) {
+        let from_amount = lots * coin_lot_size;
+        let amount_in = from_amount.saturating_add(unfilled);
+        let met = meets_fill_policy(FillPolicy::FillOrKill, Side::Ask, amount_in, from_amount, 1, coin_lot_size);
+        prop_assert_eq!(met, amount_in - from_amount < coin_lot_size);
+    }
+
+    #[test]
+    fn fill_or_kill_bids_leave_less_than_a_lot(
+        coin_lot_size in 1u64..=1_000_000,
+        lots in 1u64..=1_000_000,
+        price_per_lot in 1u64..=1_000_000,
+        unfilled in 0u64..=2_000_000,
+    ) {
+        // Bought `lots` lots at `price_per_lot` quote each.
+        let from_amount = lots * price_per_lot;
+        let to_amount = lots * coin_lot_size;
+        let amount_in = from_amount + unfilled;
+        let met = meets_fill_policy(FillPolicy::FillOrKill, Side::Bid, amount_in, from_amount, to_amount, coin_lot_size);
+        prop_assert_eq!(met, unfilled < price_per_lot);
+    }
+}
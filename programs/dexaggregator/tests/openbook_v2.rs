@@ -0,0 +1,464 @@
+//! Routes swaps through OpenBook v2 markets, against the local stand-in program of
+//! `common::openbook_v2` and the mainnet program dumped to `tests/fixtures/openbook_v2.so`.
+
+mod common;
+
+use anchor_lang::{AnchorSerialize, InstructionData, ToAccountMetas};
+use common::*;
+use dexaggregator::{dex::openbook_v2, RouteLeg, Side, Venue};
+use solana_program_test::ProgramTestContext;
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_program,
+};
+
+// Anchor discriminators, i.e. sha256("global:<instruction>")[..8].
+const CREATE_MARKET: [u8; 8] = [103, 226, 97, 235, 200, 188, 251, 254];
+const CREATE_OPEN_ORDERS_INDEXER: [u8; 8] = [64, 64, 153, 255, 217, 71, 249, 133];
+const CREATE_OPEN_ORDERS_ACCOUNT: [u8; 8] = [204, 181, 175, 222, 40, 125, 188, 71];
+const PLACE_ORDER: [u8; 8] = [51, 194, 155, 175, 109, 130, 96, 106];
+
+// Account sizes, discriminator included.
+const BOOK_SIDE_LEN: usize = 8 + 90_944;
+const EVENT_HEAP_LEN: usize = 8 + 91_280;
+
+const BASE_LOT_SIZE: i64 = 1_000;
+const QUOTE_LOT_SIZE: i64 = 1;
+// 10 quote per base, both at 6 decimals.
+const PRICE_LOTS: i64 = 10_000;
+
+#[derive(AnchorSerialize)]
+struct OracleConfigParams {
+    conf_filter: f32,
+    max_staleness_slots: Option<u32>,
+}
+
+#[derive(AnchorSerialize)]
+struct CreateMarketArgs {
+    name: String,
+    oracle_config: OracleConfigParams,
+    quote_lot_size: i64,
+    base_lot_size: i64,
+    maker_fee: i64,
+    taker_fee: i64,
+    time_expiry: i64,
+}
+
+#[derive(AnchorSerialize)]
+struct PlaceOrderArgs {
+    side: u8,
+    price_lots: i64,
+    max_base_lots: i64,
+    max_quote_lots_including_fees: i64,
+    client_order_id: u64,
+    order_type: u8,
+    expiry_timestamp: u64,
+    self_trade_behavior: u8,
+    limit: u8,
+}
+
+fn instruction_data(discriminator: [u8; 8], args: impl AnchorSerialize) -> Vec<u8> {
+    let mut data = discriminator.to_vec();
+    args.serialize(&mut data).unwrap();
+    data
+}
+
+struct Market {
+    market: Pubkey,
+    market_authority: Pubkey,
+    bids: Pubkey,
+    asks: Pubkey,
+    event_heap: Pubkey,
+    base_vault: Pubkey,
+    quote_vault: Pubkey,
+}
+
+async fn create_market(
+    ctx: &mut ProgramTestContext,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> Market {
+    let market = Keypair::new();
+    let bids = Keypair::new();
+    let asks = Keypair::new();
+    let event_heap = Keypair::new();
+    create_account(ctx, &bids, BOOK_SIDE_LEN, &openbook_v2::ID).await;
+    create_account(ctx, &asks, BOOK_SIDE_LEN, &openbook_v2::ID).await;
+    create_account(ctx, &event_heap, EVENT_HEAP_LEN, &openbook_v2::ID).await;
+
+    let market_authority =
+        Pubkey::find_program_address(&[b"Market", market.pubkey().as_ref()], &openbook_v2::ID).0;
+    let event_authority = Pubkey::find_program_address(&[b"__event_authority"], &openbook_v2::ID).0;
+    let base_vault =
+        spl_associated_token_account::get_associated_token_address(&market_authority, base_mint);
+    let quote_vault =
+        spl_associated_token_account::get_associated_token_address(&market_authority, quote_mint);
+    let payer = ctx.payer.pubkey();
+
+    let ix = Instruction {
+        program_id: openbook_v2::ID,
+        accounts: vec![
+            AccountMeta::new(market.pubkey(), true),
+            AccountMeta::new_readonly(market_authority, false),
+            AccountMeta::new(bids.pubkey(), false),
+            AccountMeta::new(asks.pubkey(), false),
+            AccountMeta::new(event_heap.pubkey(), false),
+            AccountMeta::new(payer, true),
+            AccountMeta::new(base_vault, false),
+            AccountMeta::new(quote_vault, false),
+            AccountMeta::new_readonly(*base_mint, false),
+            AccountMeta::new_readonly(*quote_mint, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+            AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+            // No oracles.
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            // Collect fee admin.
+            AccountMeta::new_readonly(payer, false),
+            // No open orders, consume events or close market admins.
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(event_authority, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+        ],
+        data: instruction_data(
+            CREATE_MARKET,
+            CreateMarketArgs {
+                name: "BASE-QUOTE".to_string(),
+                oracle_config: OracleConfigParams {
+                    conf_filter: 0.1,
+                    max_staleness_slots: None,
+                },
+                quote_lot_size: QUOTE_LOT_SIZE,
+                base_lot_size: BASE_LOT_SIZE,
+                maker_fee: 0,
+                taker_fee: 0,
+                time_expiry: 0,
+            },
+        ),
+    };
+    process(ctx, &[ix], &[&market]).await.unwrap();
+
+    Market {
+        market: market.pubkey(),
+        market_authority,
+        bids: bids.pubkey(),
+        asks: asks.pubkey(),
+        event_heap: event_heap.pubkey(),
+        base_vault,
+        quote_vault,
+    }
+}
+
+// Rests an ask of `base_lots` at `PRICE_LOTS` from a new maker.
+async fn place_maker_ask(
+    ctx: &mut ProgramTestContext,
+    market: &Market,
+    base_mint: &Pubkey,
+    base_lots: i64,
+) {
+    let maker = Keypair::new();
+    let maker_base = create_token_account(ctx, base_mint, &maker.pubkey()).await;
+    mint_to(
+        ctx,
+        base_mint,
+        &maker_base,
+        (base_lots * BASE_LOT_SIZE) as u64,
+    )
+    .await;
+
+    let payer = ctx.payer.pubkey();
+    let indexer = Pubkey::find_program_address(
+        &[b"OpenOrdersIndexer", maker.pubkey().as_ref()],
+        &openbook_v2::ID,
+    )
+    .0;
+    let open_orders = Pubkey::find_program_address(
+        &[b"OpenOrders", maker.pubkey().as_ref(), &1u32.to_le_bytes()],
+        &openbook_v2::ID,
+    )
+    .0;
+
+    let create_indexer = Instruction {
+        program_id: openbook_v2::ID,
+        accounts: vec![
+            AccountMeta::new(payer, true),
+            AccountMeta::new_readonly(maker.pubkey(), true),
+            AccountMeta::new(indexer, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+        data: CREATE_OPEN_ORDERS_INDEXER.to_vec(),
+    };
+    let create_open_orders = Instruction {
+        program_id: openbook_v2::ID,
+        accounts: vec![
+            AccountMeta::new(payer, true),
+            AccountMeta::new_readonly(maker.pubkey(), true),
+            // No delegate.
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new(indexer, false),
+            AccountMeta::new(open_orders, false),
+            AccountMeta::new_readonly(market.market, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+        data: instruction_data(CREATE_OPEN_ORDERS_ACCOUNT, "maker".to_string()),
+    };
+    let place_order = Instruction {
+        program_id: openbook_v2::ID,
+        accounts: vec![
+            AccountMeta::new_readonly(maker.pubkey(), true),
+            AccountMeta::new(open_orders, false),
+            // No open orders admin.
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new(maker_base, false),
+            AccountMeta::new(market.market, false),
+            AccountMeta::new(market.bids, false),
+            AccountMeta::new(market.asks, false),
+            AccountMeta::new(market.event_heap, false),
+            AccountMeta::new(market.base_vault, false),
+            // No oracles.
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(openbook_v2::ID, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+        ],
+        data: instruction_data(
+            PLACE_ORDER,
+            PlaceOrderArgs {
+                // Ask.
+                side: 1,
+                price_lots: PRICE_LOTS,
+                max_base_lots: base_lots,
+                max_quote_lots_including_fees: i64::MAX,
+                client_order_id: 1,
+                // Limit.
+                order_type: 0,
+                expiry_timestamp: 0,
+                // Decrement take.
+                self_trade_behavior: 0,
+                limit: 10,
+            },
+        ),
+    };
+    process(
+        ctx,
+        &[create_indexer, create_open_orders, place_order],
+        &[&maker],
+    )
+    .await
+    .unwrap();
+}
+
+fn route_ix(accounts: Vec<AccountMeta>, side: Side, amount_in: u64) -> Instruction {
+    Instruction {
+        program_id: dexaggregator::ID,
+        accounts,
+        data: dexaggregator::instruction::SwapRoute {
+            legs: vec![RouteLeg {
+                venue: Venue::OpenBookV2,
+                side,
+                match_limit: None,
+            }],
+            amount_in,
+            amount_out_min: 0,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn swap_route_takes_from_an_openbook_v2_standin() {
+    let mut program_test = program_test();
+    common::openbook_v2::add_openbook_v2_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    let base_mint = create_mint(&mut ctx, 6).await;
+    let quote_mint = create_mint(&mut ctx, 6).await;
+    // 0.1% taker fee, 1 base bid at 9 quote and offered at 10 quote.
+    let taker_fee = 1_000;
+    let market = common::openbook_v2::create_standin_market(
+        &mut ctx,
+        &base_mint,
+        &quote_mint,
+        BASE_LOT_SIZE,
+        QUOTE_LOT_SIZE,
+        taker_fee,
+        (9_000, 1_000),
+        (PRICE_LOTS, 1_000),
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let taker = ctx.payer.pubkey();
+    let taker_base = create_token_account(&mut ctx, &base_mint, &taker).await;
+    let taker_quote = create_token_account(&mut ctx, &quote_mint, &taker).await;
+    mint_to(&mut ctx, &quote_mint, &taker_quote, 5_005_000).await;
+
+    // A bid of 5.005 quote buys 0.5 base for 5 quote and the taker fee.
+    let mut accounts = swap_route_accounts(&taker, &base_mint);
+    accounts.extend(market.leg(taker_base, taker_quote));
+    process(&mut ctx, &[route_ix(accounts, Side::Bid, 5_005_000)], &[])
+        .await
+        .unwrap();
+    assert_eq!(common::openbook_v2::taker_fee(5_000_000, taker_fee), 5_000);
+    let fee_base = 500_000 / 100;
+    assert_eq!(token_balance(&mut ctx, &taker_quote).await, 0);
+    assert_eq!(
+        token_balance(&mut ctx, &taker_base).await,
+        500_000 - fee_base
+    );
+    assert_eq!(
+        token_balance(&mut ctx, &market.quote_vault).await,
+        14_005_000
+    );
+
+    // An ask sells the whole base lots of the input, 495 of them, into the bid at 9 quote.
+    let mut accounts = swap_route_accounts(&taker, &quote_mint);
+    accounts.extend(market.leg(taker_base, taker_quote));
+    process(
+        &mut ctx,
+        &[route_ix(accounts, Side::Ask, 500_000 - fee_base)],
+        &[],
+    )
+    .await
+    .unwrap();
+    let quote = 495 * 9_000;
+    let quote_received = quote - common::openbook_v2::taker_fee(quote, taker_fee);
+    let fee_quote = quote_received / 100;
+    assert_eq!(token_balance(&mut ctx, &taker_base).await, 0);
+    assert_eq!(
+        token_balance(&mut ctx, &taker_quote).await,
+        quote_received - fee_quote
+    );
+}
+
+#[tokio::test]
+async fn swap_route_checks_openbook_v2_legs() {
+    let mut program_test = program_test();
+    common::openbook_v2::add_openbook_v2_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    let base_mint = create_mint(&mut ctx, 6).await;
+    let quote_mint = create_mint(&mut ctx, 6).await;
+    let market = common::openbook_v2::create_standin_market(
+        &mut ctx,
+        &base_mint,
+        &quote_mint,
+        BASE_LOT_SIZE,
+        QUOTE_LOT_SIZE,
+        0,
+        (9_000, 1_000),
+        (PRICE_LOTS, 1_000),
+    )
+    .await;
+    // A market without a base lot size.
+    let unlotted_market = common::openbook_v2::create_standin_market(
+        &mut ctx,
+        &base_mint,
+        &quote_mint,
+        0,
+        QUOTE_LOT_SIZE,
+        0,
+        (9_000, 1_000),
+        (PRICE_LOTS, 1_000),
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let taker = ctx.payer.pubkey();
+    let taker_base = create_token_account(&mut ctx, &base_mint, &taker).await;
+    let taker_quote = create_token_account(&mut ctx, &quote_mint, &taker).await;
+    mint_to(&mut ctx, &base_mint, &taker_base, 1_000_000).await;
+
+    // A leg must end with the OpenBook v2 program.
+    let mut leg = market.leg(taker_base, taker_quote);
+    leg.last_mut().unwrap().pubkey = Pubkey::new_unique();
+    let mut accounts = swap_route_accounts(&taker, &quote_mint);
+    accounts.extend(leg);
+    let result = process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_000)], &[]).await;
+    assert_error(result, dexaggregator::ErrorCode::InvalidDexProgram);
+
+    let mut accounts = swap_route_accounts(&taker, &quote_mint);
+    accounts.extend(unlotted_market.leg(taker_base, taker_quote));
+    let result = process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_000)], &[]).await;
+    assert_error(result, dexaggregator::ErrorCode::InvalidLotSize);
+    assert_eq!(token_balance(&mut ctx, &taker_base).await, 1_000_000);
+}
+
+#[tokio::test]
+async fn swap_route_takes_from_openbook_v2() {
+    require_fixture("openbook_v2");
+    let mut program_test = program_test();
+    program_test.add_program("openbook_v2", openbook_v2::ID, None);
+    let mut ctx = program_test.start_with_context().await;
+
+    let base_mint = create_mint(&mut ctx, 6).await;
+    let quote_mint = create_mint(&mut ctx, 6).await;
+    let market = create_market(&mut ctx, &base_mint, &quote_mint).await;
+    // 1 base offered at 10 quote.
+    place_maker_ask(&mut ctx, &market, &base_mint, 1_000).await;
+
+    let state = initialize_state(&mut ctx).await;
+    let taker = ctx.payer.pubkey();
+    let taker_base = create_token_account(&mut ctx, &base_mint, &taker).await;
+    let taker_quote = create_token_account(&mut ctx, &quote_mint, &taker).await;
+    mint_to(&mut ctx, &quote_mint, &taker_quote, 5_000_000).await;
+
+    let treasury = treasury();
+    let treasury_output =
+        spl_associated_token_account::get_associated_token_address(&treasury, &base_mint);
+    let mut accounts = dexaggregator::accounts::SwapRoute {
+        wallet_owner: taker,
+        state,
+        user_stats: user_stats(&taker),
+        fee_exemption: None,
+        program_fee_exemption: None,
+        instructions: None,
+        output_mint: base_mint,
+        treasury,
+        treasury_output,
+        system_program: system_program::ID,
+        associated_token_program: spl_associated_token_account::ID,
+        token_program: spl_token::ID,
+    }
+    .to_account_metas(None);
+    // The OpenBook v2 leg, in the order of `openbook_v2::LEG_ACCOUNTS`.
+    accounts.extend([
+        AccountMeta::new(market.market, false),
+        AccountMeta::new_readonly(market.market_authority, false),
+        AccountMeta::new(market.bids, false),
+        AccountMeta::new(market.asks, false),
+        AccountMeta::new(market.base_vault, false),
+        AccountMeta::new(market.quote_vault, false),
+        AccountMeta::new(market.event_heap, false),
+        AccountMeta::new(taker_base, false),
+        AccountMeta::new(taker_quote, false),
+        AccountMeta::new_readonly(openbook_v2::ID, false),
+        AccountMeta::new_readonly(openbook_v2::ID, false),
+        AccountMeta::new_readonly(openbook_v2::ID, false),
+        AccountMeta::new_readonly(openbook_v2::ID, false),
+    ]);
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts,
+        data: dexaggregator::instruction::SwapRoute {
+            legs: vec![RouteLeg {
+                venue: Venue::OpenBookV2,
+                side: Side::Bid,
+                match_limit: None,
+            }],
+            amount_in: 5_000_000,
+            amount_out_min: 400_000,
+        }
+        .data(),
+    };
+    process(&mut ctx, &[ix], &[]).await.unwrap();
+
+    // 5 quote buys 0.5 base, less the 1% protocol fee.
+    assert_eq!(token_balance(&mut ctx, &taker_quote).await, 0);
+    assert_eq!(token_balance(&mut ctx, &taker_base).await, 495_000);
+    assert_eq!(token_balance(&mut ctx, &treasury_output).await, 5_000);
+}
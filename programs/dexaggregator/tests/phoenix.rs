@@ -0,0 +1,497 @@
+//! Routes swaps through Phoenix markets, against the local stand-in program of
+//! `common::phoenix` and the mainnet program dumped to `tests/fixtures/phoenix.so`.
+
+mod common;
+
+use anchor_lang::{AnchorSerialize, InstructionData};
+use common::phoenix::{add_phoenix_standin, create_standin_market, log_authority, vault, Market};
+use common::*;
+use dexaggregator::{dex::phoenix, RouteLeg, Side, Venue, USDC_MINT};
+use solana_program_test::ProgramTestContext;
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_program,
+};
+
+const BASE_LOT_SIZE: u64 = 1_000;
+const QUOTE_LOT_SIZE: u64 = 10;
+// Quote lots per base lot, i.e. 10 quote per base.
+const PRICE: u64 = 1_000;
+
+// Phoenix instruction tags.
+const PLACE_LIMIT_ORDER: u8 = 2;
+const INITIALIZE_MARKET: u8 = 100;
+const CHANGE_MARKET_STATUS: u8 = 103;
+const CHANGE_SEAT_STATUS: u8 = 104;
+const REQUEST_SEAT_AUTHORIZED: u8 = 105;
+
+// The size of a market of 512 bids, 512 asks and 128 seats: its 576 byte header, then the
+// 304 bytes of the `FIFOMarket` fields, two books of 32 + 512 * 64 bytes and a seat tree of
+// 32 + 128 * 144 bytes.
+const MARKET_LEN: usize = 576 + 304 + 2 * (32 + 512 * 64) + 32 + 128 * 144;
+
+const TAKER_FEE_BPS: u16 = 10;
+
+#[derive(AnchorSerialize)]
+struct MarketSizeParams {
+    bids_size: u64,
+    asks_size: u64,
+    num_seats: u64,
+}
+
+#[derive(AnchorSerialize)]
+struct InitializeParams {
+    market_size_params: MarketSizeParams,
+    num_quote_lots_per_quote_unit: u64,
+    tick_size_in_quote_lots_per_base_unit: u64,
+    num_base_lots_per_base_unit: u64,
+    taker_fee_bps: u16,
+    fee_collector: Pubkey,
+    raw_base_units_per_base_unit: Option<u32>,
+}
+
+// `OrderPacket::Limit`, the packet's variant 1.
+#[derive(AnchorSerialize)]
+struct LimitOrderPacket {
+    variant: u8,
+    side: u8,
+    price_in_ticks: u64,
+    num_base_lots: u64,
+    self_trade_behavior: u8,
+    match_limit: Option<u64>,
+    client_order_id: u128,
+    use_only_deposited_funds: bool,
+    last_valid_slot: Option<u64>,
+    last_valid_unix_timestamp_in_seconds: Option<u64>,
+    fail_silently_on_insufficient_funds: bool,
+}
+
+fn instruction_data(tag: u8, args: impl AnchorSerialize) -> Vec<u8> {
+    let mut data = vec![tag];
+    args.serialize(&mut data).unwrap();
+    data
+}
+
+// Creates an active market of the Phoenix program with `TAKER_FEE_BPS` taker fees, its
+// lots, ticks and decimals those of the stand-in markets, authorized by the payer.
+async fn create_market(
+    ctx: &mut ProgramTestContext,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> Market {
+    let market = Keypair::new();
+    create_account(ctx, &market, MARKET_LEN, &phoenix::ID).await;
+    let base_vault = vault(&market.pubkey(), base_mint).0;
+    let quote_vault = vault(&market.pubkey(), quote_mint).0;
+    let authority = ctx.payer.pubkey();
+
+    let initialize = Instruction {
+        program_id: phoenix::ID,
+        accounts: vec![
+            AccountMeta::new_readonly(phoenix::ID, false),
+            AccountMeta::new_readonly(log_authority(), false),
+            AccountMeta::new(market.pubkey(), false),
+            AccountMeta::new(authority, true),
+            AccountMeta::new_readonly(*base_mint, false),
+            AccountMeta::new_readonly(*quote_mint, false),
+            AccountMeta::new(base_vault, false),
+            AccountMeta::new(quote_vault, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+        ],
+        data: instruction_data(
+            INITIALIZE_MARKET,
+            InitializeParams {
+                market_size_params: MarketSizeParams {
+                    bids_size: 512,
+                    asks_size: 512,
+                    num_seats: 128,
+                },
+                // Lots of 10 quote and 1_000 base atoms, both at 6 decimals.
+                num_quote_lots_per_quote_unit: 1_000_000 / QUOTE_LOT_SIZE,
+                tick_size_in_quote_lots_per_base_unit: 1_000,
+                num_base_lots_per_base_unit: 1_000_000 / BASE_LOT_SIZE,
+                taker_fee_bps: TAKER_FEE_BPS,
+                fee_collector: authority,
+                raw_base_units_per_base_unit: None,
+            },
+        ),
+    };
+    let activate = Instruction {
+        program_id: phoenix::ID,
+        accounts: vec![
+            AccountMeta::new_readonly(phoenix::ID, false),
+            AccountMeta::new_readonly(log_authority(), false),
+            AccountMeta::new(market.pubkey(), false),
+            AccountMeta::new_readonly(authority, true),
+        ],
+        // `MarketStatus::Active`.
+        data: instruction_data(CHANGE_MARKET_STATUS, 1u8),
+    };
+    process(ctx, &[initialize, activate], &[]).await.unwrap();
+
+    Market {
+        market: market.pubkey(),
+        base_vault,
+        quote_vault,
+    }
+}
+
+// Rests a bid of `base_lots` at `PRICE` quote lots per base lot from a new maker, seated
+// by the market authority.
+async fn place_maker_bid(
+    ctx: &mut ProgramTestContext,
+    market: &Market,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+    base_lots: u64,
+) {
+    let maker = Keypair::new();
+    let maker_base = create_token_account(ctx, base_mint, &maker.pubkey()).await;
+    let maker_quote = create_token_account(ctx, quote_mint, &maker.pubkey()).await;
+    mint_to(
+        ctx,
+        quote_mint,
+        &maker_quote,
+        base_lots * PRICE * QUOTE_LOT_SIZE,
+    )
+    .await;
+
+    let authority = ctx.payer.pubkey();
+    let seat = Pubkey::find_program_address(
+        &[b"seat", market.market.as_ref(), maker.pubkey().as_ref()],
+        &phoenix::ID,
+    )
+    .0;
+    let request_seat = Instruction {
+        program_id: phoenix::ID,
+        accounts: vec![
+            AccountMeta::new_readonly(phoenix::ID, false),
+            AccountMeta::new_readonly(log_authority(), false),
+            AccountMeta::new(market.market, false),
+            AccountMeta::new_readonly(authority, true),
+            AccountMeta::new(authority, true),
+            AccountMeta::new_readonly(maker.pubkey(), false),
+            AccountMeta::new(seat, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+        data: vec![REQUEST_SEAT_AUTHORIZED],
+    };
+    let approve_seat = Instruction {
+        program_id: phoenix::ID,
+        accounts: vec![
+            AccountMeta::new_readonly(phoenix::ID, false),
+            AccountMeta::new_readonly(log_authority(), false),
+            AccountMeta::new(market.market, false),
+            AccountMeta::new_readonly(authority, true),
+            AccountMeta::new(seat, false),
+        ],
+        // `SeatApprovalStatus::Approved`.
+        data: instruction_data(CHANGE_SEAT_STATUS, 1u8),
+    };
+    let place_order = Instruction {
+        program_id: phoenix::ID,
+        accounts: vec![
+            AccountMeta::new_readonly(phoenix::ID, false),
+            AccountMeta::new_readonly(log_authority(), false),
+            AccountMeta::new(market.market, false),
+            AccountMeta::new_readonly(maker.pubkey(), true),
+            AccountMeta::new_readonly(seat, false),
+            AccountMeta::new(maker_base, false),
+            AccountMeta::new(maker_quote, false),
+            AccountMeta::new(market.base_vault, false),
+            AccountMeta::new(market.quote_vault, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+        ],
+        data: instruction_data(
+            PLACE_LIMIT_ORDER,
+            LimitOrderPacket {
+                variant: 1,
+                // Bid.
+                side: 0,
+                // Ticks of 1_000 quote lots per base unit, i.e. of 1 quote lot per base lot.
+                price_in_ticks: PRICE,
+                num_base_lots: base_lots,
+                // Decrement take.
+                self_trade_behavior: 2,
+                match_limit: None,
+                client_order_id: 1,
+                use_only_deposited_funds: false,
+                last_valid_slot: None,
+                last_valid_unix_timestamp_in_seconds: None,
+                fail_silently_on_insufficient_funds: false,
+            },
+        ),
+    };
+    process(ctx, &[request_seat, approve_seat, place_order], &[&maker])
+        .await
+        .unwrap();
+}
+
+fn route_ix(accounts: Vec<AccountMeta>, side: Side, amount_in: u64) -> Instruction {
+    Instruction {
+        program_id: dexaggregator::ID,
+        accounts,
+        data: dexaggregator::instruction::SwapRoute {
+            legs: vec![RouteLeg {
+                venue: Venue::Phoenix,
+                side,
+                match_limit: None,
+            }],
+            amount_in,
+            amount_out_min: 0,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn swap_route_trades_whole_lots_on_phoenix() {
+    let mut program_test = program_test();
+    add_phoenix_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    let base_mint = create_mint(&mut ctx, 6).await;
+    let quote_mint = create_mint(&mut ctx, 6).await;
+    let market = create_standin_market(
+        &mut ctx,
+        &base_mint,
+        &quote_mint,
+        BASE_LOT_SIZE,
+        QUOTE_LOT_SIZE,
+        PRICE,
+        1_000_000_000,
+        10_000_000_000,
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_base = create_token_account(&mut ctx, &base_mint, &user).await;
+    let user_quote = create_token_account(&mut ctx, &quote_mint, &user).await;
+    mint_to(&mut ctx, &base_mint, &user_base, 1_000_500).await;
+
+    // An ask sells the whole base lots of the input, 1_000 of them, for 10 quote per base.
+    let mut accounts = swap_route_accounts(&user, &quote_mint);
+    accounts.extend(market.leg(user_base, user_quote));
+    process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_500)], &[])
+        .await
+        .unwrap();
+    let quote_received = 10_000_000;
+    let fee_quote = quote_received / 100;
+    assert_eq!(token_balance(&mut ctx, &user_base).await, 500);
+    assert_eq!(
+        token_balance(&mut ctx, &user_quote).await,
+        quote_received - fee_quote
+    );
+    assert_eq!(
+        token_balance(&mut ctx, &market.base_vault).await,
+        1_001_000_000
+    );
+
+    // A bid spends the whole quote lots of the input on whole base lots, 990 of them, and
+    // leaves the rest in the wallet.
+    mint_to(&mut ctx, &quote_mint, &user_quote, 5).await;
+    let mut accounts = swap_route_accounts(&user, &base_mint);
+    accounts.extend(market.leg(user_base, user_quote));
+    process(
+        &mut ctx,
+        &[route_ix(
+            accounts,
+            Side::Bid,
+            quote_received - fee_quote + 5,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    let base_received = 990_000;
+    let fee_base = base_received / 100;
+    assert_eq!(token_balance(&mut ctx, &user_quote).await, 5);
+    assert_eq!(
+        token_balance(&mut ctx, &user_base).await,
+        500 + base_received - fee_base
+    );
+    let treasury_base =
+        spl_associated_token_account::get_associated_token_address(&treasury(), &base_mint);
+    let treasury_quote =
+        spl_associated_token_account::get_associated_token_address(&treasury(), &quote_mint);
+    assert_eq!(token_balance(&mut ctx, &treasury_base).await, fee_base);
+    assert_eq!(token_balance(&mut ctx, &treasury_quote).await, fee_quote);
+}
+
+#[tokio::test]
+async fn swap_route_records_the_usdc_side_volume() {
+    let mut program_test = program_test();
+    add_phoenix_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    create_mint_at(&mut ctx, &USDC_MINT, 6);
+    let base_mint = create_mint(&mut ctx, 6).await;
+    let market = create_standin_market(
+        &mut ctx,
+        &base_mint,
+        &USDC_MINT,
+        BASE_LOT_SIZE,
+        QUOTE_LOT_SIZE,
+        PRICE,
+        1_000_000_000,
+        10_000_000_000,
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_base = create_token_account(&mut ctx, &base_mint, &user).await;
+    let user_quote = create_token_account(&mut ctx, &USDC_MINT, &user).await;
+    mint_to(&mut ctx, &base_mint, &user_base, 1_000_000).await;
+
+    // A route ending in USDC counts its output before the fee.
+    let mut accounts = swap_route_accounts(&user, &USDC_MINT);
+    accounts.extend(market.leg(user_base, user_quote));
+    process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_000)], &[])
+        .await
+        .unwrap();
+    let stats = load_user_stats(&mut ctx, &user).await;
+    assert_eq!(stats.trade_count, 1);
+    assert_eq!(
+        (stats.volume_in, stats.volume_out),
+        (10_000_000, 10_000_000)
+    );
+
+    // A route starting in USDC counts its input.
+    let mut accounts = swap_route_accounts(&user, &base_mint);
+    accounts.extend(market.leg(user_base, user_quote));
+    process(&mut ctx, &[route_ix(accounts, Side::Bid, 5_000_000)], &[])
+        .await
+        .unwrap();
+    let stats = load_user_stats(&mut ctx, &user).await;
+    assert_eq!(stats.trade_count, 2);
+    assert_eq!(
+        (stats.volume_in, stats.volume_out),
+        (15_000_000, 15_000_000)
+    );
+    assert_eq!(stats.window_volume(stats.last_day), 15_000_000);
+}
+
+#[tokio::test]
+async fn swap_route_rejects_a_market_of_another_program() {
+    let mut program_test = program_test();
+    add_phoenix_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    let base_mint = create_mint(&mut ctx, 6).await;
+    let quote_mint = create_mint(&mut ctx, 6).await;
+    let market = create_standin_market(
+        &mut ctx,
+        &base_mint,
+        &quote_mint,
+        BASE_LOT_SIZE,
+        QUOTE_LOT_SIZE,
+        PRICE,
+        1_000_000_000,
+        10_000_000_000,
+    )
+    .await;
+    // The same market, owned by another program.
+    let mut account = ctx
+        .banks_client
+        .get_account(market.market)
+        .await
+        .unwrap()
+        .unwrap();
+    account.owner = solana_sdk::pubkey::Pubkey::new_unique();
+    ctx.set_account(&market.market, &account.into());
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_base = create_token_account(&mut ctx, &base_mint, &user).await;
+    let user_quote = create_token_account(&mut ctx, &quote_mint, &user).await;
+    mint_to(&mut ctx, &base_mint, &user_base, 1_000_000).await;
+
+    let mut accounts = swap_route_accounts(&user, &quote_mint);
+    accounts.extend(market.leg(user_base, user_quote));
+    let result = process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_000)], &[]).await;
+    assert!(result.is_err());
+    assert_eq!(token_balance(&mut ctx, &user_base).await, 1_000_000);
+}
+
+#[tokio::test]
+async fn swap_route_rejects_a_market_without_lot_sizes() {
+    let mut program_test = program_test();
+    add_phoenix_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    let base_mint = create_mint(&mut ctx, 6).await;
+    let quote_mint = create_mint(&mut ctx, 6).await;
+    let market = create_standin_market(
+        &mut ctx,
+        &base_mint,
+        &quote_mint,
+        0,
+        QUOTE_LOT_SIZE,
+        PRICE,
+        1_000_000_000,
+        10_000_000_000,
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_base = create_token_account(&mut ctx, &base_mint, &user).await;
+    let user_quote = create_token_account(&mut ctx, &quote_mint, &user).await;
+    mint_to(&mut ctx, &base_mint, &user_base, 1_000_000).await;
+
+    let mut accounts = swap_route_accounts(&user, &quote_mint);
+    accounts.extend(market.leg(user_base, user_quote));
+    let result = process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_000)], &[]).await;
+    assert_error(result, dexaggregator::ErrorCode::InvalidLotSize);
+}
+
+#[tokio::test]
+async fn swap_route_pays_the_taker_fee_on_phoenix() {
+    require_fixture("phoenix");
+    let mut program_test = program_test();
+    program_test.add_program("phoenix", phoenix::ID, None);
+    let mut ctx = program_test.start_with_context().await;
+
+    let base_mint = create_mint(&mut ctx, 6).await;
+    let quote_mint = create_mint(&mut ctx, 6).await;
+    let market = create_market(&mut ctx, &base_mint, &quote_mint).await;
+    // 2 base bid at 10 quote.
+    place_maker_bid(&mut ctx, &market, &base_mint, &quote_mint, 2_000).await;
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_base = create_token_account(&mut ctx, &base_mint, &user).await;
+    let user_quote = create_token_account(&mut ctx, &quote_mint, &user).await;
+    mint_to(&mut ctx, &base_mint, &user_base, 1_000_000).await;
+
+    // Selling 1 base fills 1_000_000 quote lots, less Phoenix's taker fee on them, before the
+    // aggregator takes its own fee from the proceeds.
+    let mut accounts = swap_route_accounts(&user, &quote_mint);
+    accounts.extend(market.leg(user_base, user_quote));
+    process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_000)], &[])
+        .await
+        .unwrap();
+    let quote_filled = 1_000_000 * QUOTE_LOT_SIZE;
+    let taker_fee = quote_filled * TAKER_FEE_BPS as u64 / 10_000;
+    let quote_received = quote_filled - taker_fee;
+    let fee_quote = quote_received / 100;
+    assert_eq!(taker_fee, 10_000);
+    assert_eq!(token_balance(&mut ctx, &user_base).await, 0);
+    assert_eq!(
+        token_balance(&mut ctx, &user_quote).await,
+        quote_received - fee_quote
+    );
+    let treasury_quote =
+        spl_associated_token_account::get_associated_token_address(&treasury(), &quote_mint);
+    assert_eq!(token_balance(&mut ctx, &treasury_quote).await, fee_quote);
+    // The taker fee stays in the quote vault, with the rest of the maker's bid.
+    assert_eq!(
+        token_balance(&mut ctx, &market.quote_vault).await,
+        2_000 * PRICE * QUOTE_LOT_SIZE - quote_received
+    );
+}
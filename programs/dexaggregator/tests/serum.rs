@@ -0,0 +1,2210 @@
+//! Swaps through Serum markets on the DEX dumped to `tests/fixtures/serum_dex.so`, and the
+//! program's error paths, without a cluster.
+
+mod common;
+
+use anchor_lang::{
+    AccountDeserialize, AccountSerialize, Discriminator, InstructionData, ToAccountMetas,
+};
+use common::serum::*;
+use common::*;
+use dexaggregator::dex::{self, serum_dex};
+use dexaggregator::oracle::{MAX_STALENESS_SLOTS, PYTH_PROGRAM_ID};
+use dexaggregator::{
+    BatchSwapSpec, ErrorCode, ExecutionMode, FeeTier, FillPolicy, PriceSource, RouteLeg, Side,
+    SpillPolicy, State, TriggerDirection, TriggerOrder, Venue, DEFAULT_FEE_BPS, PRICE_SCALE,
+    STATE_VERSION, USDC_MINT,
+};
+use solana_program_test::{BanksClientError, ProgramTestContext};
+use solana_sdk::{
+    account::{AccountSharedData, WritableAccount},
+    clock::Clock,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_instruction, system_program, sysvar,
+};
+use spl_associated_token_account::get_associated_token_address;
+
+// 10 USDC per base on the ask, 9 on the bid, in pc lots per coin lot.
+const ASK_PRICE_LOTS: u64 = 10_000;
+const BID_PRICE_LOTS: u64 = 9_000;
+const BOOK_DEPTH_LOTS: u64 = 1_000;
+
+const ONE: u64 = 1_000_000;
+// Lamports escrowed for the keeper of a trigger order.
+const TRIGGER_BOUNTY: u64 = 5_000;
+
+// BTC/USDC and ETH/USDC markets with books on both sides, and a user holding all three.
+struct Setup {
+    ctx: ProgramTestContext,
+    state: Pubkey,
+    btc: SerumMarket,
+    eth: SerumMarket,
+    user: Pubkey,
+    user_usdc: Pubkey,
+    user_btc: Pubkey,
+    user_eth: Pubkey,
+    treasury_usdc: Pubkey,
+}
+
+async fn setup() -> Setup {
+    require_fixture("serum_dex");
+    let mut program_test = program_test();
+    add_serum_dex(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    create_mint_at(&mut ctx, &USDC_MINT, 6);
+    let btc_mint = create_mint(&mut ctx, 6).await;
+    let eth_mint = create_mint(&mut ctx, 6).await;
+    let btc = create_market(&mut ctx, &btc_mint, &USDC_MINT).await;
+    let eth = create_market(&mut ctx, &eth_mint, &USDC_MINT).await;
+    for market in [&btc, &eth] {
+        place_maker_order(
+            &mut ctx,
+            market,
+            serum_dex::matching::Side::Ask,
+            ASK_PRICE_LOTS,
+            BOOK_DEPTH_LOTS,
+        )
+        .await;
+        place_maker_order(
+            &mut ctx,
+            market,
+            serum_dex::matching::Side::Bid,
+            BID_PRICE_LOTS,
+            BOOK_DEPTH_LOTS,
+        )
+        .await;
+    }
+
+    let state = initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_usdc = create_token_account(&mut ctx, &USDC_MINT, &user).await;
+    let user_btc = create_token_account(&mut ctx, &btc_mint, &user).await;
+    let user_eth = create_token_account(&mut ctx, &eth_mint, &user).await;
+    mint_to(&mut ctx, &USDC_MINT, &user_usdc, 100 * ONE).await;
+    mint_to(&mut ctx, &btc_mint, &user_btc, 10 * ONE).await;
+    mint_to(&mut ctx, &eth_mint, &user_eth, 10 * ONE).await;
+    let treasury_usdc = create_token_account(&mut ctx, &USDC_MINT, &treasury()).await;
+
+    Setup {
+        ctx,
+        state,
+        btc,
+        eth,
+        user,
+        user_usdc,
+        user_btc,
+        user_eth,
+        treasury_usdc,
+    }
+}
+
+impl Setup {
+    fn swap_accounts(
+        &self,
+        market: &SerumMarket,
+        coin_wallet: Pubkey,
+    ) -> dexaggregator::accounts::Swap {
+        dexaggregator::accounts::Swap {
+            market: market.accounts(coin_wallet),
+            market_config: None,
+            market_cache: None,
+            wallet_owner: self.user,
+            state: self.state,
+            user_stats: user_stats(&self.user),
+            fee_exemption: None,
+            program_fee_exemption: None,
+            instructions: None,
+            mint_a: USDC_MINT,
+            treasury: treasury(),
+            treasury_usdc: self.treasury_usdc,
+            referral: None,
+            pc_wallet: self.user_usdc,
+            recipient: None,
+            memo_program: None,
+            open_orders: None,
+            rent: None,
+            wallet_delegate: None,
+            system_program: system_program::ID,
+            associated_token_program: spl_associated_token_account::ID,
+            dex_program: dex::ID,
+            token_program: spl_token::ID,
+        }
+    }
+
+    async fn swap(
+        &mut self,
+        accounts: dexaggregator::accounts::Swap,
+        side: Side,
+        amount_in_max: u64,
+        amount_out_min: u64,
+        execution_mode: Option<ExecutionMode>,
+    ) -> Result<(), BanksClientError> {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: accounts.to_account_metas(None),
+            data: dexaggregator::instruction::Swap {
+                side,
+                amount_in_max,
+                amount_out_min,
+                execution_mode,
+                match_limit: None,
+                fill_policy: None,
+                memo: None,
+            }
+            .data(),
+        };
+        self.process(ix, &[]).await
+    }
+
+    async fn swap_transitive(
+        &mut self,
+        amount_in_max: u64,
+        amount_out_min: u64,
+        spill_policy: Option<SpillPolicy>,
+    ) -> Result<(), BanksClientError> {
+        let accounts = dexaggregator::accounts::SwapTransitive {
+            from: self.btc.accounts(self.user_btc),
+            to: self.eth.accounts(self.user_eth),
+            from_market_config: None,
+            to_market_config: None,
+            from_market_cache: None,
+            to_market_cache: None,
+            wallet_owner: self.user,
+            state: self.state,
+            user_stats: user_stats(&self.user),
+            fee_exemption: None,
+            program_fee_exemption: None,
+            instructions: None,
+            pc_wallet: self.user_usdc,
+            treasury: treasury(),
+            treasury_usdc: self.treasury_usdc,
+            referral: None,
+            recipient: None,
+            memo_program: None,
+            dex_program: dex::ID,
+            token_program: spl_token::ID,
+            system_program: system_program::ID,
+        };
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: accounts.to_account_metas(None),
+            data: dexaggregator::instruction::SwapTransitive {
+                amount_in_max,
+                amount_out_min,
+                from_match_limit: None,
+                to_match_limit: None,
+                spill_policy,
+                memo: None,
+            }
+            .data(),
+        };
+        self.process(ix, &[]).await
+    }
+
+    async fn swap_with_fill_policy(
+        &mut self,
+        accounts: dexaggregator::accounts::Swap,
+        side: Side,
+        amount_in_max: u64,
+        fill_policy: FillPolicy,
+    ) -> Result<(), BanksClientError> {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: accounts.to_account_metas(None),
+            data: dexaggregator::instruction::Swap {
+                side,
+                amount_in_max,
+                amount_out_min: 0,
+                execution_mode: None,
+                match_limit: None,
+                fill_policy: Some(fill_policy),
+                memo: None,
+            }
+            .data(),
+        };
+        self.process(ix, &[]).await
+    }
+
+    // Processes `ix` under a fresh blockhash, so repeating an instruction is not a duplicate.
+    async fn process(
+        &mut self,
+        ix: Instruction,
+        signers: &[&Keypair],
+    ) -> Result<(), BanksClientError> {
+        self.ctx.get_new_latest_blockhash().await.unwrap();
+        process(&mut self.ctx, &[ix], signers).await
+    }
+
+    async fn balance(&mut self, account: &Pubkey) -> u64 {
+        token_balance(&mut self.ctx, account).await
+    }
+
+    // A funded wallet other than the state owner.
+    async fn intruder(&mut self) -> Keypair {
+        let intruder = Keypair::new();
+        let ix = system_instruction::transfer(&self.user, &intruder.pubkey(), 1_000_000_000);
+        process(&mut self.ctx, &[ix], &[]).await.unwrap();
+        intruder
+    }
+
+    async fn update_state(
+        &mut self,
+        owner: &Keypair,
+        data: impl InstructionData,
+    ) -> Result<(), BanksClientError> {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::UpdateState {
+                state: self.state,
+                owner: owner.pubkey(),
+            }
+            .to_account_metas(None),
+            data: data.data(),
+        };
+        self.process(ix, &[owner]).await
+    }
+
+    async fn migrate_state(
+        &mut self,
+        legacy_state: Option<Pubkey>,
+        authority: &Keypair,
+    ) -> Result<(), BanksClientError> {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::MigrateState {
+                state: self.state,
+                legacy_state,
+                authority: authority.pubkey(),
+                program: dexaggregator::ID,
+                program_data: program_data(),
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::MigrateState {}.data(),
+        };
+        self.process(ix, &[authority]).await
+    }
+}
+
+// A `batch_swap` user's BTC and USDC wallets.
+struct BatchUser {
+    owner: Pubkey,
+    btc: Pubkey,
+    usdc: Pubkey,
+    signs: bool,
+}
+
+impl Setup {
+    // The setup's user, signing the batch as its relayer.
+    fn signing_user(&self) -> BatchUser {
+        BatchUser {
+            owner: self.user,
+            btc: self.user_btc,
+            usdc: self.user_usdc,
+            signs: true,
+        }
+    }
+
+    // A user who doesn't sign the swap, holding 10 BTC, with `delegate` approved for
+    // `allowance` of both wallets unless zero.
+    async fn delegating_user(
+        &mut self,
+        owner: &Keypair,
+        delegate: &Pubkey,
+        allowance: u64,
+    ) -> BatchUser {
+        let btc = create_token_account(&mut self.ctx, &self.btc.coin_mint, &owner.pubkey()).await;
+        let usdc = create_token_account(&mut self.ctx, &USDC_MINT, &owner.pubkey()).await;
+        mint_to(&mut self.ctx, &self.btc.coin_mint, &btc, 10 * ONE).await;
+        if allowance > 0 {
+            let approvals = [btc, usdc].map(|wallet| {
+                spl_token::instruction::approve(
+                    &spl_token::ID,
+                    &wallet,
+                    delegate,
+                    &owner.pubkey(),
+                    &[],
+                    allowance,
+                )
+                .unwrap()
+            });
+            process(&mut self.ctx, &approvals, &[owner]).await.unwrap();
+        }
+        BatchUser {
+            owner: owner.pubkey(),
+            btc,
+            usdc,
+            signs: false,
+        }
+    }
+
+    // Swaps for every spec's user, a delegating user through their intent of seed 0. With
+    // the allowlist enabled, each leg is followed by the BTC market's config.
+    async fn batch_swap(
+        &mut self,
+        specs: Vec<(&BatchUser, Side, u64)>,
+        skip_failed: bool,
+    ) -> Result<(), BanksClientError> {
+        let state = self
+            .ctx
+            .banks_client
+            .get_account(self.state)
+            .await
+            .unwrap()
+            .unwrap();
+        let allowlist_enabled = State::try_deserialize(&mut &state.data[..])
+            .unwrap()
+            .market_allowlist_enabled;
+        let mut accounts = dexaggregator::accounts::BatchSwap {
+            relayer: self.user,
+            state: self.state,
+            delegate: batch_delegate(),
+            treasury: treasury(),
+            treasury_usdc: self.treasury_usdc,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None);
+        let specs = specs
+            .into_iter()
+            .map(|(user, side, amount_in_max)| {
+                accounts.push(AccountMeta {
+                    pubkey: user.owner,
+                    is_signer: user.signs,
+                    is_writable: !user.signs,
+                });
+                accounts.push(AccountMeta::new(user_stats(&user.owner), false));
+                accounts.push(match user.signs {
+                    true => AccountMeta::new_readonly(dexaggregator::ID, false),
+                    false => AccountMeta::new(intent(&user.owner, 0), false),
+                });
+                accounts.extend(self.btc.leg_accounts(user.btc, user.usdc));
+                if allowlist_enabled {
+                    accounts.push(AccountMeta::new_readonly(
+                        market_config(&self.btc.market),
+                        false,
+                    ));
+                }
+                BatchSwapSpec {
+                    side,
+                    amount_in_max,
+                    amount_out_min: 0,
+                }
+            })
+            .collect();
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts,
+            data: dexaggregator::instruction::BatchSwap { specs, skip_failed }.data(),
+        };
+        self.process(ix, &[]).await
+    }
+}
+
+impl Setup {
+    async fn create_intent(
+        &mut self,
+        owner: &Keypair,
+        seed: u64,
+        amount_in: u64,
+        min_amount_out: u64,
+        expiry: i64,
+    ) -> Result<(), BanksClientError> {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::CreateIntent {
+                wallet_owner: owner.pubkey(),
+                intent: intent(&owner.pubkey(), seed),
+                input_mint: self.btc.coin_mint,
+                output_mint: USDC_MINT,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::CreateIntent {
+                seed,
+                amount_in,
+                min_amount_out,
+                expiry,
+            }
+            .data(),
+        };
+        self.process(ix, &[owner]).await
+    }
+
+    async fn swap_delegated(
+        &mut self,
+        executor: &Keypair,
+        user: &BatchUser,
+        seed: u64,
+    ) -> Result<(), BanksClientError> {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::SwapDelegated {
+                executor: executor.pubkey(),
+                executor_entry: executor_entry(&executor.pubkey()),
+                intent: intent(&user.owner, seed),
+                wallet_owner: user.owner,
+                delegate: intent_delegate(),
+                market: self.btc.accounts(user.btc),
+                market_config: None,
+                market_cache: None,
+                pc_wallet: user.usdc,
+                state: self.state,
+                user_stats: user_stats(&user.owner),
+                fee_exemption: fee_exemption(&user.owner),
+                treasury: treasury(),
+                treasury_pc: self.treasury_usdc,
+                dex_program: dex::ID,
+                token_program: spl_token::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::SwapDelegated {}.data(),
+        };
+        self.process(ix, &[executor]).await
+    }
+
+    // A copy of the state at another address, owned by the program, charging no fees.
+    async fn fake_state(&mut self) -> Pubkey {
+        let mut account = self
+            .ctx
+            .banks_client
+            .get_account(self.state)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut state = State::try_deserialize(&mut &account.data[..]).unwrap();
+        state.fee_bps = 0;
+        state.fee_tiers.clear();
+        state.market_allowlist_enabled = false;
+        account.data.clear();
+        state.try_serialize(&mut account.data).unwrap();
+        account.data.resize(8 + State::LEN, 0);
+
+        let fake_state = Pubkey::new_unique();
+        self.ctx.set_account(&fake_state, &account.into());
+        fake_state
+    }
+
+    async fn register_market(&mut self, market: &Pubkey) -> Result<(), BanksClientError> {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::RegisterMarket {
+                state: self.state,
+                market: *market,
+                dex_program: dex::ID,
+                market_config: market_config(market),
+                owner: self.user,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::RegisterMarket {
+                execution_mode: ExecutionMode::SendTake,
+            }
+            .data(),
+        };
+        self.process(ix, &[]).await
+    }
+
+    async fn unix_timestamp(&mut self) -> i64 {
+        let clock: Clock = self.ctx.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp
+    }
+
+    async fn slot(&mut self) -> u64 {
+        let clock: Clock = self.ctx.banks_client.get_sysvar().await.unwrap();
+        clock.slot
+    }
+
+    async fn advance_clock(&mut self, seconds: i64) {
+        let mut clock: Clock = self.ctx.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp += seconds;
+        self.ctx.set_sysvar(&clock);
+    }
+
+    async fn add_executor(&mut self, executor: &Pubkey) -> Result<(), BanksClientError> {
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::AddExecutor {
+                state: self.state,
+                executor_entry: executor_entry(executor),
+                owner: self.user,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::AddExecutor {
+                executor: *executor,
+            }
+            .data(),
+        };
+        self.process(ix, &[]).await
+    }
+}
+
+impl Setup {
+    // Creates the user's trigger order `seed`, buying BTC with 1 USDC.
+    async fn create_trigger(
+        &mut self,
+        seed: u64,
+        price_source: PriceSource,
+        oracle: Option<Pubkey>,
+        min_amount_out: u64,
+        direction: TriggerDirection,
+        threshold: u64,
+    ) -> Result<(), BanksClientError> {
+        let trigger_order = trigger_order(&self.user, seed);
+        let (escrow, output_vault) = order_vaults(self, &trigger_order);
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::CreateTrigger {
+                wallet_owner: self.user,
+                trigger_order,
+                market: self.btc.market,
+                oracle,
+                input_mint: USDC_MINT,
+                output_mint: self.btc.coin_mint,
+                escrow,
+                output_vault,
+                source: self.user_usdc,
+                destination: self.user_btc,
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+                associated_token_program: spl_associated_token_account::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::CreateTrigger {
+                seed,
+                side: Side::Bid,
+                amount_in: ONE,
+                min_amount_out,
+                price_source,
+                direction,
+                threshold,
+                bounty_lamports: TRIGGER_BOUNTY,
+            }
+            .data(),
+        };
+        self.process(ix, &[]).await
+    }
+
+    // Executes the user's trigger order `seed` as `keeper`, buying BTC into `coin_wallet`.
+    async fn trigger(
+        &mut self,
+        keeper: &Keypair,
+        seed: u64,
+        oracle: Option<Pubkey>,
+        coin_wallet: Pubkey,
+    ) -> Result<(), BanksClientError> {
+        let trigger_order = trigger_order(&self.user, seed);
+        let (escrow, output_vault) = order_vaults(self, &trigger_order);
+        let ix = Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::ExecuteTrigger {
+                keeper: keeper.pubkey(),
+                trigger_order,
+                oracle,
+                market: self.btc.accounts(coin_wallet),
+                market_config: None,
+                market_cache: None,
+                pc_wallet: escrow,
+                escrow,
+                output_vault,
+                destination: self.user_btc,
+                state: self.state,
+                user_stats: user_stats(&self.user),
+                fee_exemption: fee_exemption(&self.user),
+                treasury: treasury(),
+                treasury_pc: self.treasury_usdc,
+                dex_program: dex::ID,
+                token_program: spl_token::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::Trigger {}.data(),
+        };
+        self.process(ix, &[keeper]).await
+    }
+}
+
+// A Pyth v2 price account of `price * 10^expo`, published at `publish_slot`.
+fn pyth_price(price: i64, expo: i32, status: u32, publish_slot: u64) -> AccountSharedData {
+    let mut data = vec![0u8; 240];
+    data[0..4].copy_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    data[4..8].copy_from_slice(&2u32.to_le_bytes());
+    data[8..12].copy_from_slice(&3u32.to_le_bytes());
+    data[20..24].copy_from_slice(&expo.to_le_bytes());
+    data[208..216].copy_from_slice(&price.to_le_bytes());
+    data[224..228].copy_from_slice(&status.to_le_bytes());
+    data[232..240].copy_from_slice(&publish_slot.to_le_bytes());
+    let mut account = AccountSharedData::new(1_000_000_000, data.len(), &PYTH_PROGRAM_ID);
+    account.data_as_mut_slice().copy_from_slice(&data);
+    account
+}
+
+fn intent(wallet_owner: &Pubkey, seed: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"intent", wallet_owner.as_ref(), &seed.to_le_bytes()],
+        &dexaggregator::ID,
+    )
+    .0
+}
+
+fn trigger_order(wallet_owner: &Pubkey, seed: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"trigger_order", wallet_owner.as_ref(), &seed.to_le_bytes()],
+        &dexaggregator::ID,
+    )
+    .0
+}
+
+fn order_ledger(market: &Pubkey, wallet_owner: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"order_ledger", market.as_ref(), wallet_owner.as_ref()],
+        &dexaggregator::ID,
+    )
+    .0
+}
+
+fn executor_entry(executor: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"executor", executor.as_ref()], &dexaggregator::ID).0
+}
+
+fn intent_delegate() -> Pubkey {
+    Pubkey::find_program_address(&[b"intent_delegate"], &dexaggregator::ID).0
+}
+
+fn batch_delegate() -> Pubkey {
+    Pubkey::find_program_address(&[b"batch_delegate"], &dexaggregator::ID).0
+}
+
+fn fee_exemption(exempt: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"fee_exemption", exempt.as_ref()], &dexaggregator::ID).0
+}
+
+fn market_config(market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"market", market.as_ref()], &dexaggregator::ID).0
+}
+
+fn market_cache(market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"market_cache", market.as_ref()], &dexaggregator::ID).0
+}
+
+fn open_orders(market: &Pubkey, wallet_owner: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"open_orders", market.as_ref(), wallet_owner.as_ref()],
+        &dexaggregator::ID,
+    )
+    .0
+}
+
+#[tokio::test]
+async fn swap_bid_charges_the_fee_in_quote() {
+    let mut s = setup().await;
+    let accounts = s.swap_accounts(&s.btc, s.user_btc);
+    s.swap(accounts, Side::Bid, 10 * ONE, 900_000, None)
+        .await
+        .unwrap();
+
+    // 1% of the input goes to the treasury, the rest buys up to 0.99 BTC at 10 USDC.
+    assert_eq!(s.balance(&s.treasury_usdc).await, ONE / 10);
+    assert!(s.balance(&s.user_usdc).await >= 90 * ONE);
+    let btc_received = s.balance(&s.user_btc).await - 10 * ONE;
+    assert!((900_000..=990_000).contains(&btc_received));
+
+    // Both volumes count the quote spent, the fee included in the input only.
+    let stats = load_user_stats(&mut s.ctx, &s.user).await;
+    assert_eq!(stats.trade_count, 1);
+    assert_eq!(stats.volume_in, 100 * ONE - s.balance(&s.user_usdc).await);
+    assert_eq!(stats.volume_out, stats.volume_in - ONE / 10);
+}
+
+#[tokio::test]
+async fn swap_ask_charges_the_fee_on_the_proceeds() {
+    let mut s = setup().await;
+    let accounts = s.swap_accounts(&s.btc, s.user_btc);
+    s.swap(accounts, Side::Ask, ONE, 8 * ONE, None)
+        .await
+        .unwrap();
+
+    // 1 BTC sells at 9 USDC, less Serum's taker fee, and 1% of that goes to the treasury.
+    assert_eq!(s.balance(&s.user_btc).await, 9 * ONE);
+    let fee = s.balance(&s.treasury_usdc).await;
+    let proceeds = s.balance(&s.user_usdc).await - 100 * ONE + fee;
+    assert!(proceeds > 8 * ONE && proceeds <= 9 * ONE);
+    assert_eq!(fee, proceeds / 100);
+}
+
+#[tokio::test]
+async fn swap_counts_only_usdc_quoted_volume() {
+    let mut s = setup().await;
+    // A BTC/ETH market, with BTC on offer at 10 ETH.
+    let btc_eth = create_market(&mut s.ctx, &s.btc.coin_mint, &s.eth.coin_mint).await;
+    place_maker_order(
+        &mut s.ctx,
+        &btc_eth,
+        serum_dex::matching::Side::Ask,
+        ASK_PRICE_LOTS,
+        BOOK_DEPTH_LOTS,
+    )
+    .await;
+
+    // The trade counts, but its ETH doesn't count towards the USDC fee tiers.
+    let mut accounts = s.swap_accounts(&btc_eth, s.user_btc);
+    accounts.mint_a = s.eth.coin_mint;
+    accounts.treasury_usdc = get_associated_token_address(&treasury(), &s.eth.coin_mint);
+    accounts.pc_wallet = s.user_eth;
+    s.swap(accounts, Side::Bid, 5 * ONE, 0, None).await.unwrap();
+    let stats = load_user_stats(&mut s.ctx, &s.user).await;
+    assert_eq!(stats.trade_count, 1);
+    assert_eq!((stats.volume_in, stats.volume_out), (0, 0));
+    assert_eq!(stats.window_volume(stats.last_day), 0);
+
+    // An ask on BTC/USDC counts its gross proceeds.
+    let accounts = s.swap_accounts(&s.btc, s.user_btc);
+    s.swap(accounts, Side::Ask, ONE, 0, None).await.unwrap();
+    let proceeds = s.balance(&s.user_usdc).await - 100 * ONE + s.balance(&s.treasury_usdc).await;
+    let stats = load_user_stats(&mut s.ctx, &s.user).await;
+    assert_eq!(stats.trade_count, 2);
+    assert_eq!((stats.volume_in, stats.volume_out), (proceeds, proceeds));
+    assert_eq!(stats.window_volume(stats.last_day), proceeds);
+}
+
+#[tokio::test]
+async fn swap_asks_fill_partially_only_when_allowed() {
+    let mut s = setup().await;
+    // 2 BTC against 1 BTC of bids.
+    let accounts = s.swap_accounts(&s.btc, s.user_btc);
+    assert!(s
+        .swap(accounts.clone(), Side::Ask, 2 * ONE, 0, None)
+        .await
+        .is_err());
+    let result = s
+        .swap_with_fill_policy(accounts.clone(), Side::Ask, 2 * ONE, FillPolicy::FillOrKill)
+        .await;
+    assert!(result.is_err());
+    let policy = FillPolicy::AllowPartial {
+        min_fill_bps: 6_000,
+    };
+    let result = s
+        .swap_with_fill_policy(accounts.clone(), Side::Ask, 2 * ONE, policy)
+        .await;
+    assert_error(result, ErrorCode::FillBelowMinimum);
+
+    let policy = FillPolicy::AllowPartial {
+        min_fill_bps: 4_000,
+    };
+    s.swap_with_fill_policy(accounts, Side::Ask, 2 * ONE, policy)
+        .await
+        .unwrap();
+    assert_eq!(s.balance(&s.user_btc).await, 9 * ONE);
+}
+
+#[tokio::test]
+async fn swap_partial_bids_pay_the_fee_on_the_fill() {
+    let mut s = setup().await;
+    // 20 USDC against 10 USDC of asks.
+    let accounts = s.swap_accounts(&s.btc, s.user_btc);
+    let result = s
+        .swap_with_fill_policy(
+            accounts.clone(),
+            Side::Bid,
+            20 * ONE,
+            FillPolicy::FillOrKill,
+        )
+        .await;
+    assert_error(result, ErrorCode::FillBelowMinimum);
+    let policy = FillPolicy::AllowPartial {
+        min_fill_bps: 10_001,
+    };
+    let result = s
+        .swap_with_fill_policy(accounts.clone(), Side::Bid, 20 * ONE, policy)
+        .await;
+    assert_error(result, ErrorCode::InvalidFillPolicy);
+
+    let policy = FillPolicy::AllowPartial {
+        min_fill_bps: 4_000,
+    };
+    s.swap_with_fill_policy(accounts, Side::Bid, 20 * ONE, policy)
+        .await
+        .unwrap();
+    assert_eq!(s.balance(&s.user_btc).await, 11 * ONE);
+    // The fee is on the ~10 USDC spent, not the 20 USDC input.
+    let fee = s.balance(&s.treasury_usdc).await;
+    let filled = 100 * ONE - s.balance(&s.user_usdc).await - fee;
+    assert!(filled >= 10 * ONE && filled < 11 * ONE);
+    assert_eq!(fee, filled / 100);
+}
+
+#[tokio::test]
+async fn swap_sends_the_output_to_a_recipient_with_a_memo() {
+    let mut s = setup().await;
+    let merchant = Pubkey::new_unique();
+    let merchant_btc = create_token_account(&mut s.ctx, &s.btc.coin_mint, &merchant).await;
+    let merchant_usdc = create_token_account(&mut s.ctx, &USDC_MINT, &merchant).await;
+    let swap = |recipient: Pubkey, memo_program: Option<Pubkey>| {
+        let mut accounts = s.swap_accounts(&s.btc, s.user_btc);
+        accounts.recipient = Some(recipient);
+        accounts.memo_program = memo_program;
+        Instruction {
+            program_id: dexaggregator::ID,
+            accounts: accounts.to_account_metas(None),
+            data: dexaggregator::instruction::Swap {
+                side: Side::Bid,
+                amount_in_max: 10 * ONE,
+                amount_out_min: 900_000,
+                execution_mode: None,
+                match_limit: None,
+                fill_policy: None,
+                memo: Some("invoice-42".to_string()),
+            }
+            .data(),
+        }
+    };
+
+    let memo_program = Some(anchor_spl::memo::ID);
+    let wrong_mint = swap(merchant_usdc, memo_program);
+    let without_memo_program = swap(merchant_btc, None);
+    let to_merchant = swap(merchant_btc, memo_program);
+    assert_error(
+        s.process(wrong_mint, &[]).await,
+        ErrorCode::RecipientMintMismatch,
+    );
+    assert_error(
+        s.process(without_memo_program, &[]).await,
+        ErrorCode::MemoProgramRequired,
+    );
+    s.process(to_merchant, &[]).await.unwrap();
+
+    // All of the BTC bought goes to the merchant, none stays with the user.
+    assert_eq!(s.balance(&s.user_btc).await, 10 * ONE);
+    let btc_received = s.balance(&merchant_btc).await;
+    assert!((900_000..=990_000).contains(&btc_received));
+}
+
+#[tokio::test]
+async fn swap_transitive_routes_through_usdc() {
+    let mut s = setup().await;
+    s.swap_transitive(ONE / 2, 400_000, None).await.unwrap();
+
+    // 0.5 BTC sells for ~4.5 USDC, which buys ~0.44 ETH at 10 USDC after the 1% fee.
+    assert_eq!(s.balance(&s.user_btc).await, 10 * ONE - ONE / 2);
+    let eth_received = s.balance(&s.user_eth).await - 10 * ONE;
+    assert!((400_000..450_000).contains(&eth_received));
+    let fee = s.balance(&s.treasury_usdc).await;
+    assert!(fee > 0 && fee <= 45_000);
+    // Only the lot size spill of the intermediate USDC is left with the user.
+    let spill = s.balance(&s.user_usdc).await - 100 * ONE;
+    assert!(spill < 10_000);
+
+    // The USDC received selling BTC, less what the fee and spill kept from buying ETH.
+    let stats = load_user_stats(&mut s.ctx, &s.user).await;
+    assert!(stats.volume_in > 4 * ONE);
+    assert_eq!(stats.volume_out, stats.volume_in - fee - spill);
+}
+
+#[tokio::test]
+async fn swap_transitive_sweeps_the_spill_to_the_treasury() {
+    let mut s = setup().await;
+    s.swap_transitive(ONE / 2, 400_000, Some(SpillPolicy::Treasury))
+        .await
+        .unwrap();
+
+    assert_eq!(s.balance(&s.user_usdc).await, 100 * ONE);
+    // The fee on ~4.5 USDC, and a spill under the 0.01 USDC of an ETH lot.
+    let swept = s.balance(&s.treasury_usdc).await;
+    assert!(swept > 0 && swept < 45_000 + 10_000);
+}
+
+#[tokio::test]
+async fn batch_swap_swaps_for_signing_and_delegating_users() {
+    let mut s = setup().await;
+    let signer = s.signing_user();
+    let owner = s.intruder().await;
+    let delegator = s.delegating_user(&owner, &batch_delegate(), ONE).await;
+    let now = s.unix_timestamp().await;
+    s.create_intent(&owner, 0, ONE, 8 * ONE, now + 60)
+        .await
+        .unwrap();
+    s.batch_swap(
+        vec![(&signer, Side::Bid, 10 * ONE), (&delegator, Side::Ask, ONE)],
+        false,
+    )
+    .await
+    .unwrap();
+
+    // The signer buys up to 0.99 BTC with 10 USDC, paying 0.1 USDC of fee up front.
+    assert!(s.balance(&s.user_usdc).await >= 90 * ONE);
+    let btc_bought = s.balance(&s.user_btc).await - 10 * ONE;
+    assert!((900_000..=990_000).contains(&btc_bought));
+    // The delegator sells 1 BTC for ~9 USDC through the delegate, less the 1% fee.
+    assert_eq!(s.balance(&delegator.btc).await, 9 * ONE);
+    let proceeds = s.balance(&delegator.usdc).await;
+    assert!((8 * ONE..9 * ONE).contains(&proceeds));
+    let fees = s.balance(&s.treasury_usdc).await;
+    assert!(fees > ONE / 10 && fees < ONE / 10 + 90_000);
+    // The intent is spent.
+    let account = s
+        .ctx
+        .banks_client
+        .get_account(intent(&delegator.owner, 0))
+        .await
+        .unwrap();
+    assert!(account.is_none());
+}
+
+#[tokio::test]
+async fn batch_swap_holds_delegating_users_to_their_intent() {
+    let mut s = setup().await;
+    let owner = s.intruder().await;
+    let delegator = s.delegating_user(&owner, &batch_delegate(), ONE).await;
+
+    // Approving the delegate isn't enough without an intent.
+    let result = s
+        .batch_swap(vec![(&delegator, Side::Ask, ONE)], false)
+        .await;
+    assert_error(result, ErrorCode::IntentAccountsMismatch);
+
+    // 1 BTC sells for ~9 USDC, short of the intent's minimum.
+    let now = s.unix_timestamp().await;
+    s.create_intent(&owner, 0, ONE, 10 * ONE, now + 60)
+        .await
+        .unwrap();
+    let result = s
+        .batch_swap(vec![(&delegator, Side::Ask, ONE)], false)
+        .await;
+    assert!(result.is_err());
+    assert_eq!(s.balance(&delegator.btc).await, 10 * ONE);
+    let result = s
+        .batch_swap(vec![(&delegator, Side::Ask, ONE / 2)], false)
+        .await;
+    assert_error(result, ErrorCode::IntentAccountsMismatch);
+
+    s.advance_clock(60).await;
+    let result = s
+        .batch_swap(vec![(&delegator, Side::Ask, ONE)], false)
+        .await;
+    assert_error(result, ErrorCode::IntentExpired);
+    s.batch_swap(vec![(&delegator, Side::Ask, ONE)], true)
+        .await
+        .unwrap();
+    assert_eq!(s.balance(&delegator.btc).await, 10 * ONE);
+
+    // An approval of the intent delegate of `swap_delegated` doesn't authorize a batch.
+    let other_owner = s.intruder().await;
+    let other = s
+        .delegating_user(&other_owner, &intent_delegate(), ONE)
+        .await;
+    let result = s.batch_swap(vec![(&other, Side::Ask, ONE)], false).await;
+    assert_error(result, ErrorCode::BatchSwapUnauthorized);
+}
+
+#[tokio::test]
+async fn batch_swap_skips_failed_specs_only_when_asked() {
+    let mut s = setup().await;
+    let signer = s.signing_user();
+    let stranger = s
+        .delegating_user(&Keypair::new(), &batch_delegate(), 0)
+        .await;
+
+    let result = s.batch_swap(vec![(&stranger, Side::Ask, ONE)], false).await;
+    assert_error(result, ErrorCode::BatchSwapUnauthorized);
+    let result = s
+        .batch_swap(vec![(&signer, Side::Bid, 1_000 * ONE)], false)
+        .await;
+    assert_error(result, ErrorCode::InsufficientBalance);
+
+    s.batch_swap(
+        vec![
+            (&stranger, Side::Ask, ONE),
+            (&signer, Side::Bid, 1_000 * ONE),
+            (&signer, Side::Bid, 10 * ONE),
+        ],
+        true,
+    )
+    .await
+    .unwrap();
+    assert_eq!(s.balance(&stranger.btc).await, 10 * ONE);
+    // Only the last spec swapped.
+    assert!((90 * ONE..91 * ONE).contains(&s.balance(&s.user_usdc).await));
+    assert!(s.balance(&s.user_btc).await > 10 * ONE);
+}
+
+#[tokio::test]
+async fn swap_delegated_executes_an_intent_for_a_whitelisted_executor() {
+    let mut s = setup().await;
+    let owner = s.intruder().await;
+    let user = s.delegating_user(&owner, &intent_delegate(), ONE).await;
+    let executor = s.intruder().await;
+    let now = s.unix_timestamp().await;
+
+    let result = s.create_intent(&owner, 0, ONE, 0, now).await;
+    assert_error(result, ErrorCode::InvalidIntent);
+    s.create_intent(&owner, 0, ONE, 8 * ONE, now + 3_600)
+        .await
+        .unwrap();
+
+    // Not whitelisted yet.
+    let result = s.swap_delegated(&executor, &user, 0).await;
+    assert_error(result, anchor_lang::error::ErrorCode::AccountNotInitialized);
+
+    s.add_executor(&executor.pubkey()).await.unwrap();
+    s.swap_delegated(&executor, &user, 0).await.unwrap();
+
+    // 1 BTC sells at 9 USDC out of the user's wallet, less Serum's taker fee and 1%.
+    assert_eq!(s.balance(&user.btc).await, 9 * ONE);
+    let fee = s.balance(&s.treasury_usdc).await;
+    let proceeds = s.balance(&user.usdc).await + fee;
+    assert!(proceeds > 8 * ONE && proceeds <= 9 * ONE);
+    assert_eq!(fee, proceeds / 100);
+    // The intent is spent.
+    let account = s
+        .ctx
+        .banks_client
+        .get_account(intent(&user.owner, 0))
+        .await
+        .unwrap();
+    assert!(account.is_none());
+}
+
+#[tokio::test]
+async fn swap_delegated_exempts_the_intent_owner_from_the_fee() {
+    let mut s = setup().await;
+    let owner = s.intruder().await;
+    let user = s.delegating_user(&owner, &intent_delegate(), ONE).await;
+    let executor = s.intruder().await;
+
+    // Executors are whitelisted in the canonical state only.
+    let fake_state = s.fake_state().await;
+    let state = std::mem::replace(&mut s.state, fake_state);
+    let result = s.add_executor(&executor.pubkey()).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintSeeds);
+    s.state = state;
+    s.add_executor(&executor.pubkey()).await.unwrap();
+
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::AddFeeExemption {
+            state: s.state,
+            fee_exemption: fee_exemption(&user.owner),
+            owner: s.user,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::AddFeeExemption { exempt: user.owner }.data(),
+    };
+    s.process(ix, &[]).await.unwrap();
+    let now = s.unix_timestamp().await;
+    s.create_intent(&owner, 0, ONE, 8 * ONE, now + 60)
+        .await
+        .unwrap();
+    s.swap_delegated(&executor, &user, 0).await.unwrap();
+
+    assert_eq!(s.balance(&user.btc).await, 9 * ONE);
+    assert_eq!(s.balance(&s.treasury_usdc).await, 0);
+}
+
+#[tokio::test]
+async fn batch_swap_validates_its_accounts() {
+    let mut s = setup().await;
+    let result = s.batch_swap(vec![], false).await;
+    assert_error(result, ErrorCode::EmptyBatch);
+
+    // The signer's BTC wallet paired with another user's USDC wallet.
+    let delegator = s
+        .delegating_user(&Keypair::new(), &batch_delegate(), ONE)
+        .await;
+    let mixed = BatchUser {
+        owner: s.user,
+        btc: s.user_btc,
+        usdc: delegator.usdc,
+        signs: true,
+    };
+    let result = s.batch_swap(vec![(&mixed, Side::Ask, ONE)], false).await;
+    assert_error(result, ErrorCode::BatchAccountsMismatch);
+
+    // With the allowlist enabled, every leg's market must be registered.
+    let owner = s.ctx.payer.insecure_clone();
+    let allowlist = dexaggregator::instruction::SetMarketAllowlist { enabled: true };
+    s.update_state(&owner, allowlist).await.unwrap();
+    let signer = s.signing_user();
+    let result = s.batch_swap(vec![(&signer, Side::Bid, ONE)], false).await;
+    assert_error(result, ErrorCode::MarketNotRegistered);
+    let btc_market = s.btc.market;
+    s.register_market(&btc_market).await.unwrap();
+    s.batch_swap(vec![(&signer, Side::Bid, ONE)], false)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn swap_delegated_checks_the_intent_wallets_and_expiry() {
+    let mut s = setup().await;
+    let owner = s.intruder().await;
+    let user = s.delegating_user(&owner, &intent_delegate(), ONE).await;
+    let executor = s.intruder().await;
+    s.add_executor(&executor.pubkey()).await.unwrap();
+    let now = s.unix_timestamp().await;
+    s.create_intent(&owner, 0, ONE, 8 * ONE, now + 60)
+        .await
+        .unwrap();
+
+    // The intent's owner, with the wallets of someone else.
+    let other_wallets = BatchUser {
+        owner: user.owner,
+        btc: s.user_btc,
+        usdc: s.user_usdc,
+        signs: false,
+    };
+    let result = s.swap_delegated(&executor, &other_wallets, 0).await;
+    assert_error(result, ErrorCode::IntentAccountsMismatch);
+
+    s.advance_clock(60).await;
+    let result = s.swap_delegated(&executor, &user, 0).await;
+    assert_error(result, ErrorCode::IntentExpired);
+    assert_eq!(s.balance(&user.btc).await, 10 * ONE);
+}
+
+#[tokio::test]
+async fn owner_instructions_reject_other_signers() {
+    let mut s = setup().await;
+    let intruder = s.intruder().await;
+
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::Withdraw {
+            state: s.state,
+            treasury: treasury(),
+            treasury_usdc: s.treasury_usdc,
+            owner: intruder.pubkey(),
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::Withdraw {}.data(),
+    };
+    let result = s.process(ix, &[&intruder]).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintHasOne);
+
+    let set_fees = dexaggregator::instruction::SetFees {
+        fee_bps: 0,
+        fee_tiers: vec![],
+    };
+    let result = s.update_state(&intruder, set_fees).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintHasOne);
+
+    let allowlist = dexaggregator::instruction::SetMarketAllowlist { enabled: true };
+    let result = s.update_state(&intruder, allowlist).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintHasOne);
+}
+
+#[tokio::test]
+async fn set_fees_rejects_invalid_tiers() {
+    let mut s = setup().await;
+    let owner = s.ctx.payer.insecure_clone();
+    let unsorted = dexaggregator::instruction::SetFees {
+        fee_bps: 100,
+        fee_tiers: vec![
+            FeeTier {
+                min_volume: 2 * ONE,
+                fee_bps: 50,
+            },
+            FeeTier {
+                min_volume: ONE,
+                fee_bps: 80,
+            },
+        ],
+    };
+    assert_error(
+        s.update_state(&owner, unsorted).await,
+        ErrorCode::InvalidFeeTiers,
+    );
+
+    let above_base = dexaggregator::instruction::SetFees {
+        fee_bps: 100,
+        fee_tiers: vec![FeeTier {
+            min_volume: ONE,
+            fee_bps: 150,
+        }],
+    };
+    assert_error(
+        s.update_state(&owner, above_base).await,
+        ErrorCode::InvalidFeeTiers,
+    );
+}
+
+#[tokio::test]
+async fn migrate_state_grows_a_legacy_state() {
+    let mut s = setup().await;
+    let owner = s.ctx.payer.insecure_clone();
+    let intruder = s.intruder().await;
+    assert_error(
+        s.migrate_state(None, &owner).await,
+        ErrorCode::StateUpToDate,
+    );
+
+    // An account of the program that isn't a state.
+    let not_a_state = Pubkey::new_unique();
+    let account = AccountSharedData::new(1_000_000_000, 8 + 32, &dexaggregator::ID);
+    s.ctx.set_account(&not_a_state, &account);
+    assert_error(
+        s.migrate_state(Some(not_a_state), &owner).await,
+        ErrorCode::InvalidState,
+    );
+
+    // A keypair state from before fees, holding just its owner.
+    let legacy_owner = Pubkey::new_unique();
+    let legacy = Pubkey::new_unique();
+    let mut data = State::DISCRIMINATOR.to_vec();
+    data.extend_from_slice(legacy_owner.as_ref());
+    let rent = s.ctx.banks_client.get_rent().await.unwrap();
+    let mut account = AccountSharedData::new(
+        rent.minimum_balance(data.len()),
+        data.len(),
+        &dexaggregator::ID,
+    );
+    account.data_as_mut_slice().copy_from_slice(&data);
+    s.ctx.set_account(&legacy, &account);
+
+    assert_error(
+        s.migrate_state(Some(legacy), &intruder).await,
+        ErrorCode::Unauthorized,
+    );
+    assert_error(
+        s.migrate_state(Some(legacy), &owner).await,
+        ErrorCode::StateAlreadyExists,
+    );
+
+    // A cluster initialized before the state became a PDA, where only the upgrade authority
+    // may create it.
+    let state = s.state;
+    s.ctx.set_account(&state, &AccountSharedData::default());
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::Initialize {
+            state,
+            user: intruder.pubkey(),
+            program: dexaggregator::ID,
+            program_data: program_data(),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::Initialize {
+            owner: intruder.pubkey(),
+        }
+        .data(),
+    };
+    let result = s.process(ix, &[&intruder]).await;
+    assert_error(result, ErrorCode::Unauthorized);
+    s.migrate_state(Some(legacy), &owner).await.unwrap();
+
+    let account = s.ctx.banks_client.get_account(legacy).await.unwrap();
+    assert!(account.is_none());
+    let account = s
+        .ctx
+        .banks_client
+        .get_account(state)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(account.owner, dexaggregator::ID);
+    assert_eq!(account.data.len(), 8 + State::LEN);
+    assert!(account.lamports >= rent.minimum_balance(8 + State::LEN));
+    let state = State::try_deserialize(&mut &account.data[..]).unwrap();
+    assert_eq!(state.owner, legacy_owner);
+    assert_eq!(state.fee_bps, DEFAULT_FEE_BPS);
+    assert!(state.fee_tiers.is_empty());
+    assert_eq!(state.version, STATE_VERSION);
+
+    assert_error(
+        s.migrate_state(None, &owner).await,
+        ErrorCode::StateUpToDate,
+    );
+}
+
+#[tokio::test]
+async fn swaps_reject_a_state_other_than_the_pda() {
+    let mut s = setup().await;
+    let fake_state = s.fake_state().await;
+
+    let mut accounts = s.swap_accounts(&s.btc, s.user_btc);
+    accounts.state = fake_state;
+    let result = s.swap(accounts, Side::Bid, ONE, 0, None).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintSeeds);
+
+    s.state = fake_state;
+    let result = s.swap_transitive(ONE, 0, None).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintSeeds);
+    let user = s.signing_user();
+    let result = s.batch_swap(vec![(&user, Side::Bid, ONE)], false).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintSeeds);
+
+    // Nor does the treasury pay out to the owner of another state.
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::Withdraw {
+            state: fake_state,
+            treasury: treasury(),
+            treasury_usdc: s.treasury_usdc,
+            owner: s.user,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::Withdraw {}.data(),
+    };
+    let result = s.process(ix, &[]).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintSeeds);
+}
+
+#[tokio::test]
+async fn swap_fee_exemptions_only_cover_their_own_wallet() {
+    let mut s = setup().await;
+    let owner = s.ctx.payer.insecure_clone();
+    let other = Pubkey::new_unique();
+    let add_fee_exemption = |state: Pubkey, exempt: Pubkey| Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::AddFeeExemption {
+            state,
+            fee_exemption: fee_exemption(&exempt),
+            owner: owner.pubkey(),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::AddFeeExemption { exempt }.data(),
+    };
+    let fake_state = s.fake_state().await;
+    let result = s.process(add_fee_exemption(fake_state, s.user), &[]).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintSeeds);
+    s.process(add_fee_exemption(s.state, other), &[])
+        .await
+        .unwrap();
+
+    let mut accounts = s.swap_accounts(&s.btc, s.user_btc);
+    accounts.fee_exemption = Some(fee_exemption(&other));
+    let result = s.swap(accounts.clone(), Side::Bid, ONE, 0, None).await;
+    assert_error(result, anchor_lang::error::ErrorCode::ConstraintSeeds);
+
+    s.process(add_fee_exemption(s.state, s.user), &[])
+        .await
+        .unwrap();
+    accounts.fee_exemption = Some(fee_exemption(&s.user));
+    s.swap(accounts, Side::Bid, ONE, 0, None).await.unwrap();
+    assert_eq!(s.balance(&s.treasury_usdc).await, 0);
+}
+
+#[tokio::test]
+async fn swap_below_a_lot_fails_instead_of_panicking() {
+    let mut s = setup().await;
+    let accounts = s.swap_accounts(&s.btc, s.user_btc);
+    let result = s
+        .swap(accounts, Side::Ask, COIN_LOT_SIZE - 1, 0, None)
+        .await;
+    assert_error(result, ErrorCode::OrderBelowLotSize);
+    assert_eq!(s.balance(&s.user_btc).await, 10 * ONE);
+}
+
+#[tokio::test]
+async fn swap_rejects_matching_mints() {
+    let mut s = setup().await;
+    let mut accounts = s.swap_accounts(&s.btc, s.user_btc);
+    accounts.pc_wallet = s.user_btc;
+    let result = s.swap(accounts, Side::Bid, ONE, 0, None).await;
+    assert_error(result, ErrorCode::SwapTokensCannotMatch);
+}
+
+#[tokio::test]
+async fn swap_rejects_a_referral_outside_the_quote_mint() {
+    let mut s = setup().await;
+    let treasury_btc = create_token_account(&mut s.ctx, &s.btc.coin_mint, &treasury()).await;
+    let mut accounts = s.swap_accounts(&s.btc, s.user_btc);
+    accounts.referral = Some(treasury_btc);
+    let result = s.swap(accounts, Side::Bid, ONE, 0, None).await;
+    assert_error(result, ErrorCode::InvalidReferralMint);
+}
+
+#[tokio::test]
+async fn swap_checks_the_market_allowlist_and_cache() {
+    let mut s = setup().await;
+    let owner = s.ctx.payer.insecure_clone();
+    let allowlist = dexaggregator::instruction::SetMarketAllowlist { enabled: true };
+    s.update_state(&owner, allowlist).await.unwrap();
+
+    let accounts = s.swap_accounts(&s.btc, s.user_btc);
+    let result = s.swap(accounts, Side::Bid, ONE, 0, None).await;
+    assert_error(result, ErrorCode::MarketNotRegistered);
+
+    let btc_market = s.btc.market;
+    s.register_market(&btc_market).await.unwrap();
+
+    // The BTC market's config does not cover the ETH market.
+    let mut accounts = s.swap_accounts(&s.eth, s.user_eth);
+    accounts.market_config = Some(market_config(&s.btc.market));
+    let result = s.swap(accounts, Side::Bid, ONE, 0, None).await;
+    assert_error(result, ErrorCode::MarketConfigMismatch);
+
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::CacheMarket {
+            market: s.btc.market,
+            dex_program: dex::ID,
+            market_cache: market_cache(&s.btc.market),
+            payer: s.user,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::CacheMarket {}.data(),
+    };
+    s.process(ix, &[]).await.unwrap();
+
+    // With its own config and cache the market swaps.
+    let mut accounts = s.swap_accounts(&s.btc, s.user_btc);
+    accounts.market_config = Some(market_config(&s.btc.market));
+    accounts.market_cache = Some(market_cache(&s.btc.market));
+    s.swap(accounts, Side::Bid, ONE, 0, None).await.unwrap();
+}
+
+#[tokio::test]
+async fn swap_rejects_a_cache_of_another_market() {
+    let mut s = setup().await;
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::CacheMarket {
+            market: s.btc.market,
+            dex_program: dex::ID,
+            market_cache: market_cache(&s.btc.market),
+            payer: s.user,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::CacheMarket {}.data(),
+    };
+    s.process(ix, &[]).await.unwrap();
+
+    let mut accounts = s.swap_accounts(&s.eth, s.user_eth);
+    accounts.market_cache = Some(market_cache(&s.btc.market));
+    let result = s.swap(accounts, Side::Bid, ONE, 0, None).await;
+    assert_error(result, ErrorCode::MarketCacheMismatch);
+}
+
+#[tokio::test]
+async fn new_order_execution_needs_the_open_orders_pda() {
+    let mut s = setup().await;
+    let new_order = Some(ExecutionMode::NewOrder);
+
+    let mut accounts = s.swap_accounts(&s.btc, s.user_btc);
+    let result = s.swap(accounts.clone(), Side::Bid, ONE, 0, new_order).await;
+    assert_error(result, ErrorCode::OpenOrdersRequired);
+
+    accounts.rent = Some(sysvar::rent::ID);
+    accounts.open_orders = Some(Pubkey::new_unique());
+    let result = s.swap(accounts.clone(), Side::Bid, ONE, 0, new_order).await;
+    assert_error(result, ErrorCode::InvalidOpenOrders);
+
+    // 1 USDC can't buy 1 BTC, the settled fill is checked against the minimum.
+    accounts.open_orders = Some(open_orders(&s.btc.market, &s.user));
+    let result = s
+        .swap(accounts.clone(), Side::Bid, ONE, ONE, new_order)
+        .await;
+    assert_error(result, ErrorCode::SwapTokenAmountLessThanMin);
+
+    s.swap(accounts, Side::Bid, ONE, 0, new_order)
+        .await
+        .unwrap();
+    assert!(s.balance(&s.user_btc).await > 10 * ONE);
+}
+
+#[tokio::test]
+async fn new_order_swaps_settle_the_fill_back_to_the_wallets() {
+    let mut s = setup().await;
+    let new_order = Some(ExecutionMode::NewOrder);
+    let open_orders = open_orders(&s.btc.market, &s.user);
+    let mut accounts = s.swap_accounts(&s.btc, s.user_btc);
+    accounts.rent = Some(sysvar::rent::ID);
+    accounts.open_orders = Some(open_orders);
+
+    s.swap(accounts.clone(), Side::Ask, ONE, 8 * ONE, new_order)
+        .await
+        .unwrap();
+    // 1 BTC sells at 9 USDC, less Serum's taker fee, settled straight to the wallet with 1%
+    // of it to the treasury, as with SendTake.
+    assert_eq!(s.balance(&s.user_btc).await, 9 * ONE);
+    let fee = s.balance(&s.treasury_usdc).await;
+    let proceeds = s.balance(&s.user_usdc).await - 100 * ONE + fee;
+    assert!(proceeds > 8 * ONE && proceeds <= 9 * ONE);
+    assert_eq!(fee, proceeds / 100);
+    assert_eq!(open_orders_free(&mut s.ctx, &open_orders).await, (0, 0));
+    assert!(resting_order_ids(&mut s.ctx, &open_orders).await.is_empty());
+
+    // The open orders account is reused, and the unfilled rest of an IOC bid is refunded.
+    let usdc_before = s.balance(&s.user_usdc).await;
+    s.swap(accounts, Side::Bid, 10 * ONE, 900_000, new_order)
+        .await
+        .unwrap();
+    let btc_bought = s.balance(&s.user_btc).await - 9 * ONE;
+    assert!((900_000..=990_000).contains(&btc_bought));
+    let spent = usdc_before - s.balance(&s.user_usdc).await;
+    assert!(spent > 9 * ONE && spent <= 10 * ONE);
+    assert_eq!(open_orders_free(&mut s.ctx, &open_orders).await, (0, 0));
+}
+
+#[tokio::test]
+async fn swap_route_validates_the_route() {
+    let mut s = setup().await;
+    let route = |s: &Setup, output_mint: Pubkey, legs: Vec<Vec<AccountMeta>>| {
+        let mut accounts = dexaggregator::accounts::SwapRoute {
+            wallet_owner: s.user,
+            state: s.state,
+            user_stats: user_stats(&s.user),
+            fee_exemption: None,
+            program_fee_exemption: None,
+            instructions: None,
+            output_mint,
+            treasury: treasury(),
+            treasury_output: get_associated_token_address(&treasury(), &output_mint),
+            system_program: system_program::ID,
+            associated_token_program: spl_associated_token_account::ID,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None);
+        accounts.extend(legs.into_iter().flatten());
+        accounts
+    };
+    let swap_route =
+        |accounts: Vec<AccountMeta>, sides: &[Side], amount_out_min: u64| Instruction {
+            program_id: dexaggregator::ID,
+            accounts,
+            data: dexaggregator::instruction::SwapRoute {
+                legs: sides
+                    .iter()
+                    .map(|side| RouteLeg {
+                        venue: Venue::Serum,
+                        side: *side,
+                        match_limit: None,
+                    })
+                    .collect(),
+                amount_in: ONE,
+                amount_out_min,
+            }
+            .data(),
+        };
+
+    let accounts = route(&s, s.btc.coin_mint, vec![]);
+    let result = s.process(swap_route(accounts, &[], 0), &[]).await;
+    assert_error(result, ErrorCode::EmptyRoute);
+
+    // Both legs spend USDC, the second doesn't start from the first's BTC output.
+    let legs = vec![
+        s.btc.leg_accounts(s.user_btc, s.user_usdc),
+        s.eth.leg_accounts(s.user_eth, s.user_usdc),
+    ];
+    let accounts = route(&s, s.eth.coin_mint, legs);
+    let result = s
+        .process(swap_route(accounts, &[Side::Bid, Side::Bid], 0), &[])
+        .await;
+    assert_error(result, ErrorCode::RouteLegsNotChained);
+
+    let legs = vec![s.btc.leg_accounts(s.user_btc, s.user_usdc)];
+    let accounts = route(&s, USDC_MINT, legs);
+    let result = s.process(swap_route(accounts, &[Side::Bid], 0), &[]).await;
+    assert_error(result, ErrorCode::RouteOutputMintMismatch);
+
+    let legs = vec![s.btc.leg_accounts(s.user_btc, s.user_usdc)];
+    let accounts = route(&s, s.btc.coin_mint, legs);
+    let result = s
+        .process(swap_route(accounts, &[Side::Bid], ONE), &[])
+        .await;
+    assert_error(result, ErrorCode::SwapTokenAmountLessThanMin);
+
+    // With the allowlist enabled, a Serum leg must be followed by its market's config.
+    let owner = s.ctx.payer.insecure_clone();
+    let allowlist = dexaggregator::instruction::SetMarketAllowlist { enabled: true };
+    s.update_state(&owner, allowlist).await.unwrap();
+    let legs = vec![s.btc.leg_accounts(s.user_btc, s.user_usdc)];
+    let accounts = route(&s, s.btc.coin_mint, legs);
+    let result = s.process(swap_route(accounts, &[Side::Bid], 0), &[]).await;
+    assert_error(result, ErrorCode::MarketNotRegistered);
+
+    let btc_market = s.btc.market;
+    s.register_market(&btc_market).await.unwrap();
+    let mut leg = s.btc.leg_accounts(s.user_btc, s.user_usdc);
+    leg.push(AccountMeta::new_readonly(
+        market_config(&s.eth.market),
+        false,
+    ));
+    let accounts = route(&s, s.btc.coin_mint, vec![leg]);
+    let result = s.process(swap_route(accounts, &[Side::Bid], 0), &[]).await;
+    assert_error(result, ErrorCode::MarketNotRegistered);
+
+    let mut leg = s.btc.leg_accounts(s.user_btc, s.user_usdc);
+    leg.push(AccountMeta::new_readonly(
+        market_config(&s.btc.market),
+        false,
+    ));
+    let accounts = route(&s, s.btc.coin_mint, vec![leg]);
+    s.process(swap_route(accounts, &[Side::Bid], 0), &[])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn place_limit_order_rejects_a_zero_order() {
+    let mut s = setup().await;
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::LimitOrder {
+            market: s.btc.accounts(s.user_btc),
+            market_config: None,
+            wallet_owner: s.user,
+            state: s.state,
+            pc_wallet: s.user_usdc,
+            open_orders: open_orders(&s.btc.market, &s.user),
+            order_ledger: order_ledger(&s.btc.market, &s.user),
+            wallet_delegate: None,
+            rent: sysvar::rent::ID,
+            system_program: system_program::ID,
+            dex_program: dex::ID,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::PlaceLimitOrder {
+            side: Side::Bid,
+            limit_price: 0,
+            max_coin_qty: 1,
+            max_native_pc_qty_including_fees: ONE,
+            client_order_id: 0,
+        }
+        .data(),
+    };
+    assert_error(s.process(ix, &[]).await, ErrorCode::ZeroSwap);
+}
+
+#[tokio::test]
+async fn limit_orders_rest_cancel_and_settle() {
+    let mut s = setup().await;
+    let open_orders = open_orders(&s.btc.market, &s.user);
+    let limit_order = |s: &Setup, data: Vec<u8>| Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::LimitOrder {
+            market: s.btc.accounts(s.user_btc),
+            market_config: None,
+            wallet_owner: s.user,
+            state: s.state,
+            pc_wallet: s.user_usdc,
+            open_orders,
+            order_ledger: order_ledger(&s.btc.market, &s.user),
+            wallet_delegate: None,
+            rent: sysvar::rent::ID,
+            system_program: system_program::ID,
+            dex_program: dex::ID,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data,
+    };
+    let settle = |s: &Setup| Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::SettleOrders {
+            market: s.btc.accounts(s.user_btc),
+            wallet_owner: s.user,
+            state: s.state,
+            user_stats: None,
+            fee_exemption: None,
+            program_fee_exemption: None,
+            instructions: None,
+            pc_wallet: s.user_usdc,
+            open_orders,
+            order_ledger: order_ledger(&s.btc.market, &s.user),
+            coin_mint: s.btc.coin_mint,
+            pc_mint: USDC_MINT,
+            treasury: treasury(),
+            treasury_coin: get_associated_token_address(&treasury(), &s.btc.coin_mint),
+            treasury_pc: s.treasury_usdc,
+            rent: sysvar::rent::ID,
+            system_program: system_program::ID,
+            associated_token_program: spl_associated_token_account::ID,
+            dex_program: dex::ID,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::Settle {}.data(),
+    };
+
+    // A bid of 2 lots at 8 USDC rests under the 10 USDC ask.
+    let bid = dexaggregator::instruction::PlaceLimitOrder {
+        side: Side::Bid,
+        limit_price: 8_000,
+        max_coin_qty: 2,
+        max_native_pc_qty_including_fees: ONE,
+        client_order_id: 1,
+    };
+    s.process(limit_order(&s, bid.data()), &[]).await.unwrap();
+    let order_ids = resting_order_ids(&mut s.ctx, &open_orders).await;
+    assert_eq!(order_ids.len(), 1);
+    let locked = 100 * ONE - s.balance(&s.user_usdc).await;
+    assert!((16_000..ONE).contains(&locked));
+
+    // Cancelling frees what the bid locked, which settles back without a fee.
+    let cancel = dexaggregator::instruction::CancelOrder {
+        side: Side::Bid,
+        order_id: order_ids[0],
+    };
+    s.process(limit_order(&s, cancel.data()), &[])
+        .await
+        .unwrap();
+    assert!(resting_order_ids(&mut s.ctx, &open_orders).await.is_empty());
+    assert_eq!(
+        open_orders_free(&mut s.ctx, &open_orders).await,
+        (0, locked)
+    );
+    s.process(settle(&s), &[]).await.unwrap();
+    assert_eq!(s.balance(&s.user_usdc).await, 100 * ONE);
+    assert_eq!(s.balance(&s.treasury_usdc).await, 0);
+
+    // An ask crossing the 9 USDC bid fills at once, and its proceeds settle less the fee.
+    let ask = dexaggregator::instruction::PlaceLimitOrder {
+        side: Side::Ask,
+        limit_price: BID_PRICE_LOTS,
+        max_coin_qty: 1,
+        max_native_pc_qty_including_fees: ONE,
+        client_order_id: 2,
+    };
+    s.process(limit_order(&s, ask.data()), &[]).await.unwrap();
+    assert_eq!(s.balance(&s.user_btc).await, 10 * ONE - COIN_LOT_SIZE);
+    let (_, proceeds) = open_orders_free(&mut s.ctx, &open_orders).await;
+    // 0.001 BTC at 9 USDC, less Serum's taker fee.
+    assert!(proceeds > 8_900 && proceeds <= 9_000);
+    s.process(settle(&s), &[]).await.unwrap();
+    let fee = s.balance(&s.treasury_usdc).await;
+    assert_eq!(fee, proceeds / 100);
+    assert_eq!(s.balance(&s.user_usdc).await, 100 * ONE + proceeds - fee);
+}
+
+#[tokio::test]
+async fn limit_orders_restore_the_wallet_delegate() {
+    let mut s = setup().await;
+    let delegate = Pubkey::new_unique();
+    let approve =
+        spl_token::instruction::approve(&spl_token::ID, &s.user_usdc, &delegate, &s.user, &[], ONE)
+            .unwrap();
+    s.process(approve, &[]).await.unwrap();
+
+    let limit_order =
+        |s: &Setup, wallet_delegate: Option<Pubkey>, client_order_id: u64| Instruction {
+            program_id: dexaggregator::ID,
+            accounts: dexaggregator::accounts::LimitOrder {
+                market: s.btc.accounts(s.user_btc),
+                market_config: None,
+                wallet_owner: s.user,
+                state: s.state,
+                pc_wallet: s.user_usdc,
+                open_orders: open_orders(&s.btc.market, &s.user),
+                order_ledger: order_ledger(&s.btc.market, &s.user),
+                wallet_delegate,
+                rent: sysvar::rent::ID,
+                system_program: system_program::ID,
+                dex_program: dex::ID,
+                token_program: spl_token::ID,
+            }
+            .to_account_metas(None),
+            data: dexaggregator::instruction::PlaceLimitOrder {
+                side: Side::Bid,
+                limit_price: 8_000,
+                max_coin_qty: 1,
+                max_native_pc_qty_including_fees: ONE,
+                client_order_id,
+            }
+            .data(),
+        };
+
+    // The wallet's delegate must be given, to be approved again after the order.
+    let result = s.process(limit_order(&s, None, 1), &[]).await;
+    assert_error(result, ErrorCode::WalletDelegateRequired);
+    let result = s
+        .process(limit_order(&s, Some(Pubkey::new_unique()), 1), &[])
+        .await;
+    assert_error(result, ErrorCode::WalletDelegateRequired);
+
+    s.process(limit_order(&s, Some(delegate), 1), &[])
+        .await
+        .unwrap();
+    assert!(s.balance(&s.user_usdc).await < 100 * ONE);
+    assert_eq!(
+        token_delegate(&mut s.ctx, &s.user_usdc).await,
+        Some((delegate, ONE))
+    );
+
+    // Without one, the order's allowance is dropped.
+    let revoke =
+        spl_token::instruction::revoke(&spl_token::ID, &s.user_usdc, &s.user, &[]).unwrap();
+    s.process(revoke, &[]).await.unwrap();
+    s.process(limit_order(&s, None, 2), &[]).await.unwrap();
+    assert_eq!(token_delegate(&mut s.ctx, &s.user_usdc).await, None);
+}
+
+// Escrow and output vault of an order PDA buying BTC with USDC.
+fn order_vaults(s: &Setup, order: &Pubkey) -> (Pubkey, Pubkey) {
+    (
+        get_associated_token_address(order, &USDC_MINT),
+        get_associated_token_address(order, &s.btc.coin_mint),
+    )
+}
+
+#[tokio::test]
+async fn dca_fills_once_per_interval() {
+    let mut s = setup().await;
+    let seed = 7u64;
+    let dca_order = Pubkey::find_program_address(
+        &[b"dca_order", s.user.as_ref(), &seed.to_le_bytes()],
+        &dexaggregator::ID,
+    )
+    .0;
+    let (escrow, output_vault) = order_vaults(&s, &dca_order);
+    let create_dca = |fill_count: u64| Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::CreateDca {
+            wallet_owner: s.user,
+            dca_order,
+            market: s.btc.market,
+            input_mint: USDC_MINT,
+            output_mint: s.btc.coin_mint,
+            escrow,
+            output_vault,
+            source: s.user_usdc,
+            destination: s.user_btc,
+            system_program: system_program::ID,
+            token_program: spl_token::ID,
+            associated_token_program: spl_associated_token_account::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::CreateDca {
+            seed,
+            side: Side::Bid,
+            amount_per_fill: ONE,
+            interval_seconds: 3_600,
+            min_price: 0,
+            fill_count,
+            bounty_lamports: 5_000,
+        }
+        .data(),
+    };
+    let result = s.process(create_dca(0), &[]).await;
+    assert_error(result, ErrorCode::InvalidDcaOrder);
+    s.process(create_dca(3), &[]).await.unwrap();
+    assert_eq!(s.balance(&escrow).await, 3 * ONE);
+
+    let execute_dca = |s: &Setup, coin_wallet: Pubkey| Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::ExecuteDca {
+            keeper: s.user,
+            dca_order,
+            market: s.btc.accounts(coin_wallet),
+            market_config: None,
+            market_cache: None,
+            pc_wallet: escrow,
+            escrow,
+            output_vault,
+            destination: s.user_btc,
+            state: s.state,
+            user_stats: user_stats(&s.user),
+            fee_exemption: fee_exemption(&s.user),
+            treasury: treasury(),
+            treasury_pc: s.treasury_usdc,
+            dex_program: dex::ID,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::ExecuteDca {}.data(),
+    };
+    // The fill must pay out into the order's output vault, not straight to a wallet.
+    let result = s.process(execute_dca(&s, s.user_btc), &[]).await;
+    assert_error(result, ErrorCode::DcaAccountsMismatch);
+
+    s.process(execute_dca(&s, output_vault), &[]).await.unwrap();
+    // Whatever the lot size leaves of the fill stays in escrow.
+    let escrow_left = s.balance(&escrow).await;
+    assert!((2 * ONE..3 * ONE).contains(&escrow_left));
+    assert_eq!(s.balance(&s.treasury_usdc).await, ONE / 100);
+    assert!(s.balance(&s.user_btc).await > 10 * ONE);
+
+    let result = s.process(execute_dca(&s, output_vault), &[]).await;
+    assert_error(result, ErrorCode::DcaIntervalNotElapsed);
+
+    // Two more fills, an interval apart, spend the order.
+    for _ in 0..2 {
+        s.advance_clock(3_600).await;
+        s.process(execute_dca(&s, output_vault), &[]).await.unwrap();
+    }
+    assert_eq!(s.balance(&s.treasury_usdc).await, 3 * (ONE / 100));
+    s.advance_clock(3_600).await;
+    let result = s.process(execute_dca(&s, output_vault), &[]).await;
+    assert_error(result, ErrorCode::DcaOrderFilled);
+}
+
+#[tokio::test]
+async fn twap_and_trigger_orders_validate_their_spec() {
+    let mut s = setup().await;
+    let seed = 1u64;
+
+    let twap_order = Pubkey::find_program_address(
+        &[b"twap_order", s.user.as_ref(), &seed.to_le_bytes()],
+        &dexaggregator::ID,
+    )
+    .0;
+    let (escrow, output_vault) = order_vaults(&s, &twap_order);
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::CreateTwap {
+            wallet_owner: s.user,
+            twap_order,
+            market: s.btc.market,
+            input_mint: USDC_MINT,
+            output_mint: s.btc.coin_mint,
+            escrow,
+            output_vault,
+            source: s.user_usdc,
+            destination: s.user_btc,
+            system_program: system_program::ID,
+            token_program: spl_token::ID,
+            associated_token_program: spl_associated_token_account::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::CreateTwap {
+            seed,
+            side: Side::Bid,
+            total_amount: ONE,
+            start_slot: 0,
+            end_slot: u64::MAX,
+            band_slots: 0,
+            min_price: 0,
+        }
+        .data(),
+    };
+    assert_error(s.process(ix, &[]).await, ErrorCode::InvalidTwapOrder);
+
+    // 1000 USDC per BTC, far above the book.
+    let threshold = 1_000 * PRICE_SCALE;
+    let above = TriggerDirection::Above;
+    let result = s
+        .create_trigger(seed, PriceSource::Oracle, None, 0, above, threshold)
+        .await;
+    assert_error(result, ErrorCode::InvalidOracle);
+
+    // A well-formed Pyth price account that the Pyth program does not own.
+    let oracle = Pubkey::new_unique();
+    let mut account = pyth_price(1_500, -2, 1, 0);
+    account.set_owner(Pubkey::new_unique());
+    s.ctx.set_account(&oracle, &account);
+    let result = s
+        .create_trigger(seed, PriceSource::Oracle, Some(oracle), 0, above, threshold)
+        .await;
+    assert_error(result, ErrorCode::InvalidOracle);
+
+    // 1 USDC buys 0.001 BTC at the threshold, so a book-priced order must ask for at least
+    // 5% less than that.
+    let min_amount_out = 950;
+    let result = s
+        .create_trigger(
+            seed,
+            PriceSource::BestAsk,
+            None,
+            min_amount_out - 1,
+            above,
+            threshold,
+        )
+        .await;
+    assert_error(result, ErrorCode::InvalidTriggerOrder);
+    s.create_trigger(
+        seed,
+        PriceSource::BestAsk,
+        None,
+        min_amount_out,
+        above,
+        threshold,
+    )
+    .await
+    .unwrap();
+
+    let keeper = s.intruder().await;
+    let (_, output_vault) = order_vaults(&s, &trigger_order(&s.user, seed));
+    let result = s.trigger(&keeper, seed, None, output_vault).await;
+    assert_error(result, ErrorCode::TriggerConditionNotMet);
+}
+
+#[tokio::test]
+async fn twap_fills_a_band_at_a_time_within_its_window() {
+    let mut s = setup().await;
+    let seed = 2u64;
+    let twap_order = Pubkey::find_program_address(
+        &[b"twap_order", s.user.as_ref(), &seed.to_le_bytes()],
+        &dexaggregator::ID,
+    )
+    .0;
+    // Selling BTC, the order escrows BTC and receives USDC.
+    let escrow = get_associated_token_address(&twap_order, &s.btc.coin_mint);
+    let output_vault = get_associated_token_address(&twap_order, &USDC_MINT);
+    // Two bands of 50 slots, selling 1 BTC each.
+    let start_slot = s.slot().await + 10;
+    let ix = Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::CreateTwap {
+            wallet_owner: s.user,
+            twap_order,
+            market: s.btc.market,
+            input_mint: s.btc.coin_mint,
+            output_mint: USDC_MINT,
+            escrow,
+            output_vault,
+            source: s.user_btc,
+            destination: s.user_usdc,
+            system_program: system_program::ID,
+            token_program: spl_token::ID,
+            associated_token_program: spl_associated_token_account::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::CreateTwap {
+            seed,
+            side: Side::Ask,
+            total_amount: 2 * ONE,
+            start_slot,
+            end_slot: start_slot + 99,
+            band_slots: 50,
+            min_price: 0,
+        }
+        .data(),
+    };
+    s.process(ix, &[]).await.unwrap();
+
+    let execute_twap = |s: &Setup, coin_wallet: Pubkey| Instruction {
+        program_id: dexaggregator::ID,
+        accounts: dexaggregator::accounts::ExecuteTwap {
+            keeper: s.user,
+            twap_order,
+            market: s.btc.accounts(coin_wallet),
+            market_config: None,
+            market_cache: None,
+            pc_wallet: output_vault,
+            escrow,
+            output_vault,
+            destination: s.user_usdc,
+            state: s.state,
+            user_stats: user_stats(&s.user),
+            fee_exemption: fee_exemption(&s.user),
+            treasury: treasury(),
+            treasury_pc: s.treasury_usdc,
+            dex_program: dex::ID,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data: dexaggregator::instruction::ExecuteTwap {}.data(),
+    };
+    let result = s.process(execute_twap(&s, escrow), &[]).await;
+    assert_error(result, ErrorCode::TwapOutsideWindow);
+
+    s.ctx.warp_to_slot(start_slot).unwrap();
+    // The fill must sell out of the order's escrow, not a wallet.
+    let result = s.process(execute_twap(&s, s.user_btc), &[]).await;
+    assert_error(result, ErrorCode::TwapAccountsMismatch);
+
+    // The first band sells its 1 BTC into the book's 1 BTC of bids at 9 USDC.
+    s.process(execute_twap(&s, escrow), &[]).await.unwrap();
+    assert_eq!(s.balance(&escrow).await, ONE);
+    let fee = s.balance(&s.treasury_usdc).await;
+    let proceeds = s.balance(&s.user_usdc).await - 100 * ONE + fee;
+    assert!(proceeds > 8 * ONE && proceeds <= 9 * ONE);
+    assert_eq!(fee, proceeds / 100);
+
+    let result = s.process(execute_twap(&s, escrow), &[]).await;
+    assert_error(result, ErrorCode::TwapBandFilled);
+}
+
+#[tokio::test]
+async fn trigger_fills_once_its_condition_holds() {
+    let mut s = setup().await;
+    let keeper = s.intruder().await;
+    // A stop-loss at 1000 USDC per BTC, far above the 10 USDC ask, triggers right away.
+    s.create_trigger(
+        3,
+        PriceSource::BestAsk,
+        None,
+        950,
+        TriggerDirection::Below,
+        1_000 * PRICE_SCALE,
+    )
+    .await
+    .unwrap();
+    let trigger_order = trigger_order(&s.user, 3);
+    let (escrow, output_vault) = order_vaults(&s, &trigger_order);
+    assert_eq!(s.balance(&escrow).await, ONE);
+
+    // The fill must pay out into the order's output vault, not straight to a wallet.
+    let result = s.trigger(&keeper, 3, None, s.user_btc).await;
+    assert_error(result, ErrorCode::TriggerAccountsMismatch);
+
+    let lamports_before = s
+        .ctx
+        .banks_client
+        .get_balance(keeper.pubkey())
+        .await
+        .unwrap();
+    s.trigger(&keeper, 3, None, output_vault).await.unwrap();
+    // 1% of the USDC goes to the treasury, the rest buys up to 0.099 BTC at 10 USDC.
+    assert_eq!(s.balance(&s.treasury_usdc).await, ONE / 100);
+    let btc_received = s.balance(&s.user_btc).await - 10 * ONE;
+    assert!((90_000..=99_000).contains(&btc_received));
+    assert_eq!(s.balance(&output_vault).await, 0);
+    let lamports_after = s
+        .ctx
+        .banks_client
+        .get_balance(keeper.pubkey())
+        .await
+        .unwrap();
+    assert_eq!(lamports_after - lamports_before, TRIGGER_BOUNTY);
+    let account = s
+        .ctx
+        .banks_client
+        .get_account(trigger_order)
+        .await
+        .unwrap()
+        .unwrap();
+    let order = TriggerOrder::try_deserialize(&mut account.data.as_slice()).unwrap();
+    assert!(order.triggered);
+
+    let result = s.trigger(&keeper, 3, None, output_vault).await;
+    assert_error(result, ErrorCode::TriggerOrderExecuted);
+}
+
+#[tokio::test]
+async fn oracle_triggers_need_a_fresh_trading_price() {
+    let mut s = setup().await;
+    let keeper = s.intruder().await;
+    let oracle = Pubkey::new_unique();
+    let (halted, trading) = (2, 1);
+    // 15 USDC per BTC, under a stop-loss at 20.
+    let slot = s.slot().await;
+    s.ctx
+        .set_account(&oracle, &pyth_price(1_500, -2, halted, slot));
+    let below = TriggerDirection::Below;
+    let threshold = 20 * PRICE_SCALE;
+    let result = s
+        .create_trigger(4, PriceSource::Oracle, Some(oracle), 0, below, threshold)
+        .await;
+    assert_error(result, ErrorCode::OraclePriceUnavailable);
+
+    s.ctx
+        .set_account(&oracle, &pyth_price(1_500, -2, trading, slot));
+    s.create_trigger(4, PriceSource::Oracle, Some(oracle), 0, below, threshold)
+        .await
+        .unwrap();
+    let (_, output_vault) = order_vaults(&s, &trigger_order(&s.user, 4));
+
+    // A price published too many slots ago doesn't trigger.
+    let slot = slot + MAX_STALENESS_SLOTS + 1;
+    s.ctx.warp_to_slot(slot).unwrap();
+    let result = s.trigger(&keeper, 4, Some(oracle), output_vault).await;
+    assert_error(result, ErrorCode::OraclePriceUnavailable);
+
+    s.ctx
+        .set_account(&oracle, &pyth_price(1_500, -2, trading, slot));
+    s.trigger(&keeper, 4, Some(oracle), output_vault)
+        .await
+        .unwrap();
+    assert!(s.balance(&s.user_btc).await > 10 * ONE);
+}
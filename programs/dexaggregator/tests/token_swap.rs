@@ -0,0 +1,274 @@
+//! Routes swaps through SPL token-swap constant product pools, against the local stand-in
+//! program of `common::token_swap` and the mainnet program dumped to
+//! `tests/fixtures/token_swap.so`.
+
+mod common;
+
+use anchor_lang::InstructionData;
+use common::*;
+use dexaggregator::{dex::token_swap, RouteLeg, Side, Venue};
+use solana_program_test::ProgramTestContext;
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    program_pack::Pack,
+    pubkey,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+};
+
+// `SwapInstruction::Initialize` tag.
+const INITIALIZE_TAG: u8 = 0;
+// Size of the versioned `SwapV1` pool state.
+const POOL_LEN: usize = 324;
+// Owner of the pool fee account required by the mainnet program.
+const POOL_FEE_OWNER: Pubkey = pubkey!("HfoTxFR1Tm6kGmWgYWD6J7YHVy1UwqSULUGVLXkJqaKN");
+// Trade and owner trade fees required by the mainnet program, out of 10_000.
+const TRADE_FEE: u64 = 25;
+const OWNER_TRADE_FEE: u64 = 5;
+
+struct Pool {
+    pool: Pubkey,
+    authority: Pubkey,
+    token_a: Pubkey,
+    token_b: Pubkey,
+    pool_mint: Pubkey,
+    fee_account: Pubkey,
+}
+
+impl Pool {
+    // The pool's leg accounts, in the order of `token_swap::LEG_ACCOUNTS`.
+    fn leg(&self, user_token_a: Pubkey, user_token_b: Pubkey) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.pool, false),
+            AccountMeta::new_readonly(self.authority, false),
+            AccountMeta::new(self.token_a, false),
+            AccountMeta::new(self.token_b, false),
+            AccountMeta::new(self.pool_mint, false),
+            AccountMeta::new(self.fee_account, false),
+            AccountMeta::new(user_token_a, false),
+            AccountMeta::new(user_token_b, false),
+            AccountMeta::new_readonly(token_swap::ID, false),
+        ]
+    }
+}
+
+// Creates a constant product pool seeded with `amount_a` and `amount_b`.
+async fn create_pool(
+    ctx: &mut ProgramTestContext,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+    amount_a: u64,
+    amount_b: u64,
+) -> Pool {
+    let pool = Keypair::new();
+    let authority = Pubkey::find_program_address(&[pool.pubkey().as_ref()], &token_swap::ID).0;
+    let token_a = create_token_account(ctx, mint_a, &authority).await;
+    let token_b = create_token_account(ctx, mint_b, &authority).await;
+    mint_to(ctx, mint_a, &token_a, amount_a).await;
+    mint_to(ctx, mint_b, &token_b, amount_b).await;
+
+    let pool_mint = Keypair::new();
+    create_account(ctx, &pool_mint, spl_token::state::Mint::LEN, &spl_token::ID).await;
+    let ix = spl_token::instruction::initialize_mint(
+        &spl_token::ID,
+        &pool_mint.pubkey(),
+        &authority,
+        None,
+        6,
+    )
+    .unwrap();
+    process(ctx, &[ix], &[]).await.unwrap();
+    let fee_account = create_token_account(ctx, &pool_mint.pubkey(), &POOL_FEE_OWNER).await;
+    let payer = ctx.payer.pubkey();
+    let lp_account = create_token_account(ctx, &pool_mint.pubkey(), &payer).await;
+    create_account(ctx, &pool, POOL_LEN, &token_swap::ID).await;
+
+    let mut data = vec![INITIALIZE_TAG];
+    for fee in [TRADE_FEE, 10_000, OWNER_TRADE_FEE, 10_000, 0, 0, 20, 100] {
+        data.extend_from_slice(&fee.to_le_bytes());
+    }
+    // `CurveType::ConstantProduct`, which has no parameters.
+    data.push(0);
+    data.extend_from_slice(&[0; 32]);
+    let ix = Instruction {
+        program_id: token_swap::ID,
+        accounts: vec![
+            AccountMeta::new(pool.pubkey(), false),
+            AccountMeta::new_readonly(authority, false),
+            AccountMeta::new_readonly(token_a, false),
+            AccountMeta::new_readonly(token_b, false),
+            AccountMeta::new(pool_mint.pubkey(), false),
+            AccountMeta::new_readonly(fee_account, false),
+            AccountMeta::new(lp_account, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+        ],
+        data,
+    };
+    process(ctx, &[ix], &[]).await.unwrap();
+
+    Pool {
+        pool: pool.pubkey(),
+        authority,
+        token_a,
+        token_b,
+        pool_mint: pool_mint.pubkey(),
+        fee_account,
+    }
+}
+
+// Output of the pool for `amount_in`, as computed by the token-swap program.
+fn expected_out(reserve_in: u64, reserve_out: u64, amount_in: u64) -> u64 {
+    let fee = |numerator: u64| (amount_in * numerator / 10_000).max(1);
+    let amount_in = (amount_in - fee(TRADE_FEE) - fee(OWNER_TRADE_FEE)) as u128;
+    let invariant = reserve_in as u128 * reserve_out as u128;
+    let new_reserve_in = reserve_in as u128 + amount_in;
+    (reserve_out as u128 - invariant.div_ceil(new_reserve_in)) as u64
+}
+
+// Routes A to C through the A/B and C/B pools, selling A for B then buying C with B.
+fn route_ix(accounts: Vec<AccountMeta>, amount_in: u64, amount_out_min: u64) -> Instruction {
+    Instruction {
+        program_id: dexaggregator::ID,
+        accounts,
+        data: dexaggregator::instruction::SwapRoute {
+            legs: vec![
+                RouteLeg {
+                    venue: Venue::TokenSwap,
+                    side: Side::Ask,
+                    match_limit: None,
+                },
+                RouteLeg {
+                    venue: Venue::TokenSwap,
+                    side: Side::Bid,
+                    match_limit: None,
+                },
+            ],
+            amount_in,
+            amount_out_min,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn swap_route_chains_token_swap_standin_pools() {
+    let mut program_test = program_test();
+    common::token_swap::add_token_swap_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    let fees = common::token_swap::Fees {
+        trade_fee_numerator: TRADE_FEE,
+        trade_fee_denominator: 10_000,
+        owner_trade_fee_numerator: OWNER_TRADE_FEE,
+        owner_trade_fee_denominator: 10_000,
+        ..Default::default()
+    };
+    let mint_a = create_mint(&mut ctx, 6).await;
+    let mint_b = create_mint(&mut ctx, 6).await;
+    let mint_c = create_mint(&mut ctx, 6).await;
+    // A at 10 B, and B at 2 C.
+    let pool_ab = common::token_swap::create_standin_pool(
+        &mut ctx,
+        &mint_a,
+        &mint_b,
+        1_000_000_000,
+        10_000_000_000,
+        fees,
+    )
+    .await;
+    let pool_cb = common::token_swap::create_standin_pool(
+        &mut ctx,
+        &mint_c,
+        &mint_b,
+        20_000_000_000,
+        10_000_000_000,
+        fees,
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_a = create_token_account(&mut ctx, &mint_a, &user).await;
+    let user_b = create_token_account(&mut ctx, &mint_b, &user).await;
+    let user_c = create_token_account(&mut ctx, &mint_c, &user).await;
+    mint_to(&mut ctx, &mint_a, &user_a, 2_000_000).await;
+
+    let amount_b =
+        common::token_swap::constant_product_out(&fees, 1_000_000_000, 10_000_000_000, 1_000_000);
+    let amount_c =
+        common::token_swap::constant_product_out(&fees, 10_000_000_000, 20_000_000_000, amount_b);
+    let fee = amount_c / 100;
+
+    // A minimum above the route's output fails the whole route.
+    let mut accounts = swap_route_accounts(&user, &mint_c);
+    accounts.extend(pool_ab.leg(user_a, user_b));
+    accounts.extend(pool_cb.leg(user_c, user_b));
+    let result = process(
+        &mut ctx,
+        &[route_ix(accounts.clone(), 1_000_000, amount_c - fee + 1)],
+        &[],
+    )
+    .await;
+    assert_error(result, dexaggregator::ErrorCode::SwapTokenAmountLessThanMin);
+    assert_eq!(token_balance(&mut ctx, &user_a).await, 2_000_000);
+
+    process(
+        &mut ctx,
+        &[route_ix(accounts, 1_000_000, amount_c - fee)],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(token_balance(&mut ctx, &user_a).await, 1_000_000);
+    assert_eq!(token_balance(&mut ctx, &user_b).await, 0);
+    assert_eq!(token_balance(&mut ctx, &user_c).await, amount_c - fee);
+    assert_eq!(
+        token_balance(&mut ctx, &pool_ab.token_a).await,
+        1_001_000_000
+    );
+    assert_eq!(
+        token_balance(&mut ctx, &pool_cb.token_b).await,
+        10_000_000_000 + amount_b
+    );
+    let treasury_output =
+        spl_associated_token_account::get_associated_token_address(&treasury(), &mint_c);
+    assert_eq!(token_balance(&mut ctx, &treasury_output).await, fee);
+}
+
+#[tokio::test]
+async fn swap_route_chains_token_swap_pools() {
+    require_fixture("token_swap");
+    let mut program_test = program_test();
+    program_test.add_program("token_swap", token_swap::ID, None);
+    let mut ctx = program_test.start_with_context().await;
+
+    let mint_a = create_mint(&mut ctx, 6).await;
+    let mint_b = create_mint(&mut ctx, 6).await;
+    let mint_c = create_mint(&mut ctx, 6).await;
+    // A at 10 B, and B at 2 C.
+    let pool_ab = create_pool(&mut ctx, &mint_a, &mint_b, 1_000_000_000, 10_000_000_000).await;
+    let pool_cb = create_pool(&mut ctx, &mint_c, &mint_b, 20_000_000_000, 10_000_000_000).await;
+
+    let state = initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_a = create_token_account(&mut ctx, &mint_a, &user).await;
+    let user_b = create_token_account(&mut ctx, &mint_b, &user).await;
+    let user_c = create_token_account(&mut ctx, &mint_c, &user).await;
+    mint_to(&mut ctx, &mint_a, &user_a, 1_000_000).await;
+
+    let mut accounts = swap_route_accounts(&user, &mint_c);
+    accounts.extend(pool_ab.leg(user_a, user_b));
+    accounts.extend(pool_cb.leg(user_c, user_b));
+    let ix = route_ix(accounts, 1_000_000, 0);
+    process(&mut ctx, &[ix], &[]).await.unwrap();
+
+    let amount_b = expected_out(1_000_000_000, 10_000_000_000, 1_000_000);
+    let amount_c = expected_out(10_000_000_000, 20_000_000_000, amount_b);
+    let fee = amount_c / 100;
+    assert_eq!(token_balance(&mut ctx, &user_a).await, 0);
+    assert_eq!(token_balance(&mut ctx, &user_b).await, 0);
+    assert_eq!(token_balance(&mut ctx, &user_c).await, amount_c - fee);
+    let treasury_output =
+        spl_associated_token_account::get_associated_token_address(&treasury(), &mint_c);
+    assert_eq!(token_balance(&mut ctx, &treasury_output).await, fee);
+}
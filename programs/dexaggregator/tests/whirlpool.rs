@@ -0,0 +1,455 @@
+//! Routes swaps through Whirlpool pools, against the local stand-in program of
+//! `common::whirlpool` and the mainnet program dumped to `tests/fixtures/whirlpool.so`,
+//! whose swaps the off-chain quoter of `dexaggregator_client` must match.
+
+mod common;
+
+use anchor_lang::{AnchorSerialize, InstructionData};
+use common::whirlpool::{a_for_b, add_whirlpool_standin, b_for_a, create_standin_whirlpool};
+use common::*;
+use dexaggregator::dex::whirlpool;
+use dexaggregator::{RouteLeg, Side, Venue};
+use dexaggregator_client::whirlpool as quoter;
+use solana_program_test::ProgramTestContext;
+use solana_sdk::{
+    account::Account,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_program, sysvar,
+};
+
+// Square root of a price of 4 B per A, in Q64.64.
+const SQRT_PRICE_X64: u128 = 2 << 64;
+
+// Pools of the Whirlpool program, at 0.3% and a tick spacing of 64.
+const TICK_SPACING: u16 = 64;
+const FEE_RATE: u16 = 3_000;
+// Account sizes, discriminator included.
+const WHIRLPOOLS_CONFIG_LEN: usize = 108;
+const FEE_TIER_LEN: usize = 44;
+
+fn route_ix(accounts: Vec<AccountMeta>, side: Side, amount_in: u64) -> Instruction {
+    Instruction {
+        program_id: dexaggregator::ID,
+        accounts,
+        data: dexaggregator::instruction::SwapRoute {
+            legs: vec![RouteLeg {
+                venue: Venue::Whirlpool,
+                side,
+                match_limit: None,
+            }],
+            amount_in,
+            amount_out_min: 0,
+        }
+        .data(),
+    }
+}
+
+#[tokio::test]
+async fn swap_route_trades_both_ways_through_a_whirlpool() {
+    let mut program_test = program_test();
+    add_whirlpool_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    let mint_a = create_mint(&mut ctx, 6).await;
+    let mint_b = create_mint(&mut ctx, 6).await;
+    let pool = create_standin_whirlpool(
+        &mut ctx,
+        &mint_a,
+        &mint_b,
+        SQRT_PRICE_X64,
+        1_000_000_000,
+        4_000_000_000,
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_a = create_token_account(&mut ctx, &mint_a, &user).await;
+    let user_b = create_token_account(&mut ctx, &mint_b, &user).await;
+    mint_to(&mut ctx, &mint_a, &user_a, 1_000_000).await;
+
+    // An ask sells A for B, a to b, with the price limit at the bottom of the range.
+    let amount_b = b_for_a(SQRT_PRICE_X64, 1_000_000);
+    assert_eq!(amount_b, 4_000_000);
+    let mut accounts = swap_route_accounts(&user, &mint_b);
+    accounts.extend(pool.leg(user_a, user_b));
+    process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_000)], &[])
+        .await
+        .unwrap();
+    let fee_b = amount_b / 100;
+    assert_eq!(token_balance(&mut ctx, &user_a).await, 0);
+    assert_eq!(token_balance(&mut ctx, &user_b).await, amount_b - fee_b);
+    assert_eq!(token_balance(&mut ctx, &pool.vault_a).await, 1_001_000_000);
+    assert_eq!(
+        token_balance(&mut ctx, &pool.vault_b).await,
+        4_000_000_000 - amount_b
+    );
+
+    // A bid buys A with B, b to a, with the price limit at the top of the range.
+    let amount_in = amount_b - fee_b;
+    let amount_a = a_for_b(SQRT_PRICE_X64, amount_in);
+    let mut accounts = swap_route_accounts(&user, &mint_a);
+    accounts.extend(pool.leg(user_a, user_b));
+    process(&mut ctx, &[route_ix(accounts, Side::Bid, amount_in)], &[])
+        .await
+        .unwrap();
+    let fee_a = amount_a / 100;
+    assert_eq!(token_balance(&mut ctx, &user_a).await, amount_a - fee_a);
+    assert_eq!(token_balance(&mut ctx, &user_b).await, 0);
+    let treasury_a =
+        spl_associated_token_account::get_associated_token_address(&treasury(), &mint_a);
+    let treasury_b =
+        spl_associated_token_account::get_associated_token_address(&treasury(), &mint_b);
+    assert_eq!(token_balance(&mut ctx, &treasury_a).await, fee_a);
+    assert_eq!(token_balance(&mut ctx, &treasury_b).await, fee_b);
+}
+
+#[tokio::test]
+async fn swap_route_rejects_another_pools_vaults() {
+    let mut program_test = program_test();
+    add_whirlpool_standin(&mut program_test);
+    let mut ctx = program_test.start_with_context().await;
+
+    let mint_a = create_mint(&mut ctx, 6).await;
+    let mint_b = create_mint(&mut ctx, 6).await;
+    let pool = create_standin_whirlpool(
+        &mut ctx,
+        &mint_a,
+        &mint_b,
+        SQRT_PRICE_X64,
+        1_000_000_000,
+        4_000_000_000,
+    )
+    .await;
+    let other_pool = create_standin_whirlpool(
+        &mut ctx,
+        &mint_a,
+        &mint_b,
+        SQRT_PRICE_X64,
+        1_000_000_000,
+        4_000_000_000,
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_a = create_token_account(&mut ctx, &mint_a, &user).await;
+    let user_b = create_token_account(&mut ctx, &mint_b, &user).await;
+    mint_to(&mut ctx, &mint_a, &user_a, 1_000_000).await;
+
+    let mut leg = pool.leg(user_a, user_b);
+    leg[2].pubkey = other_pool.vault_b;
+    let mut accounts = swap_route_accounts(&user, &mint_b);
+    accounts.extend(leg);
+    let result = process(&mut ctx, &[route_ix(accounts, Side::Ask, 1_000_000)], &[]).await;
+    assert!(result.is_err());
+    assert_eq!(token_balance(&mut ctx, &user_a).await, 1_000_000);
+}
+
+fn instruction_data(name: &str, args: impl AnchorSerialize) -> Vec<u8> {
+    let mut data = discriminator("global", name).to_vec();
+    args.serialize(&mut data).unwrap();
+    data
+}
+
+// A pool of the Whirlpool program, at a price of 1 with liquidity in ranges around it.
+struct Pool {
+    whirlpool: Pubkey,
+    vault_a: Pubkey,
+    vault_b: Pubkey,
+}
+
+impl Pool {
+    // The pool's leg accounts for a swap in the direction `a_to_b` from the current price,
+    // with the tick arrays the client quotes through.
+    async fn leg(
+        &self,
+        ctx: &mut ProgramTestContext,
+        user_token_a: Pubkey,
+        user_token_b: Pubkey,
+        a_to_b: bool,
+    ) -> Vec<AccountMeta> {
+        let (pool, _) = self.load(ctx, a_to_b).await;
+        let mut leg = vec![
+            AccountMeta::new(self.whirlpool, false),
+            AccountMeta::new(self.vault_a, false),
+            AccountMeta::new(self.vault_b, false),
+        ];
+        leg.extend(
+            pool.tick_array_start_indexes(a_to_b)
+                .into_iter()
+                .map(|start| {
+                    AccountMeta::new(quoter::tick_array_address(&self.whirlpool, start), false)
+                }),
+        );
+        leg.extend([
+            AccountMeta::new_readonly(quoter::oracle_address(&self.whirlpool), false),
+            AccountMeta::new(user_token_a, false),
+            AccountMeta::new(user_token_b, false),
+            AccountMeta::new_readonly(whirlpool::ID, false),
+        ]);
+        leg
+    }
+
+    // The pool and the tick arrays a swap in the direction `a_to_b` passes through.
+    async fn load(
+        &self,
+        ctx: &mut ProgramTestContext,
+        a_to_b: bool,
+    ) -> (quoter::Whirlpool, Vec<quoter::TickArray>) {
+        let account = ctx
+            .banks_client
+            .get_account(self.whirlpool)
+            .await
+            .unwrap()
+            .unwrap();
+        let pool = quoter::Whirlpool::unpack(&account.data).unwrap();
+        let mut tick_arrays = vec![];
+        for start in pool.tick_array_start_indexes(a_to_b) {
+            let address = quoter::tick_array_address(&self.whirlpool, start);
+            let account = ctx
+                .banks_client
+                .get_account(address)
+                .await
+                .unwrap()
+                .unwrap();
+            tick_arrays.push(quoter::TickArray::unpack(&account.data).unwrap());
+        }
+        (pool, tick_arrays)
+    }
+}
+
+// Creates a pool of `mint_a` and `mint_b`, in that order of their addresses, at a price of 1,
+// with the tick arrays two around the current one and a position of `liquidity` over each
+// of `ranges`. The config and fee tier are written directly, leaving their admin
+// instructions out of the test.
+async fn create_pool(
+    ctx: &mut ProgramTestContext,
+    mint_a: &Pubkey,
+    mint_b: &Pubkey,
+    ranges: &[(i32, i32)],
+    liquidity: u128,
+) -> Pool {
+    let payer = ctx.payer.pubkey();
+    let whirlpools_config = Pubkey::new_unique();
+    let mut data = discriminator("account", "WhirlpoolsConfig").to_vec();
+    // Fee authority, collect protocol fees authority and reward emissions super authority,
+    // without a protocol fee.
+    (payer, payer, payer, 0u16).serialize(&mut data).unwrap();
+    data.resize(WHIRLPOOLS_CONFIG_LEN, 0);
+    set_whirlpool_account(ctx, &whirlpools_config, data);
+
+    let fee_tier = Pubkey::find_program_address(
+        &[
+            b"fee_tier",
+            whirlpools_config.as_ref(),
+            &TICK_SPACING.to_le_bytes(),
+        ],
+        &whirlpool::ID,
+    )
+    .0;
+    let mut data = discriminator("account", "FeeTier").to_vec();
+    (whirlpools_config, TICK_SPACING, FEE_RATE)
+        .serialize(&mut data)
+        .unwrap();
+    data.resize(FEE_TIER_LEN, 0);
+    set_whirlpool_account(ctx, &fee_tier, data);
+
+    let (pool, bump) = Pubkey::find_program_address(
+        &[
+            b"whirlpool",
+            whirlpools_config.as_ref(),
+            mint_a.as_ref(),
+            mint_b.as_ref(),
+            &TICK_SPACING.to_le_bytes(),
+        ],
+        &whirlpool::ID,
+    );
+    let vault_a = Keypair::new();
+    let vault_b = Keypair::new();
+    let initialize_pool = Instruction {
+        program_id: whirlpool::ID,
+        accounts: vec![
+            AccountMeta::new_readonly(whirlpools_config, false),
+            AccountMeta::new_readonly(*mint_a, false),
+            AccountMeta::new_readonly(*mint_b, false),
+            AccountMeta::new(payer, true),
+            AccountMeta::new(pool, false),
+            AccountMeta::new(vault_a.pubkey(), true),
+            AccountMeta::new(vault_b.pubkey(), true),
+            AccountMeta::new_readonly(fee_tier, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(sysvar::rent::ID, false),
+        ],
+        // The pool's bump, its tick spacing and a square root price of 1.
+        data: instruction_data("initialize_pool", (bump, TICK_SPACING, 1u128 << 64)),
+    };
+    process(ctx, &[initialize_pool], &[&vault_a, &vault_b])
+        .await
+        .unwrap();
+
+    let ticks_in_array = quoter::TICK_ARRAY_SIZE * TICK_SPACING as i32;
+    let initialize_tick_arrays: Vec<Instruction> = (-2..=2)
+        .map(|i| Instruction {
+            program_id: whirlpool::ID,
+            accounts: vec![
+                AccountMeta::new_readonly(pool, false),
+                AccountMeta::new(payer, true),
+                AccountMeta::new(quoter::tick_array_address(&pool, i * ticks_in_array), false),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+            data: instruction_data("initialize_tick_array", i * ticks_in_array),
+        })
+        .collect();
+    process(ctx, &initialize_tick_arrays, &[]).await.unwrap();
+
+    let provider_a = create_token_account(ctx, mint_a, &payer).await;
+    let provider_b = create_token_account(ctx, mint_b, &payer).await;
+    mint_to(ctx, mint_a, &provider_a, u64::MAX / 2).await;
+    mint_to(ctx, mint_b, &provider_b, u64::MAX / 2).await;
+    for (tick_lower, tick_upper) in ranges {
+        let position_mint = Keypair::new();
+        let (position, position_bump) = Pubkey::find_program_address(
+            &[b"position", position_mint.pubkey().as_ref()],
+            &whirlpool::ID,
+        );
+        let position_token_account = spl_associated_token_account::get_associated_token_address(
+            &payer,
+            &position_mint.pubkey(),
+        );
+        let open_position = Instruction {
+            program_id: whirlpool::ID,
+            accounts: vec![
+                AccountMeta::new(payer, true),
+                AccountMeta::new_readonly(payer, false),
+                AccountMeta::new(position, false),
+                AccountMeta::new(position_mint.pubkey(), true),
+                AccountMeta::new(position_token_account, false),
+                AccountMeta::new_readonly(pool, false),
+                AccountMeta::new_readonly(spl_token::ID, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new_readonly(sysvar::rent::ID, false),
+                AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+            ],
+            data: instruction_data("open_position", (position_bump, *tick_lower, *tick_upper)),
+        };
+        let tick_array = |tick_index: i32| {
+            quoter::tick_array_address(
+                &pool,
+                quoter::tick_array_start_index(tick_index, TICK_SPACING),
+            )
+        };
+        let increase_liquidity = Instruction {
+            program_id: whirlpool::ID,
+            accounts: vec![
+                AccountMeta::new(pool, false),
+                AccountMeta::new_readonly(spl_token::ID, false),
+                AccountMeta::new_readonly(payer, true),
+                AccountMeta::new(position, false),
+                AccountMeta::new_readonly(position_token_account, false),
+                AccountMeta::new(provider_a, false),
+                AccountMeta::new(provider_b, false),
+                AccountMeta::new(vault_a.pubkey(), false),
+                AccountMeta::new(vault_b.pubkey(), false),
+                AccountMeta::new(tick_array(*tick_lower), false),
+                AccountMeta::new(tick_array(*tick_upper), false),
+            ],
+            data: instruction_data(
+                "increase_liquidity",
+                (liquidity, u64::MAX / 2, u64::MAX / 2),
+            ),
+        };
+        process(ctx, &[open_position, increase_liquidity], &[&position_mint])
+            .await
+            .unwrap();
+    }
+
+    Pool {
+        whirlpool: pool,
+        vault_a: vault_a.pubkey(),
+        vault_b: vault_b.pubkey(),
+    }
+}
+
+fn set_whirlpool_account(ctx: &mut ProgramTestContext, address: &Pubkey, data: Vec<u8>) {
+    let account = Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: whirlpool::ID,
+        executable: false,
+        rent_epoch: 0,
+    };
+    ctx.set_account(address, &account.into());
+}
+
+#[tokio::test]
+async fn swap_route_matches_the_client_quote_on_whirlpool() {
+    require_fixture("whirlpool");
+    let mut program_test = program_test();
+    program_test.add_program("whirlpool", whirlpool::ID, None);
+    let mut ctx = program_test.start_with_context().await;
+
+    let mint_0 = create_mint(&mut ctx, 6).await;
+    let mint_1 = create_mint(&mut ctx, 6).await;
+    let (mint_a, mint_b) = (mint_0.min(mint_1), mint_0.max(mint_1));
+    // A wide range, and a narrow one whose lower tick the ask crosses.
+    let pool = create_pool(
+        &mut ctx,
+        &mint_a,
+        &mint_b,
+        &[(-2_560, 2_560), (-640, 640)],
+        10_000_000_000,
+    )
+    .await;
+
+    initialize_state(&mut ctx).await;
+    let user = ctx.payer.pubkey();
+    let user_a = create_token_account(&mut ctx, &mint_a, &user).await;
+    let user_b = create_token_account(&mut ctx, &mint_b, &user).await;
+    mint_to(&mut ctx, &mint_a, &user_a, 1_000_000_000).await;
+
+    // An ask sells A for B, a to b, receiving exactly the quote, less the protocol fee.
+    let (whirlpool, tick_arrays) = pool.load(&mut ctx, true).await;
+    let quote = quoter::quote_exact_in(&whirlpool, &tick_arrays, 1_000_000_000, true).unwrap();
+    assert_eq!(quote.amount_in, 1_000_000_000);
+    assert_eq!(quote.ticks_crossed, 1);
+    let mut accounts = swap_route_accounts(&user, &mint_b);
+    accounts.extend(pool.leg(&mut ctx, user_a, user_b, true).await);
+    process(
+        &mut ctx,
+        &[route_ix(accounts, Side::Ask, 1_000_000_000)],
+        &[],
+    )
+    .await
+    .unwrap();
+    let fee_b = quote.amount_out / 100;
+    assert_eq!(token_balance(&mut ctx, &user_a).await, 0);
+    assert_eq!(
+        token_balance(&mut ctx, &user_b).await,
+        quote.amount_out - fee_b
+    );
+    let (whirlpool, _) = pool.load(&mut ctx, true).await;
+    assert_eq!(whirlpool.sqrt_price, quote.sqrt_price);
+
+    // A bid buys A back with the B, b to a, crossing the tick up again.
+    let amount_in = quote.amount_out - fee_b;
+    let (whirlpool, tick_arrays) = pool.load(&mut ctx, false).await;
+    let quote = quoter::quote_exact_in(&whirlpool, &tick_arrays, amount_in, false).unwrap();
+    assert_eq!(quote.ticks_crossed, 1);
+    let mut accounts = swap_route_accounts(&user, &mint_a);
+    accounts.extend(pool.leg(&mut ctx, user_a, user_b, false).await);
+    process(&mut ctx, &[route_ix(accounts, Side::Bid, amount_in)], &[])
+        .await
+        .unwrap();
+    let fee_a = quote.amount_out / 100;
+    assert_eq!(token_balance(&mut ctx, &user_b).await, 0);
+    assert_eq!(
+        token_balance(&mut ctx, &user_a).await,
+        quote.amount_out - fee_a
+    );
+    let (whirlpool, _) = pool.load(&mut ctx, false).await;
+    assert_eq!(whirlpool.sqrt_price, quote.sqrt_price);
+}
@@ -0,0 +1,21 @@
+#!/usr/bin/env bash
+# Dumps the third party programs the program tests run against into
+# programs/dexaggregator/tests/fixtures, where `solana-program-test` loads them from.
+# The binaries are gitignored, rerun this after a clean checkout.
+set -euo pipefail
+
+fixtures="$(dirname "$0")/../programs/dexaggregator/tests/fixtures"
+mkdir -p "$fixtures"
+
+dump() {
+  local cluster="$1" program_id="$2" name="$3"
+  echo "Dumping $program_id from $cluster to tests/fixtures/$name.so"
+  solana program dump --url "$cluster" "$program_id" "$fixtures/$name.so"
+}
+
+# Serum v3, deployed by the tests at the program's DEX id.
+dump mainnet-beta 9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin serum_dex
+dump mainnet-beta SwaPpA9LAaLfeLi3a68M4DjnLqgtticKg6CnyNwgAC8 token_swap
+dump mainnet-beta opnb2LAfJYbRMAHHvqjCwQxanZn7ReEHp1k81EohpZb openbook_v2
+dump mainnet-beta whirLbMiicVdio4qvUfM5KAg6Ct8VwpYzGff3uctyCc whirlpool
+dump mainnet-beta PhoeNiXZ8ByJGLkxNfZRnkUfjvmuYqLR89jKjXAbgXc phoenix
@@ -4,13 +4,14 @@ import { Program } from "@coral-xyz/anchor";
 // import { Program } from "@project-serum/anchor";
 import { Dexaggregator } from "../target/types/dexaggregator";
 import { Coin, Dex, DexMarket, FileKeypair } from "@project-serum/serum-dev-tools";
-import { getAssociatedTokenAddress, TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { getAccount, getAssociatedTokenAddress, TOKEN_PROGRAM_ID } from "@solana/spl-token";
 import { Keypair, LAMPORTS_PER_SOL, PublicKey } from "@solana/web3.js";
 import { assert } from "chai";
 import * as utils from "./utils";
 import { Market } from "@project-serum/serum";
 
 const DEX_ADDRESS = 'DESVgJVGajEgKGXhb6XmqDHGz3VjdgP7rEVESBgxmroY';
+const BPF_LOADER_UPGRADEABLE_PROGRAM_ID = new PublicKey('BPFLoaderUpgradeab1e11111111111111111111111');
 
 const BTC_PRICE = 60000;
 const ETH_PRICE = 1300;
@@ -44,6 +45,11 @@ describe("dexaggregator", () => {
   let key = Uint8Array.from(secretKey.split(',').map(i => parseInt(i)));
   const Alice = Keypair.fromSecretKey(key);
 
+  const [state] = PublicKey.findProgramAddressSync(
+    [Buffer.from("state")],
+    program.programId
+  );
+
 
   let
     aliceBtcAccount: PublicKey,
@@ -53,15 +59,58 @@ describe("dexaggregator", () => {
   const dex = new Dex(dexAddres, connection);
 
   it("Initialize owner", async () => {
-    const tx = program.methods
+    // Only the upgrade authority, the wallet that deployed the program, may initialize it.
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+    );
+    await program.methods
         .initialize(
           marketsOwner.publicKey
         )
-        .accounts({})
-        .signers([marketsOwner])
+        .accounts({
+          user: program.provider.publicKey,
+          programData: programData,
+        })
         .rpc()
   })
 
+  it("Sets the fee tiers", async () => {
+    await program.methods
+      .setFees(100, [
+        { minVolume: new anchor.BN(1e6 * 10 ** 6), feeBps: 70 },
+      ])
+      .accounts({
+        state: state,
+        owner: marketsOwner.publicKey,
+      })
+      .signers([marketsOwner])
+      .rpc();
+
+    const stateAccount = await program.account.state.fetch(state);
+    assert.ok(stateAccount.feeBps === 100);
+    assert.ok(stateAccount.feeTiers[0].feeBps === 70);
+  })
+
+  it("Should fail to set unsorted fee tiers", async () => {
+    try {
+      await program.methods
+        .setFees(100, [
+          { minVolume: new anchor.BN(2e6), feeBps: 70 },
+          { minVolume: new anchor.BN(1e6), feeBps: 50 },
+        ])
+        .accounts({
+          state: state,
+          owner: marketsOwner.publicKey,
+        })
+        .signers([marketsOwner])
+        .rpc();
+    } catch (err) {
+      return;
+    }
+    assert.fail("Fee tiers must be sorted by volume");
+  })
+
   it("Sets up the dex, coins and the markets", async () => {
     BTC = await dex.createCoin('BTC', 6, marketsOwner, marketsOwner, marketsOwner);
     ETH = await dex.createCoin('ETH', 6, marketsOwner, marketsOwner, marketsOwner);
@@ -184,6 +233,10 @@ describe("dexaggregator", () => {
         // new anchor.BN(swapBtcInput * 10 ** BTC.decimals),
         new anchor.BN(swapBtcInput * 10 ** 8),
         new anchor.BN(0),
+        null,
+        null,
+        null,
+        null,
       )
       .accounts({
         market: {
@@ -199,6 +252,7 @@ describe("dexaggregator", () => {
           coinWallet: aliceBtcAccount,
         },
         walletOwner: Alice.publicKey,
+        state: state,
         pcWallet: aliceUsdcAccount,
         dexProgram: dexAddres,
         tokenProgram: TOKEN_PROGRAM_ID,
@@ -228,6 +282,10 @@ describe("dexaggregator", () => {
         Side.Bid,
         new anchor.BN(1e3 * 10 ** USDC.decimals),
         new anchor.BN(0),
+        null,
+        null,
+        null,
+        null,
       )
       .accounts({
         market: {
@@ -242,6 +300,7 @@ describe("dexaggregator", () => {
           coinWallet: aliceBtcAccount,
         },
         walletOwner: Alice.publicKey,
+        state: state,
         pcWallet: aliceUsdcAccount,
         dexProgram: dexAddres,
         tokenProgram: TOKEN_PROGRAM_ID,
@@ -257,6 +316,70 @@ describe("dexaggregator", () => {
 
     assert.ok(-usdcBalanceChange <= swapUsdcInput);
     assert.ok(btcBalanceChange / (1 - USER_FEE) <= expectedBtcOutput);
+
+    const [userStats] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stats"), Alice.publicKey.toBuffer()],
+      program.programId
+    );
+    const stats = await program.account.userStats.fetch(userStats);
+    assert.ok(stats.tradeCount.toNumber() > 0);
+    assert.ok(stats.volumeIn.toNumber() > 0);
+  });
+
+  it('should swap USDC -> BTC with the treasury as Serum referrer', async () => {
+    const [treasury] = PublicKey.findProgramAddressSync(
+      [Buffer.from("treasury")],
+      program.programId
+    );
+    const treasuryUsdc = await getAssociatedTokenAddress(USDCMint, treasury, true);
+    const amountIn = new anchor.BN(1e3 * 10 ** USDC.decimals);
+
+    const btcBalanceBefore = await connection.getTokenAccountBalance(aliceBtcAccount);
+    const treasuryBefore = await connection.getTokenAccountBalance(treasuryUsdc);
+
+    await program.methods
+      .swap(
+        Side.Bid,
+        amountIn,
+        new anchor.BN(0),
+        null,
+        null,
+        null,
+        null,
+      )
+      .accounts({
+        market: {
+          market: btcAddress,
+          requestQueue: requestQueue,
+          eventQueue: eventQueue,
+          marketBids: bids,
+          marketAsks: asks,
+          coinVault: baseVault,
+          pcVault: quoteVault,
+          vaultSigner: btcMarketVaultSigner,
+          coinWallet: aliceBtcAccount,
+        },
+        walletOwner: Alice.publicKey,
+        state: state,
+        pcWallet: aliceUsdcAccount,
+        referral: treasuryUsdc,
+        dexProgram: dexAddres,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    const btcBalanceAfter = await connection.getTokenAccountBalance(aliceBtcAccount);
+    const treasuryAfter = await connection.getTokenAccountBalance(treasuryUsdc);
+    assert.ok(btcBalanceAfter.value.uiAmount > btcBalanceBefore.value.uiAmount);
+
+    // Volume tiers only lower the fee, so anything above the base fee is the rebate.
+    const stateAccount = await program.account.state.fetch(state);
+    const maxProtocolFee = amountIn.muln(stateAccount.feeBps).divn(10_000);
+    const treasuryDelta = new anchor.BN(treasuryAfter.value.amount).sub(
+      new anchor.BN(treasuryBefore.value.amount)
+    );
+    assert.ok(treasuryDelta.gt(maxProtocolFee));
   });
 
   it('should fail to swap because min output not met', async () => {
@@ -273,6 +396,10 @@ describe("dexaggregator", () => {
           Side.Ask,
           new anchor.BN(swapBtcInput * 10 ** BTC.decimals),
           new anchor.BN(expectedToFailMinUsdcOutput * 10 ** USDC.decimals),
+          null,
+          null,
+          null,
+          null,
         )
         .accounts({
           market: {
@@ -287,6 +414,8 @@ describe("dexaggregator", () => {
           coinWallet: aliceBtcAccount,
           },
           walletOwner: Alice.publicKey,
+          state: state,
+        state: state,
           pcWallet: aliceUsdcAccount,
           dexProgram: dexAddres,
           tokenProgram: TOKEN_PROGRAM_ID,
@@ -321,6 +450,10 @@ describe("dexaggregator", () => {
           Side.Ask,
           new anchor.BN(swapBtcInput * 10 ** BTC.decimals),
           new anchor.BN(0),
+          null,
+          null,
+          null,
+          null,
         )
         .accounts({
           market: {
@@ -335,6 +468,8 @@ describe("dexaggregator", () => {
             coinWallet: aliceBtcAccount,
           },
           walletOwner: Alice.publicKey,
+          state: state,
+        state: state,
           pcWallet: aliceBtcAccount,
           dexProgram: dexAddres,
           tokenProgram: TOKEN_PROGRAM_ID,
@@ -368,6 +503,10 @@ describe("dexaggregator", () => {
     const swapTx = await program.methods.swapTransitive(
       new anchor.BN(1 * 10 ** ETH.decimals),
       new anchor.BN(0),
+      null,
+      null,
+      null,
+      null,
     )
       .accounts({
         from: {
@@ -393,6 +532,7 @@ describe("dexaggregator", () => {
           coinWallet: aliceBtcAccount,
         },
         walletOwner: Alice.publicKey,
+        state: state,
         pcWallet: aliceUsdcAccount,
         dexProgram: dexAddres,
         tokenProgram: TOKEN_PROGRAM_ID,
@@ -426,6 +566,10 @@ describe("dexaggregator", () => {
       const swapTx = await program.methods.swapTransitive(
         new anchor.BN(1 * 10 ** ETH.decimals),
         new anchor.BN(expectedToFailMinBtcOutput * 10 ** BTC.decimals),
+        null,
+        null,
+        null,
+        null,
       )
         .accounts({
           from: {
@@ -451,6 +595,8 @@ describe("dexaggregator", () => {
             coinWallet: aliceBtcAccount,
           },
           walletOwner: Alice.publicKey,
+          state: state,
+        state: state,
           pcWallet: aliceUsdcAccount,
           dexProgram: dexAddres,
           tokenProgram: TOKEN_PROGRAM_ID,
@@ -489,6 +635,10 @@ describe("dexaggregator", () => {
       const swapTx = await program.methods.swapTransitive(
         new anchor.BN(1 * 10 ** ETH.decimals),
         new anchor.BN(0),
+        null,
+        null,
+        null,
+        null,
       )
         .accounts({
           from: {
@@ -514,6 +664,8 @@ describe("dexaggregator", () => {
             coinWallet: aliceBtcAccount,
           },
           walletOwner: Alice.publicKey,
+          state: state,
+        state: state,
           pcWallet: aliceBtcAccount,
           dexProgram: dexAddres,
           tokenProgram: TOKEN_PROGRAM_ID,
@@ -539,10 +691,686 @@ describe("dexaggregator", () => {
     assert.fail('Swap Transitive should have failed because mints cannot match');
   });
 
+  it('should swap USDC -> BTC without fee when exempt', async () => {
+    const [feeExemption] = PublicKey.findProgramAddressSync(
+      [Buffer.from("fee_exemption"), Alice.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .addFeeExemption(Alice.publicKey)
+      .accounts({
+        state: state,
+        owner: marketsOwner.publicKey,
+      })
+      .signers([marketsOwner])
+      .rpc();
+
+    const [treasury] = PublicKey.findProgramAddressSync(
+      [Buffer.from("treasury")],
+      program.programId
+    );
+    const treasuryUsdc = await getAssociatedTokenAddress(USDCMint, treasury, true);
+    const treasuryBalanceBefore = await connection.getTokenAccountBalance(treasuryUsdc);
+
+    await program.methods
+      .swap(
+        Side.Bid,
+        new anchor.BN(1e3 * 10 ** USDC.decimals),
+        new anchor.BN(0),
+        null,
+        null,
+        null,
+        null,
+      )
+      .accounts({
+        market: {
+          market: btcAddress,
+          requestQueue: requestQueue,
+          eventQueue: eventQueue,
+          marketBids: bids,
+          marketAsks: asks,
+          coinVault: baseVault,
+          pcVault: quoteVault,
+          vaultSigner: btcMarketVaultSigner,
+          coinWallet: aliceBtcAccount,
+        },
+        walletOwner: Alice.publicKey,
+        state: state,
+        feeExemption,
+        pcWallet: aliceUsdcAccount,
+        dexProgram: dexAddres,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    const treasuryBalanceAfter = await connection.getTokenAccountBalance(treasuryUsdc);
+    assert.ok(treasuryBalanceAfter.value.amount === treasuryBalanceBefore.value.amount);
+
+    await program.methods
+      .removeFeeExemption()
+      .accounts({
+        state: state,
+        feeExemption,
+        owner: marketsOwner.publicKey,
+      })
+      .signers([marketsOwner])
+      .rpc();
+  });
+
+  it('should only swap on registered markets when the allowlist is enabled', async () => {
+    const [btcMarketConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("market"), btcAddress.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .registerMarket({ sendTake: {} })
+      .accounts({
+        state: state,
+        market: btcAddress,
+        dexProgram: dexAddres,
+        owner: marketsOwner.publicKey,
+      })
+      .signers([marketsOwner])
+      .rpc();
+    await program.methods
+      .setMarketAllowlist(true)
+      .accounts({
+        state: state,
+        owner: marketsOwner.publicKey,
+      })
+      .signers([marketsOwner])
+      .rpc();
+
+    const swap = (marketConfig: PublicKey | null) => program.methods
+      .swap(
+        Side.Bid,
+        new anchor.BN(1e3 * 10 ** USDC.decimals),
+        new anchor.BN(0),
+        null,
+        null,
+        null,
+        null,
+      )
+      .accounts({
+        market: {
+          market: btcAddress,
+          requestQueue: requestQueue,
+          eventQueue: eventQueue,
+          marketBids: bids,
+          marketAsks: asks,
+          coinVault: baseVault,
+          pcVault: quoteVault,
+          vaultSigner: btcMarketVaultSigner,
+          coinWallet: aliceBtcAccount,
+        },
+        marketConfig,
+        walletOwner: Alice.publicKey,
+        state: state,
+        pcWallet: aliceUsdcAccount,
+        dexProgram: dexAddres,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    await swap(btcMarketConfig);
+
+    let failed = false;
+    try {
+      await swap(null);
+    } catch (err) {
+      failed = true;
+    }
+    assert.ok(failed, "Swap without a market config should have failed");
+
+    await program.methods
+      .setMarketAllowlist(false)
+      .accounts({
+        state: state,
+        owner: marketsOwner.publicKey,
+      })
+      .signers([marketsOwner])
+      .rpc();
+  });
+
+  it('should use fewer compute units swapping with a market cache', async () => {
+    const [btcMarketCache] = PublicKey.findProgramAddressSync(
+      [Buffer.from("market_cache"), btcAddress.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .cacheMarket()
+      .accounts({
+        market: btcAddress,
+        dexProgram: dexAddres,
+        payer: Alice.publicKey,
+      })
+      .signers([Alice])
+      .rpc();
+
+    const unitsConsumed = async (marketCache: PublicKey | null) => {
+      const simulation = await program.methods
+        .swap(
+          Side.Ask,
+          new anchor.BN(10 ** BTC.decimals),
+          new anchor.BN(0),
+          null,
+          null,
+          null,
+          null,
+        )
+        .accounts({
+          market: {
+            market: btcAddress,
+            requestQueue: requestQueue,
+            eventQueue: eventQueue,
+            marketBids: bids,
+            marketAsks: asks,
+            coinVault: baseVault,
+            pcVault: quoteVault,
+            vaultSigner: btcMarketVaultSigner,
+            coinWallet: aliceBtcAccount,
+          },
+          marketCache,
+          walletOwner: Alice.publicKey,
+          state: state,
+          pcWallet: aliceUsdcAccount,
+          dexProgram: dexAddres,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([Alice])
+        .simulate();
+      const consumed = simulation.raw
+        .map((log) => log.match(new RegExp(`Program ${program.programId} consumed (\\d+) of`)))
+        .find((match) => match !== null);
+      return parseInt(consumed[1]);
+    };
+
+    const withoutCache = await unitsConsumed(null);
+    const withCache = await unitsConsumed(btcMarketCache);
+    console.log(`swap compute units: ${withoutCache} without cache, ${withCache} with cache`);
+    assert.ok(withCache < withoutCache);
+  });
+
+  it('should swap BTC -> USDC through a single Serum route leg', async () => {
+    const btcBalanceBefore = await connection.getTokenAccountBalance(aliceBtcAccount);
+    const usdcBalanceBefore = await connection.getTokenAccountBalance(aliceUsdcAccount);
+
+    const serumLeg = [
+      btcAddress,
+      requestQueue,
+      eventQueue,
+      bids,
+      asks,
+      baseVault,
+      quoteVault,
+      btcMarketVaultSigner,
+      aliceBtcAccount,
+      aliceUsdcAccount,
+      dexAddres,
+    ].map((pubkey) => ({ pubkey, isSigner: false, isWritable: !pubkey.equals(dexAddres) }));
+
+    await program.methods
+      .swapRoute(
+        [{ venue: { serum: {} }, side: Side.Ask, matchLimit: null }],
+        new anchor.BN(10 ** BTC.decimals),
+        new anchor.BN(0),
+      )
+      .accounts({
+        walletOwner: Alice.publicKey,
+        state: state,
+        outputMint: USDCMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts(serumLeg)
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    const btcBalanceAfter = await connection.getTokenAccountBalance(aliceBtcAccount);
+    const usdcBalanceAfter = await connection.getTokenAccountBalance(aliceUsdcAccount);
+
+    assert.ok(btcBalanceAfter.value.uiAmount < btcBalanceBefore.value.uiAmount);
+    assert.ok(usdcBalanceAfter.value.uiAmount > usdcBalanceBefore.value.uiAmount);
+  });
+
+  it('should swap USDC -> BTC with NewOrderV3 through a PDA open orders account', async () => {
+    const btcBalanceBefore = await connection.getTokenAccountBalance(aliceBtcAccount);
+    const usdcBalanceBefore = await connection.getTokenAccountBalance(aliceUsdcAccount);
+
+    const [openOrders] = PublicKey.findProgramAddressSync(
+      [Buffer.from("open_orders"), btcAddress.toBuffer(), Alice.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .swap(
+        Side.Bid,
+        new anchor.BN(1e3 * 10 ** USDC.decimals),
+        new anchor.BN(0),
+        { newOrder: {} },
+        null,
+        null,
+        null,
+      )
+      .accounts({
+        market: {
+          market: btcAddress,
+          requestQueue: requestQueue,
+          eventQueue: eventQueue,
+          marketBids: bids,
+          marketAsks: asks,
+          coinVault: baseVault,
+          pcVault: quoteVault,
+          vaultSigner: btcMarketVaultSigner,
+          coinWallet: aliceBtcAccount,
+        },
+        walletOwner: Alice.publicKey,
+        state: state,
+        pcWallet: aliceUsdcAccount,
+        openOrders,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        dexProgram: dexAddres,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    const btcBalanceAfter = await connection.getTokenAccountBalance(aliceBtcAccount);
+    const usdcBalanceAfter = await connection.getTokenAccountBalance(aliceUsdcAccount);
+
+    assert.ok(btcBalanceAfter.value.uiAmount > btcBalanceBefore.value.uiAmount);
+    assert.ok(usdcBalanceAfter.value.uiAmount < usdcBalanceBefore.value.uiAmount);
+
+    // The open orders account is the DEX's, settled and with no allowance left over.
+    const openOrdersInfo = await connection.getAccountInfo(openOrders);
+    assert.ok(openOrdersInfo.owner.equals(dexAddres));
+    const usdcAccount = await getAccount(connection, aliceUsdcAccount);
+    assert.ok(usdcAccount.delegate === null);
+  });
+
+  it('should rest, cancel and settle a limit order without a fee on the refund', async () => {
+    const usdcBalanceBefore = await connection.getTokenAccountBalance(aliceUsdcAccount);
+
+    const [openOrders] = PublicKey.findProgramAddressSync(
+      [Buffer.from("open_orders"), btcAddress.toBuffer(), Alice.publicKey.toBuffer()],
+      program.programId
+    );
+    const [orderLedger] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_ledger"), btcAddress.toBuffer(), Alice.publicKey.toBuffer()],
+      program.programId
+    );
+    const market = {
+      market: btcAddress,
+      requestQueue: requestQueue,
+      eventQueue: eventQueue,
+      marketBids: bids,
+      marketAsks: asks,
+      coinVault: baseVault,
+      pcVault: quoteVault,
+      vaultSigner: btcMarketVaultSigner,
+      coinWallet: aliceBtcAccount,
+    };
+    const limitOrderAccounts = {
+      market,
+      walletOwner: Alice.publicKey,
+      state: state,
+      pcWallet: aliceUsdcAccount,
+      openOrders,
+      orderLedger,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      dexProgram: dexAddres,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    // A bid far below the book rests without filling.
+    await program.methods
+      .placeLimitOrder(
+        Side.Bid,
+        new anchor.BN(1),
+        new anchor.BN(10),
+        new anchor.BN(10 ** USDC.decimals),
+        new anchor.BN(42),
+      )
+      .accounts(limitOrderAccounts)
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    const serumMarket = await Market.load(connection, btcAddress, undefined, dexAddres);
+    const [restingOrder] = (await serumMarket.loadOrdersForOwner(connection, openOrders))
+      .filter((order) => order.clientId?.eqn(42));
+    assert.ok(restingOrder);
+
+    await program.methods
+      .cancelOrder(Side.Bid, restingOrder.orderId)
+      .accounts(limitOrderAccounts)
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    await program.methods
+      .settle()
+      .accounts({
+        market,
+        walletOwner: Alice.publicKey,
+        state: state,
+        pcWallet: aliceUsdcAccount,
+        openOrders,
+        orderLedger,
+        coinMint: BTCMint,
+        pcMint: USDCMint,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        dexProgram: dexAddres,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    // The whole refund is back, with no protocol fee charged on it.
+    const usdcBalanceAfter = await connection.getTokenAccountBalance(aliceUsdcAccount);
+    assert.equal(usdcBalanceAfter.value.amount, usdcBalanceBefore.value.amount);
+    const ledger = await program.account.orderLedger.fetch(orderLedger);
+    assert.ok(ledger.refundedPc.isZero());
+  });
+
+  it('should fill a USDC -> BTC DCA order by a keeper and refund the rest on cancel', async () => {
+    const keeper = Keypair.generate();
+    const airdrop = await connection.requestAirdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+    await connection.confirmTransaction(airdrop);
+
+    const [treasury] = PublicKey.findProgramAddressSync(
+      [Buffer.from("treasury")],
+      program.programId
+    );
+    const seed = new anchor.BN(1);
+    const [dcaOrder] = PublicKey.findProgramAddressSync(
+      [Buffer.from("dca_order"), Alice.publicKey.toBuffer(), seed.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const escrow = await getAssociatedTokenAddress(USDCMint, dcaOrder, true);
+    const outputVault = await getAssociatedTokenAddress(BTCMint, dcaOrder, true);
+    const amountPerFill = new anchor.BN(100 * 10 ** USDC.decimals);
+    const bountyLamports = new anchor.BN(10_000);
+
+    await program.methods
+      .createDca(seed, Side.Bid, amountPerFill, new anchor.BN(3600), new anchor.BN(0), new anchor.BN(3), bountyLamports)
+      .accounts({
+        walletOwner: Alice.publicKey,
+        market: btcAddress,
+        inputMint: USDCMint,
+        outputMint: BTCMint,
+        source: aliceUsdcAccount,
+        destination: aliceBtcAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    const btcBalanceBefore = await connection.getTokenAccountBalance(aliceBtcAccount);
+    const keeperLamportsBefore = await connection.getBalance(keeper.publicKey);
+    const executeDca = () => program.methods
+      .executeDca()
+      .accounts({
+        keeper: keeper.publicKey,
+        dcaOrder,
+        market: {
+          market: btcAddress,
+          requestQueue: requestQueue,
+          eventQueue: eventQueue,
+          marketBids: bids,
+          marketAsks: asks,
+          coinVault: baseVault,
+          pcVault: quoteVault,
+          vaultSigner: btcMarketVaultSigner,
+          coinWallet: outputVault,
+        },
+        pcWallet: escrow,
+        escrow,
+        outputVault,
+        destination: aliceBtcAccount,
+        state: state,
+        userStats: PublicKey.findProgramAddressSync(
+          [Buffer.from("user_stats"), Alice.publicKey.toBuffer()],
+          program.programId
+        )[0],
+        feeExemption: PublicKey.findProgramAddressSync(
+          [Buffer.from("fee_exemption"), Alice.publicKey.toBuffer()],
+          program.programId
+        )[0],
+        treasuryPc: await getAssociatedTokenAddress(USDCMint, treasury, true),
+        dexProgram: dexAddres,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([keeper])
+      .rpc({ skipPreflight: false });
+
+    await executeDca();
+
+    const btcBalanceAfter = await connection.getTokenAccountBalance(aliceBtcAccount);
+    assert.ok(btcBalanceAfter.value.uiAmount > btcBalanceBefore.value.uiAmount);
+    const keeperLamportsAfter = await connection.getBalance(keeper.publicKey);
+    assert.ok(keeperLamportsAfter > keeperLamportsBefore);
+    const order = await program.account.dcaOrder.fetch(dcaOrder);
+    assert.equal(order.remainingFills.toNumber(), 2);
+
+    // The next fill is not due for another interval.
+    try {
+      await executeDca();
+      assert.fail("Filled before the interval elapsed");
+    } catch (err) {
+      assert.include(err.toString(), "DcaIntervalNotElapsed");
+    }
+
+    const usdcBalanceBefore = await connection.getTokenAccountBalance(aliceUsdcAccount);
+    await program.methods
+      .cancelDca()
+      .accounts({
+        walletOwner: Alice.publicKey,
+        dcaOrder,
+        escrow,
+        outputVault,
+        refundAccount: aliceUsdcAccount,
+        destination: aliceBtcAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    const usdcBalanceAfter = await connection.getTokenAccountBalance(aliceUsdcAccount);
+    assert.equal(
+      new anchor.BN(usdcBalanceAfter.value.amount).sub(new anchor.BN(usdcBalanceBefore.value.amount)).toString(),
+      amountPerFill.muln(2).toString()
+    );
+    assert.ok((await connection.getAccountInfo(dcaOrder)) === null);
+  });
+
+  it('should crank a USDC -> BTC TWAP order within its band cap and track its average price', async () => {
+    const keeper = Keypair.generate();
+    const [treasury] = PublicKey.findProgramAddressSync(
+      [Buffer.from("treasury")],
+      program.programId
+    );
+    const seed = new anchor.BN(1);
+    const [twapOrder] = PublicKey.findProgramAddressSync(
+      [Buffer.from("twap_order"), Alice.publicKey.toBuffer(), seed.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const escrow = await getAssociatedTokenAddress(USDCMint, twapOrder, true);
+    const outputVault = await getAssociatedTokenAddress(BTCMint, twapOrder, true);
+    const totalAmount = new anchor.BN(400 * 10 ** USDC.decimals);
+    const startSlot = await connection.getSlot();
+
+    // Four bands of 1000 slots, each capped to a quarter of the size.
+    await program.methods
+      .createTwap(
+        seed,
+        Side.Bid,
+        totalAmount,
+        new anchor.BN(startSlot),
+        new anchor.BN(startSlot + 3999),
+        new anchor.BN(1000),
+        new anchor.BN(0),
+      )
+      .accounts({
+        walletOwner: Alice.publicKey,
+        market: btcAddress,
+        inputMint: USDCMint,
+        outputMint: BTCMint,
+        source: aliceUsdcAccount,
+        destination: aliceBtcAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    await program.methods
+      .executeTwap()
+      .accounts({
+        keeper: keeper.publicKey,
+        twapOrder,
+        market: {
+          market: btcAddress,
+          requestQueue: requestQueue,
+          eventQueue: eventQueue,
+          marketBids: bids,
+          marketAsks: asks,
+          coinVault: baseVault,
+          pcVault: quoteVault,
+          vaultSigner: btcMarketVaultSigner,
+          coinWallet: outputVault,
+        },
+        pcWallet: escrow,
+        escrow,
+        outputVault,
+        destination: aliceBtcAccount,
+        state: state,
+        userStats: PublicKey.findProgramAddressSync(
+          [Buffer.from("user_stats"), Alice.publicKey.toBuffer()],
+          program.programId
+        )[0],
+        feeExemption: PublicKey.findProgramAddressSync(
+          [Buffer.from("fee_exemption"), Alice.publicKey.toBuffer()],
+          program.programId
+        )[0],
+        treasuryPc: await getAssociatedTokenAddress(USDCMint, treasury, true),
+        dexProgram: dexAddres,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([keeper])
+      .rpc({ skipPreflight: false });
+
+    const order = await program.account.twapOrder.fetch(twapOrder);
+    assert.ok(order.inputFilled.gtn(0));
+    assert.ok(order.inputFilled.lte(totalAmount.divn(4)));
+    assert.ok(order.averagePrice.gtn(0));
+
+    const usdcBalanceBefore = await connection.getTokenAccountBalance(aliceUsdcAccount);
+    await program.methods
+      .cancelTwap()
+      .accounts({
+        walletOwner: Alice.publicKey,
+        twapOrder,
+        escrow,
+        outputVault,
+        refundAccount: aliceUsdcAccount,
+        destination: aliceBtcAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([Alice])
+      .rpc({ skipPreflight: false });
+
+    const usdcBalanceAfter = await connection.getTokenAccountBalance(aliceUsdcAccount);
+    assert.equal(
+      new anchor.BN(usdcBalanceAfter.value.amount).sub(new anchor.BN(usdcBalanceBefore.value.amount)).toString(),
+      totalAmount.sub(order.inputFilled).toString()
+    );
+  });
+
+  it('should execute a USDC -> BTC trigger order only once the best ask crosses its threshold', async () => {
+    const keeper = Keypair.generate();
+    const airdrop = await connection.requestAirdrop(keeper.publicKey, LAMPORTS_PER_SOL);
+    await connection.confirmTransaction(airdrop);
+    const [treasury] = PublicKey.findProgramAddressSync(
+      [Buffer.from("treasury")],
+      program.programId
+    );
+    const amountIn = new anchor.BN(100 * 10 ** USDC.decimals);
+
+    const createTrigger = async (seed: anchor.BN, direction: object, threshold: anchor.BN) => {
+      const [triggerOrder] = PublicKey.findProgramAddressSync(
+        [Buffer.from("trigger_order"), Alice.publicKey.toBuffer(), seed.toArrayLike(Buffer, "le", 8)],
+        program.programId
+      );
+      await program.methods
+        .createTrigger(seed, Side.Bid, amountIn, new anchor.BN(0), { bestAsk: {} }, direction, threshold, new anchor.BN(10_000))
+        .accounts({
+          walletOwner: Alice.publicKey,
+          market: btcAddress,
+          oracle: null,
+          inputMint: USDCMint,
+          outputMint: BTCMint,
+          source: aliceUsdcAccount,
+          destination: aliceBtcAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([Alice])
+        .rpc({ skipPreflight: false });
+      return triggerOrder;
+    };
+    const trigger = async (triggerOrder: PublicKey) => {
+      const escrow = await getAssociatedTokenAddress(USDCMint, triggerOrder, true);
+      const outputVault = await getAssociatedTokenAddress(BTCMint, triggerOrder, true);
+      return program.methods
+        .trigger()
+        .accounts({
+          keeper: keeper.publicKey,
+          triggerOrder,
+          oracle: null,
+          market: {
+            market: btcAddress,
+            requestQueue: requestQueue,
+            eventQueue: eventQueue,
+            marketBids: bids,
+            marketAsks: asks,
+            coinVault: baseVault,
+            pcVault: quoteVault,
+            vaultSigner: btcMarketVaultSigner,
+            coinWallet: outputVault,
+          },
+          pcWallet: escrow,
+          escrow,
+          outputVault,
+          destination: aliceBtcAccount,
+          state: state,
+          treasuryPc: await getAssociatedTokenAddress(USDCMint, treasury, true),
+          dexProgram: dexAddres,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([keeper])
+        .rpc({ skipPreflight: false });
+    };
+
+    // A take-profit far above the book does not trigger.
+    const waiting = await createTrigger(new anchor.BN(1), { above: {} }, new anchor.BN(10).pow(new anchor.BN(15)));
+    try {
+      await trigger(waiting);
+      assert.fail("Triggered above the best ask");
+    } catch (err) {
+      assert.include(err.toString(), "TriggerConditionNotMet");
+    }
+
+    // A stop-loss above the book triggers right away.
+    const btcBalanceBefore = await connection.getTokenAccountBalance(aliceBtcAccount);
+    const crossed = await createTrigger(new anchor.BN(2), { below: {} }, new anchor.BN(10).pow(new anchor.BN(15)));
+    await trigger(crossed);
+    const btcBalanceAfter = await connection.getTokenAccountBalance(aliceBtcAccount);
+    assert.ok(btcBalanceAfter.value.uiAmount > btcBalanceBefore.value.uiAmount);
+    assert.ok((await program.account.triggerOrder.fetch(crossed)).triggered);
+  });
+
   it('Should fail to withdraw becuase not owner', async () => {
     const withdrawTx = await program.methods
         .withdraw()
-        .accounts({})
+        .accounts({ state: state })
         .signers([Alice])
         .rpc({ skipPreflight: false });
 
@@ -554,7 +1382,7 @@ describe("dexaggregator", () => {
   it("Should withdraw if owner is true", async () => {
     const withdrawTx = await program.methods
         .withdraw()
-        .accounts({})
+        .accounts({ state: state })
         .signers([marketsOwner])
         .rpc({ skipPreflight: false })
 